@@ -0,0 +1,36 @@
+DROP TABLE IF EXISTS booking;
+CREATE TABLE booking (
+    id SERIAL PRIMARY KEY,
+    customer_id INT NOT NULL REFERENCES customer(id) ON DELETE CASCADE,
+    shop_id INTEGER NOT NULL REFERENCES shop(id) ON DELETE CASCADE,
+    creation TIMESTAMP NOT NULL,
+    start_time TIMESTAMP NOT NULL,
+    duration INTEGER NOT NULL,
+    valid BOOLEAN NOT NULL,
+    active BOOLEAN NOT NULL,
+    CHECK(duration > 0 AND duration < 1440)
+);
+CREATE INDEX IF NOT EXISTS booking_start_time ON booking(start_time);
+
+DROP TABLE IF EXISTS booking_department;
+CREATE TABLE booking_department(
+    booking_id INT NOT NULL REFERENCES booking(id) ON DELETE CASCADE,
+    department_id INT NOT NULL REFERENCES department(id) ON DELETE CASCADE,
+    PRIMARY KEY (booking_id, department_id)
+);
+
+CREATE OR REPLACE FUNCTION check_booking_departments_same_shop() RETURNS TRIGGER
+    LANGUAGE PLPGSQL
+    AS
+    $$
+    BEGIN
+        IF (SELECT shop_id FROM booking WHERE id = NEW.booking_id) <> (SELECT shop_id FROM department WHERE id = NEW.department_id) THEN
+            RAISE EXCEPTION 'All of the departments of a booking must be from the same shop';
+        END IF;
+        RETURN NEW;
+    END;
+    $$;
+CREATE TRIGGER booking_departments_same_shop
+    BEFORE INSERT ON booking_department
+    FOR EACH ROW
+    EXECUTE FUNCTION check_booking_departments_same_shop();
@@ -0,0 +1,3 @@
+ALTER TABLE booking
+    ADD COLUMN entry TIMESTAMP,
+    ADD COLUMN exit TIMESTAMP;
@@ -0,0 +1,5 @@
+ALTER TABLE ticket
+    ALTER COLUMN customer_id DROP NOT NULL,
+    ADD COLUMN substitute BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN label VARCHAR,
+    ADD CHECK (customer_id IS NOT NULL OR substitute);
@@ -0,0 +1,8 @@
+ALTER TABLE staff
+    ADD COLUMN manager BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TABLE shop
+    ADD COLUMN hidden BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TABLE schedule
+    ADD CHECK (open < close);
@@ -0,0 +1,7 @@
+DROP TABLE IF EXISTS temp_staff;
+CREATE TABLE temp_staff (
+    code BYTEA PRIMARY KEY,
+    email VARCHAR UNIQUE NOT NULL,
+    shop_id INT NOT NULL REFERENCES shop(id) ON DELETE CASCADE,
+    manager BOOLEAN NOT NULL DEFAULT FALSE
+);
@@ -0,0 +1,6 @@
+ALTER TABLE customer
+    ADD COLUMN notify_minutes INTEGER NOT NULL DEFAULT 10,
+    ADD CHECK (notify_minutes >= 0);
+
+ALTER TABLE ticket
+    ADD COLUMN notified TIMESTAMP;
@@ -0,0 +1,17 @@
+CREATE OR REPLACE FUNCTION notify_ticket_event() RETURNS TRIGGER
+    LANGUAGE PLPGSQL
+    AS
+    $$
+    BEGIN
+        IF TG_OP = 'DELETE' THEN
+            PERFORM pg_notify('ticket_events', OLD.shop_id::text);
+        ELSE
+            PERFORM pg_notify('ticket_events', NEW.shop_id::text);
+        END IF;
+        RETURN NULL;
+    END;
+    $$;
+CREATE TRIGGER ticket_events
+    AFTER INSERT OR UPDATE OF entry, exit, valid, active OR DELETE ON ticket
+    FOR EACH ROW
+    EXECUTE FUNCTION notify_ticket_event();
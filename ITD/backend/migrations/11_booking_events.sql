@@ -0,0 +1,4 @@
+CREATE TRIGGER booking_events
+    AFTER UPDATE OF entry, exit ON booking
+    FOR EACH ROW
+    EXECUTE FUNCTION notify_ticket_event();
@@ -0,0 +1,9 @@
+DROP TABLE IF EXISTS customer_visit;
+CREATE TABLE customer_visit (
+    customer_id INT NOT NULL REFERENCES customer(id) ON DELETE CASCADE,
+    shop_id INT NOT NULL REFERENCES shop(id) ON DELETE CASCADE,
+    ma_visit REAL NOT NULL,
+    visits INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (customer_id, shop_id),
+    CHECK (visits >= 0)
+);
@@ -0,0 +1,42 @@
+ALTER TABLE shop
+    ADD COLUMN lat DOUBLE PRECISION,
+    ADD COLUMN lon DOUBLE PRECISION,
+    ADD COLUMN street VARCHAR,
+    ADD COLUMN city VARCHAR,
+    ADD COLUMN postal_code VARCHAR,
+    ADD COLUMN country VARCHAR;
+
+-- Parse locations in the form '45.4642N,9.1900E' or '45.4642,9.1900', out of range values are left empty
+UPDATE shop SET lat = parsed.lat, lon = parsed.lon
+FROM (
+    SELECT
+        id,
+        CASE WHEN upper(m[2]) = 'S' THEN -m[1]::DOUBLE PRECISION ELSE m[1]::DOUBLE PRECISION END AS lat,
+        CASE WHEN upper(m[4]) = 'W' THEN -m[3]::DOUBLE PRECISION ELSE m[3]::DOUBLE PRECISION END AS lon
+    FROM (
+        SELECT id, regexp_match(location, '^\s*(-?\d+(?:\.\d+)?)\s*([NnSs]?)\s*,\s*(-?\d+(?:\.\d+)?)\s*([EeWw]?)\s*$') AS m
+        FROM shop
+    ) matches
+    WHERE m IS NOT NULL
+) parsed
+WHERE
+    shop.id = parsed.id AND
+    parsed.lat BETWEEN -90 AND 90 AND
+    parsed.lon BETWEEN -180 AND 180;
+
+ALTER TABLE shop
+    ADD CHECK ((lat IS NULL) = (lon IS NULL)),
+    ADD CHECK (lat BETWEEN -90 AND 90),
+    ADD CHECK (lon BETWEEN -180 AND 180);
+
+-- Great-circle distance in km
+CREATE OR REPLACE FUNCTION distance_km(lat1 DOUBLE PRECISION, lon1 DOUBLE PRECISION, lat2 DOUBLE PRECISION, lon2 DOUBLE PRECISION) RETURNS DOUBLE PRECISION
+    LANGUAGE SQL
+    IMMUTABLE STRICT
+    AS
+    $$
+        SELECT 2 * 6371 * asin(least(1, sqrt(
+            power(sin(radians(lat2 - lat1) / 2), 2) +
+            cos(radians(lat1)) * cos(radians(lat2)) * power(sin(radians(lon2 - lon1) / 2), 2)
+        )))
+    $$;
@@ -0,0 +1,50 @@
+ALTER TABLE shop
+    ADD COLUMN search_document TSVECTOR NOT NULL DEFAULT ''::TSVECTOR;
+
+-- Name, description and department descriptions of a shop, weighted in this order
+CREATE OR REPLACE FUNCTION shop_search_document(shop_id INTEGER, name VARCHAR, description VARCHAR) RETURNS TSVECTOR
+    LANGUAGE SQL
+    STABLE
+    AS
+    $$
+        SELECT
+            setweight(to_tsvector('simple', name), 'A') ||
+            setweight(to_tsvector('simple', description), 'B') ||
+            setweight(to_tsvector('simple', COALESCE((SELECT string_agg(department.description, ' ') FROM department WHERE department.shop_id = $1), '')), 'C')
+    $$;
+
+CREATE OR REPLACE FUNCTION update_shop_search_document() RETURNS TRIGGER
+    LANGUAGE PLPGSQL
+    AS
+    $$
+    BEGIN
+        NEW.search_document := shop_search_document(NEW.id, NEW.name, NEW.description);
+        RETURN NEW;
+    END;
+    $$;
+CREATE TRIGGER shop_search_document
+    BEFORE INSERT OR UPDATE OF name, description ON shop
+    FOR EACH ROW
+    EXECUTE FUNCTION update_shop_search_document();
+
+CREATE OR REPLACE FUNCTION update_department_search_document() RETURNS TRIGGER
+    LANGUAGE PLPGSQL
+    AS
+    $$
+    BEGIN
+        IF TG_OP <> 'INSERT' THEN
+            UPDATE shop SET search_document = shop_search_document(id, name, description) WHERE id = OLD.shop_id;
+        END IF;
+        IF TG_OP <> 'DELETE' THEN
+            UPDATE shop SET search_document = shop_search_document(id, name, description) WHERE id = NEW.shop_id;
+        END IF;
+        RETURN NULL;
+    END;
+    $$;
+CREATE TRIGGER department_search_document
+    AFTER INSERT OR UPDATE OF description, shop_id OR DELETE ON department
+    FOR EACH ROW
+    EXECUTE FUNCTION update_department_search_document();
+
+UPDATE shop SET search_document = shop_search_document(id, name, description);
+CREATE INDEX shop_search_document_idx ON shop USING GIN (search_document);
@@ -0,0 +1,19 @@
+-- Incremented when the password changes, sessions created with an older version are no longer valid
+ALTER TABLE customer
+    ADD COLUMN session_version INT NOT NULL DEFAULT 0;
+ALTER TABLE staff
+    ADD COLUMN session_version INT NOT NULL DEFAULT 0;
+
+DROP TABLE IF EXISTS reset_customer;
+CREATE TABLE reset_customer (
+    code BYTEA PRIMARY KEY,
+    customer_id INT UNIQUE NOT NULL REFERENCES customer(id) ON DELETE CASCADE,
+    expiration TIMESTAMP NOT NULL
+);
+
+DROP TABLE IF EXISTS reset_staff;
+CREATE TABLE reset_staff (
+    code BYTEA PRIMARY KEY,
+    staff_id INT UNIQUE NOT NULL REFERENCES staff(id) ON DELETE CASCADE,
+    expiration TIMESTAMP NOT NULL
+);
@@ -0,0 +1,4 @@
+-- Pending registrations expire, rows created before this migration count as created now
+ALTER TABLE temp_customer
+    ADD COLUMN created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP;
+CREATE INDEX IF NOT EXISTS temp_customer_created ON temp_customer (created);
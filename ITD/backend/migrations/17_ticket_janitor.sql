@@ -0,0 +1,17 @@
+-- Exits logged by the janitor instead of the staff, excluded from the visit statistics
+ALTER TABLE ticket
+    ADD COLUMN auto_closed BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD CHECK (NOT auto_closed OR exit IS NOT NULL);
+CREATE INDEX IF NOT EXISTS ticket_open ON ticket(shop_id) WHERE active AND exit IS NULL;
+
+-- Automatic actions taken on tickets, shown to the staff of the shop
+DROP TABLE IF EXISTS ticket_action;
+CREATE TABLE ticket_action (
+    id SERIAL PRIMARY KEY,
+    ticket_id INT NOT NULL REFERENCES ticket(id) ON DELETE CASCADE,
+    shop_id INT NOT NULL REFERENCES shop(id) ON DELETE CASCADE,
+    action VARCHAR NOT NULL,
+    time TIMESTAMP NOT NULL,
+    CHECK (action IN ('expired', 'auto_closed'))
+);
+CREATE INDEX IF NOT EXISTS ticket_action_shop ON ticket_action(shop_id, time);
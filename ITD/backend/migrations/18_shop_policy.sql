@@ -0,0 +1,14 @@
+-- Ticket rules of a shop, shops without a row use the defaults and NULL means no limit
+DROP TABLE IF EXISTS shop_policy;
+CREATE TABLE shop_policy (
+    shop_id INT PRIMARY KEY REFERENCES shop(id) ON DELETE CASCADE,
+    ticket_expiry_hours INT,
+    max_queue INT,
+    walk_in BOOLEAN NOT NULL DEFAULT TRUE,
+    max_wait_minutes INT,
+    max_customer_tickets INT,
+    CHECK (ticket_expiry_hours > 0),
+    CHECK (max_queue >= 0),
+    CHECK (max_wait_minutes >= 0),
+    CHECK (max_customer_tickets > 0)
+);
@@ -0,0 +1,12 @@
+-- Opening hours of a shop on a specific date, they replace the weekly schedule of that date.
+-- A day with a single row without times is closed
+DROP TABLE IF EXISTS schedule_exception;
+CREATE TABLE schedule_exception (
+    shop_id INTEGER NOT NULL REFERENCES shop(id) ON DELETE CASCADE,
+    day DATE NOT NULL,
+    open TIME,
+    close TIME,
+    UNIQUE (shop_id, day, open),
+    CHECK ((open IS NULL) = (close IS NULL)),
+    CHECK (open < close)
+);
@@ -0,0 +1,20 @@
+-- IANA time zone of the shop, its schedule and exceptions are in local time
+ALTER TABLE shop
+    ADD COLUMN time_zone VARCHAR NOT NULL DEFAULT 'UTC';
+
+-- Ticket times were stored as UTC without a time zone,
+-- the event trigger depends on the columns and is recreated after the change
+DROP TRIGGER IF EXISTS ticket_events ON ticket;
+ALTER TABLE ticket
+    ALTER COLUMN creation TYPE TIMESTAMPTZ USING creation AT TIME ZONE 'UTC',
+    ALTER COLUMN expiration TYPE TIMESTAMPTZ USING expiration AT TIME ZONE 'UTC',
+    ALTER COLUMN entry TYPE TIMESTAMPTZ USING entry AT TIME ZONE 'UTC',
+    ALTER COLUMN exit TYPE TIMESTAMPTZ USING exit AT TIME ZONE 'UTC',
+    ALTER COLUMN notified TYPE TIMESTAMPTZ USING notified AT TIME ZONE 'UTC';
+CREATE TRIGGER ticket_events
+    AFTER INSERT OR UPDATE OF entry, exit, valid, active OR DELETE ON ticket
+    FOR EACH ROW
+    EXECUTE FUNCTION notify_ticket_event();
+
+ALTER TABLE ticket_action
+    ALTER COLUMN time TYPE TIMESTAMPTZ USING time AT TIME ZONE 'UTC';
@@ -0,0 +1,12 @@
+-- Ed25519 keys signing the offline tokens of a shop, the active key is the one not retired.
+-- Retired keys are still published until the tokens they signed expire
+DROP TABLE IF EXISTS signing_key;
+CREATE TABLE signing_key (
+    id SERIAL PRIMARY KEY,
+    shop_id INT NOT NULL REFERENCES shop(id) ON DELETE CASCADE,
+    public_key BYTEA NOT NULL,
+    secret_key BYTEA NOT NULL,
+    created TIMESTAMPTZ NOT NULL,
+    retired TIMESTAMPTZ
+);
+CREATE UNIQUE INDEX IF NOT EXISTS signing_key_active ON signing_key(shop_id) WHERE retired IS NULL;
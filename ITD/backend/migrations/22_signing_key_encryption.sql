@@ -0,0 +1,5 @@
+-- Secret keys are encrypted with the token key of the server, see `token_key` in the configuration.
+-- The keys stored in plain text can't be trusted anymore and are dropped, shops get a new one when they sign the next token
+DELETE FROM signing_key;
+ALTER TABLE signing_key
+    RENAME COLUMN secret_key TO encrypted_secret_key;
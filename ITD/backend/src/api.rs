@@ -2,4 +2,9 @@ pub mod account;
 pub mod dev;
 pub mod ticket;
 pub mod shop;
-pub mod staff;
\ No newline at end of file
+pub mod booking;
+pub mod staff;
+pub mod manage;
+pub mod occupancy;
+pub mod error;
+pub mod session_guard;
\ No newline at end of file
@@ -1,7 +1,11 @@
+use super::error::ApiError;
+use crate::config::Config;
+use crate::mail::{template, Mailer};
 use crate::models::customer::PersistentCustomer;
+use crate::models::notification;
 use crate::utils::session;
 
-use actix_web::{web, get, post, Responder, HttpResponse};
+use actix_web::{web, get, post, HttpResponse};
 use actix_session::Session;
 use sqlx::PgPool;
 use serde::{Serialize, Deserialize};
@@ -12,6 +16,11 @@ pub fn endpoints(cfg: &mut web::ServiceConfig) {
     cfg.service(register);
     cfg.service(confirm);
     cfg.service(whoami);
+    cfg.service(password_forgot);
+    cfg.service(password_reset);
+    cfg.service(password_change);
+    cfg.service(notification_settings);
+    cfg.service(notification_settings_edit);
 }
 #[allow(dead_code)]
 #[derive(Deserialize, Serialize, Debug)]
@@ -21,26 +30,24 @@ pub struct RequestLogin {
     pub remember: Option<bool>,
 }
 #[post("/login")]
-async fn login(conn: web::Data<PgPool>, body: web::Json<RequestLogin>, session: Session) -> HttpResponse {
+async fn login(conn: web::Data<PgPool>, body: web::Json<RequestLogin>, session: Session) -> Result<HttpResponse, ApiError> {
     let conn = conn.into_inner();
     let req = body.into_inner();
-    let error = HttpResponse::BadRequest().body("Invalid email or password");
-    let acc = PersistentCustomer::find(&conn, &req.email).await;
-    
-    if let Ok(Some(acc)) = acc {
+
+    if let Some(acc) = PersistentCustomer::find(&conn, &req.email).await? {
         let acc = acc.into_inner();
         if acc.verify_authentication(req.password.as_bytes()) {
-            session::set_account(&session, acc.id(), acc.email());
+            session::set_account(&session, acc.id(), acc.email(), acc.session_version());
 
             // session.renew();
-            HttpResponse::Ok().finish()
+            Ok(HttpResponse::Ok().finish())
         } else {
             log::debug!("Invalid password");
-            error
+            Err(ApiError::InvalidCredentials)
         }
     } else {
         log::debug!("Account does not exist");
-        error
+        Err(ApiError::InvalidCredentials)
     }
 }
 
@@ -56,41 +63,114 @@ pub struct RequestRegistration {
     pub email: String,
     pub password: String,
 }
+/// Start the registration of a customer, the confirmation link is sent by email
 #[post("/register")]
-async fn register(conn: web::Data<PgPool>, body: web::Json<RequestRegistration>) -> HttpResponse {
+async fn register(conn: web::Data<PgPool>, config: web::Data<Config>, mailer: web::Data<dyn Mailer>, body: web::Json<RequestRegistration>) -> Result<HttpResponse, ApiError> {
     let conn = conn.into_inner();
     let req = body.into_inner();
 
-    // if req.password.len() < 12 {return HttpResponse::BadRequest().body("Password too short")} // Left out for tesing purposes
+    // if req.password.len() < 12 {return Err(ApiError::invalid_field("password", "Password too short"))} // Left out for tesing purposes
+    if req.email.parse::<lettre::Address>().is_err() {
+        return Err(ApiError::invalid_field("email", "Invalid email address"));
+    }
 
-    let code = PersistentCustomer::create(&conn, &req.email, &req.password).await;
-    match code {
-        Ok(Some(c)) => HttpResponse::Ok().body(hex::encode(c)), // Final version will send it as email
-        Ok(None) => HttpResponse::BadRequest().body("Account already exists"),
-        Err(_) => HttpResponse::InternalServerError().finish()
+    let hours = config.account.registration_expiry_hours;
+    match PersistentCustomer::create(&conn, &req.email, &req.password, hours).await? {
+        Some(c) => {
+            send(&**mailer, template::confirmation(&req.email, &config.mail.base_url, &c, hours)).await?;
+            Ok(HttpResponse::Ok().finish())
+        }
+        None => Err(ApiError::AlreadyExists("Account already exists or its registration is pending".to_owned())),
     }
 }
 
+/// Send `email`, delivery errors are internal errors
+pub(super) async fn send(mailer: &dyn Mailer, email: crate::mail::Email) -> Result<(), ApiError> {
+    mailer.send(&email).await
+        .map_err(|e| ApiError::Internal(format!("Could not send email to {}: {}", email.to, e)))
+}
+
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct ConfirmQuery {
     pub code: String
 }
 #[get("/register/confirm")]
-async fn confirm(conn: web::Data<PgPool>, query: web::Query<ConfirmQuery>) -> impl Responder {
+async fn confirm(conn: web::Data<PgPool>, config: web::Data<Config>, query: web::Query<ConfirmQuery>) -> Result<HttpResponse, ApiError> {
     let conn = conn.into_inner();
     let q = query.into_inner();
 
-    if let Ok(code) = hex::decode(q.code) {
-        let res = PersistentCustomer::finalize(&conn, &code).await;
-        match res {
-            Ok(Some(_)) => HttpResponse::Ok().finish(),
-            Ok(None) => HttpResponse::BadRequest().finish(),
-            Err(_) => HttpResponse::InternalServerError().finish(),
-        }
-    } else {
-        HttpResponse::BadRequest().body("Invalid code format")
+    let code = hex::decode(q.code).map_err(|_| ApiError::invalid_field("code", "Invalid code format"))?;
+    match PersistentCustomer::finalize(&conn, &code, config.account.registration_expiry_hours).await? {
+        Some(_) => Ok(HttpResponse::Ok().finish()),
+        None => Err(ApiError::InvalidCode),
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PasswordForgotRequest {
+    pub email: String,
+}
+/// Start a password reset, the reset code is sent by email if the account exists
+#[post("/password/forgot")]
+async fn password_forgot(conn: web::Data<PgPool>, config: web::Data<Config>, mailer: web::Data<dyn Mailer>, body: web::Json<PasswordForgotRequest>) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let req = body.into_inner();
+
+    let minutes = config.account.reset_expiry_minutes;
+    match PersistentCustomer::create_reset(&conn, &req.email, minutes).await? {
+        Some(c) => send(&**mailer, template::password_reset(&req.email, &c, minutes)).await?,
+        None => log::debug!("Account does not exist"),
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PasswordResetRequest {
+    pub code: String,
+    pub password: String,
+}
+/// Choose a new password using a reset code, logs out every session of the account
+#[post("/password/reset")]
+async fn password_reset(conn: web::Data<PgPool>, body: web::Json<PasswordResetRequest>) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let req = body.into_inner();
+    if req.password.is_empty() {
+        return Err(ApiError::invalid_field("password", "Password must not be empty"));
     }
+
+    let code = hex::decode(req.code).map_err(|_| ApiError::invalid_field("code", "Invalid code format"))?;
+    match PersistentCustomer::reset_password(&conn, &code, &req.password).await? {
+        Some(_) => Ok(HttpResponse::Ok().finish()),
+        None => Err(ApiError::InvalidCode),
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PasswordChangeRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+/// Change the password of the logged in customer, logs out the other sessions of the account
+#[post("/password/change")]
+async fn password_change(conn: web::Data<PgPool>, body: web::Json<PasswordChangeRequest>, session: Session) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let req = body.into_inner();
+    let sess = session::get_account(&session).ok_or(ApiError::Forbidden)?;
+    if req.new_password.is_empty() {
+        return Err(ApiError::invalid_field("new_password", "Password must not be empty"));
+    }
+
+    let mut acc = PersistentCustomer::get(&conn, sess.id).await?
+        .ok_or(ApiError::NotFound("Customer"))?;
+    if !acc.inner().verify_authentication(req.current_password.as_bytes()) {
+        log::debug!("Invalid password");
+        return Err(ApiError::InvalidCredentials);
+    }
+
+    let acc = acc.update_password(&req.new_password).await?.inner();
+    session::set_account(&session, acc.id(), acc.email(), acc.session_version());
+    Ok(HttpResponse::Ok().finish())
 }
 
 #[derive(Serialize)]
@@ -109,4 +189,34 @@ async fn whoami(session: Session) -> HttpResponse {
     } else {
         HttpResponse::Ok().json(WhoamiResponse{authenticated: false, email: None})
     }
-}
\ No newline at end of file
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NotificationSettings {
+    /// Notify when the estimated wait drops below this number of minutes, 0 disables notifications
+    pub minutes: i32,
+}
+/// Get the notification settings of the customer
+#[get("/notifications")]
+async fn notification_settings(conn: web::Data<PgPool>, session: Session) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let sess = session::get_account(&session).ok_or(ApiError::Forbidden)?;
+
+    let minutes = notification::get_threshold(&conn, sess.id).await?
+        .ok_or(ApiError::NotFound("Customer"))?;
+    Ok(HttpResponse::Ok().json(NotificationSettings{minutes}))
+}
+
+/// Change the notification settings of the customer
+#[post("/notifications")]
+async fn notification_settings_edit(conn: web::Data<PgPool>, body: web::Json<NotificationSettings>, session: Session) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let req = body.into_inner();
+    let sess = session::get_account(&session).ok_or(ApiError::Forbidden)?;
+    if req.minutes < 0 {
+        return Err(ApiError::invalid_field("minutes", "Minutes must not be negative"));
+    }
+
+    notification::set_threshold(&conn, sess.id, req.minutes).await?;
+    Ok(HttpResponse::Ok().finish())
+}
@@ -0,0 +1,101 @@
+use super::error::ApiError;
+use crate::models::booking::{BookingResponse, NewBookingResult, PersistentBooking};
+use crate::models::shop::PersistentShop;
+use crate::utils::encoding::{decode_serial, decode_serial_vec};
+use crate::utils::session;
+
+use actix_web::{web, get, post, HttpResponse};
+use actix_session::Session;
+use chrono::{DateTime, NaiveDate, Utc};
+use sqlx::PgPool;
+use serde::{Serialize, Deserialize};
+
+pub fn endpoints(cfg: &mut web::ServiceConfig) {
+    cfg.service(booking_new);
+    cfg.service(booking_availability);
+    cfg.service(booking_cancel);
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BookingNewRequest {
+    pub department_ids: Vec<String>,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+}
+/// Book a visit to a shop for a future time slot
+#[post("/shop/{shop_id}/booking/new")]
+async fn booking_new(conn: web::Data<PgPool>, shop_id: web::Path<String>, body: web::Json<BookingNewRequest>, session: Session) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let shop_id = shop_id.into_inner();
+    let req = body.into_inner();
+    let sess = session::get_account(&session).ok_or(ApiError::Forbidden)?;
+
+    if req.department_ids.is_empty() {
+        return Err(ApiError::invalid_field("department_ids", "Must specify departments"));
+    }
+    if req.start_time < Utc::now() {
+        return Err(ApiError::invalid_field("start_time", "Bookings must start in the future"));
+    }
+    let duration = (req.end_time - req.start_time).num_minutes();
+    if duration <= 0 || duration >= 1440 {
+        return Err(ApiError::invalid_field("end_time", "Invalid booking duration"));
+    }
+
+    booking_new_inner(&conn, sess.id, &shop_id, req, duration as i32).await
+}
+
+async fn booking_new_inner(conn: &PgPool, customer_id: i32, shop_id: &str, req: BookingNewRequest, duration: i32) -> Result<HttpResponse, ApiError> {
+    let id = decode_serial(shop_id).map_err(|_| ApiError::InvalidId("shop_id"))?;
+    let shop = PersistentShop::get(conn, id).await?
+        .ok_or(ApiError::NotFound("Shop"))?;
+
+    let ids = decode_serial_vec(req.department_ids).map_err(|_| ApiError::InvalidId("department_ids"))?;
+
+    match PersistentBooking::try_new(conn, customer_id, shop.inner().id, ids, req.start_time.naive_utc(), duration).await? {
+        NewBookingResult::Created(b) =>
+            Ok(HttpResponse::Ok().json(BookingResponse::from(b.into_inner()))),
+        r => Err(r.into()),
+    }
+}
+
+#[derive(Deserialize)]
+struct AvailabilityQuery {
+    day: Option<NaiveDate>,
+}
+/// Get the number of places left for each department in the booking slots of a day, defaults to today in the time zone of the shop
+#[get("/shop/{shop_id}/booking/availability")]
+async fn booking_availability(conn: web::Data<PgPool>, shop_id: web::Path<String>, query: web::Query<AvailabilityQuery>, session: Session) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let shop_id = decode_serial(&shop_id.into_inner()).map_err(|_| ApiError::InvalidId("shop_id"))?;
+    if let (None, None) = (session::get_account(&session), session::get_staff_account(&session)) {
+        return Err(ApiError::Forbidden);
+    }
+    let shop = PersistentShop::get(&conn, shop_id).await?
+        .ok_or(ApiError::NotFound("Shop"))?;
+    let day = query.into_inner().day
+        .unwrap_or_else(|| Utc::now().with_timezone(&shop.inner().time_zone()).date().naive_local());
+
+    let v = PersistentBooking::availability(&conn, shop_id, day).await?;
+    Ok(HttpResponse::Ok().json(v))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BookingCancelRequest {
+    pub uid: String
+}
+/// Cancel a booking owned by the customer
+#[post("/booking/cancel")]
+async fn booking_cancel(conn: web::Data<PgPool>, body: web::Json<BookingCancelRequest>, session: Session) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let req = body.into_inner();
+    let sess = session::get_account(&session).ok_or(ApiError::Forbidden)?;
+    let bid = decode_serial(&req.uid).map_err(|_| ApiError::InvalidId("uid"))?;
+
+    match PersistentBooking::get(&conn, bid).await? {
+        Some(booking) if booking.inner().customer_id == sess.id => {
+            booking.cancel().await?;
+            Ok(HttpResponse::Ok().finish())
+        }
+        _ => Err(ApiError::NotFound("Booking")),
+    }
+}
@@ -4,6 +4,7 @@ use actix_web::{HttpResponse, web, get, post};
 use sqlx::{PgPool, query};
 use serde::{Serialize, Deserialize};
 
+use super::error::ApiError;
 use crate::models::shop::PersistentShop;
 use crate::models::staff::PersistentStaff;
 use crate::utils::encoding::{decode_serial, encode_serial};
@@ -54,7 +55,7 @@ async fn ids(conn: web::Data<PgPool>) -> HttpResponse {
     }
     body.push_str("\nTickets:\n");
     for row in tickets {
-        body.push_str(&format!("id: {}, shop_id: {}, customer_id: {}\n", encode_serial(row.id), encode_serial(row.shop_id), encode_serial(row.customer_id)));
+        body.push_str(&format!("id: {}, shop_id: {}, customer_id: {}\n", encode_serial(row.id), encode_serial(row.shop_id), row.customer_id.map(encode_serial).unwrap_or_else(|| "substitute".to_string())));
     }
 
     HttpResponse::Ok().body(body)
@@ -68,13 +69,13 @@ async fn setup_env(conn: web::Data<PgPool>) -> HttpResponse {
     let res: Result<(), Box<dyn Error>> = async {
         let mut tx = conn.begin().await?;
         
-        query!(r"INSERT INTO shop (id, name, description, image, location) VALUES
-            (1234111, 'Unes Milano', 'Unes via unes numero unes','test1.jpg','49.1234N,12.3456E'),
-            (1234222, 'Lidl Torino', 'Lidl via lidl numero lidl','test2.jpg','123.1234N,45.3456E'),
-            (1234333, 'Fruttivendolo da Attilio', 'Frutta e verdura','test3.jpg','2.1234S,23.3456W'),
-            (1234444, 'Casa dolce casa', 'Tutto per la casa','test4.jpg','46.1234S,23.3456W'),
-            (1234555, 'Green market sas', 'Frutta e verdura per tutti i gusti','test5.jpg','23.1234S,23.3456W'),
-            (1234666, 'ParmaTop Salumeria', 'La miglior mortadella di Parma','test6.jpg','5.1234S,123.3456E');")
+        query!(r"INSERT INTO shop (id, name, description, image, location, lat, lon, street, city, postal_code, country, time_zone) VALUES
+            (1234111, 'Unes Milano', 'Unes via unes numero unes','test1.jpg','45.4642N,9.1900E', 45.4642, 9.19, 'Via Unes 1', 'Milano', '20121', 'IT', 'Europe/Rome'),
+            (1234222, 'Lidl Torino', 'Lidl via lidl numero lidl','test2.jpg','45.0703N,7.6869E', 45.0703, 7.6869, 'Via Lidl 2', 'Torino', '10121', 'IT', 'Europe/Rome'),
+            (1234333, 'Fruttivendolo da Attilio', 'Frutta e verdura','test3.jpg','45.4781N,9.2270E', 45.4781, 9.227, NULL, 'Milano', NULL, 'IT', 'Europe/Rome'),
+            (1234444, 'Casa dolce casa', 'Tutto per la casa','test4.jpg','45.5845N,9.2744E', 45.5845, 9.2744, NULL, 'Monza', NULL, 'IT', 'Europe/Rome'),
+            (1234555, 'Green market sas', 'Frutta e verdura per tutti i gusti','test5.jpg','45.6983N,9.6773E', 45.6983, 9.6773, NULL, 'Bergamo', NULL, 'IT', 'Europe/Rome'),
+            (1234666, 'ParmaTop Salumeria', 'La miglior mortadella di Parma','test6.jpg','44.8015N,10.3279E', 44.8015, 10.3279, NULL, 'Parma', NULL, 'IT', 'Europe/Rome');")
             .execute(&mut tx)
             .await?;
             
@@ -156,39 +157,29 @@ pub struct NewStaffRequest {
     pub email: String,
     pub password: String,
     pub shop_id: String,
+    #[serde(default)]
+    pub manager: bool,
 }
 
 /// ### Create a new staff account
+/// Development only, managers create staff accounts with `/staff/manage/create-account/{shop_id}`
 #[post("/new_staff")]
-async fn new_staff(conn: web::Data<PgPool>, query: web::Json<NewStaffRequest>) -> HttpResponse {
+async fn new_staff(conn: web::Data<PgPool>, query: web::Json<NewStaffRequest>) -> Result<HttpResponse, ApiError> {
     let conn = conn.into_inner();
     let q = query.into_inner();
-    let shop_id = match decode_serial(&q.shop_id) {
-        Ok(s) => s,
-        _ => return HttpResponse::BadRequest().body("Invalid shop id format"),
-    };
-
-    match PersistentStaff::create(&conn, &q.email, &q.password, shop_id).await {
-        Ok(Some(_)) =>
-            HttpResponse::Ok().body(&format!(r#"Created staff for "{}" with email "{}" and password "{}""#, q.shop_id, q.email, q.password)),
-        Ok(None) => 
-            HttpResponse::Ok().body("A staff account with the same email already exists!"),
-        Err(e) => {
-            log::error!("Error in staff creation {}", e);
-            HttpResponse::InternalServerError().finish()
-        }
+    let shop_id = decode_serial(&q.shop_id).map_err(|_| ApiError::InvalidId("shop_id"))?;
+
+    match PersistentStaff::create(&conn, &q.email, &q.password, shop_id, q.manager).await? {
+        Some(_) =>
+            Ok(HttpResponse::Ok().body(format!(r#"Created staff for "{}" with email "{}""#, q.shop_id, q.email))),
+        None => 
+            Ok(HttpResponse::Ok().body("A staff account with the same email already exists!")),
     }
 }
 
 #[get("/shops")]
-async fn list_shops(conn: web::Data<PgPool>) -> HttpResponse {
+async fn list_shops(conn: web::Data<PgPool>) -> Result<HttpResponse, ApiError> {
     let conn = conn.into_inner();
-    match PersistentShop::search(&conn, None).await {
-        Ok(shops) => 
-            HttpResponse::Ok().json(shops),
-        Err(e) => {
-            log::error!("Error in search: {}", e);
-            HttpResponse::InternalServerError().finish()
-        },
-    }
+    let shops = PersistentShop::list(&conn).await?;
+    Ok(HttpResponse::Ok().json(shops))
 }
@@ -0,0 +1,313 @@
+use std::fmt;
+use std::num::ParseIntError;
+
+use actix_web::{web, HttpResponse, ResponseError};
+use actix_web::http::StatusCode;
+use serde::{Serialize, Deserialize};
+
+use crate::models::booking::NewBookingResult;
+use crate::models::shop::{DepartmentResult, ExceptionResult, ScheduleResult};
+use crate::models::ticket::{EnterResult, NewTicketResult};
+use crate::utils::encoding::encode_serial;
+
+/// Request field that was rejected and the reason
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+/// Body of every error response
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ErrorResponse {
+    /// Stable identifier of the error, clients should branch on this and not on the message
+    pub code: String,
+    /// Human readable description
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+}
+
+/// ## Error returned by the api handlers
+/// + Forbidden: Not logged in, or the account can't access the resource
+/// + InvalidCredentials: Wrong email or password
+/// + InvalidRequest(String): The request could not be parsed
+/// + InvalidField: A field of the request has an invalid value
+/// + InvalidId(&str): The field does not contain a valid id
+/// + InvalidCode: The confirmation or activation code is wrong or was already used
+/// + NotFound(&str): The requested resource does not exist
+/// + AlreadyExists(String): The resource can't be created because a conflicting one exists
+/// + ShopClosed: The shop does not accept new tokens for the requested time
+/// + QueueFull: The queue of the shop reached its maximum length
+/// + WaitTooLong(i32): The estimated wait in minutes is longer than the maximum accepted by the shop
+/// + TooManyTickets: The customer holds the maximum number of active tickets accepted by the shop
+/// + WalkInDisabled: The shop does not issue substitute tickets
+/// + DepartmentFull(i32): The department with the returned id has no places left
+/// + NotFirst(i64): The token is not first in line, returns the number of people ahead
+/// + TooEarly: The booked time slot has not started yet
+/// + TokenExpired: The token is expired
+/// + InvalidToken: The token is not valid for the operation
+/// + ConstraintViolation(String): The request breaks a constraint checked by the database, e.g. departments from another shop
+/// + Database(sqlx::Error): Unexpected database error
+/// + Internal(String): Unexpected error
+#[derive(Debug)]
+pub enum ApiError {
+    Forbidden,
+    InvalidCredentials,
+    InvalidRequest(String),
+    InvalidField {
+        field: &'static str,
+        message: String,
+    },
+    InvalidId(&'static str),
+    InvalidCode,
+    NotFound(&'static str),
+    AlreadyExists(String),
+    ShopClosed,
+    QueueFull,
+    WaitTooLong(i32),
+    TooManyTickets,
+    WalkInDisabled,
+    DepartmentFull(i32),
+    NotFirst(i64),
+    TooEarly,
+    TokenExpired,
+    InvalidToken,
+    ConstraintViolation(String),
+    Database(sqlx::Error),
+    Internal(String),
+}
+
+impl ApiError {
+    /// Shorthand for [`ApiError::InvalidField`]
+    pub fn invalid_field(field: &'static str, message: impl Into<String>) -> Self {
+        ApiError::InvalidField { field, message: message.into() }
+    }
+
+    /// Stable identifier sent to the client
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::Forbidden => "forbidden",
+            ApiError::InvalidCredentials => "invalid_credentials",
+            ApiError::InvalidRequest(_) => "invalid_request",
+            ApiError::InvalidField { .. } => "invalid_field",
+            ApiError::InvalidId(_) => "invalid_id",
+            ApiError::InvalidCode => "invalid_code",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::AlreadyExists(_) => "already_exists",
+            ApiError::ShopClosed => "shop_closed",
+            ApiError::QueueFull => "queue_full",
+            ApiError::WaitTooLong(_) => "wait_too_long",
+            ApiError::TooManyTickets => "too_many_tickets",
+            ApiError::WalkInDisabled => "walk_in_disabled",
+            ApiError::DepartmentFull(_) => "department_full",
+            ApiError::NotFirst(_) => "not_first",
+            ApiError::TooEarly => "too_early",
+            ApiError::TokenExpired => "token_expired",
+            ApiError::InvalidToken => "invalid_token",
+            ApiError::ConstraintViolation(_) => "constraint_violation",
+            ApiError::Database(_) | ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    fn fields(&self) -> Vec<FieldError> {
+        let field = |field: &str, message: String| vec![FieldError { field: field.to_owned(), message }];
+        match self {
+            ApiError::InvalidField { field: f, message } => field(f, message.clone()),
+            ApiError::InvalidId(f) => field(f, "Invalid id format".to_owned()),
+            ApiError::DepartmentFull(did) => field("department_ids", encode_serial(*did)),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Forbidden => write!(f, "Forbidden"),
+            ApiError::InvalidCredentials => write!(f, "Invalid email or password"),
+            ApiError::InvalidRequest(e) => write!(f, "Invalid request: {}", e),
+            ApiError::InvalidField { message, .. } => write!(f, "{}", message),
+            ApiError::InvalidId(field) => write!(f, "Invalid {} format", field),
+            ApiError::InvalidCode => write!(f, "Invalid or already used code"),
+            ApiError::NotFound(resource) => write!(f, "{} does not exist", resource),
+            ApiError::AlreadyExists(message) => write!(f, "{}", message),
+            ApiError::ShopClosed => write!(f, "The shop is closed"),
+            ApiError::QueueFull => write!(f, "The queue of the shop is full"),
+            ApiError::WaitTooLong(minutes) => write!(f, "The estimated wait of {} minutes is too long", minutes),
+            ApiError::TooManyTickets => write!(f, "Customer already has too many active tickets"),
+            ApiError::WalkInDisabled => write!(f, "The shop does not issue walk-in tickets"),
+            ApiError::DepartmentFull(did) => write!(f, "Department {} is full", encode_serial(*did)),
+            ApiError::NotFirst(n) => write!(f, "Not first in line, {} ahead", n),
+            ApiError::TooEarly => write!(f, "Too early"),
+            ApiError::TokenExpired => write!(f, "Expired"),
+            ApiError::InvalidToken => write!(f, "Invalid"),
+            ApiError::ConstraintViolation(message) => write!(f, "{}", message),
+            ApiError::Database(_) | ApiError::Internal(_) => write!(f, "Internal server error"),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::Forbidden => StatusCode::FORBIDDEN,
+            ApiError::Database(_) | ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            ApiError::Database(e) => log::error!("Database error: {}", e),
+            ApiError::Internal(e) => log::error!("Internal error: {}", e),
+            e => log::debug!("Request rejected: {}", e),
+        }
+        HttpResponse::build(self.status_code()).json(ErrorResponse {
+            code: self.code().to_owned(),
+            message: self.to_string(),
+            fields: self.fields(),
+        })
+    }
+}
+
+/// Violations of integrity constraints (class 23) and exceptions raised by the triggers (P0001)
+/// are caused by the request, all the other database errors are internal
+impl From<sqlx::Error> for ApiError {
+    fn from(e: sqlx::Error) -> Self {
+        match &e {
+            sqlx::Error::Database(db) if matches!(db.code(), Some(c) if c.starts_with("23") || c == "P0001") =>
+                ApiError::ConstraintViolation(db.message().to_owned()),
+            _ => ApiError::Database(e),
+        }
+    }
+}
+
+/// Ids that could not be decoded, use [`ApiError::InvalidId`] directly to name the field
+impl From<ParseIntError> for ApiError {
+    fn from(_: ParseIntError) -> Self {
+        ApiError::InvalidId("id")
+    }
+}
+
+/// Reason a token could not enter, `Entered` is not an error
+impl From<EnterResult> for ApiError {
+    fn from(r: EnterResult) -> Self {
+        match r {
+            EnterResult::Full(did) => ApiError::DepartmentFull(did),
+            EnterResult::NotFirst(n) => ApiError::NotFirst(n),
+            EnterResult::TooEarly => ApiError::TooEarly,
+            EnterResult::Closed => ApiError::ShopClosed,
+            EnterResult::Expired => ApiError::TokenExpired,
+            EnterResult::Invalid => ApiError::InvalidToken,
+            EnterResult::Entered => ApiError::Internal("Entered result converted to error".to_owned()),
+        }
+    }
+}
+
+/// Reason a ticket was not created, `Created` is not an error
+impl<'a> From<NewTicketResult<'a>> for ApiError {
+    fn from(r: NewTicketResult<'a>) -> Self {
+        match r {
+            NewTicketResult::AlreadyExists => ApiError::AlreadyExists("Customer already has an active ticket for that shop".to_owned()),
+            NewTicketResult::Closed => ApiError::ShopClosed,
+            NewTicketResult::QueueFull => ApiError::QueueFull,
+            NewTicketResult::WaitTooLong(minutes) => ApiError::WaitTooLong(minutes),
+            NewTicketResult::TooManyTickets => ApiError::TooManyTickets,
+            NewTicketResult::WalkInDisabled => ApiError::WalkInDisabled,
+            NewTicketResult::Created(_) => ApiError::Internal("Created result converted to error".to_owned()),
+        }
+    }
+}
+
+/// Reason a booking was not created, `Created` is not an error
+impl<'a> From<NewBookingResult<'a>> for ApiError {
+    fn from(r: NewBookingResult<'a>) -> Self {
+        match r {
+            NewBookingResult::AlreadyExists => ApiError::AlreadyExists("Customer already has a booking for that shop in this time slot".to_owned()),
+            NewBookingResult::Closed => ApiError::ShopClosed,
+            NewBookingResult::Full(did) => ApiError::DepartmentFull(did),
+            NewBookingResult::Created(_) => ApiError::Internal("Created result converted to error".to_owned()),
+        }
+    }
+}
+
+/// Reason a department was not created or edited, `Done` is not an error
+impl From<DepartmentResult> for ApiError {
+    fn from(r: DepartmentResult) -> Self {
+        match r {
+            DepartmentResult::NotFound => ApiError::NotFound("Department"),
+            DepartmentResult::AlreadyExists => ApiError::AlreadyExists("A department with the same description already exists".to_owned()),
+            DepartmentResult::InvalidCapacity => ApiError::invalid_field("capacity", "Capacity must not be negative"),
+            DepartmentResult::Done(_) => ApiError::Internal("Done result converted to error".to_owned()),
+        }
+    }
+}
+
+/// Reason a schedule was not updated, `Updated` is not an error
+impl From<ScheduleResult> for ApiError {
+    fn from(r: ScheduleResult) -> Self {
+        let message = match r {
+            ScheduleResult::InvalidDay(dow) => format!("Invalid day of the week {}", dow),
+            ScheduleResult::InvalidInterval(dow) => format!("Slot in day {} must open before closing", dow),
+            ScheduleResult::Overlapping(dow) => format!("Overlapping slots in day {}", dow),
+            ScheduleResult::Updated(_) => return ApiError::Internal("Updated result converted to error".to_owned()),
+        };
+        ApiError::invalid_field("weekly_schedule", message)
+    }
+}
+
+/// Reason a schedule exception was not updated, `Updated` is not an error
+impl From<ExceptionResult> for ApiError {
+    fn from(r: ExceptionResult) -> Self {
+        match r {
+            ExceptionResult::InPast => ApiError::invalid_field("day", "Day must not be in the past"),
+            ExceptionResult::InvalidInterval => ApiError::invalid_field("slots", "Slots must open before closing"),
+            ExceptionResult::Overlapping => ApiError::invalid_field("slots", "Overlapping slots"),
+            ExceptionResult::Updated(_) => ApiError::Internal("Updated result converted to error".to_owned()),
+        }
+    }
+}
+
+/// Answer with an [`ApiError`] when the path, the query or the json body of a request can't be parsed
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.app_data(web::JsonConfig::default()
+        .error_handler(|e, _| ApiError::InvalidRequest(e.to_string()).into()));
+    cfg.app_data(web::QueryConfig::default()
+        .error_handler(|e, _| ApiError::InvalidRequest(e.to_string()).into()));
+    cfg.app_data(web::PathConfig::default()
+        .error_handler(|e, _| ApiError::InvalidRequest(e.to_string()).into()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+
+    async fn render(e: ApiError) -> (StatusCode, ErrorResponse) {
+        let mut resp = e.error_response();
+        let body = test::load_stream(resp.take_body()).await.unwrap();
+        (resp.status(), serde_json::from_slice(&body).unwrap())
+    }
+
+    #[actix_rt::test]
+    async fn error_response_test() {
+        let (status, body) = render(ApiError::Forbidden).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body.code, "forbidden");
+        assert!(body.fields.is_empty());
+
+        let (status, body) = render(EnterResult::Full(1234).into()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body.code, "department_full");
+        assert_eq!(body.fields, vec![FieldError { field: "department_ids".into(), message: encode_serial(1234) }]);
+
+        let (_, body) = render("x".parse::<i32>().unwrap_err().into()).await;
+        assert_eq!(body.code, "invalid_id");
+
+        let (status, body) = render(sqlx::Error::RowNotFound.into()).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body.code, "internal_error");
+        assert_eq!(body.message, "Internal server error"); // Details are only logged
+    }
+}
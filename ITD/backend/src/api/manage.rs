@@ -0,0 +1,377 @@
+use super::account::send;
+use super::error::ApiError;
+use crate::config::Config;
+use crate::mail::{template, Mailer};
+use crate::models::policy::ShopPolicy;
+use crate::models::shop::{Address, DepartmentResponse, DepartmentResult, ExceptionResult, OpeningSlot, PersistentShop, ScheduleResult, ShopDetails};
+use crate::models::signing;
+use crate::models::staff::PersistentStaff;
+use crate::utils::encoding::decode_serial;
+use crate::utils::geo::{self, Coordinates};
+use crate::utils::session;
+
+use actix_web::{web, get, post, HttpResponse};
+use actix_session::Session;
+use chrono::{NaiveDate, NaiveTime};
+use chrono_tz::Tz;
+use sqlx::PgPool;
+use serde::{Serialize, Deserialize};
+
+/// Shop management endpoints, only available to managers.
+/// Endpoints acting on a shop are restricted to the managers of that shop
+pub fn endpoints(cfg: &mut web::ServiceConfig) {
+    cfg.service(shop_add);
+    cfg.service(shop_list);
+    cfg.service(shop_edit);
+    cfg.service(shop_show);
+    cfg.service(shop_hide);
+    cfg.service(department_add);
+    cfg.service(department_edit);
+    cfg.service(schedule_edit);
+    cfg.service(exception_list);
+    cfg.service(exception_edit);
+    cfg.service(policy_get);
+    cfg.service(policy_edit);
+    cfg.service(keys_rotate);
+    cfg.service(create_account);
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ShopRequest {
+    pub name: String,
+    pub description: String,
+    pub image: Option<String>,
+    pub location: String,
+    /// Coordinates of the shop, parsed from `location` if omitted
+    pub position: Option<Coordinates>,
+    #[serde(default)]
+    pub address: Address,
+    /// IANA name of the time zone of the shop, UTC for new shops and unchanged for existing ones if omitted
+    pub time_zone: Option<String>,
+}
+
+impl ShopRequest {
+    fn validate(&self) -> Result<(), ApiError> {
+        if self.name.trim().is_empty() {
+            return Err(ApiError::invalid_field("name", "Name must not be empty"));
+        }
+        if self.location.trim().is_empty() {
+            return Err(ApiError::invalid_field("location", "Location must not be empty"));
+        }
+        self.position()?;
+        self.time_zone().map(|_| ())
+    }
+
+    /// Coordinates to store for the shop, `Err` if the ones in the request are out of range
+    fn position(&self) -> Result<Option<Coordinates>, ApiError> {
+        match self.position {
+            Some(c) => Coordinates::new(c.lat, c.lon)
+                .map(Some)
+                .ok_or_else(|| ApiError::invalid_field("position", "Invalid position")),
+            None => Ok(geo::parse_location(&self.location)),
+        }
+    }
+
+    /// Time zone in the request, `Err` if it is not a known IANA time zone
+    fn time_zone(&self) -> Result<Option<Tz>, ApiError> {
+        self.time_zone.as_deref()
+            .map(|tz| tz.parse().map_err(|_| ApiError::invalid_field("time_zone", "Unknown time zone")))
+            .transpose()
+    }
+
+    /// Details to store for the shop, `Err` if position or time zone are invalid
+    fn details(self) -> Result<ShopDetails, ApiError> {
+        Ok(ShopDetails {
+            position: self.position()?,
+            time_zone: self.time_zone()?,
+            name: self.name,
+            description: self.description,
+            image: self.image,
+            location: self.location,
+            address: self.address,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ShopAddRequest {
+    #[serde(flatten)]
+    pub shop: ShopRequest,
+    /// Email of the manager of the new shop, the activation code of their account is sent to it
+    pub manager_email: String,
+}
+/// Create a new shop and the account of its manager, any manager can add shops
+/// but only the managers of a shop can manage it
+#[post("/shop/add")]
+async fn shop_add(conn: web::Data<PgPool>, mailer: web::Data<dyn Mailer>, body: web::Json<ShopAddRequest>, session: Session) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let req = body.into_inner();
+    session::get_manager_account(&session).ok_or(ApiError::Forbidden)?;
+    req.shop.validate()?;
+    if req.manager_email.trim().is_empty() {
+        return Err(ApiError::invalid_field("manager_email", "Email must not be empty"));
+    }
+
+    shop_add_inner(&conn, &**mailer, req).await
+}
+async fn shop_add_inner(conn: &PgPool, mailer: &dyn Mailer, req: ShopAddRequest) -> Result<HttpResponse, ApiError> {
+    match PersistentShop::create_with_manager(conn, &req.shop.details()?, &req.manager_email).await? {
+        Some((shop, code)) => {
+            send(mailer, template::staff_activation(&req.manager_email, &shop.inner().name, &code)).await?;
+            Ok(HttpResponse::Ok().json(shop.to_response().await?))
+        }
+        None => Err(ApiError::AlreadyExists("Account already exists".to_owned())),
+    }
+}
+
+/// List all visible shops and the shop of the manager, even if hidden
+#[get("/shop/list")]
+async fn shop_list(conn: web::Data<PgPool>, session: Session) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let sess = session::get_manager_account(&session).ok_or(ApiError::Forbidden)?;
+
+    let shops = PersistentShop::list_for_manager(&conn, sess.shop_id).await?;
+    Ok(HttpResponse::Ok().json(shops))
+}
+
+/// Edit name, description, image, location, address and time zone of a shop
+#[post("/shop/{shop_id}/edit")]
+async fn shop_edit(conn: web::Data<PgPool>, shop_id: web::Path<String>, body: web::Json<ShopRequest>, session: Session) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let req = body.into_inner();
+    let shop_id = shop_id.into_inner();
+    session::check_manager_auth(&session, &shop_id).ok_or(ApiError::Forbidden)?;
+    req.validate()?;
+
+    shop_edit_inner(&conn, &shop_id, req).await
+}
+async fn shop_edit_inner(conn: &PgPool, shop_id: &str, req: ShopRequest) -> Result<HttpResponse, ApiError> {
+    let mut shop = get_shop(conn, shop_id).await?;
+
+    shop.update(&req.details()?).await?;
+    Ok(HttpResponse::Ok().json(shop.to_response().await?))
+}
+
+/// Make a shop visible in search results
+#[post("/shop/{shop_id}/show")]
+async fn shop_show(conn: web::Data<PgPool>, shop_id: web::Path<String>, session: Session) -> Result<HttpResponse, ApiError> {
+    set_hidden(&conn, &shop_id.into_inner(), false, &session).await
+}
+
+/// Hide a shop from search results
+#[post("/shop/{shop_id}/hide")]
+async fn shop_hide(conn: web::Data<PgPool>, shop_id: web::Path<String>, session: Session) -> Result<HttpResponse, ApiError> {
+    set_hidden(&conn, &shop_id.into_inner(), true, &session).await
+}
+
+async fn set_hidden(conn: &PgPool, shop_id: &str, hidden: bool, session: &Session) -> Result<HttpResponse, ApiError> {
+    session::check_manager_auth(session, shop_id).ok_or(ApiError::Forbidden)?;
+
+    let mut shop = get_shop(conn, shop_id).await?;
+    shop.set_hidden(hidden).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Decode `shop_id` and retrieve the shop
+async fn get_shop<'a>(conn: &'a PgPool, shop_id: &str) -> Result<PersistentShop<'a>, ApiError> {
+    let id = decode_serial(shop_id).map_err(|_| ApiError::InvalidId("shop_id"))?;
+    PersistentShop::get(conn, id).await?
+        .ok_or(ApiError::NotFound("Shop"))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DepartmentAddRequest {
+    pub description: String,
+    pub capacity: i32,
+}
+/// Add a department to a shop
+#[post("/shop/{shop_id}/department/add")]
+async fn department_add(conn: web::Data<PgPool>, shop_id: web::Path<String>, body: web::Json<DepartmentAddRequest>, session: Session) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let req = body.into_inner();
+    let shop_id = shop_id.into_inner();
+    session::check_manager_auth(&session, &shop_id).ok_or(ApiError::Forbidden)?;
+    if req.description.trim().is_empty() {
+        return Err(ApiError::invalid_field("description", "Description must not be empty"));
+    }
+
+    department_add_inner(&conn, &shop_id, req).await
+}
+async fn department_add_inner(conn: &PgPool, shop_id: &str, req: DepartmentAddRequest) -> Result<HttpResponse, ApiError> {
+    let shop = get_shop(conn, shop_id).await?;
+
+    let result = shop.add_department(&req.description, req.capacity).await?;
+    department_response(result)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DepartmentEditRequest {
+    pub uid: String,
+    pub description: Option<String>,
+    pub capacity: Option<i32>,
+}
+/// Rename or resize a department
+#[post("/shop/{shop_id}/department/edit")]
+async fn department_edit(conn: web::Data<PgPool>, shop_id: web::Path<String>, body: web::Json<DepartmentEditRequest>, session: Session) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let req = body.into_inner();
+    let shop_id = shop_id.into_inner();
+    session::check_manager_auth(&session, &shop_id).ok_or(ApiError::Forbidden)?;
+    if matches!(&req.description, Some(d) if d.trim().is_empty()) {
+        return Err(ApiError::invalid_field("description", "Description must not be empty"));
+    }
+
+    department_edit_inner(&conn, &shop_id, req).await
+}
+async fn department_edit_inner(conn: &PgPool, shop_id: &str, req: DepartmentEditRequest) -> Result<HttpResponse, ApiError> {
+    let shop = get_shop(conn, shop_id).await?;
+
+    let did = decode_serial(&req.uid).map_err(|_| ApiError::InvalidId("uid"))?;
+    let result = shop.edit_department(did, req.description.as_deref(), req.capacity).await?;
+    department_response(result)
+}
+
+fn department_response(result: DepartmentResult) -> Result<HttpResponse, ApiError> {
+    match result {
+        DepartmentResult::Done(d) => Ok(HttpResponse::Ok().json(DepartmentResponse::from(d))),
+        r => Err(r.into()),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ScheduleSlotRequest {
+    /// Day of the week, 1 is Monday and 7 is Sunday
+    pub dow: i16,
+    pub open: NaiveTime,
+    pub close: NaiveTime,
+}
+#[derive(Serialize, Deserialize)]
+pub struct ScheduleEditRequest {
+    pub weekly_schedule: Vec<ScheduleSlotRequest>,
+}
+/// Replace the weekly schedule of a shop
+#[post("/shop/{shop_id}/schedule/edit")]
+async fn schedule_edit(conn: web::Data<PgPool>, shop_id: web::Path<String>, body: web::Json<ScheduleEditRequest>, session: Session) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let req = body.into_inner();
+    let shop_id = shop_id.into_inner();
+    session::check_manager_auth(&session, &shop_id).ok_or(ApiError::Forbidden)?;
+
+    schedule_edit_inner(&conn, &shop_id, req).await
+}
+async fn schedule_edit_inner(conn: &PgPool, shop_id: &str, req: ScheduleEditRequest) -> Result<HttpResponse, ApiError> {
+    let shop = get_shop(conn, shop_id).await?;
+
+    let slots = req.weekly_schedule.into_iter()
+        .map(|s| (s.dow, s.open, s.close))
+        .collect();
+    match shop.set_schedule(slots).await? {
+        ScheduleResult::Updated(s) => Ok(HttpResponse::Ok().json(s)),
+        r => Err(r.into()),
+    }
+}
+
+/// List the upcoming exceptions to the weekly schedule of a shop
+#[get("/shop/{shop_id}/schedule/exceptions")]
+async fn exception_list(conn: web::Data<PgPool>, shop_id: web::Path<String>, session: Session) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let shop_id = shop_id.into_inner();
+    session::check_manager_auth(&session, &shop_id).ok_or(ApiError::Forbidden)?;
+
+    let shop = get_shop(&conn, &shop_id).await?;
+    Ok(HttpResponse::Ok().json(shop.exceptions().await?))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExceptionEditRequest {
+    pub day: NaiveDate,
+    /// Opening hours of the day, an empty list closes the shop and `None` restores the weekly schedule
+    pub slots: Option<Vec<OpeningSlot>>,
+}
+/// Set or remove the exception to the weekly schedule of a shop for a day, returns the upcoming exceptions
+#[post("/shop/{shop_id}/schedule/exception/edit")]
+async fn exception_edit(conn: web::Data<PgPool>, shop_id: web::Path<String>, body: web::Json<ExceptionEditRequest>, session: Session) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let req = body.into_inner();
+    let shop_id = shop_id.into_inner();
+    session::check_manager_auth(&session, &shop_id).ok_or(ApiError::Forbidden)?;
+
+    let shop = get_shop(&conn, &shop_id).await?;
+    match shop.set_exception(req.day, req.slots).await? {
+        ExceptionResult::Updated(e) => Ok(HttpResponse::Ok().json(e)),
+        r => Err(r.into()),
+    }
+}
+
+/// Get the ticket policy of a shop
+#[get("/shop/{shop_id}/policy")]
+async fn policy_get(conn: web::Data<PgPool>, shop_id: web::Path<String>, session: Session) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let shop_id = shop_id.into_inner();
+    session::check_manager_auth(&session, &shop_id).ok_or(ApiError::Forbidden)?;
+
+    let shop = get_shop(&conn, &shop_id).await?;
+    let policy = ShopPolicy::load(&mut *conn.acquire().await?, shop.inner().id).await?;
+    Ok(HttpResponse::Ok().json(policy))
+}
+
+/// Replace the ticket policy of a shop, omitted limits are removed
+#[post("/shop/{shop_id}/policy/edit")]
+async fn policy_edit(conn: web::Data<PgPool>, shop_id: web::Path<String>, body: web::Json<ShopPolicy>, session: Session) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let policy = body.into_inner();
+    let shop_id = shop_id.into_inner();
+    session::check_manager_auth(&session, &shop_id).ok_or(ApiError::Forbidden)?;
+    if let Some((field, message)) = policy.check() {
+        return Err(ApiError::invalid_field(field, message));
+    }
+
+    let shop = get_shop(&conn, &shop_id).await?;
+    policy.save(&conn, shop.inner().id).await?;
+    Ok(HttpResponse::Ok().json(policy))
+}
+
+/// Replace the key signing the offline tokens of a shop, returns the published public keys.
+/// Tokens signed by the previous key stay valid until they expire
+#[post("/shop/{shop_id}/keys/rotate")]
+async fn keys_rotate(conn: web::Data<PgPool>, config: web::Data<Config>, shop_id: web::Path<String>, session: Session) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let shop_id = shop_id.into_inner();
+    session::check_manager_auth(&session, &shop_id).ok_or(ApiError::Forbidden)?;
+
+    let shop = get_shop(&conn, &shop_id).await?;
+    signing::rotate(&conn, &config.token_key, shop.inner().id, config.ticket.expiry_hours).await?;
+    let keys = signing::public_keys(&conn, &config.token_key, shop.inner().id, config.ticket.expiry_hours).await?;
+    Ok(HttpResponse::Ok().json(keys))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateAccountRequest {
+    pub email: String,
+    pub manager: Option<bool>,
+}
+/// Create a temporary staff account for a shop, the activation code the new
+/// staff member will use to choose a password is sent to their email
+#[post("/create-account/{shop_id}")]
+async fn create_account(conn: web::Data<PgPool>, mailer: web::Data<dyn Mailer>, shop_id: web::Path<String>, body: web::Json<CreateAccountRequest>, session: Session) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let req = body.into_inner();
+    let shop_id = shop_id.into_inner();
+    session::check_manager_auth(&session, &shop_id).ok_or(ApiError::Forbidden)?;
+    if req.email.trim().is_empty() {
+        return Err(ApiError::invalid_field("email", "Email must not be empty"));
+    }
+
+    create_account_inner(&conn, &**mailer, &shop_id, req).await
+}
+async fn create_account_inner(conn: &PgPool, mailer: &dyn Mailer, shop_id: &str, req: CreateAccountRequest) -> Result<HttpResponse, ApiError> {
+    let shop = get_shop(conn, shop_id).await?;
+
+    match PersistentStaff::create_temp(conn, &req.email, shop.inner().id, req.manager.unwrap_or(false)).await? {
+        Some(code) => {
+            send(mailer, template::staff_activation(&req.email, &shop.inner().name, &code)).await?;
+            Ok(HttpResponse::Ok().finish())
+        }
+        None => Err(ApiError::AlreadyExists("Account already exists".to_owned())),
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix::{Actor, ActorContext, AsyncContext, StreamHandler, WrapFuture, ActorFuture};
+use actix_web_actors::ws;
+use sqlx::PgPool;
+use serde::{Serialize, Deserialize};
+
+use crate::events::ShopEvents;
+use crate::models::shop::{DepartmentOccupancyResponse, PersistentShop};
+use crate::models::ticket::PersistentTicket;
+
+/// Interval between pings sent to the client
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+/// Close the connection if the client does not answer for this long
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// Occupancy change of a single department
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DepartmentDelta {
+    pub uid: String,
+    pub occupancy: i32,
+    /// Difference from the last value sent
+    pub change: i32,
+}
+
+/// ## Message pushed to staff terminals
+/// + Snapshot: Full occupancy status, sent once when the connection is opened
+/// + Delta: Departments whose occupancy changed since the last message and the current queue length
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OccupancyMessage {
+    Snapshot {
+        departments: Vec<DepartmentOccupancyResponse>,
+        queue: usize,
+    },
+    Delta {
+        departments: Vec<DepartmentDelta>,
+        queue: usize,
+    },
+}
+
+/// Last status sent to a client, used to compute the deltas
+#[derive(Debug, Default)]
+struct OccupancyState {
+    departments: HashMap<String, i32>,
+    queue: usize,
+}
+
+impl OccupancyState {
+    /// Update the state with the current status, returns the message to send or None if nothing changed
+    fn update(&mut self, departments: Vec<DepartmentOccupancyResponse>, queue: usize, first: bool) -> Option<OccupancyMessage> {
+        let mut deltas = Vec::new();
+        for d in departments.iter() {
+            let last = self.departments.insert(d.department.uid.clone(), d.occupancy).unwrap_or(0);
+            if last != d.occupancy {
+                deltas.push(DepartmentDelta {
+                    uid: d.department.uid.clone(),
+                    occupancy: d.occupancy,
+                    change: d.occupancy - last,
+                });
+            }
+        }
+        let queue_changed = self.queue != queue;
+        self.queue = queue;
+
+        if first {
+            Some(OccupancyMessage::Snapshot { departments, queue })
+        } else if !deltas.is_empty() || queue_changed {
+            Some(OccupancyMessage::Delta { departments: deltas, queue })
+        } else {
+            None
+        }
+    }
+}
+
+/// Websocket session pushing the occupancy of a shop to a staff terminal
+///
+/// The status is recomputed every time the queue or the bookings of the shop change,
+/// so that all the terminals of a shop show the same numbers.
+pub struct OccupancyFeed {
+    conn: Arc<PgPool>,
+    events: ShopEvents,
+    shop_id: i32,
+    state: Option<OccupancyState>,
+    heartbeat: Instant,
+}
+
+impl OccupancyFeed {
+    pub fn new(conn: Arc<PgPool>, events: ShopEvents, shop_id: i32) -> Self {
+        Self { conn, events, shop_id, state: None, heartbeat: Instant::now() }
+    }
+
+    /// Recompute the status and send the changes. Refreshes are serialized so the deltas are sent in order
+    fn refresh(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        let conn = self.conn.clone();
+        let shop_id = self.shop_id;
+        let fut = async move {
+            let departments = PersistentShop::get_occupancy(&conn, shop_id).await?;
+            let queue = PersistentTicket::queue(&conn, shop_id).await?.len();
+            Ok::<_, sqlx::Error>((departments, queue))
+        };
+
+        ctx.wait(fut.into_actor(self).map(|res, act, ctx| {
+            match res {
+                Ok((departments, queue)) => {
+                    let first = act.state.is_none();
+                    let msg = act.state.get_or_insert_with(OccupancyState::default)
+                        .update(departments, queue, first);
+                    if let Some(msg) = msg {
+                        ctx.text(serde_json::to_string(&msg).unwrap());
+                    }
+                }
+                Err(e) => log::error!("Error retrieving occupancy of shop {}: {}", act.shop_id, e),
+            }
+        }));
+    }
+}
+
+impl Actor for OccupancyFeed {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.add_stream(self.events.subscribe(self.shop_id));
+        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+            if act.heartbeat.elapsed() > CLIENT_TIMEOUT {
+                ctx.stop();
+            } else {
+                ctx.ping(b"");
+            }
+        });
+        self.refresh(ctx);
+    }
+}
+
+/// Changes to the shop published by [`ShopEvents`]
+impl StreamHandler<()> for OccupancyFeed {
+    fn handle(&mut self, _: (), ctx: &mut Self::Context) {
+        self.refresh(ctx);
+    }
+}
+
+/// Messages from the client, only used to keep the connection alive
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for OccupancyFeed {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Ping(m)) => {
+                self.heartbeat = Instant::now();
+                ctx.pong(&m);
+            }
+            Ok(ws::Message::Pong(_)) => self.heartbeat = Instant::now(),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Ok(_) => {}
+            Err(_) => ctx.stop(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn occupancy(uid: &str, occupancy: i32) -> DepartmentOccupancyResponse {
+        serde_json::from_value(serde_json::json!({
+            "department": { "uid": uid, "description": uid, "capacity": 10 },
+            "occupancy": occupancy,
+        })).unwrap()
+    }
+
+    #[test]
+    fn occupancy_delta_test() {
+        let mut state = OccupancyState::default();
+
+        match state.update(vec![occupancy("d1", 2), occupancy("d2", 0)], 3, true) {
+            Some(OccupancyMessage::Snapshot { departments, queue }) => {
+                assert_eq!(departments.len(), 2);
+                assert_eq!(queue, 3);
+            }
+            m => panic!("Expected snapshot, got {:?}", m),
+        }
+
+        assert!(state.update(vec![occupancy("d1", 2), occupancy("d2", 0)], 3, false).is_none());
+
+        match state.update(vec![occupancy("d1", 1), occupancy("d2", 0)], 3, false) {
+            Some(OccupancyMessage::Delta { departments, queue }) => {
+                assert_eq!(departments, vec![DepartmentDelta { uid: "d1".into(), occupancy: 1, change: -1 }]);
+                assert_eq!(queue, 3);
+            }
+            m => panic!("Expected delta, got {:?}", m),
+        }
+
+        match state.update(vec![occupancy("d1", 1), occupancy("d2", 0)], 2, false) {
+            Some(OccupancyMessage::Delta { departments, queue }) => {
+                assert!(departments.is_empty());
+                assert_eq!(queue, 2);
+            }
+            m => panic!("Expected delta, got {:?}", m),
+        }
+    }
+}
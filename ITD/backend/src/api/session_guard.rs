@@ -0,0 +1,87 @@
+use super::error::ApiError;
+use crate::models::customer::PersistentCustomer;
+use crate::models::staff::PersistentStaff;
+use crate::utils::session;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use actix_session::{Session, UserSession};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{web, Error};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use sqlx::PgPool;
+
+/// Middleware logging out the accounts whose session was created before the last password change,
+/// so that changing the password invalidates the other sessions of the account.
+/// It must be wrapped by the session middleware
+pub struct SessionGuard;
+
+impl<S, B> Transform<S> for SessionGuard
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = SessionGuardMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(SessionGuardMiddleware { service: Rc::new(RefCell::new(service)) })
+    }
+}
+
+pub struct SessionGuardMiddleware<S> {
+    service: Rc<RefCell<S>>,
+}
+
+impl<S, B> Service for SessionGuardMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let session = req.get_session();
+        let conn = req.app_data::<web::Data<PgPool>>().cloned();
+
+        Box::pin(async move {
+            if let Some(conn) = conn {
+                clear_stale(&conn, &session).await
+                    .map_err(ApiError::from)?;
+            }
+            let fut = service.borrow_mut().call(req);
+            fut.await
+        })
+    }
+}
+
+/// Clear the customer and the staff account from the session if their session version is outdated
+async fn clear_stale(conn: &PgPool, session: &Session) -> sqlx::Result<()> {
+    if let Some(sess) = session::get_account(session) {
+        if PersistentCustomer::session_version(conn, sess.id).await? != Some(sess.version) {
+            log::debug!("Outdated customer session for {}", sess.email);
+            session::clear_account(session);
+        }
+    }
+    if let Some(sess) = session::get_staff_account(session) {
+        if PersistentStaff::session_version(conn, sess.id).await? != Some(sess.version) {
+            log::debug!("Outdated staff session for {}", sess.email);
+            session::clear_staff_account(session);
+        }
+    }
+    Ok(())
+}
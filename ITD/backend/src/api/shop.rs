@@ -1,57 +1,118 @@
 
-use crate::models::shop::PersistentShop;
+use super::error::ApiError;
+use crate::config::Config;
+use crate::models::shop::{PersistentShop, SearchCursor, ShopSearch, DEFAULT_PAGE_SIZE};
+use crate::models::signing;
 use crate::utils::encoding::decode_serial;
+use crate::utils::geo::Coordinates;
 use crate::utils::session;
 
 use actix_web::{web, get, HttpResponse};
 use actix_session::Session;
+use chrono::Utc;
 use sqlx::PgPool;
 use serde::Deserialize;
 
 pub fn endpoints(cfg: &mut web::ServiceConfig) {
     cfg.service(shop_info);
+    cfg.service(shop_keys);
     cfg.service(search);
 }
 
 #[get("/shop/{shop_id}")]
-async fn shop_info(conn: web::Data<PgPool>, shop_id: web::Path<String>, session: Session) -> HttpResponse {
+async fn shop_info(conn: web::Data<PgPool>, shop_id: web::Path<String>, session: Session) -> Result<HttpResponse, ApiError> {
     let conn = conn.into_inner();
-    let shop_id = if let Ok(s) = decode_serial(&shop_id.into_inner()) {
-        s
-    } else {
-        return HttpResponse::BadRequest().body("Invalid shop id format")
-    };
+    let shop_id = decode_serial(&shop_id.into_inner()).map_err(|_| ApiError::InvalidId("shop_id"))?;
     if let (None, None) = (session::get_account(&session), session::get_staff_account(&session)) {
-        return HttpResponse::Forbidden().finish();
+        return Err(ApiError::Forbidden);
     }
 
-    if let Ok(Some(s)) = PersistentShop::get(&conn, shop_id).await {
-        if let Ok(resp) = s.to_response().await {
-            return HttpResponse::Ok().json(resp);
-        }
+    let shop = PersistentShop::get(&conn, shop_id).await?
+        .ok_or(ApiError::NotFound("Shop"))?;
+    Ok(HttpResponse::Ok().json(shop.to_response().await?))
+}
+
+/// Public keys verifying the offline tokens of a shop, newest first. Staff terminals should
+/// refresh them periodically, keys retired by a rotation are listed until the tokens they signed expire
+#[get("/shop/{shop_id}/keys")]
+async fn shop_keys(conn: web::Data<PgPool>, config: web::Data<Config>, shop_id: web::Path<String>, session: Session) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let shop_id = decode_serial(&shop_id.into_inner()).map_err(|_| ApiError::InvalidId("shop_id"))?;
+    if let (None, None) = (session::get_account(&session), session::get_staff_account(&session)) {
+        return Err(ApiError::Forbidden);
     }
-    HttpResponse::BadRequest().finish()
+
+    let shop = PersistentShop::get(&conn, shop_id).await?
+        .ok_or(ApiError::NotFound("Shop"))?;
+    let keys = signing::public_keys(&conn, &config.token_key, shop.inner().id, config.ticket.expiry_hours).await?;
+    Ok(HttpResponse::Ok().json(keys))
 }
 
+/// Maximum number of shops per page
+const MAX_PAGE_SIZE: i64 = 100;
+
 #[derive(Deserialize)]
 struct SearchQuery {
     q: Option<String>,
+    /// Sort results by distance from this position
+    lat: Option<f64>,
+    lon: Option<f64>,
+    /// Only return shops within this distance from the position
+    radius_km: Option<f64>,
+    /// Only return shops that are open now
+    #[serde(default)]
+    open_now: bool,
+    /// Only return shops with a department with this name
+    department: Option<String>,
+    /// Only return shops where a new ticket would wait at most this many minutes
+    max_wait: Option<f32>,
+    /// `next_cursor` of the previous page
+    cursor: Option<String>,
+    limit: Option<i64>,
 }
 
+/// Search shops by name, description and departments, ranked by relevance or sorted by distance
+/// from the position of the customer. Results are paginated, see [`SearchPage`](crate::models::shop::SearchPage)
 #[get("/search")]
-async fn search(conn: web::Data<PgPool>, query: web::Query<SearchQuery>, session: Session) -> HttpResponse {
+async fn search(conn: web::Data<PgPool>, query: web::Query<SearchQuery>, session: Session) -> Result<HttpResponse, ApiError> {
     let conn = conn.into_inner();
-    let q = query.into_inner().q;
+    let query = query.into_inner();
     if let (None, None) = (session::get_account(&session), session::get_staff_account(&session)) {
-        return HttpResponse::Forbidden().finish();
+        return Err(ApiError::Forbidden);
     }
 
-    match PersistentShop::search(&conn, q).await {
-        Ok(shops) => 
-            HttpResponse::Ok().json(shops),
-        Err(e) => {
-            log::error!("Error in search: {}", e);
-            HttpResponse::InternalServerError().finish()
-        },
+    let near = match (query.lat, query.lon) {
+        (Some(lat), Some(lon)) => Some(Coordinates::new(lat, lon)
+            .ok_or_else(|| ApiError::invalid_field("lat", "Invalid position"))?),
+        (None, None) => None,
+        _ => return Err(ApiError::invalid_field("lat", "Position must have both lat and lon")),
+    };
+    match query.radius_km {
+        Some(_) if near.is_none() => return Err(ApiError::invalid_field("radius_km", "radius_km requires a position")),
+        Some(r) if r.is_nan() || r < 0. => return Err(ApiError::invalid_field("radius_km", "Invalid radius")),
+        _ => {}
+    }
+
+    let cursor = match query.cursor.as_deref().map(SearchCursor::decode) {
+        Some(Some(c)) => Some(c),
+        Some(None) => return Err(ApiError::invalid_field("cursor", "Invalid cursor")),
+        None => None,
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Err(ApiError::invalid_field("limit", "Invalid limit"));
     }
+
+    let search = ShopSearch {
+        near,
+        radius_km: query.radius_km,
+        open_at: if query.open_now { Some(Utc::now()) } else { None },
+        department: query.department,
+        max_wait_minutes: query.max_wait,
+        cursor,
+        limit,
+        ..ShopSearch::new(query.q)
+    };
+    let page = PersistentShop::search(&conn, &search).await?;
+    Ok(HttpResponse::Ok().json(page))
 }
@@ -1,10 +1,19 @@
+use crate::models::booking::{BookingResponse, PersistentBooking};
 use crate::models::staff::PersistentStaff;
-use crate::models::ticket::{PersistentTicket, TicketResponse, EnterResult};
+use crate::models::ticket::{PersistentTicket, TicketResponse, EnterResult, NewTicketResult};
 use crate::models::shop::PersistentShop;
-use crate::utils::encoding::{decode_serial, encode_serial};
+use crate::models::signing;
+use crate::config::Config;
+use crate::events::ShopEvents;
+use crate::mail::{template, Mailer};
+use super::account::{send, PasswordChangeRequest, PasswordForgotRequest, PasswordResetRequest};
+use super::error::ApiError;
+use super::occupancy::OccupancyFeed;
+use crate::utils::encoding::{decode_serial, decode_serial_vec, encode_serial};
 use crate::utils::session;
 
-use actix_web::{web, get, post, HttpResponse};
+use actix_web::{web, get, post, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
 use actix_session::Session;
 use sqlx::PgPool;
 use serde::{Serialize, Deserialize};
@@ -12,13 +21,24 @@ use serde::{Serialize, Deserialize};
 pub fn endpoints(cfg: &mut web::ServiceConfig) {
     cfg.service(login);
     cfg.service(logout);
+    cfg.service(activate);
+    cfg.service(password_forgot);
+    cfg.service(password_reset);
+    cfg.service(password_change);
     cfg.service(token_info);
     cfg.service(log_entry);
     cfg.service(log_exit);
+    cfg.service(booking_list);
+    cfg.service(booking_log_entry);
+    cfg.service(booking_log_exit);
     cfg.service(ticket_queue);
+    cfg.service(ticket_new_substitute);
     cfg.service(ticket_skip);
+    cfg.service(ticket_actions);
     cfg.service(whoami);
     cfg.service(status);
+    cfg.service(status_live);
+    cfg.service(web::scope("/manage").configure(super::manage::endpoints));
 }
 #[allow(dead_code)]
 #[derive(Deserialize, Serialize, Debug)]
@@ -29,26 +49,24 @@ pub struct RequestLogin {
 }
 
 #[post("/login")]
-async fn login(conn: web::Data<PgPool>, body: web::Json<RequestLogin>, session: Session) -> HttpResponse {
+async fn login(conn: web::Data<PgPool>, body: web::Json<RequestLogin>, session: Session) -> Result<HttpResponse, ApiError> {
     let conn = conn.into_inner();
     let req = body.into_inner();
-    let error = HttpResponse::BadRequest().body("Invalid email or password");
-    let staff_acc = PersistentStaff::find(&conn, &req.email).await;
-    
-    if let Ok(Some(staff_acc)) = staff_acc {
+
+    if let Some(staff_acc) = PersistentStaff::find(&conn, &req.email).await? {
         let sa = staff_acc.into_inner();
         if sa.account().verify_authentication(req.password.as_bytes()) {
-            session::set_staff_account(&session, sa.account().id(), sa.account().email(), sa.shop_id());
+            session::set_staff_account(&session, sa.account().id(), sa.account().email(), sa.shop_id(), sa.is_manager(), sa.account().session_version());
 
             // session.renew();
-            HttpResponse::Ok().finish()
+            Ok(HttpResponse::Ok().finish())
         } else {
             log::debug!("Invalid password");
-            error
+            Err(ApiError::InvalidCredentials)
         }
     } else {
         log::debug!("Account does not exist");
-        error
+        Err(ApiError::InvalidCredentials)
     }
 }
 
@@ -59,28 +77,93 @@ async fn logout(_conn: web::Data<PgPool>, session: Session) -> HttpResponse {
     HttpResponse::Ok().finish()
 }
 
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ActivateRequest {
+    pub code: String,
+    pub password: String,
+}
+/// Activate a staff account created by a manager, choosing its password
+#[post("/activate")]
+async fn activate(conn: web::Data<PgPool>, body: web::Json<ActivateRequest>) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let req = body.into_inner();
+    if req.password.is_empty() {
+        return Err(ApiError::invalid_field("password", "Password must not be empty"));
+    }
+
+    let code = hex::decode(req.code).map_err(|_| ApiError::invalid_field("code", "Invalid code format"))?;
+    match PersistentStaff::activate(&conn, &code, &req.password).await? {
+        Some(_) => Ok(HttpResponse::Ok().finish()),
+        None => Err(ApiError::InvalidCode),
+    }
+}
+
+/// Start a password reset, the reset code is sent by email if the staff account exists
+#[post("/password/forgot")]
+async fn password_forgot(conn: web::Data<PgPool>, config: web::Data<Config>, mailer: web::Data<dyn Mailer>, body: web::Json<PasswordForgotRequest>) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let req = body.into_inner();
+
+    let minutes = config.account.reset_expiry_minutes;
+    match PersistentStaff::create_reset(&conn, &req.email, minutes).await? {
+        Some(c) => send(&**mailer, template::password_reset(&req.email, &c, minutes)).await?,
+        None => log::debug!("Account does not exist"),
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Choose a new password using a reset code, logs out every session of the staff account
+#[post("/password/reset")]
+async fn password_reset(conn: web::Data<PgPool>, body: web::Json<PasswordResetRequest>) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let req = body.into_inner();
+    if req.password.is_empty() {
+        return Err(ApiError::invalid_field("password", "Password must not be empty"));
+    }
+
+    let code = hex::decode(req.code).map_err(|_| ApiError::invalid_field("code", "Invalid code format"))?;
+    match PersistentStaff::reset_password(&conn, &code, &req.password).await? {
+        Some(_) => Ok(HttpResponse::Ok().finish()),
+        None => Err(ApiError::InvalidCode),
+    }
+}
+
+/// Change the password of the logged in staff member, logs out the other sessions of the account
+#[post("/password/change")]
+async fn password_change(conn: web::Data<PgPool>, body: web::Json<PasswordChangeRequest>, session: Session) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let req = body.into_inner();
+    let sess = session::get_staff_account(&session).ok_or(ApiError::Forbidden)?;
+    if req.new_password.is_empty() {
+        return Err(ApiError::invalid_field("new_password", "Password must not be empty"));
+    }
+
+    let mut staff = PersistentStaff::get(&conn, sess.id).await?
+        .ok_or(ApiError::NotFound("Staff"))?;
+    if !staff.inner().account().verify_authentication(req.current_password.as_bytes()) {
+        log::debug!("Invalid password");
+        return Err(ApiError::InvalidCredentials);
+    }
+
+    staff.update_password(&req.new_password).await?;
+    let sa = staff.inner();
+    session::set_staff_account(&session, sa.account().id(), sa.account().email(), sa.shop_id(), sa.is_manager(), sa.account().session_version());
+    Ok(HttpResponse::Ok().finish())
+}
+
 /// Show tickets currently in queue for this shop
 #[get("/shop/{shop_id}/ticket/queue")]
-async fn token_info(conn: web::Data<PgPool>, shop_id: web::Path<String>, session: Session) -> HttpResponse {
+async fn token_info(conn: web::Data<PgPool>, shop_id: web::Path<String>, session: Session) -> Result<HttpResponse, ApiError> {
     let conn = conn.into_inner();
-    let shop_id = if let Some(sess) = session::check_staff_auth(&session, &shop_id.into_inner()) {
-        sess.shop_id
-    } else {
-        return HttpResponse::Forbidden().finish();
-    };
-    
-    match PersistentTicket::queue(&conn, shop_id).await {
-        Ok(v) => {
-            let body: Vec<TicketResponse> = v.into_iter()
-                .map(TicketResponse::from)
-                .collect();
-            HttpResponse::Ok().json(body)
-        }
-        Err(e) => {
-            log::error!("Error retrieving ticket queue {}", e);
-            HttpResponse::InternalServerError().finish()
-        }
-    }
+    let shop_id = session::check_staff_auth(&session, &shop_id.into_inner())
+        .ok_or(ApiError::Forbidden)?
+        .shop_id;
+
+    let body: Vec<TicketResponse> = PersistentTicket::queue(&conn, shop_id).await?
+        .into_iter()
+        .map(TicketResponse::from)
+        .collect();
+    Ok(HttpResponse::Ok().json(body))
 }
 
 #[derive(Deserialize)]
@@ -89,120 +172,188 @@ struct TokenInfoQuery {
 }
 /// Show available information on a token
 #[get("/shop/{shop_id}/token/info")]
-async fn ticket_queue(conn: web::Data<PgPool>, shop_id: web::Path<String>, query: web::Query<TokenInfoQuery>, session: Session) -> HttpResponse {
+async fn ticket_queue(conn: web::Data<PgPool>, config: web::Data<Config>, shop_id: web::Path<String>, query: web::Query<TokenInfoQuery>, session: Session) -> Result<HttpResponse, ApiError> {
     let conn = conn.into_inner();
     let q = query.into_inner();
-    let s = if let Some(s) = session::check_staff_auth(&session, &shop_id.into_inner()) {
-        s
-    } else {
-        return HttpResponse::Forbidden().finish();
+    let s = session::check_staff_auth(&session, &shop_id.into_inner()).ok_or(ApiError::Forbidden)?;
+    let ticket_id = ticket_id(&conn, &config, s.shop_id, &q.uid).await?;
 
-    };
-    let ticket_id = match decode_serial(&q.uid) {
-        Ok(id) => id,
-        _ => return HttpResponse::BadRequest().body("Invalid token id format"),
-    };
-
-    match PersistentTicket::get(&conn, ticket_id).await {
-        Ok(Some(t)) if t.inner().shop_id == s.shop_id => 
-            HttpResponse::Ok().json(TicketResponse::from(t.into_inner())),
-        Ok(Some(_)) =>
-            HttpResponse::Forbidden().finish(),
-        Ok(None) => 
-            HttpResponse::Ok().json(()),
-        Err(e) => {
-            log::error!("Error retrieving ticket {}", e);
-            HttpResponse::InternalServerError().finish()
-        }
+    match PersistentTicket::get(&conn, ticket_id).await? {
+        Some(t) if t.inner().shop_id == s.shop_id =>
+            Ok(HttpResponse::Ok().json(TicketResponse::from(t.into_inner()))),
+        Some(_) => Err(ApiError::Forbidden),
+        None => Err(ApiError::NotFound("Ticket")),
     }
 }
 
 /// Get current occupancy information
 #[get("/shop/{shop_id}/status")]
-async fn status(conn: web::Data<PgPool>, shop_id: web::Path<String>, session: Session) -> HttpResponse {
+async fn status(conn: web::Data<PgPool>, shop_id: web::Path<String>, session: Session) -> Result<HttpResponse, ApiError> {
     let conn = conn.into_inner();
-    let s = if let Some(s) = session::check_staff_auth(&session, &shop_id.into_inner()) {
-        s
-    } else {
-        return HttpResponse::Forbidden().finish();
-    };
+    let s = session::check_staff_auth(&session, &shop_id.into_inner()).ok_or(ApiError::Forbidden)?;
+
+    let v = PersistentShop::get_occupancy(&conn, s.shop_id).await?;
+    Ok(HttpResponse::Ok().json(v))
+}
+
+/// Open a websocket receiving the occupancy of the shop and the length of the queue.
+/// A snapshot is sent when the connection is opened, then a delta every time a token
+/// enters, exits, is skipped or cancelled
+#[get("/shop/{shop_id}/status/live")]
+async fn status_live(req: HttpRequest, stream: web::Payload, conn: web::Data<PgPool>, events: web::Data<ShopEvents>, shop_id: web::Path<String>, session: Session) -> Result<HttpResponse, actix_web::Error> {
+    let s = session::check_staff_auth(&session, &shop_id.into_inner()).ok_or(ApiError::Forbidden)?;
+
+    let feed = OccupancyFeed::new(conn.into_inner(), events.get_ref().clone(), s.shop_id);
+    ws::start(feed, &req, stream)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SubstituteTicketRequest {
+    pub est_minutes: i32,
+    pub department_ids: Vec<String>,
+    pub label: Option<String>,
+}
+/// Issue a substitute ticket for a customer without a smartphone, the ticket joins the same queue as the others
+#[post("/shop/{shop_id}/ticket/new-substitute")]
+async fn ticket_new_substitute(conn: web::Data<PgPool>, config: web::Data<Config>, shop_id: web::Path<String>, body: web::Json<SubstituteTicketRequest>, session: Session) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let req = body.into_inner();
+    let shop_id = session::check_staff_auth(&session, &shop_id.into_inner())
+        .ok_or(ApiError::Forbidden)?
+        .shop_id;
+
+    if req.department_ids.is_empty() {
+        return Err(ApiError::invalid_field("department_ids", "Must specify departments"));
+    }
+    let ids = decode_serial_vec(req.department_ids).map_err(|_| ApiError::InvalidId("department_ids"))?;
+
+    match PersistentTicket::try_new_substitute(&conn, shop_id, ids, req.est_minutes, req.label, config.ticket.expiry_hours, config.ticket.closing_cutoff_minutes).await? {
+        NewTicketResult::Created(t) => {
+            let resp = signing::signed_responses(&conn, &config.token_key, vec![t.into_inner()]).await?;
+            Ok(HttpResponse::Ok().json(&resp[0]))
+        }
+        r => Err(r.into()),
+    }
+}
+
+/// Id of the ticket identified by `uid`, either its plain uid or an offline token that
+/// must have been signed by one of the published keys of `shop_id`
+async fn ticket_id(conn: &PgPool, config: &Config, shop_id: i32, uid: &str) -> Result<i32, ApiError> {
+    if !signing::is_token(uid) {
+        return decode_serial(uid).map_err(|_| ApiError::InvalidId("uid"));
+    }
 
-    if let Ok(v) = PersistentShop::get_occupancy(&conn, s.shop_id).await {
-        return HttpResponse::Ok().json(v);
+    let keys = signing::public_keys(conn, &config.token_key, shop_id, config.ticket.expiry_hours).await?;
+    match signing::verify(uid, &keys) {
+        Some(p) if p.shop == encode_serial(shop_id) =>
+            decode_serial(&p.ticket).map_err(|_| ApiError::InvalidToken),
+        _ => Err(ApiError::InvalidToken),
     }
-    HttpResponse::BadRequest().finish()
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct LogTicketRequest {
+    /// Plain uid of the ticket or its signed token
     pub uid: String,
 }
 /// Try to log the entry of a token
 #[post("/shop/{shop_id}/token/log-entry")]
-async fn log_entry(conn: web::Data<PgPool>, shop_id: web::Path<String>, query: web::Json<LogTicketRequest>, session: Session) -> HttpResponse {
+async fn log_entry(conn: web::Data<PgPool>, config: web::Data<Config>, shop_id: web::Path<String>, query: web::Json<LogTicketRequest>, session: Session) -> Result<HttpResponse, ApiError> {
     let conn = conn.into_inner();
     let q = query.into_inner();
-    if let None = session::check_staff_auth(&session, &shop_id.into_inner()) {
-        return HttpResponse::Forbidden().finish();
-    }
-    let ticket_id = match decode_serial(&q.uid) {
-        Ok(id) => id,
-        _ => return HttpResponse::BadRequest().body("Invalid token id format"),
-    };
-    
-    match log_entry_inner(&conn, ticket_id).await {
-        Ok(resp) => resp,
-        Err(e) => {
-            log::error!("Error logging entry: {}", e);
-            HttpResponse::InternalServerError().finish()
-        }
-    }
+    let s = session::check_staff_auth(&session, &shop_id.into_inner()).ok_or(ApiError::Forbidden)?;
+    let ticket_id = ticket_id(&conn, &config, s.shop_id, &q.uid).await?;
+
+    log_entry_inner(&conn, ticket_id).await
 }
-async fn log_entry_inner(conn: &PgPool, ticket_id: i32) -> sqlx::Result<HttpResponse> {
-    if let Some(ticket) = PersistentTicket::get(conn, ticket_id).await? {
-        let result = ticket.try_enter().await?;
-        match result {
-            EnterResult::Entered => Ok(HttpResponse::Ok().finish()),
-            EnterResult::Full(did) => Ok(HttpResponse::BadRequest().body(&format!("Department {} is full", encode_serial(did)))),
-            EnterResult::NotFirst(n) => Ok(HttpResponse::BadRequest().body(&format!("Not first in line, {} ahead", n))),
-            EnterResult::Expired => Ok(HttpResponse::BadRequest().body("Expired")),
-            EnterResult::Invalid => Ok(HttpResponse::BadRequest().body("Invalid"))
-        }
-    } else {
-        Ok(HttpResponse::BadRequest().body("Ticket does not exist"))
+async fn log_entry_inner(conn: &PgPool, ticket_id: i32) -> Result<HttpResponse, ApiError> {
+    let ticket = PersistentTicket::get(conn, ticket_id).await?
+        .ok_or(ApiError::NotFound("Ticket"))?;
+    match ticket.try_enter().await? {
+        EnterResult::Entered => Ok(HttpResponse::Ok().finish()),
+        r => Err(r.into()),
     }
 }
 
 #[post("/shop/{shop_id}/token/log-exit")]
-async fn log_exit(conn: web::Data<PgPool>, shop_id: web::Path<String>, query: web::Json<LogTicketRequest>, session: Session) -> HttpResponse {
+async fn log_exit(conn: web::Data<PgPool>, config: web::Data<Config>, shop_id: web::Path<String>, query: web::Json<LogTicketRequest>, session: Session) -> Result<HttpResponse, ApiError> {
     let conn = conn.into_inner();
     let q = query.into_inner();
-    if let None = session::check_staff_auth(&session, &shop_id.into_inner()) {
-        return HttpResponse::Forbidden().finish();
+    let s = session::check_staff_auth(&session, &shop_id.into_inner()).ok_or(ApiError::Forbidden)?;
+    let ticket_id = ticket_id(&conn, &config, s.shop_id, &q.uid).await?;
+
+    log_exit_inner(&conn, ticket_id).await
+}
+async fn log_exit_inner(conn: &PgPool, ticket_id: i32) -> Result<HttpResponse, ApiError> {
+    let ticket = PersistentTicket::get(conn, ticket_id).await?
+        .ok_or(ApiError::NotFound("Ticket"))?;
+    if ticket.exit().await? {
+        Ok(HttpResponse::Ok().finish())
+    } else {
+        Err(ApiError::InvalidToken)
     }
-    let ticket_id = match decode_serial(&q.uid) {
-        Ok(id) => id,
-        _ => return HttpResponse::BadRequest().body("Invalid token id format"),
-    };
-    
-    match log_exit_inner(&conn, ticket_id).await {
-        Ok(resp) => resp,
-        Err(e) => {
-            log::error!("Error logging exit: {}", e);
-            HttpResponse::InternalServerError().finish()
+}
+
+/// Show current and future bookings for this shop
+#[get("/shop/{shop_id}/booking/list")]
+async fn booking_list(conn: web::Data<PgPool>, shop_id: web::Path<String>, session: Session) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let shop_id = session::check_staff_auth(&session, &shop_id.into_inner())
+        .ok_or(ApiError::Forbidden)?
+        .shop_id;
+
+    let body: Vec<BookingResponse> = PersistentBooking::list(&conn, shop_id).await?
+        .into_iter()
+        .map(BookingResponse::from)
+        .collect();
+    Ok(HttpResponse::Ok().json(body))
+}
+
+/// Try to log the entry of a booking
+#[post("/shop/{shop_id}/booking/log-entry")]
+async fn booking_log_entry(conn: web::Data<PgPool>, shop_id: web::Path<String>, query: web::Json<LogTicketRequest>, session: Session) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let q = query.into_inner();
+    let shop_id = session::check_staff_auth(&session, &shop_id.into_inner())
+        .ok_or(ApiError::Forbidden)?
+        .shop_id;
+    let booking_id = decode_serial(&q.uid).map_err(|_| ApiError::InvalidId("uid"))?;
+
+    booking_log_entry_inner(&conn, shop_id, booking_id).await
+}
+async fn booking_log_entry_inner(conn: &PgPool, shop_id: i32, booking_id: i32) -> Result<HttpResponse, ApiError> {
+    match PersistentBooking::get(conn, booking_id).await? {
+        Some(booking) if booking.inner().shop_id == shop_id => {
+            match booking.try_enter().await? {
+                EnterResult::Entered => Ok(HttpResponse::Ok().finish()),
+                EnterResult::NotFirst(_) => Err(ApiError::InvalidToken),
+                r => Err(r.into()),
+            }
         }
+        Some(_) => Err(ApiError::Forbidden),
+        None => Err(ApiError::NotFound("Booking")),
     }
 }
-async fn log_exit_inner(conn: &PgPool, ticket_id: i32) -> sqlx::Result<HttpResponse> {
-    if let Some(ticket) = PersistentTicket::get(conn, ticket_id).await? {
-        let success = ticket.exit().await?;
-        if success {
-            Ok(HttpResponse::Ok().finish())
-        } else {
-            Ok(HttpResponse::BadRequest().finish())
-        }
+
+/// Try to log the exit of a booking
+#[post("/shop/{shop_id}/booking/log-exit")]
+async fn booking_log_exit(conn: web::Data<PgPool>, shop_id: web::Path<String>, query: web::Json<LogTicketRequest>, session: Session) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let q = query.into_inner();
+    let shop_id = session::check_staff_auth(&session, &shop_id.into_inner())
+        .ok_or(ApiError::Forbidden)?
+        .shop_id;
+    let booking_id = decode_serial(&q.uid).map_err(|_| ApiError::InvalidId("uid"))?;
+
+    let exited = match PersistentBooking::get(&conn, booking_id).await? {
+        Some(booking) if booking.inner().shop_id == shop_id => booking.exit().await?,
+        Some(_) => return Err(ApiError::Forbidden),
+        None => return Err(ApiError::NotFound("Booking")),
+    };
+    if exited {
+        Ok(HttpResponse::Ok().finish())
     } else {
-        Ok(HttpResponse::BadRequest().body("Ticket does not exist"))
+        Err(ApiError::InvalidToken)
     }
 }
 
@@ -212,42 +363,42 @@ struct TicketCancelRequest {
 }
 /// Skip and cancel a token for this shop. Intended use is skipping customers that are late.
 #[post("/shop/{shop_id}/token/skip")]
-async fn ticket_skip(conn: web::Data<PgPool>, body: web::Json<TicketCancelRequest>, session: Session) -> HttpResponse {
+async fn ticket_skip(conn: web::Data<PgPool>, body: web::Json<TicketCancelRequest>, session: Session) -> Result<HttpResponse, ApiError> {
     let conn = conn.into_inner();
     let req = body.into_inner();
-    let sess = if let Some(sess) = session::get_staff_account(&session) {
-        sess
-    } else {
-        return HttpResponse::Forbidden().finish();
-    };
-    let tid = if let Ok(tid) = decode_serial(&req.uid) {
-        tid
-    } else {
-        return HttpResponse::BadRequest().body("Invalid uid in query");
-    };
-
-    let t = PersistentTicket::get(&conn, tid).await;
+    let sess = session::get_staff_account(&session).ok_or(ApiError::Forbidden)?;
+    let tid = decode_serial(&req.uid).map_err(|_| ApiError::InvalidId("uid"))?;
 
-    if let Ok(Some(ticket)) = t {
-        if ticket.inner().shop_id == sess.shop_id {
-            if let Ok(_) = ticket.cancel().await {
-                HttpResponse::Ok().finish()
-            } else {
-                HttpResponse::InternalServerError().finish()
-            }
-        } else {
-            HttpResponse::Forbidden().finish()
+    match PersistentTicket::get(&conn, tid).await? {
+        Some(ticket) if ticket.inner().shop_id == sess.shop_id => {
+            ticket.cancel().await?;
+            Ok(HttpResponse::Ok().finish())
         }
-    } else {
-        HttpResponse::BadRequest().body("Ticket does not exist")
+        Some(_) => Err(ApiError::Forbidden),
+        None => Err(ApiError::NotFound("Ticket")),
     }
 }
 
+/// Number of automatic actions listed by [`ticket_actions`]
+const TICKET_ACTIONS_LIMIT: i64 = 100;
+
+/// List the last actions taken automatically on the tickets of this shop, most recent first:
+/// tickets that expired before entering and entries closed because their exit was never logged
+#[get("/shop/{shop_id}/ticket/actions")]
+async fn ticket_actions(conn: web::Data<PgPool>, shop_id: web::Path<String>, session: Session) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let s = session::check_staff_auth(&session, &shop_id.into_inner()).ok_or(ApiError::Forbidden)?;
+
+    let actions = PersistentTicket::actions(&conn, s.shop_id, TICKET_ACTIONS_LIMIT).await?;
+    Ok(HttpResponse::Ok().json(actions))
+}
+
 #[derive(Serialize)]
 struct WhoamiResponse {
     authenticated: bool,
     email: Option<String>,
     shop_id: Option<String>,
+    manager: bool,
 }
 /// Check the session and retrieve authentication status and email
 #[get("/whoami")]
@@ -256,10 +407,11 @@ async fn whoami(session: Session) -> HttpResponse {
         let body = WhoamiResponse{
                 authenticated: true,
                 email: Some(sess.email),
-                shop_id: Some(encode_serial(sess.shop_id))
+                shop_id: Some(encode_serial(sess.shop_id)),
+                manager: sess.manager,
         };
         return HttpResponse::Ok().json(body)
     } else {
-        HttpResponse::Ok().json(WhoamiResponse{authenticated: false, email: None, shop_id: None})
+        HttpResponse::Ok().json(WhoamiResponse{authenticated: false, email: None, shop_id: None, manager: false})
     }
 }
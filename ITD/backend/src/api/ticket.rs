@@ -1,238 +1,350 @@
-use std::error::Error;
-
+use super::error::ApiError;
+use crate::config::Config;
+use crate::models::booking::{BookingResponse, PersistentBooking};
 use crate::models::customer::PersistentCustomer;
-use crate::models::shop::PersistentShop;
-use crate::models::ticket::{NewTicketResult, PersistentTicket, TicketResponse};
+use crate::models::shop::{OpeningHours, PersistentShop};
+use crate::models::signing;
+use crate::events::{ShopEvents, Subscription};
+use crate::models::ticket::{NewTicketResult, PersistentTicket, Ticket, TicketResponse};
+use crate::travel::TravelTimeProvider;
 use crate::utils::encoding::{decode_serial, decode_serial_vec};
+use crate::utils::geo::Coordinates;
 use crate::utils::session;
 
 use actix_web::{web, get, post, HttpResponse};
+use actix_web::web::Bytes;
 use actix_session::Session;
 use chrono::{DateTime, Duration, Utc};
+use futures::future::{self, Either};
+use futures::{FutureExt, StreamExt};
 use sqlx::PgPool;
 use serde::{Serialize, Deserialize};
+use std::sync::Arc;
 
 pub fn endpoints(cfg: &mut web::ServiceConfig) {
     cfg.service(tokens);
     cfg.service(ticket_new);
     cfg.service(ticket_est);
+    cfg.service(ticket_events);
     cfg.service(ticket_queue);
     cfg.service(ticket_cancel);
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct TicketNewRequest {
-    pub est_minutes: i32,
+    /// Expected length of the visit, inferred from the past visits of the customer if omitted
+    pub est_minutes: Option<i32>,
     pub department_ids: Vec<String>,
 }
 #[post("/shop/{shop_id}/ticket/new")]
-async fn ticket_new(conn: web::Data<PgPool>, shop_id: web::Path<String>, body: web::Json<TicketNewRequest>, session: Session) -> HttpResponse {
+async fn ticket_new(conn: web::Data<PgPool>, config: web::Data<Config>, shop_id: web::Path<String>, body: web::Json<TicketNewRequest>, session: Session) -> Result<HttpResponse, ApiError> {
     let conn = conn.into_inner();
     let shop_id = shop_id.into_inner();
     let req = body.into_inner();
-    let sess = if let Some(sess) = session::get_account(&session) {
-        sess
-    } else {
-        return HttpResponse::Forbidden().finish();
-    };
+    let sess = session::get_account(&session).ok_or(ApiError::Forbidden)?;
 
-    if req.department_ids.len() == 0 {
-        return HttpResponse::BadRequest().body("Must specify departments");
+    if req.department_ids.is_empty() {
+        return Err(ApiError::invalid_field("department_ids", "Must specify departments"));
     }
 
-    match ticket_new_inner(&conn, sess.id, &shop_id, req).await {
-        Ok(resp) => resp,
-        Err(e) => {
-            log::error!("{}", e);
-            HttpResponse::BadRequest().finish()
-        }
-    }
+    ticket_new_inner(&conn, &config, sess.id, &shop_id, req).await
 }
 
-async fn ticket_new_inner<'a>(conn: &'a PgPool, customer_id: i32, shop_id: &str, req: TicketNewRequest) -> Result<HttpResponse, Box<dyn Error>>{
-    let id = decode_serial(shop_id)?;
-    let shop = if let Some(s) = PersistentShop::get(conn, id).await? {
-        s
-    } else {
-        return Ok(HttpResponse::BadRequest().body("Shop does not exist"));
-    };
+async fn ticket_new_inner(conn: &PgPool, config: &Config, customer_id: i32, shop_id: &str, req: TicketNewRequest) -> Result<HttpResponse, ApiError> {
+    let id = decode_serial(shop_id).map_err(|_| ApiError::InvalidId("shop_id"))?;
+    let shop = PersistentShop::get(conn, id).await?
+        .ok_or(ApiError::NotFound("Shop"))?;
 
-    let ids = decode_serial_vec(req.department_ids)?;
+    let ids = decode_serial_vec(req.department_ids).map_err(|_| ApiError::InvalidId("department_ids"))?;
 
-    let tick = PersistentTicket::try_new(&conn, customer_id, shop.inner().id, ids, req.est_minutes)
-        .await?;
-
-    match tick {
-        NewTicketResult::Created(t) =>
-            Ok(HttpResponse::Ok().json(TicketResponse::from(t.into_inner()))),
-        NewTicketResult::AlreadyExists =>
-            Ok(HttpResponse::BadRequest().body("Customer already has an active ticket for that shop")),
-        NewTicketResult::Closed =>
-            Ok(HttpResponse::BadRequest().body("Ticket creation for this shop is closed"))
+    match PersistentTicket::try_new(&conn, customer_id, shop.inner().id, ids, req.est_minutes, config.ticket.expiry_hours, config.ticket.closing_cutoff_minutes).await? {
+        NewTicketResult::Created(t) => {
+            let resp = signing::signed_responses(conn, &config.token_key, vec![t.into_inner()]).await?;
+            Ok(HttpResponse::Ok().json(&resp[0]))
+        }
+        r => Err(r.into()),
     }
 }
 
 /// Retrieve information about the length of the queue for this shop
 #[get("/shop/{shop_id}/ticket/queue")]
-async fn ticket_queue(conn: web::Data<PgPool>, shop_id: web::Path<String>, session: Session) -> HttpResponse {
+async fn ticket_queue(conn: web::Data<PgPool>, shop_id: web::Path<String>, session: Session) -> Result<HttpResponse, ApiError> {
     let conn = conn.into_inner();
-    let shop_id = if let Ok(s) = decode_serial(&shop_id.into_inner()) {
-        s
-    } else {
-        return HttpResponse::BadRequest().body("Shop does not exist");
-    };
-    
-    if let Some(_) = session::get_account(&session) {
-        match ticket_queue_inner(&conn, shop_id).await {
-            Ok(h) => h,
-            Err(e) => {
-                log::error!("{}", e);
-                HttpResponse::InternalServerError().finish()
-            }
-        }
-    } else {
-        HttpResponse::Forbidden().finish()
-    }
+    let shop_id = decode_serial(&shop_id.into_inner()).map_err(|_| ApiError::InvalidId("shop_id"))?;
+    session::get_account(&session).ok_or(ApiError::Forbidden)?;
+
+    ticket_queue_inner(&conn, shop_id).await
 }
-async fn ticket_queue_inner(conn: &PgPool, shop_id: i32) -> sqlx::Result<HttpResponse> {
+async fn ticket_queue_inner(conn: &PgPool, shop_id: i32) -> Result<HttpResponse, ApiError> {
     let people = PersistentTicket::queue(conn, shop_id).await?.len() as u32;
+    let w = PersistentTicket::est(conn, shop_id, None).await?;
 
-    PersistentTicket::est(conn, shop_id, None).await.map(|w|
-        HttpResponse::Ok().json(TicketEstResponse {
-            people,
-            est: Utc::now() + Duration::minutes((w * people as f32) as i64),
-        })
-    )
+    Ok(HttpResponse::Ok().json(TicketEstResponse {
+        people,
+        est: entry_time(conn, shop_id, (w * people as f32) as i64).await?,
+        leave_by: None,
+    }))
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct TokensResponse {
     pub tickets: Vec<TicketResponse>,
-    pub bookings: Vec<()>,
+    pub bookings: Vec<BookingResponse>,
 }
 /// List all owned active tokens
 #[get("/tokens")]
-async fn tokens(conn: web::Data<PgPool>, session: Session) -> HttpResponse {
+async fn tokens(conn: web::Data<PgPool>, config: web::Data<Config>, session: Session) -> Result<HttpResponse, ApiError> {
     let conn = conn.into_inner();
-    let sess = if let Some(sess) = session::get_account(&session) {
-        sess
-    } else {
-        return HttpResponse::Forbidden().finish();
-    };
+    let sess = session::get_account(&session).ok_or(ApiError::Forbidden)?;
 
-    match tokens_inner(&conn, sess.id).await {
-        Ok(resp) => resp,
-        Err(e) => {
-            log::error!("{}", e);
-            HttpResponse::InternalServerError().finish()
-        },
-    }
+    tokens_inner(&conn, &config, sess.id).await
 }
-async fn tokens_inner(conn: &PgPool, uid: i32) -> sqlx::Result<HttpResponse> {
-    let customer = PersistentCustomer::get(conn, uid).await?;
-    if let Some(_) = customer {
-        let tickets = PersistentTicket::get_for_customer(conn, uid).await?;
-        let ticket_resp: Vec<TicketResponse> = tickets.into_iter()
-            .map(|t|t.into())
-            .collect();
+async fn tokens_inner(conn: &PgPool, config: &Config, uid: i32) -> Result<HttpResponse, ApiError> {
+    if PersistentCustomer::get(conn, uid).await?.is_none() {
+        return Err(ApiError::NotFound("Customer"));
+    }
 
-        let resp = TokensResponse {
-            tickets: ticket_resp,
-            bookings: Vec::new(),
-        };
+    let tickets = PersistentTicket::get_for_customer(conn, uid).await?;
+    let ticket_resp = signing::signed_responses(conn, &config.token_key, tickets).await?;
 
-        Ok(HttpResponse::Ok().json(resp))
-    } else {
-        Ok(HttpResponse::BadRequest().finish())
-    }
+    let bookings = PersistentBooking::get_for_customer(conn, uid).await?;
+    let booking_resp: Vec<BookingResponse> = bookings.into_iter()
+        .map(|b|b.into())
+        .collect();
+
+    let resp = TokensResponse {
+        tickets: ticket_resp,
+        bookings: booking_resp,
+    };
+
+    Ok(HttpResponse::Ok().json(resp))
 }
 
 #[derive(Deserialize)]
 struct TicketEstQuery {
     pub uid: String,
+    /// Position of the customer, used to compute `leave_by`
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
 }
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct TicketEstResponse {
     pub people: u32,
     pub est: DateTime<Utc>,
+    /// Time the customer should leave to arrive at the shop by `est`,
+    /// only present if the position of the customer was sent and the travel time is known
+    pub leave_by: Option<DateTime<Utc>>,
 }
 /// Get the estimate wait time for this ticket
 #[get("/ticket/est")]
-async fn ticket_est(conn: web::Data<PgPool>, query: web::Query<TicketEstQuery>, session: Session) -> HttpResponse {
+async fn ticket_est(conn: web::Data<PgPool>, travel: web::Data<Box<dyn TravelTimeProvider>>, query: web::Query<TicketEstQuery>, session: Session) -> Result<HttpResponse, ApiError> {
     let conn = conn.into_inner();
     let q = query.into_inner();
-    
-    let tid = if let Ok(tid) = decode_serial(&q.uid) {
-        tid
+
+    let tid = decode_serial(&q.uid).map_err(|_| ApiError::InvalidId("uid"))?;
+
+    let position = match (q.lat, q.lon) {
+        (Some(lat), Some(lon)) => Some(Coordinates::new(lat, lon)
+            .ok_or_else(|| ApiError::invalid_field("lat", "Invalid position"))?),
+        (None, None) => None,
+        _ => return Err(ApiError::invalid_field("lat", "Position must have both lat and lon")),
+    };
+
+    let sess = session::get_account(&session).ok_or(ApiError::Forbidden)?;
+    ticket_est_inner(&conn, travel.get_ref().as_ref(), sess.id, tid, position).await
+}
+async fn ticket_est_inner(conn: &PgPool, travel: &dyn TravelTimeProvider, cid: i32, tid: i32, position: Option<Coordinates>) -> Result<HttpResponse, ApiError> {
+    let ticket = PersistentTicket::get(conn, tid).await?
+        .ok_or(ApiError::NotFound("Ticket"))?
+        .into_inner();
+    let now = Utc::now();
+    if !ticket.valid || !ticket.active || ticket.expiration < now || ticket.customer_id != Some(cid) {
+        log::debug!("Invalid ticket:\n{:?}", ticket);
+        return Err(ApiError::InvalidToken);
+    }
+    let queue = PersistentTicket::queue(conn, ticket.shop_id).await?;
+
+    let people = queue.into_iter()
+        .filter(|tick| tick.creation < ticket.creation)
+        .count() as u32;
+
+    let shop_id = ticket.shop_id;
+    let mut e = estimate(conn, ticket, people).await?;
+    if let Some(position) = position {
+        e.leave_by = leave_by(conn, travel, shop_id, position, e.est).await?;
+    }
+    Ok(HttpResponse::Ok().json(e))
+}
+
+/// Estimate the entry time of `ticket` with `people` ahead in queue
+async fn estimate(conn: &PgPool, ticket: Ticket, people: u32) -> sqlx::Result<TicketEstResponse> {
+    let shop_id = ticket.shop_id;
+    let w = PersistentTicket::est(conn, shop_id, Some(ticket)).await?;
+    Ok(TicketEstResponse {
+        people,
+        est: entry_time(conn, shop_id, (w * people as f32) as i64).await?,
+        leave_by: None,
+    })
+}
+
+/// Time at which `wait_minutes` of opening time of the shop have passed from now,
+/// the queue does not move while the shop is closed
+async fn entry_time(conn: &PgPool, shop_id: i32, wait_minutes: i64) -> sqlx::Result<DateTime<Utc>> {
+    let hours = OpeningHours::load(&mut *conn.acquire().await?, shop_id).await?;
+    Ok(hours.advance(Utc::now(), wait_minutes))
+}
+
+/// Latest time to leave `from` to arrive at the shop by `est`, never earlier than now.
+/// Returns `None` if the location of the shop is unknown or the travel time can't be computed
+async fn leave_by(conn: &PgPool, travel: &dyn TravelTimeProvider, shop_id: i32, from: Coordinates, est: DateTime<Utc>) -> sqlx::Result<Option<DateTime<Utc>>> {
+    let shop = match PersistentShop::get(conn, shop_id).await? {
+        Some(s) => s.into_inner(),
+        None => return Ok(None),
+    };
+    let to = if let Some(to) = shop.position() {
+        to
     } else {
-        return HttpResponse::BadRequest().body("Invalid uid in query");
+        log::debug!("Unknown coordinates for shop {}", shop_id);
+        return Ok(None);
     };
 
-    if let Some(sess) = session::get_account(&session) {
-        match ticket_est_inner(&conn, sess.id, tid).await {
-            Ok(h) => h,
-            Err(e) => {
-                log::error!("{}", e);
-                HttpResponse::InternalServerError().finish()
-            }
+    match travel.travel_time(from, to).await {
+        Ok(t) => {
+            let t = Duration::from_std(t).unwrap_or_else(|_| Duration::zero());
+            Ok(Some((est - t).max(Utc::now())))
+        }
+        Err(e) => {
+            log::warn!("Could not compute travel time to shop {}: {}", shop_id, e);
+            Ok(None)
         }
-    } else {
-        HttpResponse::Forbidden().finish()
     }
 }
-async fn ticket_est_inner(conn: &PgPool, cid: i32, tid: i32) -> sqlx::Result<HttpResponse> {
-    if let Some(t) = PersistentTicket::get(conn, tid).await? {
-        let ticket = t.into_inner();
-        let now = Utc::now().naive_utc();
-        if !ticket.valid || !ticket.active || ticket.expiration < now || ticket.customer_id != cid {
-            log::debug!("Invalid ticket:\n{:?}", ticket);
-            return Ok(HttpResponse::BadRequest().body("Expired or invalid ticket"));
+
+/// Seconds without changes after which a keep-alive comment is sent on event streams
+const KEEP_ALIVE_SECONDS: u64 = 30;
+
+/// Stream position in queue and estimated entry time of a ticket as Server-Sent Events
+/// + `position`: sent at the start and every time the estimate changes
+/// + `first`: the ticket is first in line, last event of the stream
+/// + `expired`: the ticket is not in queue anymore, last event of the stream
+#[get("/ticket/{uid}/events")]
+async fn ticket_events(conn: web::Data<PgPool>, events: web::Data<ShopEvents>, uid: web::Path<String>, session: Session) -> Result<HttpResponse, ApiError> {
+    let conn = conn.into_inner();
+    let sess = session::get_account(&session).ok_or(ApiError::Forbidden)?;
+    let tid = decode_serial(&uid.into_inner()).map_err(|_| ApiError::InvalidId("uid"))?;
+
+    let shop_id = match PersistentTicket::get(&conn, tid).await? {
+        Some(t) if t.inner().customer_id == Some(sess.id) => t.inner().shop_id,
+        _ => return Err(ApiError::NotFound("Ticket")),
+    };
+
+    let state = TicketEventStream {
+        conn,
+        shop_id,
+        ticket_id: tid,
+        changes: events.subscribe(shop_id),
+        last: None,
+        done: false,
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .streaming(Box::pin(futures::stream::unfold(state, TicketEventStream::next))))
+}
+
+/// State of a ticket event stream
+struct TicketEventStream {
+    conn: Arc<PgPool>,
+    shop_id: i32,
+    ticket_id: i32,
+    changes: Subscription,
+    last: Option<TicketEstResponse>,
+    done: bool,
+}
+
+impl TicketEventStream {
+    /// Wait for the next event to send
+    async fn next(mut self) -> Option<(Result<Bytes, actix_web::Error>, Self)> {
+        if self.done {
+            return None;
         }
-        let queue = PersistentTicket::queue(conn, ticket.shop_id).await?;
-
-        let people = queue.into_iter()
-            .filter(|tick| tick.creation < ticket.creation)
-            .count() as u32;
-
-        PersistentTicket::est(conn, ticket.shop_id, Some(ticket)).await.map(|w|
-            HttpResponse::Ok().json(TicketEstResponse {
-                people,
-                est: Utc::now() + Duration::minutes((w * people as f32) as i64),
-            })
-        )
-    } else {
-        Ok(HttpResponse::BadRequest().body("Ticket does not exist"))
+        loop {
+            let mut timed_out = false;
+            if self.last.is_some() {
+                let timeout = actix_web::rt::time::delay_for(std::time::Duration::from_secs(KEEP_ALIVE_SECONDS));
+                match future::select(self.changes.next(), Box::pin(timeout)).await {
+                    Either::Left((None, _)) => return None,
+                    Either::Left((Some(()), _)) => while let Some(Some(())) = self.changes.next().now_or_never() {},
+                    Either::Right(_) => timed_out = true,
+                }
+            }
+
+            let current = match self.current().await {
+                Ok(c) => c,
+                Err(e) => {
+                    log::error!("Error in ticket event stream: {}", e);
+                    return None;
+                }
+            };
+            let event = match current {
+                None => {
+                    self.done = true;
+                    Self::event("expired", &())
+                }
+                Some(e) if e.people == 0 => {
+                    self.done = true;
+                    Self::event("first", &e)
+                }
+                Some(e) if self.changed(&e) => {
+                    let event = Self::event("position", &e);
+                    self.last = Some(e);
+                    event
+                }
+                Some(_) if timed_out => Bytes::from_static(b": keep-alive\n\n"),
+                Some(_) => continue,
+            };
+            return Some((Ok(event), self));
+        }
+    }
+
+    /// Current estimate for the ticket, `None` if it is not in queue anymore
+    async fn current(&self) -> sqlx::Result<Option<TicketEstResponse>> {
+        let mut queue = PersistentTicket::queue(&self.conn, self.shop_id).await?;
+        match queue.iter().position(|t| t.id == self.ticket_id) {
+            Some(people) => Ok(Some(estimate(&self.conn, queue.swap_remove(people), people as u32).await?)),
+            None => Ok(None),
+        }
+    }
+
+    fn changed(&self, e: &TicketEstResponse) -> bool {
+        match &self.last {
+            Some(last) => last.people != e.people || (last.est - e.est).num_minutes() != 0,
+            None => true,
+        }
+    }
+
+    fn event<T: Serialize + ?Sized>(name: &str, data: &T) -> Bytes {
+        let data = serde_json::to_string(data).unwrap_or_default();
+        Bytes::from(format!("event: {}\ndata: {}\n\n", name, data))
     }
 }
 
-#[derive(Deserialize)]
-struct TicketCancelRequest {
+#[derive(Serialize, Deserialize)]
+pub struct TicketCancelRequest {
     pub uid: String
 }
 #[post("/ticket/cancel")]
-async fn ticket_cancel(conn: web::Data<PgPool>, body: web::Json<TicketCancelRequest>, session: Session) -> HttpResponse {
+async fn ticket_cancel(conn: web::Data<PgPool>, body: web::Json<TicketCancelRequest>, session: Session) -> Result<HttpResponse, ApiError> {
     let conn = conn.into_inner();
     let req = body.into_inner();
-    let sess = if let Some(sess) = session::get_account(&session) {
-        sess
-    } else {
-        return HttpResponse::Forbidden().finish();
-    };
-    let tid = if let Ok(tid) = decode_serial(&req.uid) {
-        tid
-    } else {
-        return HttpResponse::BadRequest().body("Invalid uid in query");
-    };
-
-    let t = PersistentTicket::get(&conn, tid).await;
+    let sess = session::get_account(&session).ok_or(ApiError::Forbidden)?;
+    let tid = decode_serial(&req.uid).map_err(|_| ApiError::InvalidId("uid"))?;
 
-    if let Ok(Some(ticket)) = t {
-        if ticket.inner().customer_id == sess.id {
-            if let Ok(_) = ticket.cancel().await {
-                return HttpResponse::Ok().finish()
-            }
+    match PersistentTicket::get(&conn, tid).await? {
+        Some(ticket) if ticket.inner().customer_id == Some(sess.id) => {
+            ticket.cancel().await?;
+            Ok(HttpResponse::Ok().finish())
         }
-        
+        _ => Err(ApiError::NotFound("Ticket")),
     }
-    HttpResponse::BadRequest().finish()
-}
\ No newline at end of file
+}
@@ -3,54 +3,125 @@ use actix_web::{App, HttpServer, web};
 use actix_redis::RedisSession;
 use actix_cors::Cors;
 use clup::api;
+use clup::api::session_guard::SessionGuard;
+use clup::cleanup::{RegistrationCleanup, TicketJanitor};
+use clup::config::{Config, CorsConfig, MailConfig, NotificationConfig, TravelConfig};
+use clup::events::ShopEvents;
+use clup::mail::{LogMailer, Mailer, MaildirMailer, SmtpMailer};
+use clup::notifications::{EmailNotifier, LogNotifier, NotificationScheduler, Notifier, WebhookNotifier};
+use clup::travel::{OsrmProvider, StraightLineProvider, TravelTimeProvider};
+use clup::utils::encoding;
 
-use std::env;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv::dotenv().ok();
     pretty_env_logger::init();
 
-    let conn_url = env::var("DATABASE_URL").expect("DATABASE_URL environment variable must be set");
-    let db_pool = clup::setup_db(&conn_url).await;
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    encoding::set_key(config.encoding_key);
 
-    let redis_url = env::var("REDIS_URL").expect("REDIS_URL environment variable must be set");
-    let key = session_key();
+    let db_pool = clup::setup_db_with(&config.database).await;
 
-    let api_url = env::var("API_URL").unwrap_or("0.0.0.0:5000".into());
-    HttpServer::new(move || {
-        let cors = Cors::default() // Dev purposes
-            .allow_any_origin()
-            .allow_any_method()
-            .allow_any_header()
-            .supports_credentials();
+    let mailer = mailer(&config.mail).unwrap_or_else(|e| {
+        eprintln!("Invalid mail configuration: {}", e);
+        std::process::exit(1);
+    });
+
+    let events = ShopEvents::new();
+    actix_web::rt::spawn(events.clone().listen(db_pool.clone()));
+
+    let notify_period = Duration::from_secs(config.notifications.interval_secs);
+    actix_web::rt::spawn(NotificationScheduler::new(db_pool.clone(), notifier(&config.notifications, mailer.clone()), notify_period).run());
+
+    let cleanup_period = Duration::from_secs(config.account.cleanup_interval_secs);
+    actix_web::rt::spawn(RegistrationCleanup::new(db_pool.clone(), config.account.registration_expiry_hours, cleanup_period).run());
 
+    let janitor_period = Duration::from_secs(config.ticket.janitor_interval_secs);
+    actix_web::rt::spawn(TicketJanitor::new(db_pool.clone(), config.ticket.max_visit_minutes, janitor_period).run());
+
+    let api_url = config.api_url.clone();
+    HttpServer::new(move || {
         App::new()
+        .wrap(SessionGuard)
         .wrap(Logger::default())
-        .wrap(RedisSession::new(&redis_url, &key)
-                    .cookie_same_site(actix_redis::SameSite::Lax) // Dev purposes
-                    // .cookie_secure(true) // Commented out for the prototype, production would have secure cookies
-                    .ttl(604800))
-        .wrap(cors)
+        .wrap(RedisSession::new(&config.session.redis_url, &config.session.key)
+                    .cookie_same_site(actix_redis::SameSite::Lax)
+                    .cookie_secure(config.is_production())
+                    .ttl(config.session.ttl_secs))
+        .wrap(cors(&config.cors))
         .data(db_pool.clone())
+        .data(events.clone())
+        .data(travel_provider(&config.travel))
+        .data(config.clone())
+        .app_data(web::Data::from(mailer.clone()))
+        .configure(api::error::config)
         .configure(api::account::endpoints)
         .configure(api::ticket::endpoints)
         .configure(api::shop::endpoints)
+        .configure(api::booking::endpoints)
         .service(web::scope("/staff").configure(api::staff::endpoints))
-        .service(web::scope("/dev").configure(api::dev::endpoints))
+        .configure(|cfg| {
+            // Development helpers create accounts without authentication, never expose them in production
+            if !config.is_production() {
+                cfg.service(web::scope("/dev").configure(api::dev::endpoints));
+            }
+        })
     })
     .bind(api_url)?
     .run()
     .await
 }
 
-/// For testing purposes this provides a default, it shouldn't in production
-fn session_key() -> Vec<u8> {
-    match env::var("SESSION_KEY") {
-        Ok(a) if a.len() > 0 => hex::decode(a).expect("Invalid SESSION_KEY format. Expected hex"),
-        _ => {
-            println!("WARNING USING DEFAULT SESSION KEY, THIS IS ENABLED ONLY FOR TESTING PURPOSES, DO NOT USE IN PRODUCTION");
-            vec![0; 32]
-        }
+/// Allow cross origin requests with credentials from the configured origins, any origin if `*` is one of them
+fn cors(config: &CorsConfig) -> Cors {
+    let cors = Cors::default()
+        .allow_any_method()
+        .allow_any_header()
+        .supports_credentials();
+
+    if config.allowed_origins.iter().any(|o| o == "*") {
+        cors.allow_any_origin()
+    } else {
+        config.allowed_origins.iter()
+            .fold(cors, |cors, origin| cors.allowed_origin(origin))
     }
-}
\ No newline at end of file
+}
+
+/// Choose how to deliver notifications, a webhook if `webhook_url` is set, otherwise by email if `email` is set,
+/// otherwise a file if `file` is set, otherwise the log
+fn notifier(config: &NotificationConfig, mailer: Arc<dyn Mailer>) -> Box<dyn Notifier> {
+    match (&config.webhook_url, config.email, &config.file) {
+        (Some(url), _, _) => Box::new(WebhookNotifier::new(url)),
+        (_, true, _) => Box::new(EmailNotifier::new(mailer)),
+        (_, _, Some(path)) => Box::new(LogNotifier::with_file(path.clone())),
+        _ => Box::new(LogNotifier::new()),
+    }
+}
+
+/// Choose how to deliver emails, an SMTP server if `smtp_host` is set,
+/// otherwise a maildir if `maildir` is set, otherwise the log
+fn mailer(config: &MailConfig) -> Result<Arc<dyn Mailer>, Box<dyn std::error::Error>> {
+    Ok(match (&config.smtp_host, &config.maildir) {
+        (Some(_), _) => Arc::new(SmtpMailer::new(config)?),
+        (_, Some(dir)) => Arc::new(MaildirMailer::new(dir, config.from.parse()?)),
+        _ => Arc::new(LogMailer),
+    })
+}
+
+/// Choose how to compute travel times, an OSRM compatible service if `osrm_url` is set
+/// (with the profile in `osrm_profile`, `foot` by default), otherwise the straight line distance
+fn travel_provider(config: &TravelConfig) -> Box<dyn TravelTimeProvider> {
+    match &config.osrm_url {
+        Some(url) => Box::new(OsrmProvider::new(url, &config.osrm_profile)),
+        None => Box::new(StraightLineProvider::default()),
+    }
+}
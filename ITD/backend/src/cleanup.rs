@@ -0,0 +1,77 @@
+use std::time::Duration;
+
+use sqlx::PgPool;
+
+use crate::models::customer::PersistentCustomer;
+use crate::models::ticket::PersistentTicket;
+
+/// Background job purging the pending registrations that were not confirmed in time
+///
+/// Expired registrations are already rejected on confirmation and replaced on registration,
+/// purging them keeps `temp_customer` from growing with abandoned sign-ups.
+pub struct RegistrationCleanup {
+    conn: PgPool,
+    valid_hours: i32,
+    period: Duration,
+}
+
+impl RegistrationCleanup {
+    pub fn new(conn: PgPool, valid_hours: i32, period: Duration) -> Self {
+        Self { conn, valid_hours, period }
+    }
+
+    /// Purge the expired registrations every `period` until the system is stopped
+    pub async fn run(self) {
+        let mut interval = actix_web::rt::time::interval(self.period);
+        loop {
+            interval.tick().await;
+            match self.run_once().await {
+                Ok(0) => {},
+                Ok(n) => log::info!("Purged {} expired registrations", n),
+                Err(e) => log::error!("Error purging registrations: {}", e),
+            }
+        }
+    }
+
+    /// Purge the expired registrations once, returns the number of purged registrations
+    pub async fn run_once(&self) -> sqlx::Result<u64> {
+        PersistentCustomer::purge_registrations(&self.conn, self.valid_hours).await
+    }
+}
+
+/// Background job sweeping the tickets nobody is going to use anymore
+///
+/// Tickets that expired before entering are removed from the queue, entries older than `max_visit_minutes`
+/// whose exit was never logged are closed so that they stop counting toward the occupancy.
+/// Every action is recorded for the staff of the shop, see [`PersistentTicket::actions`].
+pub struct TicketJanitor {
+    conn: PgPool,
+    max_visit_minutes: i32,
+    period: Duration,
+}
+
+impl TicketJanitor {
+    pub fn new(conn: PgPool, max_visit_minutes: i32, period: Duration) -> Self {
+        Self { conn, max_visit_minutes, period }
+    }
+
+    /// Sweep the tickets every `period` until the system is stopped
+    pub async fn run(self) {
+        let mut interval = actix_web::rt::time::interval(self.period);
+        loop {
+            interval.tick().await;
+            match self.run_once().await {
+                Ok((0, 0)) => {},
+                Ok((expired, closed)) => log::info!("Expired {} tickets, closed {} stale entries", expired, closed),
+                Err(e) => log::error!("Error sweeping tickets: {}", e),
+            }
+        }
+    }
+
+    /// Sweep the tickets once, returns the number of expired tickets and of closed entries
+    pub async fn run_once(&self) -> sqlx::Result<(u64, u64)> {
+        let expired = PersistentTicket::sweep_expired(&self.conn).await?;
+        let closed = PersistentTicket::close_stale(&self.conn, self.max_visit_minutes).await?;
+        Ok((expired, closed))
+    }
+}
@@ -0,0 +1,626 @@
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Environment variable with the path of the configuration file
+pub const CONFIG_FILE_VAR: &str = "CLUP_CONFIG";
+/// Configuration file read when [`CONFIG_FILE_VAR`] is not set, it is optional
+pub const DEFAULT_CONFIG_FILE: &str = "clup.toml";
+
+/// Session key used when none is configured, only accepted in development
+pub const DEFAULT_SESSION_KEY: [u8; 32] = [0; 32];
+/// Serial encoding key used when none is configured, only accepted in development
+pub const DEFAULT_ENCODING_KEY: u32 = 0xdeadbeef;
+/// Key encrypting the token signing keys used when none is configured, only accepted in development
+pub const DEFAULT_TOKEN_KEY: [u8; 32] = [0; 32];
+/// Hours a ticket stays valid if the customer does not show up
+pub const DEFAULT_TICKET_EXPIRY_HOURS: i32 = 6;
+/// Minutes before closing by which a customer joining the queue must be expected to enter
+pub const DEFAULT_CLOSING_CUTOFF_MINUTES: i32 = 15;
+/// Hours a pending registration stays valid if the customer does not confirm it
+pub const DEFAULT_REGISTRATION_EXPIRY_HOURS: i32 = 24;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Environment {
+    #[default]
+    Development,
+    Production,
+}
+
+/// ## Application configuration
+/// Loaded from a TOML file, every value can be overridden by the environment variable in brackets:
+/// + `environment` (`CLUP_ENV`): `development` or `production`, production refuses the default keys and disables the `/dev` endpoints
+/// + `api_url` (`API_URL`): address the server binds to
+/// + `encoding_key` (`ENCODING_KEY`): 4 hexadecimal bytes used to encode serials, see [`crate::utils::encoding`]
+/// + `token_key` (`TOKEN_KEY`): 32 hexadecimal bytes encrypting the keys that sign the offline tokens, see [`crate::models::signing`]
+/// + `database.url` (`DATABASE_URL`), `database.max_connections` (`DB_MAX_CONNECTIONS`)
+/// + `session.redis_url` (`REDIS_URL`), `session.key` (`SESSION_KEY`, hex, at least 32 bytes), `session.ttl_secs` (`SESSION_TTL_SECS`)
+/// + `cors.allowed_origins` (`CORS_ALLOWED_ORIGINS`, comma separated): origins allowed to call the api, `*` allows any
+/// + `ticket.expiry_hours` (`TICKET_EXPIRY_HOURS`)
+/// + `ticket.max_visit_minutes` (`TICKET_MAX_VISIT_MINUTES`): entries without an exit are closed after this,
+///   `ticket.janitor_interval_secs` (`TICKET_JANITOR_INTERVAL_SECS`): how often expired and stale tickets are swept
+/// + `ticket.closing_cutoff_minutes` (`TICKET_CLOSING_CUTOFF_MINUTES`): tickets are refused if the estimated entry
+///   is later than this many minutes before closing
+/// + `account.reset_expiry_minutes` (`RESET_EXPIRY_MINUTES`): validity of the password reset codes
+/// + `account.registration_expiry_hours` (`REGISTRATION_EXPIRY_HOURS`): validity of the pending registrations,
+///   `account.cleanup_interval_secs` (`ACCOUNT_CLEANUP_INTERVAL_SECS`): how often the expired ones are purged
+/// + `notifications.interval_secs` (`NOTIFY_INTERVAL_SECS`), `notifications.webhook_url` (`NOTIFY_WEBHOOK_URL`), `notifications.email` (`NOTIFY_EMAIL`), `notifications.file` (`NOTIFY_FILE`)
+/// + `mail.from` (`MAIL_FROM`), `mail.base_url` (`MAIL_BASE_URL`): sender of the emails and address of the frontend used in links
+/// + `mail.smtp_host` (`SMTP_HOST`), `mail.smtp_port` (`SMTP_PORT`), `mail.smtp_tls` (`SMTP_TLS`, `starttls`, `tls` or `none`),
+///   `mail.smtp_username` (`SMTP_USERNAME`), `mail.smtp_password` (`SMTP_PASSWORD`)
+/// + `mail.maildir` (`MAILDIR`): directory where emails are stored instead of being sent, if there is no SMTP host
+/// + `travel.osrm_url` (`OSRM_URL`), `travel.osrm_profile` (`OSRM_PROFILE`)
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub environment: Environment,
+    pub api_url: String,
+    #[serde(deserialize_with = "hex_u32")]
+    pub encoding_key: u32,
+    #[serde(deserialize_with = "hex_bytes")]
+    pub token_key: Vec<u8>,
+    pub database: DatabaseConfig,
+    pub session: SessionConfig,
+    pub cors: CorsConfig,
+    pub ticket: TicketConfig,
+    pub account: AccountConfig,
+    pub notifications: NotificationConfig,
+    pub mail: MailConfig,
+    pub travel: TravelConfig,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: String,
+    pub max_connections: u32,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    pub redis_url: String,
+    #[serde(deserialize_with = "hex_bytes")]
+    pub key: Vec<u8>,
+    pub ttl_secs: u32,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Same origin requests only if empty
+    pub allowed_origins: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TicketConfig {
+    pub expiry_hours: i32,
+    pub max_visit_minutes: i32,
+    pub janitor_interval_secs: u64,
+    pub closing_cutoff_minutes: i32,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountConfig {
+    pub reset_expiry_minutes: i32,
+    pub registration_expiry_hours: i32,
+    pub cleanup_interval_secs: u64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationConfig {
+    pub interval_secs: u64,
+    pub webhook_url: Option<String>,
+    /// Send notifications by email
+    pub email: bool,
+    pub file: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// Plain connection, only for local SMTP servers
+    None,
+    StartTls,
+    /// Implicit TLS
+    Tls,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MailConfig {
+    pub from: String,
+    pub base_url: String,
+    pub smtp_host: Option<String>,
+    pub smtp_port: u16,
+    pub smtp_tls: SmtpTls,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    pub maildir: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TravelConfig {
+    pub osrm_url: Option<String>,
+    pub osrm_profile: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            environment: Environment::default(),
+            api_url: "0.0.0.0:5000".to_owned(),
+            encoding_key: DEFAULT_ENCODING_KEY,
+            token_key: DEFAULT_TOKEN_KEY.to_vec(),
+            database: DatabaseConfig::default(),
+            session: SessionConfig::default(),
+            cors: CorsConfig::default(),
+            ticket: TicketConfig::default(),
+            account: AccountConfig::default(),
+            notifications: NotificationConfig::default(),
+            mail: MailConfig::default(),
+            travel: TravelConfig::default(),
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig { url: String::new(), max_connections: 8 }
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig { redis_url: String::new(), key: DEFAULT_SESSION_KEY.to_vec(), ttl_secs: 604800 }
+    }
+}
+
+impl Default for TicketConfig {
+    fn default() -> Self {
+        TicketConfig {
+            expiry_hours: DEFAULT_TICKET_EXPIRY_HOURS,
+            max_visit_minutes: 240,
+            janitor_interval_secs: 60,
+            closing_cutoff_minutes: DEFAULT_CLOSING_CUTOFF_MINUTES,
+        }
+    }
+}
+
+impl Default for AccountConfig {
+    fn default() -> Self {
+        AccountConfig {
+            reset_expiry_minutes: 60,
+            registration_expiry_hours: DEFAULT_REGISTRATION_EXPIRY_HOURS,
+            cleanup_interval_secs: 3600,
+        }
+    }
+}
+
+impl Default for NotificationConfig {
+    fn default() -> Self {
+        NotificationConfig { interval_secs: 30, webhook_url: None, email: false, file: None }
+    }
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        MailConfig {
+            from: "CLup <noreply@localhost>".to_owned(),
+            base_url: "http://localhost:8080".to_owned(),
+            smtp_host: None,
+            smtp_port: 587,
+            smtp_tls: SmtpTls::StartTls,
+            smtp_username: None,
+            smtp_password: None,
+            maildir: None,
+        }
+    }
+}
+
+impl Default for TravelConfig {
+    fn default() -> Self {
+        TravelConfig { osrm_url: None, osrm_profile: "foot".to_owned() }
+    }
+}
+
+/// ## Reason the configuration was rejected
+/// + Read: The configuration file could not be read
+/// + Parse: The configuration file is not valid TOML or has unknown or mistyped keys
+/// + InvalidVar: An environment variable has an invalid value
+/// + Missing: A required value is not set
+/// + Invalid: A value is out of range
+/// + Insecure: A default key is used in production
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    InvalidVar {
+        name: &'static str,
+        message: String,
+    },
+    Missing(&'static str),
+    Invalid(&'static str, String),
+    Insecure(&'static str),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "Could not read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "Invalid configuration file {}: {}", path.display(), e),
+            ConfigError::InvalidVar { name, message } => write!(f, "Invalid {} environment variable: {}", name, message),
+            ConfigError::Missing(key) => write!(f, "Missing {}, set it in the configuration file or through the environment", key),
+            ConfigError::Invalid(key, message) => write!(f, "Invalid {}: {}", key, message),
+            ConfigError::Insecure(key) => write!(f, "The default {} is insecure and can't be used in production", key),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Load the configuration from the file in `CLUP_CONFIG` (or `clup.toml` if present),
+    /// apply the overrides from the environment and validate it
+    pub fn load() -> Result<Config, ConfigError> {
+        let path = std::env::var(CONFIG_FILE_VAR).ok().filter(|p| !p.is_empty());
+        let config = match path {
+            Some(path) => Config::from_file(Path::new(&path))?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Config::from_file(Path::new(DEFAULT_CONFIG_FILE))?,
+            None => Config::default(),
+        };
+        config.with_env(|name| std::env::var(name).ok())?
+            .validate()
+    }
+
+    /// Parse a configuration file, values that are not present take the default
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let s = std::fs::read_to_string(path)
+            .map_err(|e| ConfigError::Read(path.to_owned(), e))?;
+        toml::from_str(&s)
+            .map_err(|e| ConfigError::Parse(path.to_owned(), e))
+    }
+
+    /// Override the values with the variables returned by `var`, empty variables are ignored
+    pub fn with_env<F: Fn(&str) -> Option<String>>(mut self, var: F) -> Result<Config, ConfigError> {
+        let var = |name: &str| var(name).filter(|v| !v.is_empty());
+
+        if let Some(v) = var("CLUP_ENV") {
+            self.environment = match &v[..] {
+                "development" => Environment::Development,
+                "production" => Environment::Production,
+                _ => return Err(invalid_var("CLUP_ENV", "expected development or production")),
+            };
+        }
+        if let Some(v) = var("API_URL") {
+            self.api_url = v;
+        }
+        if let Some(v) = var("ENCODING_KEY") {
+            self.encoding_key = parse_hex_u32(&v).map_err(|e| invalid_var("ENCODING_KEY", e))?;
+        }
+        if let Some(v) = var("TOKEN_KEY") {
+            self.token_key = hex::decode(v).map_err(|e| invalid_var("TOKEN_KEY", e))?;
+        }
+        if let Some(v) = var("DATABASE_URL") {
+            self.database.url = v;
+        }
+        if let Some(v) = var("DB_MAX_CONNECTIONS") {
+            self.database.max_connections = v.parse().map_err(|e| invalid_var("DB_MAX_CONNECTIONS", e))?;
+        }
+        if let Some(v) = var("REDIS_URL") {
+            self.session.redis_url = v;
+        }
+        if let Some(v) = var("SESSION_KEY") {
+            self.session.key = hex::decode(v).map_err(|e| invalid_var("SESSION_KEY", e))?;
+        }
+        if let Some(v) = var("SESSION_TTL_SECS") {
+            self.session.ttl_secs = v.parse().map_err(|e| invalid_var("SESSION_TTL_SECS", e))?;
+        }
+        if let Some(v) = var("CORS_ALLOWED_ORIGINS") {
+            self.cors.allowed_origins = v.split(',')
+                .map(|o| o.trim().to_owned())
+                .filter(|o| !o.is_empty())
+                .collect();
+        }
+        if let Some(v) = var("TICKET_EXPIRY_HOURS") {
+            self.ticket.expiry_hours = v.parse().map_err(|e| invalid_var("TICKET_EXPIRY_HOURS", e))?;
+        }
+        if let Some(v) = var("TICKET_MAX_VISIT_MINUTES") {
+            self.ticket.max_visit_minutes = v.parse().map_err(|e| invalid_var("TICKET_MAX_VISIT_MINUTES", e))?;
+        }
+        if let Some(v) = var("TICKET_JANITOR_INTERVAL_SECS") {
+            self.ticket.janitor_interval_secs = v.parse().map_err(|e| invalid_var("TICKET_JANITOR_INTERVAL_SECS", e))?;
+        }
+        if let Some(v) = var("TICKET_CLOSING_CUTOFF_MINUTES") {
+            self.ticket.closing_cutoff_minutes = v.parse().map_err(|e| invalid_var("TICKET_CLOSING_CUTOFF_MINUTES", e))?;
+        }
+        if let Some(v) = var("RESET_EXPIRY_MINUTES") {
+            self.account.reset_expiry_minutes = v.parse().map_err(|e| invalid_var("RESET_EXPIRY_MINUTES", e))?;
+        }
+        if let Some(v) = var("REGISTRATION_EXPIRY_HOURS") {
+            self.account.registration_expiry_hours = v.parse().map_err(|e| invalid_var("REGISTRATION_EXPIRY_HOURS", e))?;
+        }
+        if let Some(v) = var("ACCOUNT_CLEANUP_INTERVAL_SECS") {
+            self.account.cleanup_interval_secs = v.parse().map_err(|e| invalid_var("ACCOUNT_CLEANUP_INTERVAL_SECS", e))?;
+        }
+        if let Some(v) = var("NOTIFY_INTERVAL_SECS") {
+            self.notifications.interval_secs = v.parse().map_err(|e| invalid_var("NOTIFY_INTERVAL_SECS", e))?;
+        }
+        if let Some(v) = var("NOTIFY_WEBHOOK_URL") {
+            self.notifications.webhook_url = Some(v);
+        }
+        if let Some(v) = var("NOTIFY_EMAIL") {
+            self.notifications.email = v.parse().map_err(|e| invalid_var("NOTIFY_EMAIL", e))?;
+        }
+        if let Some(v) = var("NOTIFY_FILE") {
+            self.notifications.file = Some(v);
+        }
+        if let Some(v) = var("MAIL_FROM") {
+            self.mail.from = v;
+        }
+        if let Some(v) = var("MAIL_BASE_URL") {
+            self.mail.base_url = v;
+        }
+        if let Some(v) = var("SMTP_HOST") {
+            self.mail.smtp_host = Some(v);
+        }
+        if let Some(v) = var("SMTP_PORT") {
+            self.mail.smtp_port = v.parse().map_err(|e| invalid_var("SMTP_PORT", e))?;
+        }
+        if let Some(v) = var("SMTP_TLS") {
+            self.mail.smtp_tls = match &v[..] {
+                "none" => SmtpTls::None,
+                "starttls" => SmtpTls::StartTls,
+                "tls" => SmtpTls::Tls,
+                _ => return Err(invalid_var("SMTP_TLS", "expected starttls, tls or none")),
+            };
+        }
+        if let Some(v) = var("SMTP_USERNAME") {
+            self.mail.smtp_username = Some(v);
+        }
+        if let Some(v) = var("SMTP_PASSWORD") {
+            self.mail.smtp_password = Some(v);
+        }
+        if let Some(v) = var("MAILDIR") {
+            self.mail.maildir = Some(v);
+        }
+        if let Some(v) = var("OSRM_URL") {
+            self.travel.osrm_url = Some(v);
+        }
+        if let Some(v) = var("OSRM_PROFILE") {
+            self.travel.osrm_profile = v;
+        }
+        Ok(self)
+    }
+
+    /// Check that the required values are set and in range.
+    /// In production the default keys are rejected, in development they are accepted with a warning
+    pub fn validate(self) -> Result<Config, ConfigError> {
+        if self.database.url.is_empty() {
+            return Err(ConfigError::Missing("database.url (DATABASE_URL)"));
+        }
+        if self.session.redis_url.is_empty() {
+            return Err(ConfigError::Missing("session.redis_url (REDIS_URL)"));
+        }
+        if self.database.max_connections == 0 {
+            return Err(ConfigError::Invalid("database.max_connections", "must be at least 1".to_owned()));
+        }
+        if self.session.key.len() < 32 {
+            return Err(ConfigError::Invalid("session.key", format!("must be at least 32 bytes long, got {}", self.session.key.len())));
+        }
+        if self.token_key.len() != 32 {
+            return Err(ConfigError::Invalid("token_key", format!("must be 32 bytes long, got {}", self.token_key.len())));
+        }
+        if self.session.ttl_secs == 0 {
+            return Err(ConfigError::Invalid("session.ttl_secs", "must be positive".to_owned()));
+        }
+        if self.ticket.expiry_hours <= 0 {
+            return Err(ConfigError::Invalid("ticket.expiry_hours", "must be positive".to_owned()));
+        }
+        if self.ticket.max_visit_minutes <= 0 {
+            return Err(ConfigError::Invalid("ticket.max_visit_minutes", "must be positive".to_owned()));
+        }
+        if self.ticket.janitor_interval_secs == 0 {
+            return Err(ConfigError::Invalid("ticket.janitor_interval_secs", "must be positive".to_owned()));
+        }
+        if self.ticket.closing_cutoff_minutes < 0 {
+            return Err(ConfigError::Invalid("ticket.closing_cutoff_minutes", "must not be negative".to_owned()));
+        }
+        if self.account.reset_expiry_minutes <= 0 {
+            return Err(ConfigError::Invalid("account.reset_expiry_minutes", "must be positive".to_owned()));
+        }
+        if self.account.registration_expiry_hours <= 0 {
+            return Err(ConfigError::Invalid("account.registration_expiry_hours", "must be positive".to_owned()));
+        }
+        if self.account.cleanup_interval_secs == 0 {
+            return Err(ConfigError::Invalid("account.cleanup_interval_secs", "must be positive".to_owned()));
+        }
+        if self.notifications.interval_secs == 0 {
+            return Err(ConfigError::Invalid("notifications.interval_secs", "must be positive".to_owned()));
+        }
+        if let Err(e) = self.mail.from.parse::<lettre::message::Mailbox>() {
+            return Err(ConfigError::Invalid("mail.from", e.to_string()));
+        }
+        if self.mail.smtp_username.is_some() != self.mail.smtp_password.is_some() {
+            return Err(ConfigError::Invalid("mail.smtp_username", "username and password must be set together".to_owned()));
+        }
+        if self.is_production() && self.mail.smtp_host.is_none() && self.mail.maildir.is_none() {
+            return Err(ConfigError::Missing("mail.smtp_host (SMTP_HOST)"));
+        }
+
+        let default_session_key = self.session.key.iter().all(|b| *b == 0);
+        let default_encoding_key = self.encoding_key == DEFAULT_ENCODING_KEY;
+        let default_token_key = self.token_key == DEFAULT_TOKEN_KEY;
+        match self.environment {
+            Environment::Production if default_session_key => return Err(ConfigError::Insecure("session key (SESSION_KEY)")),
+            Environment::Production if default_encoding_key => return Err(ConfigError::Insecure("encoding key (ENCODING_KEY)")),
+            Environment::Production if default_token_key => return Err(ConfigError::Insecure("token key (TOKEN_KEY)")),
+            Environment::Production => {
+                if self.cors.allowed_origins.iter().any(|o| o == "*") {
+                    log::warn!("Any origin is allowed to make requests with credentials");
+                }
+            }
+            Environment::Development => {
+                if default_session_key {
+                    log::warn!("Using the default session key, this is only allowed in development");
+                }
+                if default_encoding_key {
+                    log::warn!("Using the default encoding key, this is only allowed in development");
+                }
+                if default_token_key {
+                    log::warn!("Using the default token key, this is only allowed in development");
+                }
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn is_production(&self) -> bool {
+        self.environment == Environment::Production
+    }
+}
+
+fn invalid_var(name: &'static str, message: impl ToString) -> ConfigError {
+    ConfigError::InvalidVar { name, message: message.to_string() }
+}
+
+fn parse_hex_u32(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 16).map_err(|_| "expected 4 hexadecimal bytes".to_owned())
+}
+
+fn hex_u32<'de, D: Deserializer<'de>>(d: D) -> Result<u32, D::Error> {
+    let s = String::deserialize(d)?;
+    parse_hex_u32(&s).map_err(serde::de::Error::custom)
+}
+
+fn hex_bytes<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+    let s = String::deserialize(d)?;
+    hex::decode(s).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    fn required() -> Vec<(&'static str, &'static str)> {
+        vec![("DATABASE_URL", "postgresql://localhost/clup"), ("REDIS_URL", "127.0.0.1:6379")]
+    }
+
+    #[test]
+    fn file_and_env_test() {
+        let config: Config = toml::from_str(r#"
+            api_url = "127.0.0.1:8000"
+            encoding_key = "0f0f0f0f"
+
+            [database]
+            url = "postgresql://file/clup"
+            max_connections = 4
+
+            [session]
+            redis_url = "redis:6379"
+
+            [cors]
+            allowed_origins = ["https://clup.example"]
+
+            [ticket]
+            expiry_hours = 2
+        "#).unwrap();
+        assert_eq!(config.api_url, "127.0.0.1:8000");
+        assert_eq!(config.encoding_key, 0x0f0f0f0f);
+        assert_eq!(config.database.max_connections, 4);
+        assert_eq!(config.session.ttl_secs, 604800); // Default
+        assert_eq!(config.ticket.expiry_hours, 2);
+        assert_eq!(config.travel.osrm_profile, "foot");
+
+        let config = config.with_env(env(&[
+            ("DATABASE_URL", "postgresql://env/clup"),
+            ("TICKET_EXPIRY_HOURS", "3"),
+            ("REGISTRATION_EXPIRY_HOURS", "48"),
+            ("CORS_ALLOWED_ORIGINS", "https://a.example, https://b.example"),
+            ("OSRM_URL", ""),
+        ])).unwrap().validate().unwrap();
+        assert_eq!(config.database.url, "postgresql://env/clup");
+        assert_eq!(config.database.max_connections, 4);
+        assert_eq!(config.ticket.expiry_hours, 3);
+        assert_eq!(config.account.registration_expiry_hours, 48);
+        assert_eq!(config.account.cleanup_interval_secs, 3600); // Default
+        assert_eq!(config.cors.allowed_origins, vec!["https://a.example", "https://b.example"]);
+        assert_eq!(config.travel.osrm_url, None);
+
+        assert!(toml::from_str::<Config>("[database]\nmax_conections = 4").is_err());
+        assert!(toml::from_str::<Config>("encoding_key = \"xyz\"").is_err());
+    }
+
+    #[test]
+    fn validate_test() {
+        let config = Config::default().with_env(env(&required())).unwrap().validate().unwrap();
+        assert!(!config.is_production());
+        assert_eq!(config.encoding_key, DEFAULT_ENCODING_KEY);
+
+        let r = Config::default().with_env(env(&[("REDIS_URL", "127.0.0.1:6379")])).unwrap().validate();
+        assert!(matches!(r, Err(ConfigError::Missing(_))));
+
+        let r = Config::default().with_env(env(&[("SESSION_KEY", "zz")]));
+        assert!(matches!(r, Err(ConfigError::InvalidVar { name: "SESSION_KEY", .. })));
+
+        let mut vars = required();
+        vars.push(("SESSION_KEY", "0f0f"));
+        let r = Config::default().with_env(env(&vars)).unwrap().validate();
+        assert!(matches!(r, Err(ConfigError::Invalid("session.key", _))));
+
+        let mut vars = required();
+        vars.push(("TOKEN_KEY", "0f0f"));
+        let r = Config::default().with_env(env(&vars)).unwrap().validate();
+        assert!(matches!(r, Err(ConfigError::Invalid("token_key", _))));
+
+        let mut vars = required();
+        vars.push(("TICKET_EXPIRY_HOURS", "0"));
+        let r = Config::default().with_env(env(&vars)).unwrap().validate();
+        assert!(matches!(r, Err(ConfigError::Invalid("ticket.expiry_hours", _))));
+
+        let mut vars = required();
+        vars.push(("TICKET_CLOSING_CUTOFF_MINUTES", "-1"));
+        let r = Config::default().with_env(env(&vars)).unwrap().validate();
+        assert!(matches!(r, Err(ConfigError::Invalid("ticket.closing_cutoff_minutes", _))));
+    }
+
+    #[test]
+    fn production_keys_test() {
+        let mut vars = required();
+        vars.push(("CLUP_ENV", "production"));
+        let r = Config::default().with_env(env(&vars)).unwrap().validate();
+        assert!(matches!(r, Err(ConfigError::Missing("mail.smtp_host (SMTP_HOST)"))));
+
+        vars.push(("SMTP_HOST", "smtp.example.com"));
+        let r = Config::default().with_env(env(&vars)).unwrap().validate();
+        assert!(matches!(r, Err(ConfigError::Insecure(_))));
+
+        vars.push(("SESSION_KEY", "0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f"));
+        let r = Config::default().with_env(env(&vars)).unwrap().validate();
+        assert!(matches!(r, Err(ConfigError::Insecure("encoding key (ENCODING_KEY)"))));
+
+        vars.push(("ENCODING_KEY", "0f0f0f0f"));
+        let r = Config::default().with_env(env(&vars)).unwrap().validate();
+        assert!(matches!(r, Err(ConfigError::Insecure("token key (TOKEN_KEY)"))));
+
+        vars.push(("TOKEN_KEY", "0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f"));
+        let config = Config::default().with_env(env(&vars)).unwrap().validate().unwrap();
+        assert!(config.is_production());
+
+        let r = Config::default().with_env(env(&[("CLUP_ENV", "prod")]));
+        assert!(matches!(r, Err(ConfigError::InvalidVar { name: "CLUP_ENV", .. })));
+    }
+}
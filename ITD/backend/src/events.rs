@@ -0,0 +1,111 @@
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::{Stream, StreamExt};
+use sqlx::PgPool;
+use sqlx::postgres::PgListener;
+
+/// Postgres channel where the `ticket` table triggers publish the id of the shop that changed
+pub const TICKET_EVENTS_CHANNEL: &str = "ticket_events";
+
+/// Shop id and channel of each subscriber
+type Subscribers = Arc<Mutex<Vec<(i32, UnboundedSender<()>)>>>;
+
+/// Distributes changes to the ticket queues of the shops to the subscribers
+///
+/// A single connection listens for the notifications sent by the database triggers, each
+/// subscriber receives a message whenever a ticket of its shop is created, enters, exits or is removed.
+#[derive(Clone, Default)]
+pub struct ShopEvents {
+    subscribers: Subscribers,
+}
+
+impl ShopEvents {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Receive a message every time the queue of `shop_id` changes
+    pub fn subscribe(&self, shop_id: i32) -> Subscription {
+        let (tx, rx) = unbounded();
+        self.subscribers.lock().unwrap().push((shop_id, tx));
+        Subscription {
+            changes: rx,
+            subscribers: self.subscribers.clone(),
+        }
+    }
+
+    /// Notify the subscribers of `shop_id`, dropping the ones that are gone
+    pub fn publish(&self, shop_id: i32) {
+        self.subscribers.lock().unwrap()
+            .retain(|(sid, tx)| *sid != shop_id || tx.unbounded_send(()).is_ok());
+    }
+
+    /// Listen for database notifications and publish them until the system is stopped
+    pub async fn listen(self, conn: PgPool) {
+        loop {
+            if let Err(e) = self.listen_inner(&conn).await {
+                log::error!("Error listening for ticket events, retrying in 5 seconds: {}", e);
+                actix_web::rt::time::delay_for(Duration::from_secs(5)).await;
+            }
+        }
+    }
+    async fn listen_inner(&self, conn: &PgPool) -> sqlx::Result<()> {
+        let mut listener = PgListener::connect_with(conn).await?;
+        listener.listen(TICKET_EVENTS_CHANNEL).await?;
+        loop {
+            let n = listener.recv().await?;
+            match n.payload().parse() {
+                Ok(shop_id) => self.publish(shop_id),
+                Err(_) => log::warn!("Invalid ticket event payload: {}", n.payload()),
+            }
+        }
+    }
+}
+
+/// Stream of the changes to the queue of a shop, see [`ShopEvents::subscribe`]
+///
+/// Dropping it unregisters the subscriber, shops without events would otherwise keep the closed channels
+pub struct Subscription {
+    changes: UnboundedReceiver<()>,
+    subscribers: Subscribers,
+}
+
+impl Stream for Subscription {
+    type Item = ();
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<()>> {
+        self.changes.poll_next_unpin(cx)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.changes.close();
+        self.subscribers.lock().unwrap()
+            .retain(|(_, tx)| !tx.is_closed());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn publish_test() {
+        let events = ShopEvents::new();
+        let mut s1 = events.subscribe(1);
+        let s2 = events.subscribe(2);
+
+        events.publish(1);
+        assert_eq!(s1.next().await, Some(()));
+
+        drop(s2);
+        assert_eq!(events.subscribers.lock().unwrap().len(), 1);
+        drop(s1);
+        assert!(events.subscribers.lock().unwrap().is_empty());
+    }
+}
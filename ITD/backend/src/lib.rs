@@ -2,17 +2,31 @@ use sqlx::{PgPool, Postgres};
 use sqlx::postgres::PgPoolOptions;
 use sqlx::migrate::*;
 
+pub mod config;
 pub mod models;
 pub mod api;
+pub mod events;
 pub mod utils;
 pub mod migrations;
+pub mod mail;
+pub mod cleanup;
+pub mod notifications;
+pub mod travel;
+
+use config::DatabaseConfig;
+
+/// Setup the database with the default pool size, see [`setup_db_with`]
+pub async fn setup_db(conn_url: &str) -> PgPool {
+    setup_db_with(&DatabaseConfig { url: conn_url.to_owned(), ..Default::default() }).await
+}
 
 /// ## Setup database schema
-/// + Try to connect to the supplied url
+/// + Try to connect to the configured url
 /// + Create database if it doesn't exist
 /// + Create connection pool
 /// + Run migrations
-pub async fn setup_db(conn_url: &str) -> PgPool {
+pub async fn setup_db_with(config: &DatabaseConfig) -> PgPool {
+    let conn_url = &config.url;
     log::info!("db_conn: {}", &conn_url);
     
     let mut retry_count = 0;
@@ -38,7 +52,7 @@ pub async fn setup_db(conn_url: &str) -> PgPool {
         Postgres::create_database(&conn_url).await.unwrap_or_else(|e| panic!("Could not create database: {}", e));
     }
     let pool = PgPoolOptions::new()
-        .max_connections(8)
+        .max_connections(config.max_connections)
         .connect(&conn_url).await.unwrap_or_else(|e| panic!("Could not connect to postgres: {}", e));
     
     migrations::migrate(&pool).await.unwrap_or_else(|e| panic!("Failed to run migration: {}", e));
@@ -0,0 +1,4 @@
+pub mod mailer;
+pub mod template;
+
+pub use mailer::{Email, LogMailer, Mailer, MaildirMailer, SmtpMailer};
@@ -0,0 +1,224 @@
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::Utc;
+use futures::future::{FutureExt, LocalBoxFuture};
+use lettre::message::header::{ContentTransferEncoding, ContentType};
+use lettre::message::{Body, Mailbox};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+
+use crate::config::{MailConfig, SmtpTls};
+
+/// Plain text email, see [`super::template`] for the emails sent by the application
+#[derive(Debug, Clone, PartialEq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl Email {
+    /// Build the message sent by `from`
+    fn message(&self, from: &Mailbox) -> Result<Message, Box<dyn Error>> {
+        // 8bit keeps links and codes on a single line, quoted-printable would wrap them.
+        // Lettre only accepts 8bit bodies with lines shorter than 76 characters, the limit of the RFC is 998
+        if self.body.lines().any(|l| l.len() > 998) || self.body.contains('\0') {
+            return Err("Email body has lines that are too long".into());
+        }
+        let crlf = self.body.lines().map(|l| format!("{}\r\n", l)).collect::<String>();
+        let body = Body::dangerous_pre_encoded(crlf.into_bytes(), ContentTransferEncoding::EightBit);
+        let message = Message::builder()
+            .from(from.clone())
+            .to(self.to.parse()?)
+            .subject(&self.subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)?;
+        Ok(message)
+    }
+}
+
+/// Delivery channel for emails, shared between the workers
+pub trait Mailer: Send + Sync {
+    /// Deliver `email`
+    fn send<'a>(&'a self, email: &'a Email) -> LocalBoxFuture<'a, Result<(), Box<dyn Error>>>;
+}
+
+/// Mailer sending emails through an SMTP server
+pub struct SmtpMailer {
+    from: Mailbox,
+    transport: SmtpTransport,
+}
+
+impl SmtpMailer {
+    /// Connect to `config.smtp_host`, fails if it is not set or it is not a valid domain for TLS
+    pub fn new(config: &MailConfig) -> Result<Self, Box<dyn Error>> {
+        let host = config.smtp_host.as_deref().ok_or("No SMTP host")?;
+        let builder = match config.smtp_tls {
+            SmtpTls::None => SmtpTransport::builder_dangerous(host),
+            SmtpTls::StartTls => SmtpTransport::starttls_relay(host)?,
+            SmtpTls::Tls => SmtpTransport::relay(host)?,
+        };
+        let mut builder = builder
+            .port(config.smtp_port)
+            .timeout(Some(Duration::from_secs(10)));
+        if let (Some(username), Some(password)) = (&config.smtp_username, &config.smtp_password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+        Ok(Self { from: config.from.parse()?, transport: builder.build() })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send<'a>(&'a self, email: &'a Email) -> LocalBoxFuture<'a, Result<(), Box<dyn Error>>> {
+        async move {
+            let message = email.message(&self.from)?;
+            let transport = self.transport.clone();
+            actix_web::web::block(move || transport.send(&message)).await
+                .map_err(|e| format!("SMTP error: {}", e))?;
+            Ok(())
+        }.boxed_local()
+    }
+}
+
+/// Mailer storing emails in a maildir instead of sending them, for local testing
+pub struct MaildirMailer {
+    from: Mailbox,
+    dir: PathBuf,
+}
+
+impl MaildirMailer {
+    pub fn new(dir: impl Into<PathBuf>, from: Mailbox) -> Self {
+        Self { from, dir: dir.into() }
+    }
+}
+
+impl Mailer for MaildirMailer {
+    fn send<'a>(&'a self, email: &'a Email) -> LocalBoxFuture<'a, Result<(), Box<dyn Error>>> {
+        let res = (|| {
+            let message = email.message(&self.from)?;
+            for sub in ["tmp", "new", "cur"].iter() {
+                fs::create_dir_all(self.dir.join(sub))?;
+            }
+            // Messages are written in tmp and moved to new once complete, names sort by delivery time
+            let name = format!("{}.{:08x}.clup", Utc::now().timestamp_nanos(), rand::random::<u32>());
+            let tmp = self.dir.join("tmp").join(&name);
+            fs::write(&tmp, message.formatted())?;
+            fs::rename(&tmp, self.dir.join("new").join(&name))?;
+            Ok(())
+        })();
+        futures::future::ready(res).boxed_local()
+    }
+}
+
+/// Mailer writing emails to the log, for development
+#[derive(Default)]
+pub struct LogMailer;
+
+impl Mailer for LogMailer {
+    fn send<'a>(&'a self, email: &'a Email) -> LocalBoxFuture<'a, Result<(), Box<dyn Error>>> {
+        log::info!("Email to {}: {}\n{}", email.to, email.subject, email.body);
+        futures::future::ready(Ok(())).boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn email() -> Email {
+        Email {
+            to: "customer@test.com".into(),
+            subject: "Test subject".into(),
+            body: format!("Line\nhttp://localhost:8080/confirm?code={}\n", "ab".repeat(32)),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn maildir_mailer_test() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("clup-maildir-{:x}", rand::random::<u64>()));
+        let mailer = MaildirMailer::new(&dir, "CLup <noreply@localhost>".parse()?);
+
+        mailer.send(&email()).await?;
+        mailer.send(&email()).await?;
+
+        let mut files = fs::read_dir(dir.join("new"))?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        files.sort();
+        assert_eq!(files.len(), 2);
+        let content = fs::read_to_string(&files[1])?;
+        assert!(content.contains("To: customer@test.com"));
+        assert!(content.contains("Subject: Test subject"));
+        assert!(content.contains(&format!("code={}", "ab".repeat(32))));
+        assert_eq!(fs::read_dir(dir.join("tmp"))?.count(), 0);
+
+        assert!(mailer.send(&Email { to: "not an address".into(), ..email() }).await.is_err());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    /// Accept a single SMTP session and return the recipients and the data
+    fn smtp_sink(listener: TcpListener) -> (Vec<String>, String) {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut stream = stream;
+        let (mut rcpt, mut data) = (Vec::new(), String::new());
+        writeln!(stream, "220 localhost sink\r").unwrap();
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 0 {
+            let cmd = line.trim_end().to_uppercase();
+            if cmd.starts_with("EHLO") {
+                writeln!(stream, "250-localhost\r\n250 8BITMIME\r").unwrap();
+            } else if cmd.starts_with("RCPT TO:") {
+                rcpt.push(line.trim_end()[8..].to_owned());
+                writeln!(stream, "250 OK\r").unwrap();
+            } else if cmd == "DATA" {
+                writeln!(stream, "354 Go ahead\r").unwrap();
+                let mut l = String::new();
+                while reader.read_line(&mut l).unwrap() > 0 && l != ".\r\n" {
+                    data.push_str(&l);
+                    l.clear();
+                }
+                writeln!(stream, "250 Queued\r").unwrap();
+            } else if cmd == "QUIT" {
+                writeln!(stream, "221 Bye\r").unwrap();
+                break;
+            } else {
+                writeln!(stream, "250 OK\r").unwrap();
+            }
+            line.clear();
+        }
+        (rcpt, data)
+    }
+
+    #[actix_rt::test]
+    async fn smtp_mailer_test() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        let sink = thread::spawn(move || smtp_sink(listener));
+
+        let config = MailConfig {
+            smtp_host: Some("127.0.0.1".into()),
+            smtp_port: port,
+            smtp_tls: SmtpTls::None,
+            ..Default::default()
+        };
+        SmtpMailer::new(&config)?.send(&email()).await?;
+
+        let (rcpt, data) = sink.join().unwrap();
+        assert_eq!(rcpt, vec!["<customer@test.com>"]);
+        assert!(data.contains("Subject: Test subject"));
+        assert!(data.contains(&format!("code={}", "ab".repeat(32))));
+
+        let unreachable = MailConfig { smtp_port: 1, ..config };
+        assert!(SmtpMailer::new(&unreachable)?.send(&email()).await.is_err());
+        Ok(())
+    }
+}
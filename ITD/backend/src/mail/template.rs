@@ -0,0 +1,86 @@
+use super::Email;
+use crate::notifications::Notification;
+
+const CONFIRMATION: &str = include_str!("../../templates/email/confirmation.txt");
+const PASSWORD_RESET: &str = include_str!("../../templates/email/password_reset.txt");
+const STAFF_ACTIVATION: &str = include_str!("../../templates/email/staff_activation.txt");
+const TICKET_NOTIFICATION: &str = include_str!("../../templates/email/ticket_notification.txt");
+
+/// Replace the `{{name}}` placeholders in `template` and split the subject from the body.
+/// Templates start with a `Subject: ` line followed by an empty line
+fn render(template: &str, to: &str, vars: &[(&str, &str)]) -> Email {
+    let text = vars.iter()
+        .fold(template.to_owned(), |t, (name, value)| t.replace(&format!("{{{{{}}}}}", name), value));
+
+    let (subject, body) = match text.split_once('\n') {
+        Some((first, rest)) if first.starts_with("Subject: ") => (first["Subject: ".len()..].trim(), rest.trim_start_matches('\n')),
+        _ => ("CLup", &text[..]),
+    };
+    Email { to: to.to_owned(), subject: subject.to_owned(), body: body.to_owned() }
+}
+
+/// Email with the link to confirm a new customer account, `base_url` is the address of the frontend
+pub fn confirmation(to: &str, base_url: &str, code: &[u8], valid_hours: i32) -> Email {
+    let link = format!("{}/confirm?code={}", base_url.trim_end_matches('/'), hex::encode(code));
+    render(CONFIRMATION, to, &[("link", &link), ("hours", &valid_hours.to_string())])
+}
+
+/// Email with the code to reset the password of an account
+pub fn password_reset(to: &str, code: &[u8], valid_minutes: i32) -> Email {
+    render(PASSWORD_RESET, to, &[("code", &hex::encode(code)), ("minutes", &valid_minutes.to_string())])
+}
+
+/// Email with the code to activate a staff account created by a manager of `shop`
+pub fn staff_activation(to: &str, shop: &str, code: &[u8]) -> Email {
+    render(STAFF_ACTIVATION, to, &[("shop", shop), ("code", &hex::encode(code))])
+}
+
+/// Email sent to a customer when their turn is about to come, the entry time is in the time zone of the shop
+pub fn ticket_notification(n: &Notification) -> Email {
+    render(TICKET_NOTIFICATION, &n.email, &[
+        ("shop", &n.shop_name),
+        ("people", &n.people.to_string()),
+        ("time", &n.est.with_timezone(&n.time_zone).format("%H:%M %Z").to_string()),
+        ("ticket", &n.ticket_uid),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use chrono_tz::Tz;
+
+    #[test]
+    fn template_test() {
+        let e = confirmation("customer@test.com", "https://clup.example/", &[0xab; 32], 24);
+        assert_eq!(e.to, "customer@test.com");
+        assert_eq!(e.subject, "Confirm your CLup account");
+        assert!(e.body.starts_with("Welcome"));
+        assert!(e.body.contains(&format!("https://clup.example/confirm?code={}\n", "ab".repeat(32))));
+        assert!(e.body.contains("24 hours"));
+
+        let e = password_reset("staff@test.com", &[0x01; 32], 60);
+        assert!(e.body.contains(&"01".repeat(32)));
+        assert!(e.body.contains("60 minutes"));
+
+        let e = staff_activation("staff@test.com", "Test shop", &[0x02; 32]);
+        assert!(e.body.contains(&"02".repeat(32)));
+        assert!(e.body.contains("Test shop"));
+
+        let e = ticket_notification(&Notification {
+            customer_id: "c0".into(),
+            email: "customer@test.com".into(),
+            ticket_uid: "t0".into(),
+            shop_id: "s0".into(),
+            shop_name: "Test shop".into(),
+            people: 2,
+            est: Utc.ymd(2021, 1, 10).and_hms(10, 30, 0),
+            time_zone: Tz::Europe__Rome,
+        });
+        assert_eq!(e.subject, "Your turn at Test shop is coming");
+        assert!(e.body.contains("2 people ahead"));
+        assert!(e.body.contains("11:30 CET"));
+        assert!(!e.body.contains("{{"));
+    }
+}
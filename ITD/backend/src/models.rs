@@ -1,5 +1,11 @@
 pub mod account;
+pub mod admission;
+pub mod policy;
 pub mod customer;
+pub mod notification;
+pub mod visit;
 pub mod staff;
 pub mod ticket;
-pub mod shop;
\ No newline at end of file
+pub mod shop;
+pub mod signing;
+pub mod booking;
\ No newline at end of file
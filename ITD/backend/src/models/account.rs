@@ -9,6 +9,8 @@ pub struct Account {
     pub(super) email: String,
     pub(super) salt: Vec<u8>,
     pub(super) digest: Vec<u8>,
+    /// Incremented on every password change, see [`crate::utils::session`]
+    pub(super) session_version: i32,
 }
 
 /// Salt and digest produced by the password hashing function
@@ -40,6 +42,7 @@ impl Account {
     pub fn email(&self) -> &str {&self.email}
     pub fn salt(&self) -> &[u8] {&self.salt[..]}
     pub fn digest(&self) -> &[u8] {&self.digest[..]}
+    pub fn session_version(&self) -> i32 {self.session_version}
 }
 
 
@@ -59,6 +62,7 @@ mod tests {
             email: "123@mail.com".to_owned(),
             salt: p.salt,
             digest: p.digest,
+            session_version: 0,
         };
         
         assert!(cust.verify_authentication(&pass));
@@ -0,0 +1,177 @@
+use chrono::prelude::*;
+use chrono::Duration;
+use sqlx::{FromRow, PgConnection, query_as};
+
+/// Minutes before the start of a booking from which its places are held back from tickets
+/// and the customer holding it is allowed in
+pub const RESERVE_BEFORE_MINUTES: i64 = 15;
+/// Minutes after the start of a booking after which its places are released if the customer has not entered yet
+pub const BOOKING_GRACE_MINUTES: i64 = 15;
+
+/// ## Result for log entry operation
+/// + Entered: Successful entry
+/// + Full(i32): Department with returned id is full, not entered
+/// + NotFirst(i32): Not first in queue, returned number people in queue, not entered
+/// + TooEarly: The booked time slot has not started yet, not entered
+/// + Closed: The shop is closed, not entered
+/// + Expired: Ticket is expired, not entered
+/// + Invalid: Ticket is invalid, not entered
+#[derive(Debug, PartialEq)]
+pub enum EnterResult {
+    Entered,
+    Full(i32),
+    NotFirst(i64),
+    TooEarly,
+    Closed,
+    Expired,
+    Invalid,
+}
+
+/// Kind of token asking to enter
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenKind {
+    Ticket,
+    Booking,
+}
+
+/// Occupancy of a department as seen by the admission policy
+/// + occupancy: customers currently inside, either with a ticket or a booking
+/// + reserved: places held for bookings that are about to start and have not entered yet
+#[derive(Debug, FromRow)]
+pub struct DepartmentAdmission {
+    pub id: i32,
+    pub capacity: i32,
+    pub occupancy: i64,
+    pub reserved: i64,
+}
+
+/// Admission policy shared by tickets and bookings
+///
+/// Tickets are only admitted if the places left after the reservations of upcoming bookings are enough,
+/// bookings are admitted as long as there is physically a place left, since the booking
+/// availability already guarantees bookings alone cannot overbook a department.
+pub struct AdmissionPolicy {
+    departments: Vec<DepartmentAdmission>,
+}
+
+impl AdmissionPolicy {
+    pub fn new(departments: Vec<DepartmentAdmission>) -> Self {
+        Self { departments }
+    }
+
+    /// Load the current occupancy and reservations of the departments of a shop at time `now`.
+    /// The reservation of `booking_id` is not counted, so that a booking does not compete with itself
+    pub async fn load(conn: &mut PgConnection, shop_id: i32, now: NaiveDateTime, booking_id: Option<i32>) -> sqlx::Result<Self> {
+        let (reserve_from, reserve_to) = (now - Duration::minutes(BOOKING_GRACE_MINUTES), now + Duration::minutes(RESERVE_BEFORE_MINUTES));
+        let rows = query_as!(DepartmentAdmissionRow, r"SELECT
+                department.id as id,
+                department.capacity as capacity,
+                (SELECT count(*) FROM ticket_department, ticket
+                    WHERE ticket_department.ticket_id = ticket.id AND
+                        ticket_department.department_id = department.id AND
+                        ticket.entry IS NOT NULL AND ticket.exit IS NULL) +
+                (SELECT count(*) FROM booking_department, booking
+                    WHERE booking_department.booking_id = booking.id AND
+                        booking_department.department_id = department.id AND
+                        booking.entry IS NOT NULL AND booking.exit IS NULL) as occupancy,
+                (SELECT count(*) FROM booking_department, booking
+                    WHERE booking_department.booking_id = booking.id AND
+                        booking_department.department_id = department.id AND
+                        booking.entry IS NULL AND
+                        booking.start_time > $2 AND booking.start_time <= $3 AND
+                        booking.id <> COALESCE($4, -1)) as reserved
+            FROM department
+            WHERE department.shop_id = $1",
+            shop_id, reserve_from, reserve_to, booking_id)
+            .fetch_all(conn)
+            .await?;
+
+        Ok(Self::new(rows.into_iter().map(DepartmentAdmission::from).collect()))
+    }
+
+    /// Find a department among `department_ids` that cannot admit a new token of kind `kind`
+    pub fn full_department(&self, kind: TokenKind, department_ids: &[i32]) -> Option<i32> {
+        self.departments.iter()
+            .filter(|d| department_ids.contains(&d.id))
+            .find(|d| {
+                let taken = match kind {
+                    TokenKind::Ticket => d.occupancy + d.reserved,
+                    TokenKind::Booking => d.occupancy,
+                };
+                taken >= d.capacity as i64
+            })
+            .map(|d| d.id)
+    }
+
+    pub fn departments(&self) -> &[DepartmentAdmission] {&self.departments}
+}
+
+/// Check if a booking for the slot from `start_time` to `end_time` can enter at time `now`
+/// ### Returns
+/// + `None` if the booking can enter
+/// + `Some(EnterResult::TooEarly)` if the slot is not about to start yet
+/// + `Some(EnterResult::Expired)` if the slot has ended
+pub fn check_booking_time(start_time: NaiveDateTime, end_time: NaiveDateTime, now: NaiveDateTime) -> Option<EnterResult> {
+    if now < start_time - Duration::minutes(RESERVE_BEFORE_MINUTES) {
+        Some(EnterResult::TooEarly)
+    } else if now >= end_time {
+        Some(EnterResult::Expired)
+    } else {
+        None
+    }
+}
+
+/// Row structure for department admission state
+#[derive(FromRow)]
+struct DepartmentAdmissionRow {
+    id: i32,
+    capacity: i32,
+    occupancy: Option<i64>,
+    reserved: Option<i64>,
+}
+
+impl From<DepartmentAdmissionRow> for DepartmentAdmission {
+    fn from(row: DepartmentAdmissionRow) -> Self {
+        Self {
+            id: row.id,
+            capacity: row.capacity,
+            occupancy: row.occupancy.unwrap_or_default(),
+            reserved: row.reserved.unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dep(id: i32, capacity: i32, occupancy: i64, reserved: i64) -> DepartmentAdmission {
+        DepartmentAdmission { id, capacity, occupancy, reserved }
+    }
+
+    #[test]
+    fn full_department_test() {
+        let policy = AdmissionPolicy::new(vec![
+            dep(1, 2, 1, 1),
+            dep(2, 5, 0, 0),
+            dep(3, 1, 1, 0),
+        ]);
+
+        assert_eq!(policy.full_department(TokenKind::Ticket, &[2]), None);
+        assert_eq!(policy.full_department(TokenKind::Ticket, &[1, 2]), Some(1));
+        assert_eq!(policy.full_department(TokenKind::Booking, &[1, 2]), None);
+        assert_eq!(policy.full_department(TokenKind::Booking, &[2, 3]), Some(3));
+        assert_eq!(policy.full_department(TokenKind::Ticket, &[4]), None);
+    }
+
+    #[test]
+    fn booking_time_test() {
+        let start = NaiveDate::from_ymd(2021, 3, 1).and_hms(10, 0, 0);
+        let end = start + Duration::minutes(30);
+
+        assert_eq!(check_booking_time(start, end, start - Duration::minutes(RESERVE_BEFORE_MINUTES + 1)), Some(EnterResult::TooEarly));
+        assert_eq!(check_booking_time(start, end, start - Duration::minutes(RESERVE_BEFORE_MINUTES)), None);
+        assert_eq!(check_booking_time(start, end, start + Duration::minutes(29)), None);
+        assert_eq!(check_booking_time(start, end, end), Some(EnterResult::Expired));
+    }
+}
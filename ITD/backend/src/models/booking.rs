@@ -0,0 +1,659 @@
+use serde::{Serialize, Deserialize};
+use sqlx::postgres::PgDone;
+use sqlx::{Done, FromRow, PgConnection, PgPool, query_as, query};
+use chrono::prelude::*;
+use chrono::Duration;
+
+use futures::StreamExt;
+
+use crate::models::admission::{check_booking_time, AdmissionPolicy, EnterResult, TokenKind};
+use crate::models::shop::{Department, OpeningHours, Schedule};
+use crate::utils::encoding::encode_serial;
+
+/// Internal structure for booking
+#[derive(Debug, PartialEq, Eq)]
+pub struct Booking {
+    pub id: i32,
+    pub customer_id: i32,
+    pub shop_id: i32,
+    pub shop_name: String,
+    pub creation: NaiveDateTime,
+    pub start_time: NaiveDateTime,
+    pub duration: i32,
+    pub valid: bool,
+    pub active: bool,
+    pub department_ids: Vec<i32>,
+}
+
+impl Booking {
+    /// End of the booked time slot
+    pub fn end_time(&self) -> NaiveDateTime {
+        self.start_time + Duration::minutes(self.duration as i64)
+    }
+}
+
+/// Response ready structure for booking
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct BookingResponse {
+    pub uid: String,
+    pub shop_id: String,
+    pub shop_name: String,
+    pub department_ids: Vec<String>,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    pub valid: bool,
+    pub active: bool,
+}
+
+impl From<Booking> for BookingResponse {
+    fn from(b: Booking) -> Self {
+        let end_time = Utc.from_utc_datetime(&b.end_time());
+        let dids = b.department_ids
+            .into_iter()
+            .map(encode_serial)
+            .collect();
+        Self {
+            uid: encode_serial(b.id),
+            shop_id: encode_serial(b.shop_id),
+            shop_name: b.shop_name,
+            department_ids: dids,
+            start_time: Utc.from_utc_datetime(&b.start_time),
+            end_time,
+            valid: b.valid,
+            active: b.active,
+        }
+    }
+}
+
+/// Length in minutes of the slots in which opening hours are split for bookings
+pub const SLOT_MINUTES: i64 = 30;
+
+/// Response ready structure for the number of places left for a department in a booking slot
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BookingAvailability {
+    pub dept_id: String,
+    pub time: Schedule,
+    pub available: i32,
+}
+
+/// Internal structure for the number of places left for a department in a booking slot
+#[derive(Debug)]
+struct SlotAvailability {
+    department_id: i32,
+    day: NaiveDate,
+    slot: Schedule,
+    available: i32,
+}
+
+impl SlotAvailability {
+    /// Check if the slot overlaps the interval from `start` to `end`
+    fn overlaps(&self, start: NaiveDateTime, end: NaiveDateTime) -> bool {
+        self.day.and_time(self.slot.open) < end && self.day.and_time(self.slot.close) > start
+    }
+}
+
+impl From<SlotAvailability> for BookingAvailability {
+    fn from(s: SlotAvailability) -> Self {
+        Self {
+            dept_id: encode_serial(s.department_id),
+            time: s.slot,
+            available: s.available,
+        }
+    }
+}
+
+/// ## Result for booking creation operation
+/// + Created: Booking created
+/// + AlreadyExists: Booking not created. The customer already has a booking for this shop overlapping the requested slot
+/// + Closed: Booking not created. The requested slot is not inside the opening hours of the shop
+/// + Full(i32): Booking not created. Department with returned id has no places left in the requested slot
+pub enum NewBookingResult<'a> {
+    Created(PersistentBooking<'a>),
+    AlreadyExists,
+    Closed,
+    Full(i32),
+}
+impl<'a> NewBookingResult<'a> {
+    /// Extract Created value if `Created`, panics otherwise
+    pub fn unwrap(self) -> PersistentBooking<'a> {
+        match self {
+            NewBookingResult::Created(b) => b,
+            NewBookingResult::AlreadyExists => panic!("Unwrap called on AlreadyExists result"),
+            NewBookingResult::Closed => panic!("Unwrap called on Closed result"),
+            NewBookingResult::Full(_) => panic!("Unwrap called on Full result"),
+        }
+    }
+}
+
+/// Data Access Object for booking
+#[allow(dead_code)]
+pub struct PersistentBooking<'a> {
+    conn: &'a PgPool,
+    inner: Booking,
+}
+
+impl<'a> PersistentBooking<'a> {
+    /// Retrieve booking from its primary key
+    pub async fn get(conn: &'a PgPool, id: i32) -> sqlx::Result<Option<PersistentBooking<'a>>> {
+        let booking = query_as!(BookingRow, r"SELECT booking.id AS id, customer_id, booking.shop_id AS shop_id, shop.name as shop_name, array_agg(booking_department.department_id) AS department_ids, creation, start_time, duration, valid, active
+            FROM booking, booking_department, shop
+            WHERE booking_department.booking_id = booking.id AND
+                booking.shop_id = shop.id AND
+                booking.id = $1
+            GROUP BY booking.id, customer_id, booking.shop_id, shop.name, creation, start_time, duration, valid, active",
+            id)
+            .fetch_optional(conn)
+            .await?
+            .map(move |row| Self{conn, inner:row.into()});
+
+        Ok(booking)
+    }
+
+    /// Retrieve all the bookings for a customer that have not ended yet
+    pub async fn get_for_customer(conn: &'a PgPool, customer_id: i32) -> sqlx::Result<Vec<Booking>> {
+        query_as!(BookingRow, r"SELECT booking.id AS id, customer_id, booking.shop_id AS shop_id, shop.name as shop_name, array_agg(booking_department.department_id) AS department_ids, creation, start_time, duration, valid, active
+            FROM booking, booking_department, shop
+            WHERE booking_department.booking_id = booking.id AND
+                booking.shop_id = shop.id AND
+                booking.customer_id = $1 AND
+                booking.start_time + duration * interval '1 minute' > CURRENT_TIMESTAMP
+            GROUP BY booking.id, customer_id, booking.shop_id, shop.name, creation, start_time, duration, valid, active
+            ORDER BY start_time",
+            customer_id)
+            .fetch(conn)
+            .fold(Ok(Vec::new()), |acc: sqlx::Result<Vec<Booking>>, x| async {
+                let mut acc = acc?;
+                acc.push(x?.into());
+                Ok(acc)
+            }).await
+    }
+
+    /// Create a new booking for the slot starting at `start_time` and lasting `duration` minutes
+    /// See [`NewBookingResult`] for the result
+    pub async fn try_new(conn: &'a PgPool, customer_id: i32, shop_id: i32, department_ids: Vec<i32>, start_time: NaiveDateTime, duration: i32) -> sqlx::Result<NewBookingResult<'a>> {
+        let mut tx = conn.begin().await?;
+        let end_time = start_time + Duration::minutes(duration as i64);
+
+        // Concurrent bookings of the same shop wait for each other, so that the availability can't change under them
+        query!(r"SELECT id FROM shop WHERE id = $1 FOR NO KEY UPDATE", shop_id)
+            .fetch_optional(&mut tx).await?;
+
+        let hours = OpeningHours::load(&mut tx, shop_id).await?;
+        let (start, end) = (Utc.from_utc_datetime(&start_time), Utc.from_utc_datetime(&end_time));
+        if !hours.contains(start, end) {
+            return Ok(NewBookingResult::Closed);
+        }
+
+        let overlapping = query!(r"SELECT id FROM booking
+            WHERE
+                customer_id = $1 AND shop_id = $2 AND
+                start_time < $4 AND start_time + duration * interval '1 minute' > $3",
+                customer_id, shop_id, start_time, end_time)
+            .fetch_optional(&mut tx).await?;
+
+        if overlapping.is_some() {
+            return Ok(NewBookingResult::AlreadyExists);
+        }
+
+        let (start, end) = (hours.local(start), hours.local(end));
+        let full = load_availability(&mut tx, &hours, shop_id, start.date())
+            .await?
+            .into_iter()
+            .find(|a| a.available <= 0 && department_ids.contains(&a.department_id) && a.overlaps(start, end));
+
+        if let Some(a) = full {
+            return Ok(NewBookingResult::Full(a.department_id));
+        }
+
+        let row = query!(r"INSERT INTO booking (customer_id, shop_id, creation, start_time, duration, valid, active) VALUES
+            ($1, $2, CURRENT_TIMESTAMP, $3, $4, TRUE, TRUE)
+            RETURNING id",
+            customer_id, shop_id, start_time, duration)
+            .fetch_one(&mut tx).await?;
+
+        for did in department_ids {
+            query!(r"INSERT INTO booking_department (booking_id, department_id)
+                VALUES ($1, $2)",
+                row.id, did)
+                .execute(&mut tx).await?;
+        }
+
+        let booking_row = query_as!(BookingRow, r"SELECT booking.id AS id, customer_id, booking.shop_id AS shop_id, shop.name as shop_name, array_agg(booking_department.department_id) AS department_ids, creation, start_time, duration, valid, active
+            FROM booking, booking_department, shop
+            WHERE
+                booking_department.booking_id = booking.id AND
+                booking.shop_id = shop.id AND
+                booking.id = $1
+            GROUP BY booking.id, customer_id, booking.shop_id, shop.name, creation, start_time, duration, valid, active",
+            row.id)
+            .fetch_one(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(NewBookingResult::Created(Self{conn, inner:booking_row.into()}))
+    }
+
+    /// Get the bookings for this shop that have not ended yet, ordered by start time
+    pub async fn list(conn: &PgPool, shop_id: i32) -> sqlx::Result<Vec<Booking>> {
+        query_as!(BookingRow, r"SELECT booking.id AS id, customer_id, booking.shop_id AS shop_id, shop.name as shop_name, array_agg(booking_department.department_id) AS department_ids, creation, start_time, duration, valid, active
+            FROM booking, booking_department, shop
+            WHERE booking_department.booking_id = booking.id AND
+                booking.shop_id = shop.id AND
+                booking.shop_id = $1 AND
+                booking.exit IS NULL AND
+                booking.start_time + duration * interval '1 minute' > CURRENT_TIMESTAMP
+            GROUP BY booking.id, customer_id, booking.shop_id, shop.name, creation, start_time, duration, valid, active
+            ORDER BY start_time",
+            shop_id)
+            .fetch(conn)
+            .fold(Ok(Vec::new()), |acc: sqlx::Result<Vec<Booking>>, x| async {
+                let mut acc = acc?;
+                acc.push(x?.into());
+                Ok(acc)
+            }).await
+    }
+
+    /// Try to log entry for this booking at this moment.
+    /// Bookings do not wait in the ticket queue, they can enter from shortly before the start of their slot.
+    /// See [`EnterResult`] for results
+    pub async fn try_enter(&self) -> sqlx::Result<EnterResult> {
+        let mut tx = self.conn.begin().await?;
+
+        // Concurrent entries to the same shop wait for each other, so that they can't both take the last place
+        query!(r"SELECT id FROM shop WHERE id = $1 FOR NO KEY UPDATE", self.inner.shop_id)
+            .fetch_optional(&mut tx).await?;
+
+        let state = query!(r"SELECT entry IS NOT NULL as entered, exit IS NOT NULL as exited FROM booking
+            WHERE id = $1 FOR NO KEY UPDATE", self.inner.id)
+            .fetch_one(&mut tx)
+            .await?;
+
+        if state.exited.unwrap() {
+            return Ok(EnterResult::Expired);
+        }
+        if state.entered.unwrap() {
+            return Ok(EnterResult::Invalid);
+        }
+
+        let now = Utc::now().naive_utc();
+        if let Some(res) = check_booking_time(self.inner.start_time, self.inner.end_time(), now) {
+            return Ok(res);
+        }
+
+        let policy = AdmissionPolicy::load(&mut tx, self.inner.shop_id, now, Some(self.inner.id)).await?;
+
+        if let Some(did) = policy.full_department(TokenKind::Booking, &self.inner.department_ids) {
+            return Ok(EnterResult::Full(did));
+        }
+
+        query!(r"UPDATE booking
+            SET
+                entry = $2
+            WHERE id = $1", self.inner.id, now)
+            .execute(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(EnterResult::Entered)
+    }
+
+    /// Try to log exit for this booking at this moment.
+    /// ### Returns
+    /// + `Ok(true)` if successful
+    /// + `Ok(false)` if exit is not allowed for the current state of the booking
+    pub async fn exit(&self) -> sqlx::Result<bool> {
+        let res = query!(r"UPDATE booking
+            SET
+                exit = CURRENT_TIMESTAMP
+            WHERE id = $1 AND entry IS NOT NULL AND exit IS NULL", self.inner.id)
+            .execute(self.conn)
+            .await?;
+
+        Ok(res.rows_affected() == 1)
+    }
+
+    /// Get the number of places left for each department in each booking slot of `day`, in the local time of the shop
+    pub async fn availability(conn: &PgPool, shop_id: i32, day: NaiveDate) -> sqlx::Result<Vec<BookingAvailability>> {
+        let mut conn = conn.acquire().await?;
+        let hours = OpeningHours::load(&mut conn, shop_id).await?;
+        let slots = load_availability(&mut conn, &hours, shop_id, day).await?;
+
+        Ok(slots.into_iter()
+            .map(BookingAvailability::from)
+            .collect())
+    }
+
+    /// Cancel and delete this booking
+    pub async fn cancel(self) -> sqlx::Result<PgDone> {
+        query!("DELETE FROM booking WHERE id = $1", self.inner.id)
+            .execute(self.conn)
+            .await
+    }
+
+    pub fn inner(&self) -> &Booking {&self.inner}
+    pub fn into_inner(self) -> Booking {self.inner}
+}
+
+/// Load departments and bookings for the local `day` and compute the availability for each slot of the opening `hours`.
+/// Booking times are converted to the local time of the shop
+async fn load_availability(conn: &mut PgConnection, hours: &OpeningHours, shop_id: i32, day: NaiveDate) -> sqlx::Result<Vec<SlotAvailability>> {
+    let dow = day.weekday().number_from_monday() as i16;
+    let schedule: Vec<Schedule> = hours
+        .day(day)
+        .into_iter()
+        .map(|s| Schedule::new(shop_id, dow, s.open, s.close))
+        .collect();
+
+    let departments = query_as!(Department,
+        r"SELECT id as uid, shop_id, description, capacity FROM department
+        WHERE shop_id = $1",
+        shop_id)
+        .fetch_all(&mut *conn)
+        .await?;
+
+    let (from, to) = (hours.instant(day.and_hms(0, 0, 0)).naive_utc(), hours.instant(day.succ().and_hms(0, 0, 0)).naive_utc());
+    let mut bookings = query_as!(BookedSlotRow,
+        r"SELECT department_id, start_time, duration
+        FROM booking, booking_department
+        WHERE
+            booking_department.booking_id = booking.id AND
+            booking.shop_id = $1 AND
+            booking.start_time < $3 AND booking.start_time + duration * interval '1 minute' > $2",
+        shop_id, from, to)
+        .fetch_all(&mut *conn)
+        .await?;
+    for b in bookings.iter_mut() {
+        b.start_time = hours.local(Utc.from_utc_datetime(&b.start_time));
+    }
+
+    Ok(slot_availability(&schedule, &departments, day, &bookings))
+}
+
+/// Split the opening hours of `day` in slots of [`SLOT_MINUTES`] minutes and
+/// count the places left in each department given the overlapping `bookings`
+fn slot_availability(schedule: &[Schedule], departments: &[Department], day: NaiveDate, bookings: &[BookedSlotRow]) -> Vec<SlotAvailability> {
+    let dow = day.weekday().number_from_monday() as i16;
+    let mut slots = Vec::new();
+
+    for s in schedule.iter().filter(|s| s.dow == dow) {
+        let mut open = s.open;
+        while open < s.close {
+            let close = match open.overflowing_add_signed(Duration::minutes(SLOT_MINUTES)) {
+                (t, 0) => t.min(s.close),
+                _ => s.close,
+            };
+            let (start, end) = (day.and_time(open), day.and_time(close));
+
+            for d in departments {
+                let booked = bookings.iter()
+                    .filter(|b| b.department_id == d.uid && b.start_time < end && b.end_time() > start)
+                    .count() as i32;
+                slots.push(SlotAvailability {
+                    department_id: d.uid,
+                    day,
+                    slot: Schedule { shop_id: s.shop_id, dow, open, close },
+                    available: (d.capacity - booked).max(0),
+                });
+            }
+            open = close;
+        }
+    }
+    slots
+}
+
+/// Row structure for the department and time of a booking
+#[derive(FromRow)]
+struct BookedSlotRow {
+    department_id: i32,
+    start_time: NaiveDateTime,
+    duration: i32,
+}
+
+impl BookedSlotRow {
+    fn end_time(&self) -> NaiveDateTime {
+        self.start_time + Duration::minutes(self.duration as i64)
+    }
+}
+
+/// Row structure for booking
+#[derive(FromRow)]
+pub(super) struct BookingRow {
+    pub id: i32,
+    pub customer_id: i32,
+    pub shop_id: i32,
+    pub shop_name: String,
+    pub creation: NaiveDateTime,
+    pub start_time: NaiveDateTime,
+    pub duration: i32,
+    pub valid: bool,
+    pub active: bool,
+    pub department_ids: Option<Vec<i32>>,
+}
+
+impl From<BookingRow> for Booking {
+    fn from(row: BookingRow) -> Self {
+        Booking {
+            id: row.id,
+            customer_id: row.customer_id,
+            shop_id: row.shop_id,
+            shop_name: row.shop_name,
+            creation: row.creation,
+            start_time: row.start_time,
+            duration: row.duration,
+            valid: row.valid,
+            active: row.active,
+            department_ids: row.department_ids.unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+    use crate::utils::tests::*;
+    use crate::with_test_shop;
+
+    #[actix_rt::test]
+    async fn new_booking_test() -> Result<(), Box<dyn Error>> {
+        let conn = db().await;
+
+        with_test_shop!(&conn, shopid [d0, d1] {
+            let customer_id = test_customer(&conn).await?;
+            let start = tomorrow_at(10, 0);
+            test_schedule(&conn, shopid, start.date(), "09:00", "17:00").await?;
+
+            let inserted = PersistentBooking::try_new(&conn, customer_id, shopid, vec![d0, d1], start, 30)
+                .await?.unwrap().into_inner();
+
+            let loaded = PersistentBooking::get(&conn, inserted.id).await?
+                .unwrap()
+                .into_inner();
+            assert_eq!(&inserted, &loaded);
+
+            let owned = PersistentBooking::get_for_customer(&conn, customer_id).await?;
+            assert_eq!(owned, vec![loaded]);
+
+            del_customer(&conn, customer_id).await?;
+        });
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn booking_slot_test() -> Result<(), Box<dyn Error>> {
+        let conn = db().await;
+
+        with_test_shop!(&conn, shopid [d0] {
+            let customer_id = test_customer(&conn).await?;
+            let start = tomorrow_at(10, 0);
+            test_schedule(&conn, shopid, start.date(), "09:00", "12:00").await?;
+
+            match PersistentBooking::try_new(&conn, customer_id, shopid, vec![d0], tomorrow_at(8, 30), 20).await? {
+                NewBookingResult::Closed => {},
+                _ => panic!("Expected Closed, booking starts before opening"),
+            }
+            match PersistentBooking::try_new(&conn, customer_id, shopid, vec![d0], tomorrow_at(11, 45), 30).await? {
+                NewBookingResult::Closed => {},
+                _ => panic!("Expected Closed, booking ends after closing"),
+            }
+            match PersistentBooking::try_new(&conn, customer_id, shopid, vec![d0], start + Duration::days(1), 30).await? {
+                NewBookingResult::Closed => {},
+                _ => panic!("Expected Closed, no schedule for that day"),
+            }
+
+            let _ = PersistentBooking::try_new(&conn, customer_id, shopid, vec![d0], start, 30).await?.unwrap();
+            match PersistentBooking::try_new(&conn, customer_id, shopid, vec![d0], tomorrow_at(10, 15), 30).await? {
+                NewBookingResult::AlreadyExists => {},
+                _ => panic!("Expected AlreadyExists"),
+            }
+            let _ = PersistentBooking::try_new(&conn, customer_id, shopid, vec![d0], tomorrow_at(10, 30), 30).await?.unwrap();
+
+            del_customer(&conn, customer_id).await?;
+        });
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn booking_full_test() -> Result<(), Box<dyn Error>> {
+        let conn = db().await;
+
+        let id_c1 = test_customer(&conn).await?;
+        let id_c2 = test_customer(&conn).await?;
+
+        with_test_shop!(&conn, shopid [d0] {
+            let d_small = test_department(&conn, shopid, 1).await?;
+            let start = tomorrow_at(10, 0);
+            test_schedule(&conn, shopid, start.date(), "09:00", "12:00").await?;
+
+            let _ = PersistentBooking::try_new(&conn, id_c1, shopid, vec![d_small], start, 30).await?.unwrap();
+
+            match PersistentBooking::try_new(&conn, id_c2, shopid, vec![d0, d_small], tomorrow_at(10, 15), 30).await? {
+                NewBookingResult::Full(d) => assert_eq!(d, d_small),
+                _ => panic!("Expected Full"),
+            }
+            let _ = PersistentBooking::try_new(&conn, id_c2, shopid, vec![d0], tomorrow_at(10, 15), 30).await?.unwrap();
+            let _ = PersistentBooking::try_new(&conn, id_c2, shopid, vec![d_small], tomorrow_at(10, 45), 30).await?.unwrap();
+
+            let avail = PersistentBooking::availability(&conn, shopid, start.date()).await?;
+            assert_eq!(avail.len(), 12);
+            for a in avail {
+                let expected = match (a.time.open().format("%H:%M").to_string().as_str(), a.dept_id) {
+                    ("10:00", d) if d == encode_serial(d_small) => 0,
+                    ("10:30", d) if d == encode_serial(d_small) => 0,
+                    ("11:00", d) if d == encode_serial(d_small) => 0,
+                    (_, d) if d == encode_serial(d_small) => 1,
+                    ("10:00", _) | ("10:30", _) => 9,
+                    _ => 10,
+                };
+                assert_eq!(a.available, expected);
+            }
+        });
+
+        del_customer(&conn, id_c1).await?;
+        del_customer(&conn, id_c2).await?;
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn concurrent_bookings_test() -> Result<(), Box<dyn Error>> {
+        let conn = db().await;
+
+        let id_c1 = test_customer(&conn).await?;
+        let id_c2 = test_customer(&conn).await?;
+
+        with_test_shop!(&conn, shopid [] {
+            let d_small = test_department(&conn, shopid, 1).await?;
+            let start = tomorrow_at(10, 0);
+            test_schedule(&conn, shopid, start.date(), "09:00", "12:00").await?;
+
+            let created = |r: &sqlx::Result<NewBookingResult>| matches!(r, Ok(NewBookingResult::Created(_)));
+            let (r1, r2) = futures::future::join(
+                PersistentBooking::try_new(&conn, id_c1, shopid, vec![d_small], start, 30),
+                PersistentBooking::try_new(&conn, id_c2, shopid, vec![d_small], start, 30),
+            ).await;
+            assert_eq!([created(&r1), created(&r2)].iter().filter(|c| **c).count(), 1);
+        });
+
+        del_customer(&conn, id_c1).await?;
+        del_customer(&conn, id_c2).await?;
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn booking_entry_exit_test() -> Result<(), Box<dyn Error>> {
+        let conn = db().await;
+
+        let id_c1 = test_customer(&conn).await?;
+        let id_c2 = test_customer(&conn).await?;
+
+        with_test_shop!(&conn, shopid [d0] {
+            let d_small = test_department(&conn, shopid, 1).await?;
+            let now = Utc::now().naive_utc();
+
+            let early = test_booking(&conn, id_c1, shopid, &[d0], now + Duration::hours(2), 30).await?;
+            let early = PersistentBooking::get(&conn, early).await?.unwrap();
+            assert_eq!(early.try_enter().await?, EnterResult::TooEarly);
+
+            let late = test_booking(&conn, id_c1, shopid, &[d0], now - Duration::hours(2), 30).await?;
+            let late = PersistentBooking::get(&conn, late).await?.unwrap();
+            assert_eq!(late.try_enter().await?, EnterResult::Expired);
+
+            let b1 = test_booking(&conn, id_c1, shopid, &[d0, d_small], now - Duration::minutes(5), 30).await?;
+            let b1 = PersistentBooking::get(&conn, b1).await?.unwrap();
+            let b2 = test_booking(&conn, id_c2, shopid, &[d_small], now, 30).await?;
+            let b2 = PersistentBooking::get(&conn, b2).await?.unwrap();
+
+            assert!(!b1.exit().await?);
+            assert_eq!(b1.try_enter().await?, EnterResult::Entered);
+            assert_eq!(b1.try_enter().await?, EnterResult::Invalid);
+            assert_eq!(b2.try_enter().await?, EnterResult::Full(d_small));
+
+            assert!(b1.exit().await?);
+            assert!(!b1.exit().await?);
+            assert_eq!(b1.try_enter().await?, EnterResult::Expired);
+            assert_eq!(b2.try_enter().await?, EnterResult::Entered);
+            assert!(b2.exit().await?);
+        });
+
+        del_customer(&conn, id_c1).await?;
+        del_customer(&conn, id_c2).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn slot_availability_test() {
+        let day = NaiveDate::from_ymd(2021, 3, 1); // Monday
+        let t = |h, m| NaiveTime::from_hms(h, m, 0);
+        let schedule = vec![
+            Schedule { shop_id: 1, dow: 1, open: t(9, 0), close: t(10, 0) },
+            Schedule { shop_id: 1, dow: 1, open: t(15, 0), close: t(15, 45) },
+            Schedule { shop_id: 1, dow: 2, open: t(9, 0), close: t(17, 0) },
+        ];
+        let departments = vec![
+            Department { uid: 10, shop_id: 1, description: "A".into(), capacity: 2 },
+            Department { uid: 11, shop_id: 1, description: "B".into(), capacity: 1 },
+        ];
+        let bookings = vec![
+            BookedSlotRow { department_id: 10, start_time: day.and_hms(9, 15, 0), duration: 30 },
+            BookedSlotRow { department_id: 11, start_time: day.and_hms(9, 30, 0), duration: 30 },
+            BookedSlotRow { department_id: 10, start_time: day.and_hms(9, 0, 0), duration: 30 },
+        ];
+
+        let slots = slot_availability(&schedule, &departments, day, &bookings);
+        assert_eq!(slots.len(), 8);
+
+        let available = |h, m, d| slots.iter()
+            .find(|s| s.slot.open == t(h, m) && s.department_id == d)
+            .unwrap()
+            .available;
+        assert_eq!(available(9, 0, 10), 0);
+        assert_eq!(available(9, 0, 11), 1);
+        assert_eq!(available(9, 30, 10), 1);
+        assert_eq!(available(9, 30, 11), 0);
+        assert_eq!(available(15, 0, 10), 2);
+        assert_eq!(available(15, 30, 11), 1);
+
+        let last = slots.iter().find(|s| s.slot.open == t(15, 30)).unwrap();
+        assert_eq!(last.slot.close, t(15, 45));
+    }
+}
@@ -1,10 +1,11 @@
 use serde::{Serialize, Deserialize};
-use sqlx::{FromRow, PgPool, query, query_as};
+use sqlx::{Done, FromRow, PgConnection, PgPool, query, query_as};
 use rand::Rng;
 
 use super::account::Account;
 
 type ConfirmationCode = Vec<u8>;
+type ResetCode = Vec<u8>;
 /// Data Access Object for Customers
 pub struct PersistentCustomer<'a> {
     inner: Account,
@@ -15,7 +16,7 @@ impl<'a> PersistentCustomer<'a> {
     /// Retrieve Customer from its primary key
     pub async fn get(conn: &'a PgPool, id: i32) -> sqlx::Result<Option<PersistentCustomer<'a>>> {
         let acc = query_as!(Account,
-            r"SELECT id, email, salt, digest FROM customer WHERE id = $1",
+            r"SELECT id, email, salt, digest, session_version FROM customer WHERE id = $1",
             id
         ).fetch_optional(conn)
         .await?;
@@ -26,7 +27,7 @@ impl<'a> PersistentCustomer<'a> {
     /// Retrieve Customer from its email
     pub async fn find(conn: &'a PgPool, email: &str) -> sqlx::Result<Option<PersistentCustomer<'a>>> {
         let acc = query_as!(Account,
-                r"SELECT id, email, salt, digest FROM customer WHERE email = $1",
+                r"SELECT id, email, salt, digest, session_version FROM customer WHERE email = $1",
                 email
             ).fetch_optional(conn)
             .await?;
@@ -38,12 +39,13 @@ impl<'a> PersistentCustomer<'a> {
     }
 
     /// ## Create a new customer
-    /// Starts account creation if no other customer with the same email exists
+    /// Starts account creation if no other customer with the same email exists.
+    /// The pending registration expires after `valid_hours`, registering again after it expired replaces the code and the password
     /// ### Returns:
     /// `Ok(Some(ConfirmationCode))` if it was created
-    /// `Ok(None)` if an customer with the same email already existed
+    /// `Ok(None)` if an customer with the same email already existed or its registration is still pending
     /// See [`finalize`](PersistentCustomer::finalize) to complete creation
-    pub async fn create(conn: &'a PgPool, email: &str, password: &str) -> sqlx::Result<Option<ConfirmationCode>> {
+    pub async fn create(conn: &'a PgPool, email: &str, password: &str, valid_hours: i32) -> sqlx::Result<Option<ConfirmationCode>> {
         let mut tx = conn.begin().await?;
 
         let exists = query!(r"SELECT email FROM customer WHERE email = $1", &email)
@@ -55,12 +57,15 @@ impl<'a> PersistentCustomer<'a> {
             let mut code = vec![0u8; 32];
             rand::thread_rng().fill(&mut code[..]);
             let acc =  query_as!(TempCustomer,
-                    r"INSERT INTO temp_customer(code, email, salt, digest) VALUES ($1, $2, $3, $4) RETURNING code, email, salt, digest",
-                    &code, &email, &p.salt, &p.digest
-                ).fetch_one(&mut tx)
+                    r"INSERT INTO temp_customer(code, email, salt, digest) VALUES ($1, $2, $3, $4)
+                    ON CONFLICT (email) DO UPDATE SET code = $1, salt = $3, digest = $4, created = CURRENT_TIMESTAMP
+                    WHERE temp_customer.created <= CURRENT_TIMESTAMP - make_interval(hours => $5)
+                    RETURNING code, email, salt, digest",
+                    &code, &email, &p.salt, &p.digest, valid_hours
+                ).fetch_optional(&mut tx)
                 .await?;
             tx.commit().await?;
-            Ok(Some(acc.code))
+            Ok(acc.map(|a| a.code))
         } else {
             tx.rollback().await?;
             Ok(None)
@@ -69,17 +74,20 @@ impl<'a> PersistentCustomer<'a> {
 
     /// ## Finalize customer creation
     /// Use `code` to finalize account creation. If it's a valid unused code generated by [`create`](PersistentCustomer::create)
-    /// the customer creation will be finalized and the customer will be ready to use
-    pub async fn finalize(conn: &'a PgPool, code: &[u8]) -> sqlx::Result<Option<Account>> {
+    /// less than `valid_hours` ago the customer creation will be finalized and the customer will be ready to use
+    pub async fn finalize(conn: &'a PgPool, code: &[u8], valid_hours: i32) -> sqlx::Result<Option<Account>> {
         let mut tx = conn.begin().await?;
 
-        let temp = query!(r"SELECT code, email, salt, digest FROM temp_customer WHERE code = $1", code)
-            .fetch_optional(&mut tx)
+        let temp = query!(
+                r"SELECT code, email, salt, digest FROM temp_customer
+                WHERE code = $1 AND created > CURRENT_TIMESTAMP - make_interval(hours => $2)",
+                code, valid_hours
+            ).fetch_optional(&mut tx)
             .await?;
         
         let result = if let Some(temp) = temp {
             let acc = query_as!(Account,
-                    r"INSERT INTO customer(email, salt, digest) VALUES ($1, $2, $3) RETURNING id, email, salt, digest",
+                    r"INSERT INTO customer(email, salt, digest) VALUES ($1, $2, $3) RETURNING id, email, salt, digest, session_version",
                     &temp.email, &temp.salt, &temp.digest
                 ).fetch_one(&mut tx)
                 .await?;
@@ -97,16 +105,82 @@ impl<'a> PersistentCustomer<'a> {
         result
     }
 
-    /// Update password for this customer
+    /// ## Purge expired registrations
+    /// Delete the pending registrations created more than `valid_hours` ago, returns the number of deleted registrations
+    pub async fn purge_registrations(conn: &'a PgPool, valid_hours: i32) -> sqlx::Result<u64> {
+        let r = query!(r"DELETE FROM temp_customer WHERE created <= CURRENT_TIMESTAMP - make_interval(hours => $1)", valid_hours)
+            .execute(conn)
+            .await?;
+        Ok(r.rows_affected())
+    }
+
+    /// ## Start a password reset
+    /// Generate a single use code to reset the password of the customer with `email`, valid for `valid_minutes`.
+    /// Requesting a code again replaces the previous one
+    /// ### Returns:
+    /// `Ok(Some(ResetCode))` if the customer exists
+    /// `Ok(None)` otherwise
+    /// See [`reset_password`](PersistentCustomer::reset_password) to choose the new password
+    pub async fn create_reset(conn: &'a PgPool, email: &str, valid_minutes: i32) -> sqlx::Result<Option<ResetCode>> {
+        let mut code = vec![0u8; 32];
+        rand::thread_rng().fill(&mut code[..]);
+        let row = query!(
+                r"INSERT INTO reset_customer (code, customer_id, expiration)
+                SELECT $1, id, CURRENT_TIMESTAMP + make_interval(mins => $3) FROM customer WHERE email = $2
+                ON CONFLICT (customer_id) DO UPDATE SET code = $1, expiration = EXCLUDED.expiration
+                RETURNING code",
+                &code, email, valid_minutes
+            ).fetch_optional(conn)
+            .await?;
+
+        Ok(row.map(|r| r.code))
+    }
+
+    /// ## Reset the password
+    /// Use `code` to set `password`. If it's a valid unused code generated by [`create_reset`](PersistentCustomer::create_reset)
+    /// that has not expired the password is updated and the other sessions of the customer are invalidated
+    pub async fn reset_password(conn: &'a PgPool, code: &[u8], password: &str) -> sqlx::Result<Option<PersistentCustomer<'a>>> {
+        let mut tx = conn.begin().await?;
+
+        // Expired codes are deleted as well
+        let reset = query!(r"DELETE FROM reset_customer WHERE code = $1 RETURNING customer_id, expiration > CURRENT_TIMESTAMP AS valid", code)
+            .fetch_optional(&mut tx)
+            .await?;
+
+        let result = match reset {
+            Some(r) if r.valid == Some(true) => Some(Self::set_password(&mut tx, r.customer_id, password).await?),
+            _ => None,
+        };
+
+        tx.commit().await?;
+        Ok(result.map(|acc| Self{inner: acc, conn}))
+    }
+
+    /// Update password for this customer, the other sessions of the customer are invalidated
     pub async fn update_password(&'a mut self, password: &str) -> sqlx::Result<&'a mut PersistentCustomer<'a>> {
+        let mut conn = self.conn.acquire().await?;
+        self.inner = Self::set_password(&mut conn, self.inner.id(), password).await?;
+        Ok(self)
+    }
+
+    /// Hash and store `password`, incrementing the session version
+    async fn set_password(conn: &mut PgConnection, id: i32, password: &str) -> sqlx::Result<Account> {
         let p = Account::hash_password(password.as_bytes());
-        let acc =  query_as!(Account,
-                r"UPDATE customer SET salt = $1, digest = $2 WHERE id = $3 RETURNING id, email, salt, digest",
-                &p.salt, &p.digest, &self.inner.id()
-            ).fetch_one(self.conn)
+        query_as!(Account,
+                r"UPDATE customer SET salt = $1, digest = $2, session_version = session_version + 1
+                WHERE id = $3
+                RETURNING id, email, salt, digest, session_version",
+                &p.salt, &p.digest, id
+            ).fetch_one(conn)
+            .await
+    }
+
+    /// Current session version of the customer, `None` if the customer does not exist
+    pub async fn session_version(conn: &PgPool, id: i32) -> sqlx::Result<Option<i32>> {
+        let row = query!(r"SELECT session_version FROM customer WHERE id = $1", id)
+            .fetch_optional(conn)
             .await?;
-        self.inner = acc;
-        Ok(self)
+        Ok(row.map(|r| r.session_version))
     }
 
     pub fn into_inner(self) -> Account {self.inner}
@@ -126,6 +200,7 @@ struct TempCustomer {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::DEFAULT_REGISTRATION_EXPIRY_HOURS as EXPIRY_HOURS;
     use crate::utils::tests::{db, del_customer};
 
     #[actix_rt::test]
@@ -133,11 +208,11 @@ mod tests {
         let conn = db().await;
         let (email, password) = ("test-email123@mail.com", "securepassword");
 
-        let token = PersistentCustomer::create(&conn, email, password)
+        let token = PersistentCustomer::create(&conn, email, password, EXPIRY_HOURS)
             .await?
             .expect("No temporary account was created");
 
-        let account = PersistentCustomer::finalize(&conn, &token)
+        let account = PersistentCustomer::finalize(&conn, &token, EXPIRY_HOURS)
             .await?
             .expect("No customer was created");
 
@@ -154,4 +229,72 @@ mod tests {
 
         Ok(())
     }
+
+    #[actix_rt::test]
+    async fn pending_registration_test() -> sqlx::Result<()> {
+        let conn = db().await;
+        let email = format!("{}@pending.com", rand::random::<u32>());
+        let age = |email: String| {
+            let conn = conn.clone();
+            async move {
+                query!(r"UPDATE temp_customer SET created = created - make_interval(hours => $2) WHERE email = $1", email, EXPIRY_HOURS)
+                    .execute(&conn)
+                    .await
+            }
+        };
+
+        let first = PersistentCustomer::create(&conn, &email, "first", EXPIRY_HOURS).await?.unwrap();
+        assert_eq!(PersistentCustomer::create(&conn, &email, "second", EXPIRY_HOURS).await?, None); // Still pending
+
+        age(email.clone()).await?;
+        assert!(PersistentCustomer::finalize(&conn, &first, EXPIRY_HOURS).await?.is_none()); // Expired
+        let second = PersistentCustomer::create(&conn, &email, "second", EXPIRY_HOURS).await?.unwrap();
+        assert!(PersistentCustomer::finalize(&conn, &first, EXPIRY_HOURS).await?.is_none()); // Replaced
+
+        age(email.clone()).await?;
+        assert!(PersistentCustomer::purge_registrations(&conn, EXPIRY_HOURS).await? >= 1);
+        assert!(query!(r"SELECT email FROM temp_customer WHERE email = $1", email).fetch_optional(&conn).await?.is_none());
+        assert!(PersistentCustomer::finalize(&conn, &second, 2 * EXPIRY_HOURS).await?.is_none()); // Purged
+
+        let third = PersistentCustomer::create(&conn, &email, "third", EXPIRY_HOURS).await?.unwrap();
+        PersistentCustomer::purge_registrations(&conn, EXPIRY_HOURS).await?;
+        let account = PersistentCustomer::finalize(&conn, &third, EXPIRY_HOURS).await?.unwrap();
+        assert!(account.verify_authentication(b"third"));
+
+        del_customer(&conn, account.id()).await?;
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn password_reset_test() -> sqlx::Result<()> {
+        let conn = db().await;
+        let (email, password) = ("test-reset123@mail.com", "securepassword");
+
+        let token = PersistentCustomer::create(&conn, email, password, EXPIRY_HOURS).await?.unwrap();
+        let account = PersistentCustomer::finalize(&conn, &token, EXPIRY_HOURS).await?.unwrap();
+        assert_eq!(account.session_version(), 0);
+
+        assert_eq!(PersistentCustomer::create_reset(&conn, "nobody@mail.com", 60).await?, None);
+
+        let expired = PersistentCustomer::create_reset(&conn, email, -1).await?.unwrap();
+        assert!(PersistentCustomer::reset_password(&conn, &expired, "newpassword").await?.is_none());
+
+        let code = PersistentCustomer::create_reset(&conn, email, 60).await?.unwrap();
+        let replaced = PersistentCustomer::create_reset(&conn, email, 60).await?.unwrap();
+        assert!(PersistentCustomer::reset_password(&conn, &code, "newpassword").await?.is_none());
+
+        let reset = PersistentCustomer::reset_password(&conn, &replaced, "newpassword").await?
+            .unwrap()
+            .into_inner();
+        assert!(reset.verify_authentication(b"newpassword"));
+        assert!(!reset.verify_authentication(password.as_bytes()));
+        assert_eq!(reset.session_version(), 1);
+        assert_eq!(PersistentCustomer::session_version(&conn, account.id()).await?, Some(1));
+
+        assert!(PersistentCustomer::reset_password(&conn, &replaced, "otherpassword").await?.is_none()); // Single use
+
+        del_customer(&conn, account.id()).await?;
+
+        Ok(())
+    }
 }
@@ -0,0 +1,70 @@
+use sqlx::{FromRow, PgPool, query, query_as};
+
+/// Ticket in queue whose customer has not been notified yet
+#[derive(Debug, FromRow)]
+pub struct PendingNotification {
+    pub ticket_id: i32,
+    pub customer_id: i32,
+    pub email: String,
+    /// Notify the customer when the estimated wait drops below this number of minutes
+    pub notify_minutes: i32,
+}
+
+/// Retrieve the shops with at least one queued ticket waiting for a notification
+pub async fn pending_shops(conn: &PgPool) -> sqlx::Result<Vec<i32>> {
+    Ok(query!(r"SELECT DISTINCT shop_id FROM ticket
+        WHERE
+            customer_id IS NOT NULL AND
+            notified IS NULL AND
+            entry IS NULL AND exit IS NULL AND
+            valid AND active AND
+            expiration > CURRENT_TIMESTAMP")
+        .fetch_all(conn)
+        .await?
+        .into_iter()
+        .map(|r| r.shop_id)
+        .collect())
+}
+
+/// Retrieve the queued tickets of a shop waiting for a notification.
+/// Substitute tickets have no customer to notify and are never returned
+pub async fn pending_for_shop(conn: &PgPool, shop_id: i32) -> sqlx::Result<Vec<PendingNotification>> {
+    query_as!(PendingNotification, r"SELECT ticket.id as ticket_id, customer.id as customer_id, customer.email, customer.notify_minutes
+        FROM ticket, customer
+        WHERE
+            ticket.customer_id = customer.id AND
+            ticket.shop_id = $1 AND
+            ticket.notified IS NULL AND
+            ticket.entry IS NULL AND ticket.exit IS NULL AND
+            ticket.valid AND ticket.active AND
+            ticket.expiration > CURRENT_TIMESTAMP",
+        shop_id)
+        .fetch_all(conn)
+        .await
+}
+
+/// Record that the customer holding `ticket_id` has been notified, returns false if it already was
+pub async fn mark_notified(conn: &PgPool, ticket_id: i32) -> sqlx::Result<bool> {
+    let row = query!(r"UPDATE ticket SET notified = CURRENT_TIMESTAMP
+        WHERE id = $1 AND notified IS NULL
+        RETURNING id", ticket_id)
+        .fetch_optional(conn)
+        .await?;
+    Ok(row.is_some())
+}
+
+/// Get the notification threshold in minutes chosen by a customer
+pub async fn get_threshold(conn: &PgPool, customer_id: i32) -> sqlx::Result<Option<i32>> {
+    Ok(query!(r"SELECT notify_minutes FROM customer WHERE id = $1", customer_id)
+        .fetch_optional(conn)
+        .await?
+        .map(|r| r.notify_minutes))
+}
+
+/// Set the notification threshold in minutes for a customer
+pub async fn set_threshold(conn: &PgPool, customer_id: i32, minutes: i32) -> sqlx::Result<()> {
+    query!(r"UPDATE customer SET notify_minutes = $2 WHERE id = $1", customer_id, minutes)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
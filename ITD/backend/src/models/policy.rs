@@ -0,0 +1,119 @@
+use serde::{Serialize, Deserialize};
+use sqlx::{FromRow, PgConnection, PgPool, query, query_as};
+
+/// Rules applied to the tickets of a shop, chosen by the managers.
+/// `None` limits are not enforced
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromRow)]
+#[serde(default)]
+pub struct ShopPolicy {
+    /// Hours a ticket stays valid, the server default if `None`
+    pub ticket_expiry_hours: Option<i32>,
+    /// Maximum number of tickets waiting in queue
+    pub max_queue: Option<i32>,
+    /// Whether the staff can issue substitute tickets to walk-in customers
+    pub walk_in: bool,
+    /// New tickets are refused while the estimated wait is longer than this
+    pub max_wait_minutes: Option<i32>,
+    /// New tickets are refused to customers already holding this many active tickets, in any shop
+    pub max_customer_tickets: Option<i32>,
+}
+
+impl Default for ShopPolicy {
+    fn default() -> Self {
+        Self {
+            ticket_expiry_hours: None,
+            max_queue: None,
+            walk_in: true,
+            max_wait_minutes: None,
+            max_customer_tickets: None,
+        }
+    }
+}
+
+impl ShopPolicy {
+    /// Retrieve the policy of `shop_id`, the default one if it was never set
+    pub async fn load(conn: &mut PgConnection, shop_id: i32) -> sqlx::Result<Self> {
+        let policy = query_as!(ShopPolicy, r"SELECT ticket_expiry_hours, max_queue, walk_in, max_wait_minutes, max_customer_tickets
+                FROM shop_policy WHERE shop_id = $1", shop_id)
+            .fetch_optional(conn)
+            .await?;
+        Ok(policy.unwrap_or_default())
+    }
+
+    /// Replace the policy of `shop_id`
+    pub async fn save(&self, conn: &PgPool, shop_id: i32) -> sqlx::Result<()> {
+        query!(r"INSERT INTO shop_policy (shop_id, ticket_expiry_hours, max_queue, walk_in, max_wait_minutes, max_customer_tickets)
+                VALUES ($1, $2, $3, $4, $5, $6)
+                ON CONFLICT (shop_id) DO UPDATE SET
+                    ticket_expiry_hours = EXCLUDED.ticket_expiry_hours,
+                    max_queue = EXCLUDED.max_queue,
+                    walk_in = EXCLUDED.walk_in,
+                    max_wait_minutes = EXCLUDED.max_wait_minutes,
+                    max_customer_tickets = EXCLUDED.max_customer_tickets",
+                shop_id, self.ticket_expiry_hours, self.max_queue, self.walk_in, self.max_wait_minutes, self.max_customer_tickets)
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Check that the limits are in range
+    /// ### Returns
+    /// + `None` if the policy is valid
+    /// + `Some((field, message))` for the first invalid field
+    pub fn check(&self) -> Option<(&'static str, &'static str)> {
+        if matches!(self.ticket_expiry_hours, Some(h) if h <= 0) {
+            return Some(("ticket_expiry_hours", "Must be positive"));
+        }
+        if matches!(self.max_queue, Some(n) if n < 0) {
+            return Some(("max_queue", "Must not be negative"));
+        }
+        if matches!(self.max_wait_minutes, Some(m) if m < 0) {
+            return Some(("max_wait_minutes", "Must not be negative"));
+        }
+        if matches!(self.max_customer_tickets, Some(n) if n <= 0) {
+            return Some(("max_customer_tickets", "Must be positive"));
+        }
+        None
+    }
+
+    /// Hours a new ticket stays valid, `default_hours` if the policy does not set them
+    pub fn expiry_hours(&self, default_hours: i32) -> i32 {
+        self.ticket_expiry_hours.unwrap_or(default_hours)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+    use crate::utils::tests::*;
+    use crate::with_test_shop;
+
+    #[test]
+    fn check_test() {
+        assert_eq!(ShopPolicy::default().check(), None);
+        assert_eq!(ShopPolicy { max_queue: Some(0), max_wait_minutes: Some(0), ..Default::default() }.check(), None);
+        assert_eq!(ShopPolicy { ticket_expiry_hours: Some(0), ..Default::default() }.check().map(|e| e.0), Some("ticket_expiry_hours"));
+        assert_eq!(ShopPolicy { max_queue: Some(-1), ..Default::default() }.check().map(|e| e.0), Some("max_queue"));
+        assert_eq!(ShopPolicy { max_customer_tickets: Some(0), ..Default::default() }.check().map(|e| e.0), Some("max_customer_tickets"));
+    }
+
+    #[actix_rt::test]
+    async fn save_load_test() -> Result<(), Box<dyn Error>> {
+        let conn = db().await;
+
+        with_test_shop!(&conn, shopid [] {
+            let mut c = conn.acquire().await?;
+            assert_eq!(ShopPolicy::load(&mut c, shopid).await?, ShopPolicy::default());
+
+            let policy = ShopPolicy { ticket_expiry_hours: Some(2), max_queue: Some(10), walk_in: false, ..Default::default() };
+            policy.save(&conn, shopid).await?;
+            assert_eq!(ShopPolicy::load(&mut c, shopid).await?, policy);
+            assert_eq!(policy.expiry_hours(6), 2);
+
+            ShopPolicy::default().save(&conn, shopid).await?;
+            assert_eq!(ShopPolicy::load(&mut c, shopid).await?, ShopPolicy::default());
+        });
+        Ok(())
+    }
+}
@@ -1,11 +1,18 @@
+use std::collections::HashMap;
+
 use serde::{Serialize, Deserialize};
 use chrono::prelude::*;
+use chrono::Duration;
+use chrono_tz::Tz;
 use futures::StreamExt;
 
-use sqlx::{FromRow, PgPool, query};
+use sqlx::{FromRow, PgConnection, PgPool, query};
 use sqlx::query_as;
 
 use crate::utils::encoding::encode_serial;
+use crate::models::staff::PersistentStaff;
+use crate::models::ticket::PersistentTicket;
+use crate::utils::geo::Coordinates;
 
 /// Row structure for shop
 #[allow(dead_code)]
@@ -16,20 +23,75 @@ pub struct Shop {
     pub description: String,
     pub image: Option<String>,
     pub location: String,
+    pub hidden: bool,
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+    pub street: Option<String>,
+    pub city: Option<String>,
+    pub postal_code: Option<String>,
+    pub country: Option<String>,
+    /// IANA name of the time zone of the shop
+    pub time_zone: String,
+}
+
+impl Shop {
+    /// Time zone of the shop, UTC if the stored name is unknown
+    pub fn time_zone(&self) -> Tz {
+        self.time_zone.parse().unwrap_or(Tz::UTC)
+    }
+
+    /// Coordinates of the shop, if known
+    pub fn position(&self) -> Option<Coordinates> {
+        match (self.lat, self.lon) {
+            (Some(lat), Some(lon)) => Coordinates::new(lat, lon),
+            _ => None,
+        }
+    }
+
+    pub fn address(&self) -> Address {
+        Address {
+            street: self.street.clone(),
+            city: self.city.clone(),
+            postal_code: self.postal_code.clone(),
+            country: self.country.clone(),
+        }
+    }
+}
+
+/// Postal address of a shop
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Address {
+    pub street: Option<String>,
+    pub city: Option<String>,
+    pub postal_code: Option<String>,
+    pub country: Option<String>,
+}
+
+/// Editable fields of a shop, see [`PersistentShop::create`] and [`PersistentShop::update`]
+#[derive(Debug, Clone, Default)]
+pub struct ShopDetails {
+    pub name: String,
+    pub description: String,
+    pub image: Option<String>,
+    pub location: String,
+    pub position: Option<Coordinates>,
+    pub address: Address,
+    /// UTC for new shops and unchanged for existing ones if `None`
+    pub time_zone: Option<Tz>,
 }
 
 /// Row structure for Department
 #[derive(Serialize, Deserialize, FromRow, Debug)]
 pub struct Department {
     pub uid: i32,
-    shop_id: i32,
-    description: String,
-    capacity: i32
+    pub(super) shop_id: i32,
+    pub(super) description: String,
+    pub(super) capacity: i32
 }
 /// Response ready structure for department
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct DepartmentResponse {
-    pub(super) uid: String,
+    pub uid: String,
     description: String,
     capacity: i32,
 }
@@ -51,14 +113,337 @@ pub struct DepartmentOccupancyResponse {
     pub occupancy: i32,
 }
 
+/// ## Result for department creation and editing
+/// + Done(Department): Department created or updated
+/// + NotFound: The shop has no department with the requested id
+/// + AlreadyExists: The shop already has a department with the same description
+/// + InvalidCapacity: The capacity is negative
+#[derive(Debug)]
+pub enum DepartmentResult {
+    Done(Department),
+    NotFound,
+    AlreadyExists,
+    InvalidCapacity,
+}
+
+impl DepartmentResult {
+    pub fn unwrap(self) -> Department {
+        match self {
+            DepartmentResult::Done(d) => d,
+            DepartmentResult::NotFound => panic!("Called unwrap on DepartmentResult::NotFound"),
+            DepartmentResult::AlreadyExists => panic!("Called unwrap on DepartmentResult::AlreadyExists"),
+            DepartmentResult::InvalidCapacity => panic!("Called unwrap on DepartmentResult::InvalidCapacity"),
+        }
+    }
+}
+
+/// ## Result for weekly schedule update
+/// + Updated(Vec<Schedule>): The weekly schedule has been replaced, returns the new schedule
+/// + InvalidDay(i16): The returned day of the week is not between 1 and 7, not updated
+/// + InvalidInterval(i16): A slot in the returned day of the week does not open before closing, not updated
+/// + Overlapping(i16): Two slots in the returned day of the week overlap, not updated
+#[derive(Debug, PartialEq)]
+pub enum ScheduleResult {
+    Updated(Vec<Schedule>),
+    InvalidDay(i16),
+    InvalidInterval(i16),
+    Overlapping(i16),
+}
+
 /// Opening time slot for a shop
 #[allow(dead_code)]
-#[derive(FromRow, Deserialize, Serialize, Debug)]
+#[derive(FromRow, Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct Schedule {
+    pub(super) shop_id: i32,
+    pub(super) dow: i16,
+    pub(super) open: NaiveTime,
+    pub(super) close: NaiveTime,
+}
+
+impl Schedule {
+    pub fn new(shop_id: i32, dow: i16, open: NaiveTime, close: NaiveTime) -> Self {
+        Self { shop_id, dow, open, close }
+    }
+
+    /// Day of the week, 1 is Monday and 7 is Sunday
+    pub fn dow(&self) -> i16 { self.dow }
+    pub fn open(&self) -> NaiveTime { self.open }
+    pub fn close(&self) -> NaiveTime { self.close }
+
+    /// Check if the interval from `start` to `end` falls entirely inside this opening slot
+    pub fn contains(&self, start: NaiveDateTime, end: NaiveDateTime) -> bool {
+        start <= end &&
+            start.date() == end.date() &&
+            start.weekday().number_from_monday() as i16 == self.dow &&
+            self.open <= start.time() &&
+            end.time() <= self.close
+    }
+}
+
+/// Validate a weekly schedule, slots must be in a valid day of the week,
+/// open before they close and must not overlap with other slots in the same day.
+/// Slots where one closes exactly when the next opens are allowed
+/// ### Returns
+/// + `None` if the schedule is valid
+/// + `Some(ScheduleResult)` with the reason why it is not
+pub fn check_schedule(slots: &[Schedule]) -> Option<ScheduleResult> {
+    let mut sorted: Vec<&Schedule> = slots.iter().collect();
+    sorted.sort_by_key(|s| (s.dow, s.open));
+
+    for (i, s) in sorted.iter().enumerate() {
+        if s.dow < 1 || s.dow > 7 {
+            return Some(ScheduleResult::InvalidDay(s.dow));
+        }
+        if s.open >= s.close {
+            return Some(ScheduleResult::InvalidInterval(s.dow));
+        }
+        if i > 0 && sorted[i - 1].dow == s.dow && sorted[i - 1].close > s.open {
+            return Some(ScheduleResult::Overlapping(s.dow));
+        }
+    }
+    None
+}
+
+/// Opening and closing time of a slot on a specific date
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct OpeningSlot {
+    pub open: NaiveTime,
+    pub close: NaiveTime,
+}
+
+/// Opening hours of a shop on a specific date, they replace the weekly schedule of that date.
+/// A day without slots is closed
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ScheduleException {
+    pub day: NaiveDate,
+    pub slots: Vec<OpeningSlot>,
+}
+
+/// Opening hours of a shop on a date, after applying the exceptions to the weekly schedule
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct EffectiveHours {
+    pub day: NaiveDate,
+    pub slots: Vec<OpeningSlot>,
+    /// The hours come from an exception instead of the weekly schedule
+    pub exception: bool,
+}
+
+/// ## Result for schedule exception update
+/// + Updated(Vec<ScheduleException>): The exceptions have been updated, returns the upcoming exceptions
+/// + InPast: The day has already passed, not updated
+/// + InvalidInterval: A slot does not open before closing, not updated
+/// + Overlapping: Two slots overlap, not updated
+#[derive(Debug, PartialEq)]
+pub enum ExceptionResult {
+    Updated(Vec<ScheduleException>),
+    InPast,
+    InvalidInterval,
+    Overlapping,
+}
+
+/// Days of effective opening hours included in [`ShopResponse`], starting from today
+pub const EFFECTIVE_HOURS_DAYS: i64 = 14;
+
+/// ## Opening state of a shop at a given time
+/// + Always: The shop has no weekly schedule and no exceptions, it is considered always open
+/// + OpenUntil(DateTime<Utc>): The shop is open until the returned time, consecutive slots are merged
+/// + Closed: The shop is closed
+#[derive(Debug, PartialEq)]
+pub enum Opening {
+    Always,
+    OpenUntil(DateTime<Utc>),
+    Closed,
+}
+
+/// Instant at which the clocks in `tz` show `local`.
+/// A time repeated when the clocks go back resolves to its first occurrence,
+/// a time skipped when the clocks go forward resolves to the end of the skipped interval
+fn local_instant(tz: Tz, local: NaiveDateTime) -> DateTime<Utc> {
+    let mut t = local;
+    loop {
+        if let Some(instant) = tz.from_local_datetime(&t).earliest() {
+            return instant.with_timezone(&Utc);
+        }
+        t = t.date().and_hms(t.hour(), t.minute(), 0) + Duration::minutes(1);
+    }
+}
+
+/// Opening hours of a shop, the weekly schedule with the exceptions for specific dates applied.
+/// Schedule and exceptions are in the local time of the shop, while instants are in UTC.
+/// A shop without a weekly schedule is considered open all day, except in the days with an exception
+pub struct OpeningHours {
+    time_zone: Tz,
+    slots: Vec<Schedule>,
+    exceptions: HashMap<NaiveDate, Vec<OpeningSlot>>,
+}
+
+impl OpeningHours {
+    pub fn new(time_zone: Tz, mut slots: Vec<Schedule>, exceptions: Vec<ScheduleException>) -> Self {
+        slots.sort_by_key(|s| (s.dow, s.open));
+        let exceptions = exceptions.into_iter()
+            .map(|mut e| {
+                e.slots.sort_by_key(|s| s.open);
+                (e.day, e.slots)
+            })
+            .collect();
+        Self { time_zone, slots, exceptions }
+    }
+
+    /// Load the time zone and the weekly schedule of a shop and its exceptions from yesterday on
+    pub async fn load(conn: &mut PgConnection, shop_id: i32) -> sqlx::Result<Self> {
+        let time_zone = query!(r"SELECT time_zone FROM shop WHERE id = $1", shop_id)
+            .fetch_optional(&mut *conn)
+            .await?
+            .and_then(|r| r.time_zone.parse().ok())
+            .unwrap_or(Tz::UTC);
+        let slots = query_as!(Schedule,
+            r"SELECT shop_id, dow, open, close FROM schedule
+            WHERE shop_id = $1",
+            shop_id)
+            .fetch_all(&mut *conn)
+            .await?;
+        let from = Utc::now().naive_utc().date().pred();
+        let exceptions = query_as!(ExceptionRow,
+            r"SELECT shop_id, day, open, close FROM schedule_exception
+            WHERE shop_id = $1 AND day >= $2
+            ORDER BY day, open",
+            shop_id, from)
+            .fetch_all(&mut *conn)
+            .await?;
+        Ok(Self::new(time_zone, slots, group_exceptions(exceptions).remove(&shop_id).unwrap_or_default()))
+    }
+
+    pub fn time_zone(&self) -> Tz {
+        self.time_zone
+    }
+
+    /// Local time of the shop at instant `at`
+    pub fn local(&self, at: DateTime<Utc>) -> NaiveDateTime {
+        at.with_timezone(&self.time_zone).naive_local()
+    }
+
+    /// Instant at which the clocks of the shop show `local`, see [`local_instant`]
+    pub fn instant(&self, local: NaiveDateTime) -> DateTime<Utc> {
+        local_instant(self.time_zone, local)
+    }
+
+    /// Scheduled opening slots of `day`, ordered by opening time.
+    /// The slots of the exception if there is one for `day`, the weekly ones otherwise
+    pub fn day(&self, day: NaiveDate) -> Vec<OpeningSlot> {
+        match self.exceptions.get(&day) {
+            Some(slots) => slots.clone(),
+            None => {
+                let dow = day.weekday().number_from_monday() as i16;
+                self.slots.iter()
+                    .filter(|s| s.dow == dow)
+                    .map(|s| OpeningSlot { open: s.open, close: s.close })
+                    .collect()
+            }
+        }
+    }
+
+    /// Opening hours for `days` days starting from `from`
+    pub fn effective(&self, from: NaiveDate, days: i64) -> Vec<EffectiveHours> {
+        (0..days)
+            .map(|i| from + Duration::days(i))
+            .map(|day| EffectiveHours {
+                day,
+                slots: self.day(day),
+                exception: self.exceptions.contains_key(&day),
+            })
+            .collect()
+    }
+
+    /// Check if the interval from `start` to `end` falls entirely inside a scheduled slot
+    pub fn contains(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> bool {
+        let (start, end) = (self.local(start), self.local(end));
+        start <= end &&
+            start.date() == end.date() &&
+            self.day(start.date()).iter().any(|s| s.open <= start.time() && end.time() <= s.close)
+    }
+
+    /// Instants in which the shop is open during the local `day`, the whole day if it has no schedule for it.
+    /// Slots are shortened or lengthened by the daylight saving time transitions they contain
+    fn intervals(&self, day: NaiveDate) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+        if self.slots.is_empty() && !self.exceptions.contains_key(&day) {
+            return vec![(self.instant(day.and_hms(0, 0, 0)), self.instant(day.succ().and_hms(0, 0, 0)))];
+        }
+        self.day(day).into_iter()
+            .map(|s| (self.instant(day.and_time(s.open)), self.instant(day.and_time(s.close))))
+            .filter(|(open, close)| open < close)
+            .collect()
+    }
+
+    fn always_open(&self) -> bool {
+        self.slots.is_empty() && self.exceptions.is_empty()
+    }
+
+    /// Opening state at time `at`, see [`Opening`].
+    /// Consecutive slots are merged up to a week after `at`
+    pub fn at(&self, at: DateTime<Utc>) -> Opening {
+        if self.always_open() {
+            return Opening::Always;
+        }
+        let mut close = match self.intervals(self.local(at).date()).into_iter().find(|&(open, close)| open <= at && at < close) {
+            Some((_, close)) => close,
+            None => return Opening::Closed,
+        };
+        while close < at + Duration::weeks(1) {
+            match self.intervals(self.local(close).date()).into_iter().find(|&(open, _)| open == close) {
+                Some((_, next)) => close = next,
+                None => break,
+            }
+        }
+        Opening::OpenUntil(close)
+    }
+
+    /// Time at which `minutes` of opening time have passed, starting from `from`.
+    /// The time spent while the shop is closed is skipped, so with no minutes this is
+    /// `from` if the shop is open and the next opening otherwise
+    pub fn advance(&self, from: DateTime<Utc>, minutes: i64) -> DateTime<Utc> {
+        let mut left = Duration::minutes(minutes.max(0));
+        if self.always_open() {
+            return from + left;
+        }
+        let mut day = self.local(from).date();
+        loop {
+            for (open, close) in self.intervals(day) {
+                let open = open.max(from);
+                if close <= open {
+                    continue;
+                }
+                if close - open >= left {
+                    return open + left;
+                }
+                left = left - (close - open);
+            }
+            day = day.succ();
+        }
+    }
+}
+
+/// Row structure for schedule exceptions, a row without times closes the day
+#[derive(FromRow)]
+struct ExceptionRow {
     shop_id: i32,
-    dow: i16,
-    open: NaiveTime,
-    close: NaiveTime,
+    day: NaiveDate,
+    open: Option<NaiveTime>,
+    close: Option<NaiveTime>,
+}
+
+/// Group the exception rows, ordered by day, by shop and day
+fn group_exceptions(rows: Vec<ExceptionRow>) -> HashMap<i32, Vec<ScheduleException>> {
+    let mut grouped: HashMap<i32, Vec<ScheduleException>> = HashMap::new();
+    for r in rows {
+        let exceptions = grouped.entry(r.shop_id).or_default();
+        if exceptions.last().map(|e| e.day) != Some(r.day) {
+            exceptions.push(ScheduleException { day: r.day, slots: Vec::new() });
+        }
+        if let (Some(open), Some(close)) = (r.open, r.close) {
+            exceptions.last_mut().unwrap().slots.push(OpeningSlot { open, close });
+        }
+    }
+    grouped
 }
 
 ///Response ready structure for shop
@@ -69,9 +454,99 @@ pub struct ShopResponse {
     pub description: String,
     pub image: Option<String>,
     pub location: String,
+    pub position: Option<Coordinates>,
+    pub address: Address,
+    /// IANA name of the time zone of the schedule
+    pub time_zone: String,
+    /// Distance in km from the position used in search, if any
+    pub distance_km: Option<f64>,
+    pub hidden: bool,
     pub departments: Vec<DepartmentResponse>,
     pub weekly_schedule: Vec<Schedule>,
+    /// Opening hours for the next [`EFFECTIVE_HOURS_DAYS`] days, with the exceptions applied
+    pub effective_hours: Vec<EffectiveHours>,
+}
+/// Number of shops returned per page by default
+pub const DEFAULT_PAGE_SIZE: i64 = 20;
+
+/// Parameters for shop search
+#[derive(Debug, Clone)]
+pub struct ShopSearch {
+    /// Words to look for in name, description and departments of the shop
+    pub query: Option<String>,
+    /// Sort results by distance from this position instead of relevance
+    pub near: Option<Coordinates>,
+    /// Only shops within this distance from `near`
+    pub radius_km: Option<f64>,
+    /// Only shops open at this moment, according to the weekly schedule and its exceptions in the local time of the shop
+    pub open_at: Option<DateTime<Utc>>,
+    /// Only shops with a department with this description, case insensitive
+    pub department: Option<String>,
+    /// Only shops where a new ticket would wait at most this many minutes
+    pub max_wait_minutes: Option<f32>,
+    /// Continue after the last result of a previous page
+    pub cursor: Option<SearchCursor>,
+    pub limit: i64,
+}
+
+impl ShopSearch {
+    pub fn new(query: Option<String>) -> Self {
+        Self {
+            query,
+            near: None,
+            radius_km: None,
+            open_at: None,
+            department: None,
+            max_wait_minutes: None,
+            cursor: None,
+            limit: DEFAULT_PAGE_SIZE,
+        }
+    }
+
+    /// Full-text query matching all the words of `query` as prefixes,
+    /// `None` if there is no query or it has no words
+    fn ts_query(&self) -> Option<String> {
+        let words: Vec<String> = self.query.as_deref()?
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|w| !w.is_empty())
+            .map(|w| format!("{}:*", w.to_lowercase()))
+            .collect();
+        if words.is_empty() {
+            None
+        } else {
+            Some(words.join(" & "))
+        }
+    }
+}
+
+/// Position in the results of a search, results are sorted by `key`, then name and id.
+/// `key` is `None` for shops without coordinates when sorting by distance, they come last
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SearchCursor {
+    key: Option<f64>,
+    name: String,
+    id: i32,
+}
+
+impl SearchCursor {
+    /// Opaque representation for clients
+    pub fn encode(&self) -> String {
+        hex::encode(serde_json::to_vec(self).unwrap())
+    }
+
+    pub fn decode(s: &str) -> Option<Self> {
+        serde_json::from_slice(&hex::decode(s).ok()?).ok()
+    }
 }
+
+/// Page of search results
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SearchPage {
+    pub shops: Vec<ShopResponse>,
+    /// Pass as cursor to get the next page, `None` if this is the last page
+    pub next_cursor: Option<String>,
+}
+
 /// Data Access Object for shop
 pub struct PersistentShop<'a> {
     conn: &'a PgPool,
@@ -82,7 +557,7 @@ impl<'a> PersistentShop<'a> {
     /// Retrieve shop from its primary key
     pub async fn get(conn: &'a PgPool, id: i32) -> sqlx::Result<Option<PersistentShop<'a>>> {
         let q = query_as!(Shop,
-            r"SELECT id, name, description, image, location FROM shop
+            r"SELECT id, name, description, image, location, hidden, lat, lon, street, city, postal_code, country, time_zone FROM shop
             WHERE id = $1",
             id
         ).fetch_optional(conn)
@@ -90,23 +565,25 @@ impl<'a> PersistentShop<'a> {
         Ok(q.map(|q| Self {conn, inner: q}))
     }
 
-    /// Retieve information about current per department occupancy
+    /// Retieve information about current per department occupancy, counting both tickets and bookings
     pub async fn get_occupancy(conn: &'a PgPool, shop_id: i32) -> sqlx::Result<Vec<DepartmentOccupancyResponse>> {
         query!(r"SELECT
                     department.id as id,
                     description,
                     capacity,
-                    count(ticket.id) as occupancy
-                FROM department
-                    LEFT JOIN ticket_department ON ticket_department.department_id = department.id
-                    LEFT JOIN ticket 
-                        ON ticket_department.ticket_id = ticket.id AND
+                    (SELECT count(*) FROM ticket_department, ticket
+                        WHERE ticket_department.ticket_id = ticket.id AND
+                            ticket_department.department_id = department.id AND
                             ticket.entry IS NOT NULL AND
-                            ticket.exit IS NULL
+                            ticket.exit IS NULL) +
+                    (SELECT count(*) FROM booking_department, booking
+                        WHERE booking_department.booking_id = booking.id AND
+                            booking_department.department_id = department.id AND
+                            booking.entry IS NOT NULL AND
+                            booking.exit IS NULL) as occupancy
+                FROM department
                 WHERE
-                    department.shop_id = $1    
-                GROUP BY
-                department.id, description, capacity", shop_id)
+                    department.shop_id = $1", shop_id)
             .fetch(conn)
             .fold(Ok(Vec::new()), |acc, r| async {
                 let mut acc = acc?;
@@ -126,21 +603,68 @@ impl<'a> PersistentShop<'a> {
 
     /// Retrieve information about schedule and departments, then transform into a response ready format
     pub async fn to_response(self) -> sqlx::Result<ShopResponse> {
-        let sched = self.schedule().await?;
-        let deps = self.departments().await?;
-
-        Ok(ShopResponse {
-            uid: encode_serial(self.inner.id),
-            name: self.inner.name,
-            description: self.inner.description,
-            image: self.inner.image,
-            location: self.inner.location,
-            departments: deps
-                .into_iter()
-                .map(DepartmentResponse::from)
-                .collect(),
-            weekly_schedule: sched,
-        })
+        let mut resp = Self::to_responses(self.conn, vec![self.inner]).await?;
+        Ok(resp.remove(0))
+    }
+
+    /// Transform many shops into responses, loading schedules, exceptions and departments of all of them with one query each
+    pub async fn to_responses(conn: &'a PgPool, shops: Vec<Shop>) -> sqlx::Result<Vec<ShopResponse>> {
+        let ids: Vec<i32> = shops.iter().map(|s| s.id).collect();
+
+        let mut scheds: HashMap<i32, Vec<Schedule>> = HashMap::new();
+        for s in query_as!(Schedule,
+                r"SELECT shop_id, dow, open, close FROM schedule
+                WHERE shop_id = ANY($1)
+                ORDER BY dow, open",
+                &ids
+            ).fetch_all(conn)
+            .await? {
+            scheds.entry(s.shop_id).or_default().push(s);
+        }
+
+        // Local dates are at most a day away from the UTC one
+        let today = Utc::now().naive_utc().date();
+        let mut exceptions = group_exceptions(query_as!(ExceptionRow,
+                r"SELECT shop_id, day, open, close FROM schedule_exception
+                WHERE shop_id = ANY($1) AND day >= $2 AND day < $3
+                ORDER BY day, open",
+                &ids, today.pred(), today + Duration::days(EFFECTIVE_HOURS_DAYS + 1)
+            ).fetch_all(conn)
+            .await?);
+
+        let mut deps: HashMap<i32, Vec<DepartmentResponse>> = HashMap::new();
+        for d in query_as!(Department,
+                r"SELECT id as uid, shop_id, description, capacity FROM department
+                WHERE shop_id = ANY($1)",
+                &ids
+            ).fetch_all(conn)
+            .await? {
+            deps.entry(d.shop_id).or_default().push(d.into());
+        }
+
+        Ok(shops.into_iter()
+            .map(|shop| {
+                let (position, address) = (shop.position(), shop.address());
+                let weekly_schedule = scheds.remove(&shop.id).unwrap_or_default();
+                let hours = OpeningHours::new(shop.time_zone(), weekly_schedule.clone(), exceptions.remove(&shop.id).unwrap_or_default());
+                let effective_hours = hours.effective(hours.local(Utc::now()).date(), EFFECTIVE_HOURS_DAYS);
+                ShopResponse {
+                    uid: encode_serial(shop.id),
+                    departments: deps.remove(&shop.id).unwrap_or_default(),
+                    weekly_schedule,
+                    effective_hours,
+                    name: shop.name,
+                    description: shop.description,
+                    image: shop.image,
+                    location: shop.location,
+                    position,
+                    address,
+                    time_zone: shop.time_zone,
+                    distance_km: None,
+                    hidden: shop.hidden,
+                }
+            })
+            .collect())
     }
 
     /// Retrieve schedule for this shop
@@ -164,30 +688,354 @@ impl<'a> PersistentShop<'a> {
         .await?)
     }
 
-    /// Search shops by name, matches case insensitive substrings
-    pub async fn search(conn: &'a PgPool, query: Option<String>) -> sqlx::Result<Vec<ShopResponse>> {
-        let stream = if let Some(q) = query {
-            query_as!(Shop,
-                r"SELECT id, name, description, image, location FROM shop
-                WHERE name ILIKE '%' || $1 || '%'
-                ORDER BY name",
-                q
-            ).fetch(conn)
-        } else {
-            query_as!(Shop,
-                r"SELECT id, name, description, image, location FROM shop
-                ORDER BY name"
-            ).fetch(conn)
-        };
-        let res = stream
-            .fold(Ok(Vec::new()), |acc, s| async {
-                let mut acc = acc?;
-                let ps = Self {conn, inner: s?};
-                acc.push(ps.to_response().await?);
-                Ok(acc)
-            }).await;
+    /// Search visible shops, see [`ShopSearch`] for the parameters.
+    /// Results are sorted by relevance, or by distance if a position is set (shops without coordinates come last).
+    /// Without a query or a position they are sorted by name
+    pub async fn search(conn: &'a PgPool, search: &ShopSearch) -> sqlx::Result<SearchPage> {
+        let ts_query = search.ts_query();
+        let (lat, lon) = (search.near.map(|c| c.lat), search.near.map(|c| c.lon));
+        let limit = search.limit.max(1);
+
+        let mut shops = Vec::new();
+        let mut distances = Vec::new();
+        let mut cursor = search.cursor.clone();
+        loop {
+            let (c_key, c_name, c_id) = match &cursor {
+                Some(c) => (c.key, Some(c.name.clone()), Some(c.id)),
+                None => (None, None, None),
+            };
+            let rows = query!(
+                r#"SELECT id, name, description, image, location, hidden, lat, lon, street, city, postal_code, country, time_zone, distance, sort_key
+                FROM (
+                    SELECT id, name, description, image, location, hidden, lat, lon, street, city, postal_code, country, time_zone,
+                        distance_km($2, $3, lat, lon) AS distance,
+                        CASE
+                            WHEN $2::DOUBLE PRECISION IS NOT NULL THEN distance_km($2, $3, lat, lon)
+                            WHEN $1::TEXT IS NOT NULL THEN -ts_rank(search_document, to_tsquery('simple', $1))::DOUBLE PRECISION
+                            ELSE 0
+                        END AS sort_key
+                    FROM shop
+                    WHERE
+                        NOT hidden AND
+                        ($1::TEXT IS NULL OR search_document @@ to_tsquery('simple', $1)) AND
+                        ($4::DOUBLE PRECISION IS NULL OR distance_km($2, $3, lat, lon) <= $4) AND
+                        ($5::TIMESTAMPTZ IS NULL OR CASE
+                            WHEN EXISTS (
+                                SELECT 1 FROM schedule_exception
+                                WHERE schedule_exception.shop_id = shop.id AND day = ($5 AT TIME ZONE time_zone)::DATE) THEN EXISTS (
+                                SELECT 1 FROM schedule_exception
+                                WHERE schedule_exception.shop_id = shop.id AND day = ($5 AT TIME ZONE time_zone)::DATE AND
+                                    open <= ($5 AT TIME ZONE time_zone)::TIME AND ($5 AT TIME ZONE time_zone)::TIME < close)
+                            WHEN NOT EXISTS (SELECT 1 FROM schedule WHERE schedule.shop_id = shop.id) THEN TRUE
+                            ELSE EXISTS (
+                                SELECT 1 FROM schedule
+                                WHERE schedule.shop_id = shop.id AND dow = EXTRACT(ISODOW FROM $5 AT TIME ZONE time_zone) AND
+                                    open <= ($5 AT TIME ZONE time_zone)::TIME AND ($5 AT TIME ZONE time_zone)::TIME < close)
+                        END) AND
+                        ($6::TEXT IS NULL OR EXISTS (
+                            SELECT 1 FROM department
+                            WHERE department.shop_id = shop.id AND lower(department.description) = lower($6)))
+                ) results
+                WHERE $8::TEXT IS NULL OR CASE
+                    WHEN $7::DOUBLE PRECISION IS NULL THEN sort_key IS NULL AND (name, id) > ($8, $9)
+                    ELSE sort_key IS NULL OR (sort_key, name, id) > ($7, $8, $9)
+                END
+                ORDER BY sort_key NULLS LAST, name, id
+                LIMIT $10"#,
+                ts_query, lat, lon, search.radius_km, search.open_at, search.department, c_key, c_name, c_id, limit + 1
+            ).fetch_all(conn)
+            .await?;
+            let exhausted = rows.len() as i64 <= limit;
+            let waits = match search.max_wait_minutes {
+                Some(_) => {
+                    let ids: Vec<i32> = rows.iter().map(|r| r.id).collect();
+                    PersistentTicket::wait_minutes_for(&mut *conn.acquire().await?, &ids).await?
+                }
+                None => HashMap::new(),
+            };
+
+            for r in rows {
+                if shops.len() as i64 == limit {
+                    return Self::search_page(conn, shops, distances, cursor).await;
+                }
+                cursor = Some(SearchCursor { key: r.sort_key, name: r.name.clone(), id: r.id });
+
+                if let Some(max_wait) = search.max_wait_minutes {
+                    if waits.get(&r.id).copied().unwrap_or(0.) > max_wait {
+                        continue;
+                    }
+                }
+                let shop = Shop {
+                    id: r.id,
+                    name: r.name,
+                    description: r.description,
+                    image: r.image,
+                    location: r.location,
+                    hidden: r.hidden,
+                    lat: r.lat,
+                    lon: r.lon,
+                    street: r.street,
+                    city: r.city,
+                    postal_code: r.postal_code,
+                    country: r.country,
+                    time_zone: r.time_zone,
+                };
+                shops.push(shop);
+                distances.push(r.distance);
+            }
+
+            if exhausted {
+                return Self::search_page(conn, shops, distances, None).await;
+            }
+        }
+    }
+
+    /// Build a page of search results from the matching shops
+    async fn search_page(conn: &'a PgPool, shops: Vec<Shop>, distances: Vec<Option<f64>>, cursor: Option<SearchCursor>) -> sqlx::Result<SearchPage> {
+        let mut shops = Self::to_responses(conn, shops).await?;
+        for (s, d) in shops.iter_mut().zip(distances) {
+            s.distance_km = d;
+        }
+        Ok(SearchPage {
+            shops,
+            next_cursor: cursor.map(|c| c.encode()),
+        })
+    }
+
+    /// Retrieve all shops, including hidden ones
+    pub async fn list(conn: &'a PgPool) -> sqlx::Result<Vec<ShopResponse>> {
+        let shops = query_as!(Shop,
+            r"SELECT id, name, description, image, location, hidden, lat, lon, street, city, postal_code, country, time_zone FROM shop
+            ORDER BY name"
+        ).fetch_all(conn)
+        .await?;
+        Self::to_responses(conn, shops).await
+    }
+
+    /// Retrieve the shops visible to the managers of `shop_id`: all the visible shops and their own, even if hidden
+    pub async fn list_for_manager(conn: &'a PgPool, shop_id: i32) -> sqlx::Result<Vec<ShopResponse>> {
+        let shops = query_as!(Shop,
+            r"SELECT id, name, description, image, location, hidden, lat, lon, street, city, postal_code, country, time_zone FROM shop
+            WHERE NOT hidden OR id = $1
+            ORDER BY name",
+            shop_id
+        ).fetch_all(conn)
+        .await?;
+        Self::to_responses(conn, shops).await
+    }
+
+    /// Create a new shop with no departments and no schedule
+    pub async fn create(conn: &'a PgPool, details: &ShopDetails) -> sqlx::Result<PersistentShop<'a>> {
+        let shop = Self::insert(&mut *conn.acquire().await?, details).await?;
+        Ok(Self {conn, inner: shop})
+    }
+
+    /// Create a new shop together with the temporary account of its first manager, see [`PersistentStaff::create_temp`]
+    /// ### Returns
+    /// + `Some((shop, code))` with the activation code of the manager account
+    /// + `None` if a staff account with `manager_email` already exists or is pending for another shop, the shop is not created
+    pub async fn create_with_manager(conn: &'a PgPool, details: &ShopDetails, manager_email: &str) -> sqlx::Result<Option<(PersistentShop<'a>, Vec<u8>)>> {
+        let mut tx = conn.begin().await?;
+        let shop = Self::insert(&mut tx, details).await?;
+        match PersistentStaff::create_temp_in(&mut tx, manager_email, shop.id, true).await? {
+            Some(code) => {
+                tx.commit().await?;
+                Ok(Some((Self {conn, inner: shop}, code)))
+            }
+            None => {
+                tx.rollback().await?;
+                Ok(None)
+            }
+        }
+    }
 
-        res
+    async fn insert(conn: &mut PgConnection, details: &ShopDetails) -> sqlx::Result<Shop> {
+        let (position, address) = (details.position, &details.address);
+        query_as!(Shop,
+            r"INSERT INTO shop (name, description, image, location, lat, lon, street, city, postal_code, country, time_zone)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, COALESCE($11, 'UTC'))
+            RETURNING id, name, description, image, location, hidden, lat, lon, street, city, postal_code, country, time_zone",
+            details.name, details.description, details.image, details.location, position.map(|c| c.lat), position.map(|c| c.lon),
+            address.street, address.city, address.postal_code, address.country, details.time_zone.map(|tz| tz.name())
+        ).fetch_one(conn)
+        .await
+    }
+
+    /// Update the details of this shop, schedule and exceptions keep their local times if the time zone changes
+    pub async fn update(&mut self, details: &ShopDetails) -> sqlx::Result<()> {
+        let (position, address) = (details.position, &details.address);
+        self.inner = query_as!(Shop,
+            r"UPDATE shop
+            SET
+                name = $2, description = $3, image = $4, location = $5, lat = $6, lon = $7,
+                street = $8, city = $9, postal_code = $10, country = $11, time_zone = COALESCE($12, time_zone)
+            WHERE id = $1
+            RETURNING id, name, description, image, location, hidden, lat, lon, street, city, postal_code, country, time_zone",
+            self.inner.id, details.name, details.description, details.image, details.location, position.map(|c| c.lat), position.map(|c| c.lon),
+            address.street, address.city, address.postal_code, address.country, details.time_zone.map(|tz| tz.name())
+        ).fetch_one(self.conn)
+        .await?;
+        Ok(())
+    }
+
+    /// Show or hide this shop, hidden shops do not appear in search results
+    pub async fn set_hidden(&mut self, hidden: bool) -> sqlx::Result<()> {
+        query!("UPDATE shop SET hidden = $2 WHERE id = $1", self.inner.id, hidden)
+            .execute(self.conn)
+            .await?;
+        self.inner.hidden = hidden;
+        Ok(())
+    }
+
+    /// Add a department to this shop
+    /// See [`DepartmentResult`] for the result
+    pub async fn add_department(&self, description: &str, capacity: i32) -> sqlx::Result<DepartmentResult> {
+        if capacity < 0 {
+            return Ok(DepartmentResult::InvalidCapacity);
+        }
+        let mut tx = self.conn.begin().await?;
+
+        let exists = query!(r"SELECT id FROM department WHERE shop_id = $1 AND description = $2",
+                self.inner.id, description)
+            .fetch_optional(&mut tx)
+            .await?;
+        if exists.is_some() {
+            return Ok(DepartmentResult::AlreadyExists);
+        }
+
+        let department = query_as!(Department,
+            r"INSERT INTO department (shop_id, description, capacity)
+            VALUES ($1, $2, $3)
+            RETURNING id as uid, shop_id, description, capacity",
+            self.inner.id, description, capacity
+        ).fetch_one(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(DepartmentResult::Done(department))
+    }
+
+    /// Rename or resize a department of this shop, fields that are `None` are left unchanged
+    /// See [`DepartmentResult`] for the result
+    pub async fn edit_department(&self, department_id: i32, description: Option<&str>, capacity: Option<i32>) -> sqlx::Result<DepartmentResult> {
+        if matches!(capacity, Some(c) if c < 0) {
+            return Ok(DepartmentResult::InvalidCapacity);
+        }
+        let mut tx = self.conn.begin().await?;
+
+        let current = query!(r"SELECT id FROM department WHERE id = $1 AND shop_id = $2 FOR UPDATE",
+                department_id, self.inner.id)
+            .fetch_optional(&mut tx)
+            .await?;
+        if current.is_none() {
+            return Ok(DepartmentResult::NotFound);
+        }
+
+        if let Some(description) = description {
+            let duplicate = query!(r"SELECT id FROM department WHERE shop_id = $1 AND description = $2 AND id <> $3",
+                    self.inner.id, description, department_id)
+                .fetch_optional(&mut tx)
+                .await?;
+            if duplicate.is_some() {
+                return Ok(DepartmentResult::AlreadyExists);
+            }
+        }
+
+        let department = query_as!(Department,
+            r"UPDATE department SET
+                description = COALESCE($2, description),
+                capacity = COALESCE($3, capacity)
+            WHERE id = $1
+            RETURNING id as uid, shop_id, description, capacity",
+            department_id, description, capacity
+        ).fetch_one(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(DepartmentResult::Done(department))
+    }
+
+    /// Replace the weekly schedule of this shop with the slots `(dow, open, close)`
+    /// See [`ScheduleResult`] for the result and [`check_schedule`] for the validation rules
+    pub async fn set_schedule(&self, slots: Vec<(i16, NaiveTime, NaiveTime)>) -> sqlx::Result<ScheduleResult> {
+        let slots: Vec<Schedule> = slots.into_iter()
+            .map(|(dow, open, close)| Schedule::new(self.inner.id, dow, open, close))
+            .collect();
+        if let Some(invalid) = check_schedule(&slots) {
+            return Ok(invalid);
+        }
+        let mut tx = self.conn.begin().await?;
+
+        query!("DELETE FROM schedule WHERE shop_id = $1", self.inner.id)
+            .execute(&mut tx)
+            .await?;
+        for s in slots.iter() {
+            query!(r"INSERT INTO schedule (shop_id, dow, open, close) VALUES ($1, $2, $3, $4)",
+                    s.shop_id, s.dow, s.open, s.close)
+                .execute(&mut tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+        Ok(ScheduleResult::Updated(self.schedule().await?))
+    }
+
+    /// Local date of the shop at this moment
+    fn today(&self) -> NaiveDate {
+        Utc::now().with_timezone(&self.inner.time_zone()).date().naive_local()
+    }
+
+    /// Retrieve the exceptions to the weekly schedule of this shop from today on, ordered by day
+    pub async fn exceptions(&self) -> sqlx::Result<Vec<ScheduleException>> {
+        let rows = query_as!(ExceptionRow,
+            r"SELECT shop_id, day, open, close FROM schedule_exception
+            WHERE shop_id = $1 AND day >= $2
+            ORDER BY day, open",
+            self.inner.id, self.today()
+        ).fetch_all(self.conn)
+        .await?;
+        Ok(group_exceptions(rows).remove(&self.inner.id).unwrap_or_default())
+    }
+
+    /// Replace the opening hours of this shop on `day` with `slots`, an empty list closes the shop for the day.
+    /// With `None` the exception is removed and the weekly schedule applies again.
+    /// See [`ExceptionResult`] for the result, slots are validated like the ones of [`check_schedule`]
+    pub async fn set_exception(&self, day: NaiveDate, slots: Option<Vec<OpeningSlot>>) -> sqlx::Result<ExceptionResult> {
+        if day < self.today() {
+            return Ok(ExceptionResult::InPast);
+        }
+        let dow = day.weekday().number_from_monday() as i16;
+        let check: Vec<Schedule> = slots.iter()
+            .flatten()
+            .map(|s| Schedule::new(self.inner.id, dow, s.open, s.close))
+            .collect();
+        match check_schedule(&check) {
+            Some(ScheduleResult::Overlapping(_)) => return Ok(ExceptionResult::Overlapping),
+            Some(_) => return Ok(ExceptionResult::InvalidInterval),
+            None => {}
+        }
+        let mut tx = self.conn.begin().await?;
+
+        query!("DELETE FROM schedule_exception WHERE shop_id = $1 AND day = $2", self.inner.id, day)
+            .execute(&mut tx)
+            .await?;
+        match slots {
+            Some(slots) if slots.is_empty() => {
+                query!(r"INSERT INTO schedule_exception (shop_id, day) VALUES ($1, $2)", self.inner.id, day)
+                    .execute(&mut tx)
+                    .await?;
+            }
+            Some(slots) => for s in slots {
+                query!(r"INSERT INTO schedule_exception (shop_id, day, open, close) VALUES ($1, $2, $3, $4)",
+                        self.inner.id, day, s.open, s.close)
+                    .execute(&mut tx)
+                    .await?;
+            }
+            None => {}
+        }
+
+        tx.commit().await?;
+        Ok(ExceptionResult::Updated(self.exceptions().await?))
     }
 
     pub fn into_inner(self) -> Shop {self.inner}
@@ -197,12 +1045,368 @@ impl<'a> PersistentShop<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::DEFAULT_TICKET_EXPIRY_HOURS as EXPIRY_HOURS;
+    use crate::config::DEFAULT_CLOSING_CUTOFF_MINUTES as CUTOFF_MINUTES;
     use std::error::Error;
 
     use crate::models::ticket::{EnterResult, PersistentTicket};
-    use crate::utils::tests::{db, del_customer, test_customer};
+    use crate::utils::tests::{db, del_customer, del_shop, test_customer};
     use crate::{ with_test_shop};
 
+    fn slot(dow: i16, open: &str, close: &str) -> Schedule {
+        let open = NaiveTime::parse_from_str(open, "%H:%M").unwrap();
+        let close = NaiveTime::parse_from_str(close, "%H:%M").unwrap();
+        Schedule::new(0, dow, open, close)
+    }
+
+    fn test_details(name: &str) -> ShopDetails {
+        ShopDetails {
+            name: name.to_owned(),
+            description: "Test".into(),
+            location: "TEST".into(),
+            ..ShopDetails::default()
+        }
+    }
+
+    #[test]
+    fn check_schedule_test() {
+        assert_eq!(check_schedule(&[]), None);
+        assert_eq!(check_schedule(&[slot(1, "09:00", "12:00"), slot(1, "12:00", "18:00"), slot(2, "09:00", "18:00")]), None);
+        assert_eq!(check_schedule(&[slot(8, "09:00", "12:00")]), Some(ScheduleResult::InvalidDay(8)));
+        assert_eq!(check_schedule(&[slot(0, "09:00", "12:00")]), Some(ScheduleResult::InvalidDay(0)));
+        assert_eq!(check_schedule(&[slot(3, "12:00", "12:00")]), Some(ScheduleResult::InvalidInterval(3)));
+        assert_eq!(check_schedule(&[slot(4, "14:00", "18:00"), slot(5, "09:00", "12:00"), slot(4, "09:00", "14:30")]), Some(ScheduleResult::Overlapping(4)));
+    }
+
+    #[test]
+    fn opening_hours_test() {
+        let at = |d: u32, h: u32, m: u32| Utc.ymd(2021, 1, d).and_hms(h, m, 0); // 2021-01-04 is a Monday
+
+        let always = OpeningHours::new(Tz::UTC, vec![], vec![]);
+        assert_eq!(always.at(at(4, 3, 0)), Opening::Always);
+        assert_eq!(always.advance(at(4, 3, 0), 30), at(4, 3, 30));
+
+        let hours = OpeningHours::new(Tz::UTC, vec![slot(1, "14:00", "18:00"), slot(1, "09:00", "12:00"), slot(1, "12:00", "13:00"), slot(3, "09:00", "12:00")], vec![]);
+        assert_eq!(hours.at(at(4, 8, 59)), Opening::Closed);
+        assert_eq!(hours.at(at(4, 9, 0)), Opening::OpenUntil(at(4, 13, 0))); // Consecutive slots are merged
+        assert_eq!(hours.at(at(4, 13, 0)), Opening::Closed);
+        assert_eq!(hours.at(at(4, 17, 59)), Opening::OpenUntil(at(4, 18, 0)));
+        assert_eq!(hours.at(at(5, 10, 0)), Opening::Closed);
+        assert_eq!(hours.at(at(11, 10, 0)), Opening::OpenUntil(at(11, 13, 0))); // Next Monday
+
+        assert_eq!(hours.advance(at(4, 10, 0), 0), at(4, 10, 0));
+        assert_eq!(hours.advance(at(4, 7, 0), 0), at(4, 9, 0));
+        assert_eq!(hours.advance(at(4, 12, 30), 60), at(4, 14, 30));
+        assert_eq!(hours.advance(at(4, 17, 0), 90), at(6, 9, 30));
+        assert_eq!(hours.advance(at(6, 11, 0), 120), at(11, 10, 0));
+    }
+
+    #[test]
+    fn schedule_exception_test() {
+        let at = |d: u32, h: u32, m: u32| Utc.ymd(2021, 1, d).and_hms(h, m, 0); // 2021-01-04 is a Monday
+        let exception = |d: u32, slots: &[(&str, &str)]| ScheduleException {
+            day: NaiveDate::from_ymd(2021, 1, d),
+            slots: slots.iter()
+                .map(|(open, close)| { let s = slot(1, open, close); OpeningSlot { open: s.open, close: s.close } })
+                .collect(),
+        };
+
+        let hours = OpeningHours::new(Tz::UTC, vec![slot(1, "09:00", "18:00")], vec![exception(11, &[]), exception(12, &[("10:00", "12:00")])]);
+        assert_eq!(hours.at(at(4, 10, 0)), Opening::OpenUntil(at(4, 18, 0)));
+        assert_eq!(hours.at(at(11, 10, 0)), Opening::Closed);
+        assert_eq!(hours.at(at(12, 10, 30)), Opening::OpenUntil(at(12, 12, 0)));
+        assert_eq!(hours.advance(at(4, 17, 0), 120), at(12, 11, 0));
+        assert!(hours.contains(at(12, 10, 0), at(12, 11, 0)));
+        assert!(!hours.contains(at(11, 10, 0), at(11, 11, 0)));
+        let day = |d| NaiveDate::from_ymd(2021, 1, d);
+        assert_eq!(hours.effective(day(11), 3), vec![
+            EffectiveHours { day: day(11), slots: vec![], exception: true },
+            EffectiveHours { day: day(12), slots: exception(12, &[("10:00", "12:00")]).slots, exception: true },
+            EffectiveHours { day: day(13), slots: vec![], exception: false },
+        ]);
+
+        // Without a weekly schedule only the exceptions close the shop
+        let hours = OpeningHours::new(Tz::UTC, vec![], vec![exception(5, &[])]);
+        assert_eq!(hours.at(at(3, 12, 0)), Opening::OpenUntil(at(5, 0, 0)));
+        assert_eq!(hours.at(at(5, 12, 0)), Opening::Closed);
+        assert_eq!(hours.advance(at(4, 23, 30), 60), at(6, 0, 30));
+        assert!(!hours.contains(at(4, 10, 0), at(4, 11, 0))); // Bookings need opening hours
+    }
+
+    #[test]
+    fn time_zone_test() {
+        let utc = |m: u32, d: u32, h: u32, min: u32| Utc.ymd(2021, m, d).and_hms(h, min, 0);
+        let every_day = |open, close| (1..=7).map(|dow| slot(dow, open, close)).collect::<Vec<_>>();
+
+        // In Rome the clocks go forward from 02:00 to 03:00 on 2021-03-28 and back from 03:00 to 02:00 on 2021-10-31
+        let hours = OpeningHours::new(Tz::Europe__Rome, every_day("09:00", "18:00"), vec![]);
+        assert_eq!(hours.at(utc(3, 27, 8, 30)), Opening::OpenUntil(utc(3, 27, 17, 0))); // UTC+1
+        assert_eq!(hours.at(utc(3, 28, 7, 30)), Opening::OpenUntil(utc(3, 28, 16, 0))); // UTC+2
+        assert_eq!(hours.at(utc(3, 28, 16, 30)), Opening::Closed);
+        assert_eq!(hours.at(utc(10, 31, 8, 30)), Opening::OpenUntil(utc(10, 31, 17, 0)));
+        assert_eq!(hours.advance(utc(3, 27, 16, 0), 120), utc(3, 28, 8, 0));
+        assert!(hours.contains(utc(3, 28, 7, 0), utc(3, 28, 8, 0)));
+        assert!(!hours.contains(utc(3, 27, 7, 0), utc(3, 27, 8, 0)));
+
+        // Slots containing a transition are shortened or lengthened by an hour
+        let night = OpeningHours::new(Tz::Europe__Rome, every_day("01:00", "04:00"), vec![]);
+        assert_eq!(night.at(utc(3, 28, 0, 0)), Opening::OpenUntil(utc(3, 28, 2, 0)));
+        assert_eq!(night.advance(utc(3, 28, 0, 0), 150), utc(3, 28, 23, 30));
+        assert_eq!(night.at(utc(10, 30, 23, 0)), Opening::OpenUntil(utc(10, 31, 3, 0)));
+
+        // Skipped times open at the end of the transition, repeated ones at their first occurrence
+        let skipped = OpeningHours::new(Tz::Europe__Rome, every_day("02:30", "05:00"), vec![]);
+        assert_eq!(skipped.at(utc(3, 28, 0, 45)), Opening::Closed);
+        assert_eq!(skipped.at(utc(3, 28, 1, 0)), Opening::OpenUntil(utc(3, 28, 3, 0)));
+        assert_eq!(skipped.at(utc(10, 31, 0, 29)), Opening::Closed);
+        assert_eq!(skipped.at(utc(10, 31, 0, 30)), Opening::OpenUntil(utc(10, 31, 4, 0)));
+
+        // Exceptions close the whole local day, 23 hours long in New York on 2021-03-14
+        let closed = ScheduleException { day: NaiveDate::from_ymd(2021, 3, 14), slots: vec![] };
+        let hours = OpeningHours::new(Tz::America__New_York, vec![], vec![closed]);
+        assert_eq!(hours.at(utc(3, 13, 12, 0)), Opening::OpenUntil(utc(3, 14, 5, 0)));
+        assert_eq!(hours.at(utc(3, 15, 3, 59)), Opening::Closed);
+        assert_eq!(hours.at(utc(3, 15, 4, 0)), Opening::OpenUntil(utc(3, 22, 4, 0)));
+    }
+
+    #[actix_rt::test]
+    async fn manage_shop_test() -> Result<(), Box<dyn Error>> {
+        let conn = db().await;
+        let name = format!("Managed shop {}", rand::random::<u32>());
+
+        let mut shop = PersistentShop::create(&conn, &test_details(&name)).await?;
+        let id = shop.inner().id;
+        assert!(!shop.inner().hidden);
+        assert_eq!(shop.inner().position(), None);
+        assert_eq!(shop.inner().time_zone(), Tz::UTC);
+        assert_eq!(PersistentShop::search(&conn, &ShopSearch::new(Some(name.clone()))).await?.shops.len(), 1);
+
+        shop.set_hidden(true).await?;
+        assert!(PersistentShop::search(&conn, &ShopSearch::new(Some(name.clone()))).await?.shops.is_empty());
+        assert!(PersistentShop::list(&conn).await?.iter().any(|s| s.name == name && s.hidden));
+
+        let address = Address {
+            street: Some("Via Roma 1".into()),
+            city: Some("Milano".into()),
+            postal_code: Some("20121".into()),
+            country: Some("IT".into()),
+        };
+        shop.update(&ShopDetails {
+            description: "Edited".into(),
+            image: Some("image.jpg".into()),
+            position: Coordinates::new(45.4642, 9.19),
+            address: address.clone(),
+            time_zone: Some(Tz::Europe__Rome),
+            ..test_details(&name)
+        }).await?;
+        let loaded = PersistentShop::get(&conn, id).await?.unwrap().into_inner();
+        assert_eq!(loaded.description, "Edited");
+        assert_eq!(loaded.image.as_deref(), Some("image.jpg"));
+        assert_eq!(loaded.position(), Coordinates::new(45.4642, 9.19));
+        assert_eq!(loaded.address(), address);
+        assert_eq!(loaded.time_zone(), Tz::Europe__Rome);
+        assert!(loaded.hidden);
+
+        shop.update(&test_details(&name)).await?; // The time zone is kept when not set
+        assert_eq!(shop.inner().time_zone(), Tz::Europe__Rome);
+
+        del_shop(&conn, id).await?;
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn search_near_test() -> Result<(), Box<dyn Error>> {
+        let conn = db().await;
+        let prefix = format!("Near shop {:08x}", rand::random::<u32>());
+        let origin = Coordinates::new(45.4642, 9.19).unwrap();
+
+        let mut ids = Vec::new();
+        for (name, position) in [("far", Coordinates::new(45.0703, 7.6869)), ("near", Coordinates::new(45.4700, 9.19)), ("mid", Coordinates::new(45.6, 9.3)), ("unknown", None), ("void", None)].iter() {
+            let shop = PersistentShop::create(&conn, &ShopDetails { position: *position, ..test_details(&format!("{} {}", prefix, name)) }).await?;
+            ids.push(shop.inner().id);
+        }
+
+        let names = |res: &[ShopResponse]| res.iter()
+            .map(|s| s.name.trim_start_matches(&prefix).trim().to_owned())
+            .collect::<Vec<_>>();
+        let search = ShopSearch { near: Some(origin), ..ShopSearch::new(Some(prefix.clone())) };
+
+        let res = PersistentShop::search(&conn, &search).await?.shops;
+        assert_eq!(names(&res), vec!["near", "mid", "far", "unknown", "void"]);
+        assert!((res[0].distance_km.unwrap() - 0.64).abs() < 0.05);
+        assert!((res[2].distance_km.unwrap() - 125.5).abs() < 1.);
+        assert_eq!(res[3].distance_km, None);
+
+        let res = PersistentShop::search(&conn, &ShopSearch { radius_km: Some(50.), ..search.clone() }).await?.shops;
+        assert_eq!(names(&res), vec!["near", "mid"]);
+
+        let res = PersistentShop::search(&conn, &ShopSearch::new(Some(prefix.clone()))).await?.shops;
+        assert!(res.iter().all(|s| s.distance_km.is_none()));
+
+        let first = PersistentShop::search(&conn, &ShopSearch { limit: 3, ..search.clone() }).await?;
+        assert_eq!(names(&first.shops), vec!["near", "mid", "far"]);
+        let cursor = SearchCursor::decode(&first.next_cursor.unwrap());
+        let second = PersistentShop::search(&conn, &ShopSearch { limit: 3, cursor, ..search.clone() }).await?;
+        assert_eq!(names(&second.shops), vec!["unknown", "void"]);
+        assert_eq!(second.next_cursor, None);
+
+        // Paging past the shops without coordinates
+        let mut page = PersistentShop::search(&conn, &ShopSearch { limit: 1, ..search.clone() }).await?;
+        let mut paged = names(&page.shops);
+        while let Some(cursor) = page.next_cursor {
+            let cursor = SearchCursor::decode(&cursor);
+            assert!(cursor.is_some());
+            page = PersistentShop::search(&conn, &ShopSearch { limit: 1, cursor, ..search.clone() }).await?;
+            paged.extend(names(&page.shops));
+        }
+        assert_eq!(paged, vec!["near", "mid", "far", "unknown", "void"]);
+
+        for id in ids {
+            del_shop(&conn, id).await?;
+        }
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn full_text_search_test() -> Result<(), Box<dyn Error>> {
+        let conn = db().await;
+        let tag = format!("fts{:08x}", rand::random::<u32>());
+        let monday_10 = Utc.from_utc_datetime(&NaiveDate::from_isoywd(2021, 10, Weekday::Mon).and_hms(10, 0, 0));
+        let four_hours = chrono::Duration::hours(4);
+
+        let details = |name: &str, description: &str| ShopDetails { description: description.into(), ..test_details(&format!("{} {}", tag, name)) };
+        let bakery = PersistentShop::create(&conn, &details("Bakery", "Fresh bread every day")).await?;
+        let grocer = PersistentShop::create(&conn, &details("Grocer", "Fruit and vegetables")).await?;
+        let market = PersistentShop::create(&conn, &ShopDetails { time_zone: Some(Tz::America__New_York), ..details("Market", "Everything") }).await?;
+        market.add_department("Bread", 5).await?.unwrap();
+        market.add_department("Fruit", 5).await?.unwrap();
+        grocer.add_department("Fruit", 2).await?.unwrap();
+        let sched = bakery.set_schedule(vec![(1, NaiveTime::from_hms(8, 0, 0), NaiveTime::from_hms(13, 0, 0))]).await?;
+        assert!(matches!(sched, ScheduleResult::Updated(_)));
+        let sched = market.set_schedule(vec![(1, NaiveTime::from_hms(8, 0, 0), NaiveTime::from_hms(13, 0, 0))]).await?;
+        assert!(matches!(sched, ScheduleResult::Updated(_)));
+
+        let names = |page: &SearchPage| page.shops.iter()
+            .map(|s| s.name.trim_start_matches(&tag).trim().to_owned())
+            .collect::<Vec<_>>();
+        let search = |q: &str| ShopSearch::new(Some(format!("{} {}", tag, q)));
+
+        // Name matches rank above description, description above departments
+        let res = PersistentShop::search(&conn, &search("bread")).await?;
+        assert_eq!(names(&res), vec!["Bakery", "Market"]);
+        let res = PersistentShop::search(&conn, &search("fru")).await?; // Prefix match
+        assert_eq!(names(&res), vec!["Grocer", "Market"]);
+        let res = PersistentShop::search(&conn, &search("bakery")).await?;
+        assert_eq!(names(&res), vec!["Bakery"]);
+        let res = PersistentShop::search(&conn, &search("'&|!")).await?; // Operators are ignored
+        assert_eq!(res.shops.len(), 3);
+
+        let res = PersistentShop::search(&conn, &ShopSearch { department: Some("fruit".into()), ..search("") }).await?;
+        assert_eq!(names(&res), vec!["Grocer", "Market"]);
+
+        // Shops without a schedule are always open
+        let res = PersistentShop::search(&conn, &ShopSearch { open_at: Some(monday_10), ..search("") }).await?;
+        assert_eq!(names(&res), vec!["Bakery", "Grocer"]);
+        let res = PersistentShop::search(&conn, &ShopSearch { open_at: Some(monday_10 + four_hours), ..search("") }).await?;
+        assert_eq!(names(&res), vec!["Grocer", "Market"]); // 09:00 in New York
+
+        // Exceptions replace the weekly schedule of their day
+        let t = |h| NaiveTime::from_hms(h, 0, 0);
+        let today = Utc::now().naive_utc().date();
+        let next_monday = (1..=7).map(|i| today + chrono::Duration::days(i)).find(|d| d.weekday() == Weekday::Mon).unwrap();
+        assert_eq!(bakery.set_exception(today.pred(), Some(vec![])).await?, ExceptionResult::InPast);
+        assert_eq!(bakery.set_exception(next_monday, Some(vec![OpeningSlot { open: t(12), close: t(10) }])).await?, ExceptionResult::InvalidInterval);
+        assert_eq!(bakery.set_exception(next_monday, Some(vec![OpeningSlot { open: t(9), close: t(12) }, OpeningSlot { open: t(11), close: t(14) }])).await?, ExceptionResult::Overlapping);
+
+        let res = bakery.set_exception(next_monday, Some(vec![])).await?;
+        assert_eq!(res, ExceptionResult::Updated(vec![ScheduleException { day: next_monday, slots: vec![] }]));
+        grocer.set_exception(next_monday, Some(vec![])).await?;
+        let res = PersistentShop::search(&conn, &ShopSearch { open_at: Some(Utc.from_utc_datetime(&next_monday.and_hms(10, 0, 0))), ..search("") }).await?;
+        assert!(res.shops.is_empty());
+        let res = PersistentShop::search(&conn, &ShopSearch { open_at: Some(Utc.from_utc_datetime(&(next_monday + chrono::Duration::days(1)).and_hms(10, 0, 0))), ..search("") }).await?;
+        assert_eq!(names(&res), vec!["Grocer"]);
+        grocer.set_exception(next_monday, None).await?;
+        bakery.set_exception(next_monday, Some(vec![OpeningSlot { open: t(10), close: t(12) }])).await?;
+        let res = PersistentShop::search(&conn, &ShopSearch { open_at: Some(Utc.from_utc_datetime(&next_monday.and_hms(11, 0, 0))), ..search("") }).await?;
+        assert_eq!(names(&res), vec!["Bakery", "Grocer"]);
+        let hours = res.shops[0].effective_hours.iter().find(|h| h.day == next_monday).unwrap();
+        assert!(hours.exception);
+        assert_eq!(bakery.set_exception(next_monday, None).await?, ExceptionResult::Updated(vec![]));
+
+        // Two tickets in a department of capacity 1
+        let c0 = test_customer(&conn).await?;
+        let c1 = test_customer(&conn).await?;
+        let small = grocer.add_department("Small", 0).await?.unwrap();
+        PersistentTicket::try_new(&conn, c0, grocer.inner().id, vec![small.uid], Some(30), EXPIRY_HOURS, CUTOFF_MINUTES).await?.unwrap();
+        PersistentTicket::try_new(&conn, c1, grocer.inner().id, vec![small.uid], Some(30), EXPIRY_HOURS, CUTOFF_MINUTES).await?.unwrap();
+        let res = PersistentShop::search(&conn, &ShopSearch { max_wait_minutes: Some(10.), ..search("") }).await?;
+        assert_eq!(names(&res), vec!["Bakery", "Market"]);
+
+        let mut page = PersistentShop::search(&conn, &ShopSearch { max_wait_minutes: Some(10.), limit: 1, ..search("") }).await?;
+        let mut all = names(&page);
+        while let Some(cursor) = page.next_cursor {
+            page = PersistentShop::search(&conn, &ShopSearch { max_wait_minutes: Some(10.), limit: 1, cursor: SearchCursor::decode(&cursor), ..search("") }).await?;
+            all.extend(names(&page));
+        }
+        assert_eq!(all, vec!["Bakery", "Market"]);
+
+        del_customer(&conn, c0).await?;
+        del_customer(&conn, c1).await?;
+        for id in [bakery.inner().id, grocer.inner().id, market.inner().id].iter() {
+            del_shop(&conn, *id).await?;
+        }
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn manage_departments_test() -> Result<(), Box<dyn Error>> {
+        let conn = db().await;
+        with_test_shop!(&conn, s0 [], s1 [d1] {
+            let shop = PersistentShop::get(&conn, s0).await?.unwrap();
+
+            let d0 = shop.add_department("Fruit", 5).await?.unwrap();
+            assert_eq!(d0.capacity, 5);
+            assert!(matches!(shop.add_department("Fruit", 3).await?, DepartmentResult::AlreadyExists));
+            assert!(matches!(shop.add_department("Bread", -1).await?, DepartmentResult::InvalidCapacity));
+            let _ = shop.add_department("Bread", 0).await?.unwrap();
+
+            let d0 = shop.edit_department(d0.uid, Some("Vegetables"), None).await?.unwrap();
+            assert_eq!((d0.description.as_str(), d0.capacity), ("Vegetables", 5));
+            let d0 = shop.edit_department(d0.uid, None, Some(12)).await?.unwrap();
+            assert_eq!((d0.description.as_str(), d0.capacity), ("Vegetables", 12));
+
+            assert!(matches!(shop.edit_department(d0.uid, Some("Bread"), None).await?, DepartmentResult::AlreadyExists));
+            assert!(matches!(shop.edit_department(d0.uid, None, Some(-2)).await?, DepartmentResult::InvalidCapacity));
+            assert!(matches!(shop.edit_department(d1, Some("Other shop"), None).await?, DepartmentResult::NotFound));
+
+            assert_eq!(shop.departments().await?.len(), 2);
+        });
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn set_schedule_test() -> Result<(), Box<dyn Error>> {
+        let conn = db().await;
+        with_test_shop!(&conn, s0 [] {
+            let shop = PersistentShop::get(&conn, s0).await?.unwrap();
+            let t = |s| NaiveTime::parse_from_str(s, "%H:%M").unwrap();
+
+            let res = shop.set_schedule(vec![(1, t("09:00"), t("13:00")), (1, t("15:00"), t("19:00"))]).await?;
+            assert_eq!(res, ScheduleResult::Updated(vec![
+                Schedule::new(s0, 1, t("09:00"), t("13:00")),
+                Schedule::new(s0, 1, t("15:00"), t("19:00")),
+            ]));
+
+            let res = shop.set_schedule(vec![(2, t("09:00"), t("13:00")), (2, t("12:00"), t("19:00"))]).await?;
+            assert_eq!(res, ScheduleResult::Overlapping(2));
+            assert_eq!(shop.schedule().await?.len(), 2);
+
+            let res = shop.set_schedule(vec![(3, t("10:00"), t("18:00"))]).await?;
+            assert_eq!(res, ScheduleResult::Updated(vec![Schedule::new(s0, 3, t("10:00"), t("18:00"))]));
+        });
+        Ok(())
+    }
+
     #[actix_rt::test]
     async fn empty_deps_occupancy_test() -> Result<(), Box<dyn Error>> {
         let conn = db().await;
@@ -235,8 +1439,8 @@ mod tests {
             let d0e = encode_serial(d0);
             let d1e = encode_serial(d1);
 
-            let t1 = PersistentTicket::try_new(&conn, id_c1, s1, vec![d0], 25).await?.unwrap();
-            let t2 = PersistentTicket::try_new(&conn, id_c2, s1, vec![d0, d1], 25).await?.unwrap();
+            let t1 = PersistentTicket::try_new(&conn, id_c1, s1, vec![d0], Some(25), EXPIRY_HOURS, CUTOFF_MINUTES).await?.unwrap();
+            let t2 = PersistentTicket::try_new(&conn, id_c2, s1, vec![d0, d1], Some(25), EXPIRY_HOURS, CUTOFF_MINUTES).await?.unwrap();
 
             assert_eq!(t1.try_enter().await.unwrap(), EnterResult::Entered);
             
@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::convert::TryFrom;
+
+use aes_gcm::Aes256Gcm;
+use aes_gcm::aead::{Aead, NewAead, Payload};
+use aes_gcm::aead::generic_array::GenericArray;
+use chrono::prelude::*;
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer};
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+use sqlx::{FromRow, PgPool, query, query_as};
+
+use crate::models::policy::ShopPolicy;
+use crate::models::ticket::{Ticket, TicketResponse};
+use crate::utils::encoding::encode_serial;
+
+/// Claims of an offline token, what a staff terminal can trust about a ticket once the signature is checked.
+/// Field names are shortened to keep the token compact
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TokenPayload {
+    /// Uid of the ticket
+    #[serde(rename = "t")]
+    pub ticket: String,
+    /// Uid of the shop
+    #[serde(rename = "s")]
+    pub shop: String,
+    /// Uids of the departments the ticket gives access to
+    #[serde(rename = "d")]
+    pub departments: Vec<String>,
+    /// Expiration of the ticket in seconds since the Unix epoch
+    #[serde(rename = "e")]
+    pub expiry: i64,
+    /// Id of the key that signed the token
+    #[serde(rename = "k")]
+    pub key: i32,
+}
+
+impl TokenPayload {
+    pub fn expired(&self, now: DateTime<Utc>) -> bool {
+        now.timestamp() >= self.expiry
+    }
+}
+
+/// Key signing the offline tokens of a shop
+pub struct SigningKey {
+    pub id: i32,
+    pub shop_id: i32,
+    keypair: Keypair,
+}
+
+impl SigningKey {
+    /// Offline token for `ticket`, the payload and its Ed25519 signature base64url encoded and separated by a dot
+    pub fn sign(&self, ticket: &Ticket) -> String {
+        let payload = TokenPayload {
+            ticket: encode_serial(ticket.id),
+            shop: encode_serial(ticket.shop_id),
+            departments: ticket.department_ids.iter().copied().map(encode_serial).collect(),
+            expiry: ticket.expiration.timestamp(),
+            key: self.id,
+        };
+        let payload = encode(&serde_json::to_vec(&payload).unwrap());
+        let signature = self.keypair.sign(payload.as_bytes());
+        format!("{}.{}", payload, encode(&signature.to_bytes()))
+    }
+
+    pub fn public_key(&self) -> String {
+        encode(self.keypair.public.as_bytes())
+    }
+}
+
+/// Public key of a shop, distributed to the staff terminals to verify the tokens offline
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PublicKeyResponse {
+    pub id: i32,
+    /// Ed25519 public key, base64url encoded
+    pub public_key: String,
+    pub created: DateTime<Utc>,
+    /// When the key stopped signing new tokens, `None` for the active key
+    pub retired: Option<DateTime<Utc>>,
+}
+
+fn encode(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode(s: &str) -> Option<Vec<u8>> {
+    base64::decode_config(s, base64::URL_SAFE_NO_PAD).ok()
+}
+
+/// Check if `uid` is an offline token rather than the plain uid of a ticket
+pub fn is_token(uid: &str) -> bool {
+    uid.contains('.')
+}
+
+/// Check the signature of `token` with `keys` and extract its payload, staff terminals can do the same offline.
+/// Expiry and shop of the payload are left to the caller
+/// ### Returns
+/// + `Some(TokenPayload)` if the token was signed by one of `keys`
+/// + `None` if the token is malformed, tampered with or signed by an unknown key
+pub fn verify(token: &str, keys: &[PublicKeyResponse]) -> Option<TokenPayload> {
+    let (payload, signature) = token.split_once('.')?;
+    let claims: TokenPayload = serde_json::from_slice(&decode(payload)?).ok()?;
+    let key = keys.iter().find(|k| k.id == claims.key)?;
+    let public = PublicKey::from_bytes(&decode(&key.public_key)?).ok()?;
+    let signature = Signature::try_from(&decode(signature)?[..]).ok()?;
+    public.verify_strict(payload.as_bytes(), &signature).ok()?;
+    Some(claims)
+}
+
+/// Length of the nonce prepended to the encrypted secret keys
+const NONCE_LEN: usize = 12;
+
+/// Encrypt `secret` with the `token_key` of the server, the nonce is prepended to the result.
+/// The public key is authenticated along with it, so that a secret can't be paired with another public key
+fn encrypt(token_key: &[u8], public: &[u8], secret: &[u8]) -> Vec<u8> {
+    let cipher = Aes256Gcm::new(GenericArray::from_slice(token_key));
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill(&mut nonce[..]);
+    let encrypted = cipher.encrypt(GenericArray::from_slice(&nonce), Payload { msg: secret, aad: public })
+        .expect("Encryption of the secret key failed");
+    [&nonce[..], &encrypted[..]].concat()
+}
+
+/// Decrypt a secret key encrypted by [`encrypt`], `None` if it was encrypted with another key or tampered with
+fn decrypt(token_key: &[u8], public: &[u8], encrypted: &[u8]) -> Option<Vec<u8>> {
+    if encrypted.len() < NONCE_LEN {
+        return None;
+    }
+    let (nonce, encrypted) = encrypted.split_at(NONCE_LEN);
+    let cipher = Aes256Gcm::new(GenericArray::from_slice(token_key));
+    cipher.decrypt(GenericArray::from_slice(nonce), Payload { msg: encrypted, aad: public }).ok()
+}
+
+/// Row structure for signing keys, the secret key is encrypted with the token key of the server
+#[derive(FromRow)]
+struct SigningKeyRow {
+    id: i32,
+    shop_id: i32,
+    public_key: Vec<u8>,
+    encrypted_secret_key: Vec<u8>,
+}
+
+impl SigningKeyRow {
+    /// Decrypt the secret key with `token_key`, fails if the key was stored with a different token key
+    fn decrypt(self, token_key: &[u8]) -> sqlx::Result<SigningKey> {
+        let secret = decrypt(token_key, &self.public_key, &self.encrypted_secret_key)
+            .and_then(|s| SecretKey::from_bytes(&s).ok())
+            .ok_or_else(|| sqlx::Error::Decode(format!("Could not decrypt signing key {}, check the token key", self.id).into()))?;
+        let public = PublicKey::from(&secret);
+        debug_assert_eq!(public.as_bytes()[..], self.public_key[..]);
+        Ok(SigningKey {
+            id: self.id,
+            shop_id: self.shop_id,
+            keypair: Keypair { secret, public },
+        })
+    }
+}
+
+/// Generate a key pair, returns the public and the secret key encrypted with `token_key`
+fn generate(token_key: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill(&mut bytes[..]);
+    let secret = SecretKey::from_bytes(&bytes).unwrap();
+    let public = PublicKey::from(&secret).as_bytes().to_vec();
+    let encrypted = encrypt(token_key, &public, secret.as_bytes());
+    (public, encrypted)
+}
+
+/// Row of the active key of `shop_id`, if it has one
+async fn find_active(conn: &PgPool, shop_id: i32) -> sqlx::Result<Option<SigningKeyRow>> {
+    query_as!(SigningKeyRow,
+            r"SELECT id, shop_id, public_key, encrypted_secret_key FROM signing_key
+            WHERE shop_id = $1 AND retired IS NULL",
+            shop_id)
+        .fetch_optional(conn)
+        .await
+}
+
+/// Retrieve the active key of `shop_id`, a new one is generated if the shop has none.
+/// `token_key` decrypts the stored secret keys, see [`Config::token_key`](crate::config::Config::token_key)
+pub async fn active_key(conn: &PgPool, token_key: &[u8], shop_id: i32) -> sqlx::Result<SigningKey> {
+    if let Some(row) = find_active(conn, shop_id).await? {
+        return row.decrypt(token_key);
+    }
+
+    let (public_key, encrypted_secret_key) = generate(token_key);
+    query!(r"INSERT INTO signing_key (shop_id, public_key, encrypted_secret_key, created)
+            VALUES ($1, $2, $3, CURRENT_TIMESTAMP)
+            ON CONFLICT (shop_id) WHERE retired IS NULL DO NOTHING",
+            shop_id, public_key, encrypted_secret_key)
+        .execute(conn)
+        .await?;
+
+    // Another request may have created it first
+    match find_active(conn, shop_id).await? {
+        Some(row) => row.decrypt(token_key),
+        None => Err(sqlx::Error::RowNotFound),
+    }
+}
+
+/// Retire the active key of `shop_id` and generate the one signing the tokens from now on.
+/// Keys retired longer ago than the ticket expiry of the [`ShopPolicy`], `expiry_hours` if the
+/// policy does not set it, can't have signed a valid token and are deleted
+pub async fn rotate(conn: &PgPool, token_key: &[u8], shop_id: i32, expiry_hours: i32) -> sqlx::Result<SigningKey> {
+    let mut tx = conn.begin().await?;
+    let expiry_hours = ShopPolicy::load(&mut tx, shop_id).await?.expiry_hours(expiry_hours);
+
+    query!(r"UPDATE signing_key SET retired = CURRENT_TIMESTAMP WHERE shop_id = $1 AND retired IS NULL", shop_id)
+        .execute(&mut tx)
+        .await?;
+    query!(r"DELETE FROM signing_key WHERE shop_id = $1 AND retired <= CURRENT_TIMESTAMP - make_interval(hours => $2)",
+            shop_id, expiry_hours)
+        .execute(&mut tx)
+        .await?;
+
+    let (public_key, encrypted_secret_key) = generate(token_key);
+    let row = query_as!(SigningKeyRow,
+            r"INSERT INTO signing_key (shop_id, public_key, encrypted_secret_key, created)
+            VALUES ($1, $2, $3, CURRENT_TIMESTAMP)
+            RETURNING id, shop_id, public_key, encrypted_secret_key",
+            shop_id, public_key, encrypted_secret_key)
+        .fetch_one(&mut tx)
+        .await?;
+
+    tx.commit().await?;
+    row.decrypt(token_key)
+}
+
+/// Public keys that may have signed a valid token of `shop_id`, newest first: the active key
+/// and the ones retired within the ticket expiry, see [`rotate`]
+pub async fn public_keys(conn: &PgPool, token_key: &[u8], shop_id: i32, expiry_hours: i32) -> sqlx::Result<Vec<PublicKeyResponse>> {
+    active_key(conn, token_key, shop_id).await?;
+    let expiry_hours = ShopPolicy::load(&mut *conn.acquire().await?, shop_id).await?.expiry_hours(expiry_hours);
+
+    Ok(query!(r"SELECT id, public_key, created, retired FROM signing_key
+            WHERE shop_id = $1 AND (retired IS NULL OR retired > CURRENT_TIMESTAMP - make_interval(hours => $2))
+            ORDER BY id DESC",
+            shop_id, expiry_hours)
+        .fetch_all(conn)
+        .await?
+        .into_iter()
+        .map(|r| PublicKeyResponse {
+            id: r.id,
+            public_key: encode(&r.public_key),
+            created: r.created,
+            retired: r.retired,
+        })
+        .collect())
+}
+
+/// Responses for `tickets` carrying their offline token, signed with the active key of their shop
+pub async fn signed_responses(conn: &PgPool, token_key: &[u8], tickets: Vec<Ticket>) -> sqlx::Result<Vec<TicketResponse>> {
+    let mut keys: HashMap<i32, SigningKey> = HashMap::new();
+    let mut responses = Vec::with_capacity(tickets.len());
+    for t in tickets {
+        if let Entry::Vacant(e) = keys.entry(t.shop_id) {
+            e.insert(active_key(conn, token_key, t.shop_id).await?);
+        }
+        let token = keys[&t.shop_id].sign(&t);
+        responses.push(TicketResponse { token: Some(token), ..t.into() });
+    }
+    Ok(responses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DEFAULT_TICKET_EXPIRY_HOURS as EXPIRY_HOURS;
+    use crate::config::DEFAULT_CLOSING_CUTOFF_MINUTES as CUTOFF_MINUTES;
+    use std::error::Error;
+
+    use crate::config::DEFAULT_TOKEN_KEY;
+    use crate::models::ticket::PersistentTicket;
+    use crate::utils::tests::*;
+    use crate::with_test_shop;
+
+    const KEY: &[u8] = &DEFAULT_TOKEN_KEY;
+
+    #[actix_rt::test]
+    async fn sign_verify_test() -> Result<(), Box<dyn Error>> {
+        let conn = db().await;
+        let customer = test_customer(&conn).await?;
+
+        with_test_shop!(&conn, s0 [d0, d1] {
+            let ticket = PersistentTicket::try_new(&conn, customer, s0, vec![d0, d1], Some(20), EXPIRY_HOURS, CUTOFF_MINUTES).await?.unwrap().into_inner();
+            let key = active_key(&conn, KEY, s0).await?;
+            assert_eq!(active_key(&conn, KEY, s0).await?.id, key.id);
+            let count = query!("SELECT COUNT(*) AS count FROM signing_key WHERE shop_id = $1", s0).fetch_one(&conn).await?.count;
+            assert_eq!(count, Some(1));
+
+            // The secret key is only stored encrypted
+            let row = find_active(&conn, s0).await?.unwrap();
+            let secret = key.keypair.secret.as_bytes();
+            assert!(!row.encrypted_secret_key.windows(secret.len()).any(|w| w == secret));
+            assert!(find_active(&conn, s0).await?.unwrap().decrypt(&[1; 32]).is_err());
+            assert_eq!(row.decrypt(KEY)?.public_key(), key.public_key());
+
+            let token = key.sign(&ticket);
+            let keys = public_keys(&conn, KEY, s0, EXPIRY_HOURS).await?;
+            assert_eq!(keys.len(), 1);
+            assert_eq!(keys[0].public_key, key.public_key());
+
+            let payload = verify(&token, &keys).unwrap();
+            assert_eq!(payload.ticket, encode_serial(ticket.id));
+            assert_eq!(payload.shop, encode_serial(s0));
+            assert_eq!(payload.departments.len(), 2);
+            assert!(!payload.expired(Utc::now()));
+            assert!(payload.expired(ticket.expiration));
+
+            // Tampered payload, signature and unknown key
+            let (payload, signature) = token.split_once('.').unwrap();
+            let mut claims: TokenPayload = serde_json::from_slice(&decode(payload).unwrap())?;
+            claims.departments.push(encode_serial(d0 + 1000));
+            let forged = format!("{}.{}", encode(&serde_json::to_vec(&claims)?), signature);
+            assert_eq!(verify(&forged, &keys), None);
+            assert_eq!(verify(&format!("{}.{}", payload, encode(&[0; 64])), &keys), None);
+            assert_eq!(verify(&token, &[]), None);
+            assert_eq!(verify("not a token", &keys), None);
+
+            // Tokens signed by the retired key stay valid
+            let rotated = rotate(&conn, KEY, s0, EXPIRY_HOURS).await?;
+            assert_ne!(rotated.id, key.id);
+            assert_eq!(active_key(&conn, KEY, s0).await?.id, rotated.id);
+            let keys = public_keys(&conn, KEY, s0, EXPIRY_HOURS).await?;
+            assert_eq!(keys.iter().map(|k| (k.id, k.retired.is_some())).collect::<Vec<_>>(), vec![(rotated.id, false), (key.id, true)]);
+            assert!(verify(&token, &keys).is_some());
+            assert_eq!(verify(&rotated.sign(&ticket), &keys).unwrap().key, rotated.id);
+
+            // Without expiry the retired key is dropped at the next rotation
+            let last = rotate(&conn, KEY, s0, 0).await?;
+            let keys = public_keys(&conn, KEY, s0, 0).await?;
+            assert_eq!(keys.iter().map(|k| k.id).collect::<Vec<_>>(), vec![last.id]);
+            assert_eq!(verify(&token, &keys), None);
+        });
+
+        del_customer(&conn, customer).await?;
+        Ok(())
+    }
+}
@@ -1,15 +1,23 @@
-use sqlx::{FromRow, PgPool, query, query_as};
+use rand::Rng;
+use sqlx::{FromRow, PgConnection, PgPool, query, query_as};
 
 use super::account::Account;
 
+type ActivationCode = Vec<u8>;
+type ResetCode = Vec<u8>;
+
 /// Internal staff structure, wraps [`Account`] adding a shop id
+/// and whether the staff member is a manager
 pub struct Staff {
     account: Account,
     shop_id: i32,
+    manager: bool,
 }
 
 impl Staff {
     pub fn shop_id(&self) -> i32 { self.shop_id }
+    /// Managers can create shops and edit departments and schedules of their shop
+    pub fn is_manager(&self) -> bool { self.manager }
     /// Get inner account structure
     pub fn account(&self) -> &Account { &self.account }
 }
@@ -21,11 +29,13 @@ impl From<StaffRow> for Staff {
             email: row.email,
             salt: row.salt,
             digest: row.digest,
+            session_version: row.session_version,
         };
 
         Self {
             account,
             shop_id: row.shop_id,
+            manager: row.manager,
         }
     }
 }
@@ -38,6 +48,8 @@ struct StaffRow {
     email: String,
     salt: Vec<u8>,
     digest: Vec<u8>,
+    manager: bool,
+    session_version: i32,
 }
 
 /// Data Access Object for staff
@@ -51,7 +63,7 @@ impl<'a> PersistentStaff<'a> {
     /// Retrieve staff from its primary key
     pub async fn get(conn: &'a PgPool, id: i32) -> sqlx::Result<Option<PersistentStaff<'a>>> {
         let acc = query_as!(StaffRow,
-            r"SELECT id, email, salt, digest, shop_id FROM staff WHERE id = $1",
+            r"SELECT id, email, salt, digest, shop_id, manager, session_version FROM staff WHERE id = $1",
             id
         ).fetch_optional(conn)
         .await?;
@@ -62,7 +74,7 @@ impl<'a> PersistentStaff<'a> {
     /// Retrieve staff from its email
     pub async fn find(conn: &'a PgPool, email: &str) -> sqlx::Result<Option<PersistentStaff<'a>>> {
         let acc = query_as!(StaffRow,
-                r"SELECT id, email, salt, digest, shop_id FROM staff WHERE email = $1",
+                r"SELECT id, email, salt, digest, shop_id, manager, session_version FROM staff WHERE email = $1",
                 email
             ).fetch_optional(conn)
             .await?;
@@ -74,7 +86,7 @@ impl<'a> PersistentStaff<'a> {
     }
 
     /// Create a new staff account (for development purposes there are no confirmation steps)
-    pub async fn create(conn: &'a PgPool, email: &str, password: &str, shop_id: i32) -> sqlx::Result<Option<PersistentStaff<'a>>> {
+    pub async fn create(conn: &'a PgPool, email: &str, password: &str, shop_id: i32, manager: bool) -> sqlx::Result<Option<PersistentStaff<'a>>> {
         let mut tx = conn.begin().await?;
 
         let exists = query!(r"SELECT email FROM staff WHERE email = $1", &email)
@@ -84,10 +96,10 @@ impl<'a> PersistentStaff<'a> {
         if let None = exists {
             let p = Account::hash_password(password.as_bytes());
             let acc =  query_as!(StaffRow,
-                    r"INSERT INTO staff (shop_id, email, salt, digest)
-                    VALUES ($1, $2, $3, $4)
-                    RETURNING id, email, salt, digest, shop_id",
-                    shop_id, &email, &p.salt, &p.digest
+                    r"INSERT INTO staff (shop_id, email, salt, digest, manager)
+                    VALUES ($1, $2, $3, $4, $5)
+                    RETURNING id, email, salt, digest, shop_id, manager, session_version",
+                    shop_id, &email, &p.salt, &p.digest, manager
                 ).fetch_one(&mut tx)
                 .await?;
             tx.commit().await?;
@@ -98,6 +110,139 @@ impl<'a> PersistentStaff<'a> {
         }
     }
 
+    /// ## Create a temporary staff account
+    /// Starts the creation of a staff account for `shop_id`, the staff member will choose the password on activation.
+    /// Creating a temporary account again for the same email and shop replaces the previous activation code
+    /// ### Returns:
+    /// `Ok(Some(ActivationCode))` if it was created
+    /// `Ok(None)` if a staff account with the same email already exists or is pending for another shop
+    /// See [`activate`](PersistentStaff::activate) to complete creation
+    pub async fn create_temp(conn: &'a PgPool, email: &str, shop_id: i32, manager: bool) -> sqlx::Result<Option<ActivationCode>> {
+        let mut tx = conn.begin().await?;
+        let code = Self::create_temp_in(&mut tx, email, shop_id, manager).await?;
+        tx.commit().await?;
+        Ok(code)
+    }
+
+    /// Same as [`create_temp`](PersistentStaff::create_temp) in the transaction of the caller
+    pub(crate) async fn create_temp_in(conn: &mut PgConnection, email: &str, shop_id: i32, manager: bool) -> sqlx::Result<Option<ActivationCode>> {
+        let exists = query!(r"SELECT email FROM staff WHERE email = $1", &email)
+            .fetch_optional(&mut *conn)
+            .await?;
+        if exists.is_some() {
+            return Ok(None);
+        }
+
+        let mut code = vec![0u8; 32];
+        rand::thread_rng().fill(&mut code[..]);
+        let row = query!(
+                r"INSERT INTO temp_staff (code, email, shop_id, manager) VALUES ($1, $2, $3, $4)
+                ON CONFLICT (email) DO UPDATE SET code = $1, manager = $4 WHERE temp_staff.shop_id = $3
+                RETURNING code",
+                &code, &email, shop_id, manager
+            ).fetch_optional(&mut *conn)
+            .await?;
+        Ok(row.map(|r| r.code))
+    }
+
+    /// ## Activate a staff account
+    /// Use `code` to finalize account creation setting `password`. If it's a valid unused code generated by
+    /// [`create_temp`](PersistentStaff::create_temp) the staff account will be ready to use
+    pub async fn activate(conn: &'a PgPool, code: &[u8], password: &str) -> sqlx::Result<Option<PersistentStaff<'a>>> {
+        let mut tx = conn.begin().await?;
+
+        let temp = query!(r"DELETE FROM temp_staff WHERE code = $1 RETURNING email, shop_id, manager", code)
+            .fetch_optional(&mut tx)
+            .await?;
+
+        let result = if let Some(temp) = temp {
+            let p = Account::hash_password(password.as_bytes());
+            let acc = query_as!(StaffRow,
+                    r"INSERT INTO staff (shop_id, email, salt, digest, manager)
+                    VALUES ($1, $2, $3, $4, $5)
+                    RETURNING id, email, salt, digest, shop_id, manager, session_version",
+                    temp.shop_id, &temp.email, &p.salt, &p.digest, temp.manager
+                ).fetch_one(&mut tx)
+                .await?;
+            Some(PersistentStaff{conn, inner: acc.into()})
+        } else {
+            None
+        };
+
+        tx.commit().await?;
+        Ok(result)
+    }
+
+    /// ## Start a password reset
+    /// Generate a single use code to reset the password of the staff member with `email`, valid for `valid_minutes`.
+    /// Requesting a code again replaces the previous one
+    /// ### Returns:
+    /// `Ok(Some(ResetCode))` if the staff account exists
+    /// `Ok(None)` otherwise
+    /// See [`reset_password`](PersistentStaff::reset_password) to choose the new password
+    pub async fn create_reset(conn: &'a PgPool, email: &str, valid_minutes: i32) -> sqlx::Result<Option<ResetCode>> {
+        let mut code = vec![0u8; 32];
+        rand::thread_rng().fill(&mut code[..]);
+        let row = query!(
+                r"INSERT INTO reset_staff (code, staff_id, expiration)
+                SELECT $1, id, CURRENT_TIMESTAMP + make_interval(mins => $3) FROM staff WHERE email = $2
+                ON CONFLICT (staff_id) DO UPDATE SET code = $1, expiration = EXCLUDED.expiration
+                RETURNING code",
+                &code, email, valid_minutes
+            ).fetch_optional(conn)
+            .await?;
+
+        Ok(row.map(|r| r.code))
+    }
+
+    /// ## Reset the password
+    /// Use `code` to set `password`. If it's a valid unused code generated by [`create_reset`](PersistentStaff::create_reset)
+    /// that has not expired the password is updated and the other sessions of the staff member are invalidated
+    pub async fn reset_password(conn: &'a PgPool, code: &[u8], password: &str) -> sqlx::Result<Option<PersistentStaff<'a>>> {
+        let mut tx = conn.begin().await?;
+
+        // Expired codes are deleted as well
+        let reset = query!(r"DELETE FROM reset_staff WHERE code = $1 RETURNING staff_id, expiration > CURRENT_TIMESTAMP AS valid", code)
+            .fetch_optional(&mut tx)
+            .await?;
+
+        let result = match reset {
+            Some(r) if r.valid == Some(true) => Some(Self::set_password(&mut tx, r.staff_id, password).await?),
+            _ => None,
+        };
+
+        tx.commit().await?;
+        Ok(result.map(|staff| Self{inner: staff, conn}))
+    }
+
+    /// Update password for this staff member, the other sessions of the staff member are invalidated
+    pub async fn update_password(&mut self, password: &str) -> sqlx::Result<()> {
+        let mut conn = self.conn.acquire().await?;
+        self.inner = Self::set_password(&mut conn, self.inner.account.id(), password).await?;
+        Ok(())
+    }
+
+    /// Hash and store `password`, incrementing the session version
+    async fn set_password(conn: &mut PgConnection, id: i32, password: &str) -> sqlx::Result<Staff> {
+        let p = Account::hash_password(password.as_bytes());
+        let row = query_as!(StaffRow,
+                r"UPDATE staff SET salt = $1, digest = $2, session_version = session_version + 1
+                WHERE id = $3
+                RETURNING id, email, salt, digest, shop_id, manager, session_version",
+                &p.salt, &p.digest, id
+            ).fetch_one(conn)
+            .await?;
+        Ok(row.into())
+    }
+
+    /// Current session version of the staff member, `None` if the account does not exist
+    pub async fn session_version(conn: &PgPool, id: i32) -> sqlx::Result<Option<i32>> {
+        let row = query!(r"SELECT session_version FROM staff WHERE id = $1", id)
+            .fetch_optional(conn)
+            .await?;
+        Ok(row.map(|r| r.session_version))
+    }
+
     pub fn into_inner(self) -> Staff {self.inner}
     pub fn inner(&self) -> &Staff {&self.inner}
 }
@@ -115,7 +260,7 @@ mod tests {
         with_test_shop!(&conn, s0 [_d0, _d1] {
             let (email, password) = ("test-email123@mail.com", "securepassword");
 
-            let staff = PersistentStaff::create(&conn, email, password, s0)
+            let staff = PersistentStaff::create(&conn, email, password, s0, false)
                 .await?
                 .unwrap()
                 .into_inner();
@@ -127,6 +272,32 @@ mod tests {
 
             assert_eq!(email, loaded.account().email());
             assert_eq!(s0, loaded.shop_id());
+            assert!(!loaded.is_manager());
+        });
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn activate_staff_test() -> sqlx::Result<()> {
+        let conn = db().await;
+        with_test_shop!(&conn, s0 [_d0], s1 [_d1] {
+            let (email, password) = ("test-activation123@mail.com", "securepassword");
+
+            let old_code = PersistentStaff::create_temp(&conn, email, s0, true).await?.unwrap();
+            let code = PersistentStaff::create_temp(&conn, email, s0, true).await?.unwrap();
+            assert_ne!(old_code, code);
+            assert!(PersistentStaff::create_temp(&conn, email, s1, true).await?.is_none()); // Pending for another shop
+
+            assert!(PersistentStaff::activate(&conn, &old_code, password).await?.is_none());
+            let staff = PersistentStaff::activate(&conn, &code, password).await?.unwrap().into_inner();
+            assert!(PersistentStaff::activate(&conn, &code, password).await?.is_none());
+
+            assert!(staff.is_manager());
+            assert_eq!(s0, staff.shop_id());
+            assert!(staff.account().verify_authentication(password.as_bytes()));
+
+            assert!(PersistentStaff::create_temp(&conn, email, s0, false).await?.is_none());
         });
 
         Ok(())
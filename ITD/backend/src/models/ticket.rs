@@ -1,26 +1,39 @@
 
+use std::collections::HashMap;
+
 use serde::{Serialize, Deserialize};
 use sqlx::postgres::PgDone;
-use sqlx::{FromRow, PgPool, query_as, query};
+use sqlx::{Done, FromRow, PgConnection, PgPool, query_as, query};
 use chrono::prelude::*;
+use chrono::Duration;
 
 use futures::StreamExt;
 
+pub use crate::models::admission::EnterResult;
+
+use crate::models::admission::{AdmissionPolicy, TokenKind};
+use crate::models::policy::ShopPolicy;
+use crate::models::shop::{Opening, OpeningHours};
+use crate::models::visit;
 use crate::utils::encoding::encode_serial;
 use crate::utils::time::{combine_expected_measured, minute_diff};
 
 /// Internal structure for ticket
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Ticket {
     pub id: i32,
-    pub customer_id: i32,
+    /// `None` for substitute tickets, which are not tied to a customer
+    /// and must not be used for per-customer statistics
+    pub customer_id: Option<i32>,
     pub shop_id: i32,
     pub shop_name: String,
-    pub creation: NaiveDateTime,
-    pub expiration: NaiveDateTime,
+    pub creation: DateTime<Utc>,
+    pub expiration: DateTime<Utc>,
     pub est_minutes: i32,
     pub valid: bool,
     pub active: bool,
+    pub substitute: bool,
+    pub label: Option<String>,
     pub department_ids: Vec<i32>,
 }
 
@@ -35,6 +48,10 @@ pub struct TicketResponse {
     pub expiration: DateTime<Utc>,
     pub valid: bool,
     pub active: bool,
+    pub substitute: bool,
+    pub label: Option<String>,
+    /// Signed token staff can verify offline with the public keys of the shop, see [`signing`](crate::models::signing)
+    pub token: Option<String>,
 }
 
 impl From<Ticket> for TicketResponse {
@@ -48,37 +65,52 @@ impl From<Ticket> for TicketResponse {
             shop_id: encode_serial(t.shop_id),
             shop_name: t.shop_name,
             department_ids: dids,
-            creation: Utc.from_utc_datetime(&t.creation),
-            expiration: Utc.from_utc_datetime(&t.expiration),
+            creation: t.creation,
+            expiration: t.expiration,
             valid: t.valid,
             active: t.active,
+            substitute: t.substitute,
+            label: t.label,
+            token: None,
         }
     }
 }
 
-/// ## Result for log entry operation
-/// + Entered: Successful entry
-/// + Full(i32): Department with returned id is full, not entered
-/// + NotFirst(i32): Not first in queue, returned number people in queue, not entered
-/// + Expired: Ticket is expired, not entered
-/// + Invalid: Ticket is invalid, not entered
-#[derive(Debug, PartialEq)]
-pub enum EnterResult {
-    Entered,
-    Full(i32),
-    NotFirst(i64),
+/// ## Automatic action taken on a ticket
+/// + Expired: The ticket expired before entering and was removed from the queue
+/// + AutoClosed: The exit of the ticket was never logged, it was closed after the maximum visit length
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TicketAction {
     Expired,
-    Invalid,
+    AutoClosed,
+}
+
+/// Automatic action taken on a ticket, shown to the staff of the shop
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct TicketActionResponse {
+    pub ticket_uid: String,
+    pub label: Option<String>,
+    pub action: TicketAction,
+    pub time: DateTime<Utc>,
 }
 
 /// ## Result for ticket creation operation
 /// + Created: Ticket created
 /// + AlreadyExists: Ticket not created. The customer already has a ticket for this shop
-/// + Closed: Ticket not created. The shop does not allow creating tickets at the moment
+/// + Closed: Ticket not created. The shop is closed or will close before the estimated entry
+/// + QueueFull: Ticket not created. The queue reached the maximum length of the shop policy
+/// + WaitTooLong(i32): Ticket not created. The estimated wait in minutes is longer than the maximum of the shop policy
+/// + TooManyTickets: Ticket not created. The customer holds the maximum number of active tickets allowed by the shop policy
+/// + WalkInDisabled: Substitute ticket not created. The shop policy does not allow walk-in tickets
 pub enum NewTicketResult<'a> {
     Created(PersistentTicket<'a>),
     AlreadyExists,
     Closed,
+    QueueFull,
+    WaitTooLong(i32),
+    TooManyTickets,
+    WalkInDisabled,
 }
 impl<'a> NewTicketResult<'a> {
     /// Extract Created value if `Created`, panics otherwise
@@ -87,6 +119,10 @@ impl<'a> NewTicketResult<'a> {
             NewTicketResult::Created(t) => t,
             NewTicketResult::AlreadyExists => panic!("Unwrap called on AlreadyExists result"),
             NewTicketResult::Closed => panic!("Unwrap called on Closed result"),
+            NewTicketResult::QueueFull => panic!("Unwrap called on QueueFull result"),
+            NewTicketResult::WaitTooLong(_) => panic!("Unwrap called on WaitTooLong result"),
+            NewTicketResult::TooManyTickets => panic!("Unwrap called on TooManyTickets result"),
+            NewTicketResult::WalkInDisabled => panic!("Unwrap called on WalkInDisabled result"),
         }
     }
 }
@@ -101,14 +137,13 @@ pub struct PersistentTicket<'a> {
 impl<'a> PersistentTicket<'a> {
     /// Retrieve ticket from its primary key
     pub async fn get(conn: &'a PgPool, id: i32) -> sqlx::Result<Option<PersistentTicket<'a>>> {
-        let ticket = query_as!(TicketRow, r"SELECT ticket.id AS id, customer_id, ticket.shop_id AS shop_id, shop.name as shop_name, array_agg(department.id) AS department_ids, creation, expiration, entry, exit, est_minutes, valid, active
-            FROM ticket, ticket_department, department, shop
+        let ticket = query_as!(TicketRow, r"SELECT ticket.id AS id, customer_id, ticket.shop_id AS shop_id, shop.name as shop_name, array_agg(ticket_department.department_id) AS department_ids, creation, expiration, entry, exit, est_minutes, valid, active, substitute, label
+            FROM ticket, ticket_department, shop
             WHERE ticket_department.ticket_id = ticket.id AND
                 ticket.shop_id = shop.id AND
-                ticket_department.department_id = department.id AND
                 ticket.id = $1 AND
                 COALESCE(expiration > CURRENT_TIMESTAMP, TRUE)
-            GROUP BY ticket.id, customer_id, ticket.shop_id, shop.name, creation, expiration, valid, active",
+            GROUP BY ticket.id, customer_id, ticket.shop_id, shop.name, creation, expiration, valid, active, substitute, label",
             id)
             .fetch_optional(conn)
             .await?
@@ -119,14 +154,13 @@ impl<'a> PersistentTicket<'a> {
 
     /// Retrieve all active tickets for a customer
     pub async fn get_for_customer(conn: &'a PgPool, customer_id: i32) -> sqlx::Result<Vec<Ticket>> {
-        query_as!(TicketRow, r"SELECT ticket.id AS id, customer_id, ticket.shop_id AS shop_id, shop.name as shop_name, array_agg(department.id) AS department_ids, creation, expiration, entry, exit, est_minutes, valid, active
-            FROM ticket, ticket_department, department, shop
+        query_as!(TicketRow, r"SELECT ticket.id AS id, customer_id, ticket.shop_id AS shop_id, shop.name as shop_name, array_agg(ticket_department.department_id) AS department_ids, creation, expiration, entry, exit, est_minutes, valid, active, substitute, label
+            FROM ticket, ticket_department, shop
             WHERE ticket_department.ticket_id = ticket.id AND
                 ticket.shop_id = shop.id AND
-                ticket_department.department_id = department.id AND
                 ticket.customer_id = $1 AND
                 COALESCE(expiration > CURRENT_TIMESTAMP, TRUE)
-            GROUP BY ticket.id, customer_id, ticket.shop_id, shop.name, creation, expiration, valid, active
+            GROUP BY ticket.id, customer_id, ticket.shop_id, shop.name, creation, expiration, valid, active, substitute, label
             ORDER BY creation",
             customer_id)
             .fetch(conn)
@@ -137,10 +171,19 @@ impl<'a> PersistentTicket<'a> {
             }).await
     }
 
-    /// Create a new ticket
+    /// Create a new ticket.
+    /// The visit length is inferred from `est_minutes`, declared by the customer, and the length of their
+    /// past visits to the shop. If neither is available the average visit length of the departments is used.
+    /// The ticket expires after the hours set by the [`ShopPolicy`], `expiry_hours` if the policy does not set them.
+    /// The shop must be open and the estimated entry must be at least `closing_cutoff_minutes` before closing.
     /// See [`NewTicketResult`] for the result
-    pub async fn try_new(conn: &'a PgPool, customer_id: i32, shop_id: i32, department_ids: Vec<i32>, est_minutes: i32) -> sqlx::Result<NewTicketResult<'a>> {
+    pub async fn try_new(conn: &'a PgPool, customer_id: i32, shop_id: i32, department_ids: Vec<i32>, est_minutes: Option<i32>, expiry_hours: i32, closing_cutoff_minutes: i32) -> sqlx::Result<NewTicketResult<'a>> {
         let mut tx = conn.begin().await?;
+        let policy = ShopPolicy::load(&mut tx, shop_id).await?;
+
+        // Concurrent requests of the same customer wait for each other, so that they can't both pass the checks
+        query!(r"SELECT id FROM customer WHERE id = $1 FOR NO KEY UPDATE", customer_id)
+            .fetch_optional(&mut tx).await?;
 
         let already_have = query!(r"SELECT id FROM ticket
             WHERE
@@ -153,33 +196,135 @@ impl<'a> PersistentTicket<'a> {
             return Ok(NewTicketResult::AlreadyExists);
         }
 
-        let row = query!(r"INSERT INTO ticket (customer_id, shop_id, creation, expiration, est_minutes, valid, active) VALUES
-            ($1, $2, CURRENT_TIMESTAMP, CURRENT_TIMESTAMP + interval '6 hour', $3, TRUE, TRUE)
+        if let Some(max) = policy.max_customer_tickets {
+            let held = query!(r#"SELECT count(*) as "count!" FROM ticket
+                WHERE
+                    customer_id = $1 AND active AND
+                    exit IS NULL AND expiration > CURRENT_TIMESTAMP"#,
+                    customer_id)
+                .fetch_one(&mut tx).await?
+                .count;
+            if held >= max as i64 {
+                return Ok(NewTicketResult::TooManyTickets);
+            }
+        }
+        if let Some(r) = Self::check_queue(&mut tx, shop_id, &policy, closing_cutoff_minutes).await? {
+            return Ok(r);
+        }
+
+        let history = visit::get_history(&mut tx, customer_id, shop_id).await?;
+        let est_minutes = match visit::infer_est_minutes(est_minutes, history.as_ref()) {
+            Some(est) => est,
+            None => query!(r"SELECT max(ma_visit) as est FROM department WHERE id = ANY($1)", &department_ids)
+                .fetch_one(&mut tx)
+                .await?
+                .est
+                .map_or(15, |est| est.round() as i32),
+        };
+
+        let ticket = Self::insert(&mut tx, Some(customer_id), shop_id, department_ids, est_minutes, None, policy.expiry_hours(expiry_hours)).await?;
+
+        tx.commit().await?;
+        Ok(NewTicketResult::Created(Self{conn, inner: ticket}))
+    }
+
+    /// Create a new substitute ticket, issued by the staff for a customer without a smartphone.
+    /// Substitute tickets are not associated to a customer and join the same queue as the other tickets,
+    /// `label` is an optional free text to print on the ticket.
+    /// The opening hours and the queue limits of the [`ShopPolicy`] apply to substitute tickets too
+    pub async fn try_new_substitute(conn: &'a PgPool, shop_id: i32, department_ids: Vec<i32>, est_minutes: i32, label: Option<String>, expiry_hours: i32, closing_cutoff_minutes: i32) -> sqlx::Result<NewTicketResult<'a>> {
+        let mut tx = conn.begin().await?;
+        let policy = ShopPolicy::load(&mut tx, shop_id).await?;
+
+        if !policy.walk_in {
+            return Ok(NewTicketResult::WalkInDisabled);
+        }
+        if let Some(r) = Self::check_queue(&mut tx, shop_id, &policy, closing_cutoff_minutes).await? {
+            return Ok(r);
+        }
+
+        let ticket = Self::insert(&mut tx, None, shop_id, department_ids, est_minutes, label, policy.expiry_hours(expiry_hours)).await?;
+
+        tx.commit().await?;
+        Ok(NewTicketResult::Created(Self{conn, inner: ticket}))
+    }
+
+    /// Check the opening hours, the queue length and the estimated wait of the shop against the limits of `policy`.
+    /// A new ticket is refused if the estimated entry is later than `closing_cutoff_minutes` before closing.
+    /// Locks the shop until the end of the transaction and reads queue and wait inside it, so that concurrent
+    /// tickets can't exceed the limits
+    /// ### Returns
+    /// + `None` if a new ticket can join the queue
+    /// + `Some(NewTicketResult)` with the reason why it can't
+    async fn check_queue(tx: &mut PgConnection, shop_id: i32, policy: &ShopPolicy, closing_cutoff_minutes: i32) -> sqlx::Result<Option<NewTicketResult<'a>>> {
+        let now = Utc::now();
+        let close = match OpeningHours::load(&mut *tx, shop_id).await?.at(now) {
+            Opening::Always => None,
+            Opening::OpenUntil(close) => Some(close),
+            Opening::Closed => return Ok(Some(NewTicketResult::Closed)),
+        };
+        if policy.max_queue.is_none() && policy.max_wait_minutes.is_none() && close.is_none() {
+            return Ok(None);
+        }
+        query!(r"SELECT id FROM shop WHERE id = $1 FOR NO KEY UPDATE", shop_id)
+            .fetch_optional(&mut *tx).await?;
+
+        if let Some(max) = policy.max_queue {
+            let queued = query!(r#"SELECT count(*) as "count!" FROM ticket
+                WHERE
+                    shop_id = $1 AND active AND
+                    entry IS NULL AND exit IS NULL AND expiration > CURRENT_TIMESTAMP"#,
+                    shop_id)
+                .fetch_one(&mut *tx).await?
+                .count;
+            if queued >= max as i64 {
+                return Ok(Some(NewTicketResult::QueueFull));
+            }
+        }
+        if policy.max_wait_minutes.is_none() && close.is_none() {
+            return Ok(None);
+        }
+        let wait = Self::wait_minutes(&mut *tx, shop_id).await?.round() as i32;
+        if let Some(max) = policy.max_wait_minutes {
+            if wait > max {
+                return Ok(Some(NewTicketResult::WaitTooLong(wait)));
+            }
+        }
+        if let Some(close) = close {
+            if now + Duration::minutes((wait + closing_cutoff_minutes) as i64) > close {
+                return Ok(Some(NewTicketResult::Closed));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Insert a ticket with its departments, the ticket is a substitute if it has no customer
+    async fn insert(conn: &mut PgConnection, customer_id: Option<i32>, shop_id: i32, department_ids: Vec<i32>, est_minutes: i32, label: Option<String>, expiry_hours: i32) -> sqlx::Result<Ticket> {
+        let row = query!(r"INSERT INTO ticket (customer_id, shop_id, creation, expiration, est_minutes, valid, active, substitute, label) VALUES
+            ($1, $2, CURRENT_TIMESTAMP, CURRENT_TIMESTAMP + make_interval(hours => $6), $3, TRUE, TRUE, $4, $5)
             RETURNING id",
-            customer_id, shop_id, est_minutes)
-            .fetch_one(&mut tx).await?;
+            customer_id, shop_id, est_minutes, customer_id.is_none(), label, expiry_hours)
+            .fetch_one(&mut *conn).await?;
 
         for did in department_ids {
             query!(r"INSERT INTO ticket_department (ticket_id, department_id)
                 VALUES ($1, $2)",
                 row.id, did)
-                .execute(&mut tx).await?;
+                .execute(&mut *conn).await?;
         }
 
-        let ticket_row = query_as!(TicketRow, r"SELECT ticket.id AS id, customer_id, ticket.shop_id AS shop_id, shop.name as shop_name, array_agg(department.id) AS department_ids, creation, expiration, entry, exit, est_minutes, valid, active
-            FROM ticket, ticket_department, department, shop
+        let ticket_row = query_as!(TicketRow, r"SELECT ticket.id AS id, customer_id, ticket.shop_id AS shop_id, shop.name as shop_name, array_agg(ticket_department.department_id) AS department_ids, creation, expiration, entry, exit, est_minutes, valid, active, substitute, label
+            FROM ticket, ticket_department, shop
             WHERE
                 ticket_department.ticket_id = ticket.id AND
                 ticket.shop_id = shop.id AND
-                ticket_department.department_id = department.id AND
                 ticket.id = $1
-            GROUP BY ticket.id, customer_id, ticket.shop_id, shop.name, creation, expiration, valid, active",
+            GROUP BY ticket.id, customer_id, ticket.shop_id, shop.name, creation, expiration, valid, active, substitute, label",
             row.id)
-            .fetch_one(&mut tx)
+            .fetch_one(&mut *conn)
             .await?;
 
-        tx.commit().await?;
-        Ok(NewTicketResult::Created(Self{conn, inner:ticket_row.into()}))
+        Ok(ticket_row.into())
     }
 
     /// Cancel and delete this ticket
@@ -191,15 +336,14 @@ impl<'a> PersistentTicket<'a> {
 
     /// Get the active ticket queue for this shop, ordered by creation
     pub async fn queue(conn: &PgPool, shop_id: i32) -> sqlx::Result<Vec<Ticket>> {
-        query_as!(TicketRow, r"SELECT ticket.id AS id, customer_id, ticket.shop_id AS shop_id, shop.name as shop_name, array_agg(department.id) AS department_ids, creation, expiration, entry, exit, est_minutes, valid, active
-                FROM ticket, ticket_department, department, shop
+        query_as!(TicketRow, r"SELECT ticket.id AS id, customer_id, ticket.shop_id AS shop_id, shop.name as shop_name, array_agg(ticket_department.department_id) AS department_ids, creation, expiration, entry, exit, est_minutes, valid, active, substitute, label
+                FROM ticket, ticket_department, shop
                 WHERE
                     ticket.shop_id = $1 AND
                     ticket.shop_id = shop.id AND
                     ticket_department.ticket_id = ticket.id AND
-                    ticket_department.department_id = department.id AND
                     entry IS NULL AND exit IS NULL AND COALESCE(expiration > CURRENT_TIMESTAMP, TRUE)
-                GROUP BY ticket.id, customer_id, ticket.shop_id, shop.name, creation, expiration, valid, active
+                GROUP BY ticket.id, customer_id, ticket.shop_id, shop.name, creation, expiration, valid, active, substitute, label
                 ORDER BY creation",
                 shop_id)
             .fetch(conn)
@@ -210,13 +354,17 @@ impl<'a> PersistentTicket<'a> {
             }).await
     }
 
-    /// Try to log entry for this ticket at this moment.
+    /// Try to log entry for this ticket at this moment, only while the shop is open.
     /// See [`EnterResult`] for results
     pub async fn try_enter(&self) -> sqlx::Result<EnterResult> {
         let mut tx = self.conn.begin().await?;
 
+        // Concurrent entries to the same shop wait for each other, so that they can't both take the last place
+        query!(r"SELECT id FROM shop WHERE id = $1 FOR NO KEY UPDATE", self.inner.shop_id)
+            .fetch_optional(&mut tx).await?;
+
         let state = query!(r"SELECT entry IS NOT NULL as entered, exit IS NOT NULL as exited, COALESCE(expiration < CURRENT_TIMESTAMP, TRUE) AS expired FROM ticket
-            WHERE id = $1", self.inner.id)
+            WHERE id = $1 FOR NO KEY UPDATE", self.inner.id)
             .fetch_one(&mut tx)
             .await?;
 
@@ -226,6 +374,10 @@ impl<'a> PersistentTicket<'a> {
         if state.entered.unwrap() {
             return Ok(EnterResult::Invalid);
         }
+        let now = Utc::now();
+        if OpeningHours::load(&mut tx, self.inner.shop_id).await?.at(now) == Opening::Closed {
+            return Ok(EnterResult::Closed);
+        }
 
         let position = query!(r"SELECT count(*) as count FROM ticket
             WHERE
@@ -241,22 +393,10 @@ impl<'a> PersistentTicket<'a> {
             return Ok(EnterResult::NotFirst(position));
         }
 
-        let rows = query!(r"SELECT department.id as id, department.capacity as capacity, (count(ticket.id) >= department.capacity) as full FROM ticket, ticket_department, department
-                        WHERE
-                            ticket_department.ticket_id = ticket.id AND
-                            ticket_department.department_id = department.id AND
-                            ticket.shop_id = $1 AND
-                            department.shop_id = $1 AND
-                            ticket.entry IS NOT NULL AND
-                            ticket.exit IS NULL
-                        GROUP BY
-                            department.id, department.capacity", self.inner.shop_id)
-            .fetch_all(&mut tx)
-            .await?;
+        let policy = AdmissionPolicy::load(&mut tx, self.inner.shop_id, now.naive_utc(), None).await?;
 
-        let full = rows.iter().find(|&row| row.full.unwrap_or(true));
-        if let Some(dep) = full {
-            return Ok(EnterResult::Full(dep.id));
+        if let Some(did) = policy.full_department(TokenKind::Ticket, &self.inner.department_ids) {
+            return Ok(EnterResult::Full(did));
         }
 
         query!(r"UPDATE ticket
@@ -266,13 +406,16 @@ impl<'a> PersistentTicket<'a> {
             .execute(&mut tx)
             .await?;
 
-        for r in rows {
-            let w  = 1. / (r.capacity as f32 + 1.);
+        let deps = policy.departments()
+            .iter()
+            .filter(|d| self.inner.department_ids.contains(&d.id));
+        for d in deps {
+            let w  = 1. / (d.capacity as f32 + 1.);
             let est_f = self.inner.est_minutes as f32;
             query!(r"UPDATE department
             SET
                 ma_est_visit = ma_est_visit * (REAL '1' - $3) + $2 * $3
-            WHERE id = $1", r.id, est_f, w)
+            WHERE id = $1", d.id, est_f, w)
             .execute(&mut tx)
             .await?;
         }
@@ -282,6 +425,7 @@ impl<'a> PersistentTicket<'a> {
     }
 
     /// Try to log exit for this ticket at this moment.
+    /// The length of the visit is added to the averages of the departments and to the history of the customer
     /// ### Returns
     /// + `Ok(true)` if successful
     /// + `Ok(false)` if exit is not allowed for the current state of the ticket
@@ -289,7 +433,7 @@ impl<'a> PersistentTicket<'a> {
         let mut tx = self.conn.begin().await?;
 
         let state = query!(r"SELECT entry, exit FROM ticket
-            WHERE id = $1", self.inner.id)
+            WHERE id = $1 FOR UPDATE", self.inner.id)
             .fetch_one(&mut tx)
             .await?;
 
@@ -305,14 +449,14 @@ impl<'a> PersistentTicket<'a> {
             .execute(&mut tx)
             .await?;
 
-        //TODO: can be optimized
         let rows = query!(r"SELECT department.id as id, department.capacity as capacity FROM ticket_department, department
                     WHERE
+                        ticket_department.department_id = department.id AND
                         ticket_department.ticket_id = $1", self.inner.id)
         .fetch_all(&mut tx)
         .await?;
 
-        let visit_length = minute_diff(entry_time, Utc::now().naive_utc());
+        let visit_length = minute_diff(entry_time, Utc::now());
 
         for r in rows {
             let w  = 1. / (r.capacity as f32 + 1.);
@@ -323,11 +467,65 @@ impl<'a> PersistentTicket<'a> {
             .execute(&mut tx)
             .await?;
         }
+
+        if let Some(customer_id) = self.inner.customer_id {
+            visit::record_visit(&mut tx, customer_id, self.inner.shop_id, visit_length).await?;
+        }
     
         tx.commit().await?;
         Ok(true)
     }
     
+    /// Remove from the queue the tickets that expired before entering.
+    /// Each ticket is recorded as [`TicketAction::Expired`], returns the number of expired tickets
+    pub async fn sweep_expired(conn: &PgPool) -> sqlx::Result<u64> {
+        let r = query!(r"WITH expired AS (
+                UPDATE ticket SET active = FALSE
+                WHERE active AND entry IS NULL AND exit IS NULL AND expiration <= CURRENT_TIMESTAMP
+                RETURNING id, shop_id)
+            INSERT INTO ticket_action (ticket_id, shop_id, action, time)
+            SELECT id, shop_id, 'expired', CURRENT_TIMESTAMP FROM expired")
+            .execute(conn)
+            .await?;
+        Ok(r.rows_affected())
+    }
+
+    /// Log the exit of the tickets that entered more than `max_visit_minutes` ago and never exited.
+    /// Unlike [`exit`](PersistentTicket::exit) the length of these visits is unknown, so the averages
+    /// of the departments and the history of the customer are left untouched.
+    /// Each ticket is recorded as [`TicketAction::AutoClosed`], returns the number of closed tickets
+    pub async fn close_stale(conn: &PgPool, max_visit_minutes: i32) -> sqlx::Result<u64> {
+        let r = query!(r"WITH closed AS (
+                UPDATE ticket SET exit = CURRENT_TIMESTAMP, active = FALSE, auto_closed = TRUE
+                WHERE entry IS NOT NULL AND exit IS NULL AND entry <= CURRENT_TIMESTAMP - make_interval(mins => $1)
+                RETURNING id, shop_id)
+            INSERT INTO ticket_action (ticket_id, shop_id, action, time)
+            SELECT id, shop_id, 'auto_closed', CURRENT_TIMESTAMP FROM closed", max_visit_minutes)
+            .execute(conn)
+            .await?;
+        Ok(r.rows_affected())
+    }
+
+    /// Last `limit` automatic actions taken on the tickets of `shop_id`, most recent first
+    pub async fn actions(conn: &PgPool, shop_id: i32, limit: i64) -> sqlx::Result<Vec<TicketActionResponse>> {
+        let rows = query!(r"SELECT ticket_action.ticket_id, ticket.label, ticket_action.action, ticket_action.time
+            FROM ticket_action, ticket
+            WHERE ticket_action.ticket_id = ticket.id AND ticket_action.shop_id = $1
+            ORDER BY ticket_action.time DESC, ticket_action.id DESC
+            LIMIT $2", shop_id, limit)
+            .fetch_all(conn)
+            .await?;
+
+        Ok(rows.into_iter()
+            .map(|r| TicketActionResponse {
+                ticket_uid: encode_serial(r.ticket_id),
+                label: r.label,
+                action: if r.action == "expired" {TicketAction::Expired} else {TicketAction::AutoClosed},
+                time: r.time,
+            })
+            .collect())
+    }
+
     pub async fn est(conn: &PgPool, shop_id: i32, ticket: Option<Ticket> ) -> sqlx::Result<f32> {
         let (creation, deps) = match ticket.map(|t| (t.creation, t.department_ids)) {
             Some((a,b)) => (Some(a), Some(b)),
@@ -346,20 +544,13 @@ impl<'a> PersistentTicket<'a> {
             ticket_department.department_id = department.id AND
             ticket.shop_id = $1 AND
             department.shop_id = $1 AND
-            ticket.exit IS NULL AND
+            ticket.active AND ticket.exit IS NULL AND
             COALESCE(ticket.creation < $2, TRUE)
         GROUP BY
             department.id, capacity, ma_est_visit, ma_visit", shop_id, creation)
             .fetch_all(conn)
             .await?;
 
-        let fold_est = |wait, r: EstJoinRow| {
-            let dt = combine_expected_measured(r.ma_est_visit, r.ma_visit);
-            let dp = r.queue_extended.unwrap() as i32 - r.capacity;
-            let w = dp as f32 * dt;
-            w.max(wait)
-        };
-
         let est = if let Some(mut deps) = deps {
             deps.sort();
             rows.into_iter()
@@ -373,6 +564,62 @@ impl<'a> PersistentTicket<'a> {
         Ok(est)
     }
     
+    /// Estimated wait in minutes for a customer joining the queue of the shop now
+    pub async fn wait_minutes(conn: &mut PgConnection, shop_id: i32) -> sqlx::Result<f32> {
+        let waits = Self::wait_minutes_for(conn, &[shop_id]).await?;
+        Ok(waits.get(&shop_id).copied().unwrap_or(0.))
+    }
+
+    /// Estimated wait in minutes for each of `shop_ids`, see [`PersistentTicket::wait_minutes`].
+    /// Uses two queries regardless of the number of shops
+    pub async fn wait_minutes_for(conn: &mut PgConnection, shop_ids: &[i32]) -> sqlx::Result<HashMap<i32, f32>> {
+        let mut est = HashMap::new();
+        query!(r#"SELECT
+                department.shop_id as shop_id,
+                department.id as id,
+                capacity as capacity,
+                count(ticket.id) as queue_extended,
+                ma_est_visit,
+                ma_visit
+            FROM ticket, ticket_department, department
+            WHERE
+                ticket_department.ticket_id = ticket.id AND
+                ticket_department.department_id = department.id AND
+                ticket.shop_id = department.shop_id AND
+                department.shop_id = ANY($1) AND
+                ticket.active AND ticket.exit IS NULL
+            GROUP BY
+                department.shop_id, department.id, capacity, ma_est_visit, ma_visit"#, shop_ids)
+            .fetch_all(&mut *conn)
+            .await?
+            .into_iter()
+            .for_each(|r| {
+                let row = EstJoinRow {
+                    id: r.id,
+                    capacity: r.capacity,
+                    queue_extended: r.queue_extended,
+                    ma_est_visit: r.ma_est_visit,
+                    ma_visit: r.ma_visit,
+                };
+                let e = est.entry(r.shop_id).or_insert(0.);
+                *e = fold_est(*e, row);
+            });
+
+        let waits = query!(r#"SELECT shop_id, count(*) as "people!" FROM ticket
+                WHERE
+                    shop_id = ANY($1) AND
+                    entry IS NULL AND exit IS NULL AND COALESCE(expiration > CURRENT_TIMESTAMP, TRUE) AND
+                    EXISTS (SELECT 1 FROM ticket_department WHERE ticket_id = ticket.id)
+                GROUP BY shop_id"#, shop_ids)
+            .fetch_all(&mut *conn)
+            .await?
+            .into_iter()
+            .map(|r| (r.shop_id, est.get(&r.shop_id).copied().unwrap_or(0.) * r.people as f32))
+            .collect();
+
+        Ok(waits)
+    }
+    
     pub fn inner(&self) -> &Ticket {&self.inner}
     pub fn into_inner(self) -> Ticket {self.inner}
 }
@@ -381,16 +628,18 @@ impl<'a> PersistentTicket<'a> {
 #[derive(FromRow)]
 pub(super) struct TicketRow {
     pub id: i32,
-    pub customer_id: i32,
+    pub customer_id: Option<i32>,
     pub shop_id: i32,
     pub shop_name: String,
-    pub creation: NaiveDateTime,
-    pub expiration: NaiveDateTime,
-    pub entry: Option<NaiveDateTime>,
-    pub exit: Option<NaiveDateTime>,
+    pub creation: DateTime<Utc>,
+    pub expiration: DateTime<Utc>,
+    pub entry: Option<DateTime<Utc>>,
+    pub exit: Option<DateTime<Utc>>,
     pub est_minutes: i32,
     pub valid: bool,
     pub active: bool,
+    pub substitute: bool,
+    pub label: Option<String>,
     pub department_ids: Option<Vec<i32>>,
 } 
 
@@ -401,16 +650,26 @@ impl From<TicketRow> for Ticket {
             customer_id: row.customer_id,
             shop_id: row.shop_id,
             shop_name: row.shop_name,
-            creation: row.creation.into(),
+            creation: row.creation,
             expiration: row.expiration,
             est_minutes: row.est_minutes,
             valid: row.valid,
             active: row.active,
+            substitute: row.substitute,
+            label: row.label,
             department_ids: row.department_ids.unwrap_or_default(),
         }
     }
 }
 
+/// Fold the rows of the estimation join into the wait for the most crowded department
+fn fold_est(wait: f32, r: EstJoinRow) -> f32 {
+    let dt = combine_expected_measured(r.ma_est_visit, r.ma_visit);
+    let dp = r.queue_extended.unwrap() as i32 - r.capacity;
+    let w = dp as f32 * dt;
+    w.max(wait)
+}
+
 #[derive(FromRow)]
 struct EstJoinRow {
     id: i32,
@@ -423,7 +682,11 @@ struct EstJoinRow {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::DEFAULT_TICKET_EXPIRY_HOURS as EXPIRY_HOURS;
+    use crate::config::DEFAULT_CLOSING_CUTOFF_MINUTES as CUTOFF_MINUTES;
     use std::error::Error;
+    use crate::models::booking::PersistentBooking;
+    use crate::models::shop::PersistentShop;
     use crate::utils::tests::*;
     use crate::with_test_shop;
 
@@ -435,7 +698,7 @@ mod tests {
         with_test_shop!(&conn, shopid [d1, d2] {
             let customer_id = test_customer(&conn).await?;
 
-            let inserted = PersistentTicket::try_new(&conn, customer_id, shopid, vec![d1, d2], 25)
+            let inserted = PersistentTicket::try_new(&conn, customer_id, shopid, vec![d1, d2], Some(25), EXPIRY_HOURS, CUTOFF_MINUTES)
                 .await?.unwrap().into_inner();
     
             let loaded = PersistentTicket::get(&conn, inserted.id).await?.map(PersistentTicket::into_inner);
@@ -454,13 +717,13 @@ mod tests {
         with_test_shop!(&conn, s0 [d0, d1], s1 [d2] {
             let customer_id = test_customer(&conn).await?;
 
-            let _ = PersistentTicket::try_new(&conn, customer_id, s0, vec![d0], 25).await?.unwrap();
+            let _ = PersistentTicket::try_new(&conn, customer_id, s0, vec![d0], Some(25), EXPIRY_HOURS, CUTOFF_MINUTES).await?.unwrap();
 
-            match PersistentTicket::try_new(&conn, customer_id, s0, vec![d1], 25).await? {
+            match PersistentTicket::try_new(&conn, customer_id, s0, vec![d1], Some(25), EXPIRY_HOURS, CUTOFF_MINUTES).await? {
                 NewTicketResult::AlreadyExists => {},
                 _ => panic!("Expected AlreadyExists"),
             }
-            let _ = PersistentTicket::try_new(&conn, customer_id, s1, vec![d2], 25).await?.unwrap();
+            let _ = PersistentTicket::try_new(&conn, customer_id, s1, vec![d2], Some(25), EXPIRY_HOURS, CUTOFF_MINUTES).await?.unwrap();
             
             del_customer(&conn, customer_id).await?;
         });
@@ -475,10 +738,10 @@ mod tests {
         let id_c2 = test_customer(&conn).await?;
 
         with_test_shop!(&conn, shopid [d0, d1, d2, d3] {
-            let t1 = PersistentTicket::try_new(&conn, id_c1, shopid, vec![d0, d3], 25)
+            let t1 = PersistentTicket::try_new(&conn, id_c1, shopid, vec![d0, d3], Some(25), EXPIRY_HOURS, CUTOFF_MINUTES)
                 .await?.unwrap().into_inner();
 
-            let t2 = PersistentTicket::try_new(&conn, id_c2, shopid, vec![d1,d2,d3], 25)
+            let t2 = PersistentTicket::try_new(&conn, id_c2, shopid, vec![d1,d2,d3], Some(25), EXPIRY_HOURS, CUTOFF_MINUTES)
                 .await?.unwrap().into_inner();
 
             let queue = PersistentTicket::queue(&conn, shopid).await?;
@@ -492,6 +755,11 @@ mod tests {
 
             assert_eq!(Some(&t1), queue.first());
 
+            let est = PersistentTicket::est(&conn, shopid, None).await?;
+            let waits = PersistentTicket::wait_minutes_for(&mut *conn.acquire().await?, &[shopid, -1]).await?;
+            assert_eq!(waits.get(&shopid), Some(&(est * 2.)));
+            assert_eq!(waits.get(&-1), None);
+
             query!("DELETE FROM ticket WHERE id = $1 OR id = $2", t1.id, t2.id)
                 .execute(&conn).await?;
 
@@ -502,6 +770,99 @@ mod tests {
         Ok(())
     }
 
+    #[actix_rt::test]
+    async fn inferred_est_test() -> Result<(), Box<dyn Error>>{
+        let conn = db().await;
+
+        let customer_id = test_customer(&conn).await?;
+
+        with_test_shop!(&conn, shopid [d0] {
+            let t = PersistentTicket::try_new(&conn, customer_id, shopid, vec![d0], None, EXPIRY_HOURS, CUTOFF_MINUTES).await?.unwrap();
+            assert_eq!(t.inner().est_minutes, 15); // Department average
+            assert_eq!(t.try_enter().await?, EnterResult::Entered);
+            assert!(t.exit().await?);
+
+            let mut c = conn.acquire().await?;
+            let h = visit::get_history(&mut c, customer_id, shopid).await?.unwrap();
+            assert_eq!(h.visits, 1);
+            assert!(h.ma_visit < 1.);
+
+            for _ in 0..3 {
+                visit::record_visit(&mut c, customer_id, shopid, 40.).await?;
+            }
+            let h = visit::get_history(&mut c, customer_id, shopid).await?.unwrap();
+
+            let t = PersistentTicket::try_new(&conn, customer_id, shopid, vec![d0], None, EXPIRY_HOURS, CUTOFF_MINUTES).await?.unwrap().into_inner();
+            assert_eq!(t.est_minutes, h.ma_visit.round() as i32);
+            query!("DELETE FROM ticket WHERE id = $1", t.id).execute(&conn).await?;
+
+            let t = PersistentTicket::try_new(&conn, customer_id, shopid, vec![d0], Some(10), EXPIRY_HOURS, CUTOFF_MINUTES).await?.unwrap().into_inner();
+            assert_eq!(Some(t.est_minutes), visit::infer_est_minutes(Some(10), Some(&h)));
+            assert!(t.est_minutes > 10);
+        });
+
+        del_customer(&conn, customer_id).await?;
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn substitute_ticket_test() -> Result<(), Box<dyn Error>>{
+        let conn = db().await;
+
+        let customer_id = test_customer(&conn).await?;
+
+        with_test_shop!(&conn, shopid [d0, d1] {
+            let t1 = PersistentTicket::try_new(&conn, customer_id, shopid, vec![d0], Some(25), EXPIRY_HOURS, CUTOFF_MINUTES)
+                .await?.unwrap().into_inner();
+
+            let s1 = PersistentTicket::try_new_substitute(&conn, shopid, vec![d0, d1], 25, Some("A12".to_string()), EXPIRY_HOURS, CUTOFF_MINUTES)
+                .await?.unwrap().into_inner();
+            let s2 = PersistentTicket::try_new_substitute(&conn, shopid, vec![d1], 25, None, EXPIRY_HOURS, CUTOFF_MINUTES)
+                .await?.unwrap().into_inner();
+
+            assert!(!t1.substitute);
+            assert!(s1.substitute && s2.substitute);
+            assert_eq!(None, s1.customer_id);
+            assert_eq!(Some("A12".to_string()), s1.label);
+
+            let queue = PersistentTicket::queue(&conn, shopid).await?;
+            assert_eq!(vec![t1, s1, s2], queue);
+        });
+
+        del_customer(&conn, customer_id).await?;
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn opening_hours_test() -> Result<(), Box<dyn Error>>{
+        let conn = db().await;
+
+        let id_c1 = test_customer(&conn).await?;
+        let id_c2 = test_customer(&conn).await?;
+
+        with_test_shop!(&conn, shopid [d0] {
+            let t1 = PersistentTicket::try_new(&conn, id_c1, shopid, vec![d0], Some(25), EXPIRY_HOURS, CUTOFF_MINUTES).await?.unwrap();
+
+            let today = Utc::now().naive_utc().date();
+            test_schedule(&conn, shopid, today.succ(), "09:00", "17:00").await?;
+            assert!(matches!(PersistentTicket::try_new(&conn, id_c2, shopid, vec![d0], Some(25), EXPIRY_HOURS, CUTOFF_MINUTES).await?, NewTicketResult::Closed));
+            assert!(matches!(PersistentTicket::try_new_substitute(&conn, shopid, vec![d0], 25, None, EXPIRY_HOURS, CUTOFF_MINUTES).await?, NewTicketResult::Closed));
+            assert_eq!(t1.try_enter().await?, EnterResult::Closed);
+
+            query!(r"INSERT INTO schedule (shop_id, dow, open, close) VALUES ($1, $2, '00:00', '23:59:59')",
+                shopid, today.weekday().number_from_monday() as i16)
+                .execute(&conn).await?;
+            // The shop closes before the estimated entry plus the cut-off
+            assert!(matches!(PersistentTicket::try_new(&conn, id_c2, shopid, vec![d0], Some(25), EXPIRY_HOURS, 24 * 60).await?, NewTicketResult::Closed));
+            PersistentTicket::try_new(&conn, id_c2, shopid, vec![d0], Some(25), EXPIRY_HOURS, CUTOFF_MINUTES).await?.unwrap();
+            assert_eq!(t1.try_enter().await?, EnterResult::Entered);
+        });
+
+        del_customer(&conn, id_c1).await?;
+        del_customer(&conn, id_c2).await?;
+        Ok(())
+    }
+
     #[actix_rt::test]
     async fn entry_exit_test() -> Result<(), Box<dyn Error>>{
         let conn = db().await;
@@ -513,9 +874,9 @@ mod tests {
         with_test_shop!(&conn, shopid [d0, d1] {
             let d_small = test_department(&conn, shopid, 2).await?;
 
-            let t1 = PersistentTicket::try_new(&conn, id_c1, shopid, vec![d_small], 25).await?.unwrap();
-            let t2 = PersistentTicket::try_new(&conn, id_c2, shopid, vec![d_small, d0], 25).await?.unwrap();
-            let t3 = PersistentTicket::try_new(&conn, id_c3, shopid, vec![d_small, d1], 25).await?.unwrap();
+            let t1 = PersistentTicket::try_new(&conn, id_c1, shopid, vec![d_small], Some(25), EXPIRY_HOURS, CUTOFF_MINUTES).await?.unwrap();
+            let t2 = PersistentTicket::try_new(&conn, id_c2, shopid, vec![d_small, d0], Some(25), EXPIRY_HOURS, CUTOFF_MINUTES).await?.unwrap();
+            let t3 = PersistentTicket::try_new(&conn, id_c3, shopid, vec![d_small, d1], Some(25), EXPIRY_HOURS, CUTOFF_MINUTES).await?.unwrap();
 
             assert_eq!(t1.exit().await.unwrap(), false);
 
@@ -549,4 +910,192 @@ mod tests {
 
         Ok(())
     }
+
+    #[actix_rt::test]
+    async fn shop_policy_test() -> Result<(), Box<dyn Error>>{
+        let conn = db().await;
+
+        let id_c1 = test_customer(&conn).await?;
+        let id_c2 = test_customer(&conn).await?;
+        let id_c3 = test_customer(&conn).await?;
+
+        with_test_shop!(&conn, s0 [d0], s1 [d1] {
+            ShopPolicy { ticket_expiry_hours: Some(1), max_wait_minutes: Some(0), walk_in: false, ..Default::default() }
+                .save(&conn, s0).await?;
+            ShopPolicy { max_queue: Some(1), max_customer_tickets: Some(1), ..Default::default() }
+                .save(&conn, s1).await?;
+
+            let t = PersistentTicket::try_new(&conn, id_c1, s0, vec![d0], Some(25), EXPIRY_HOURS, CUTOFF_MINUTES).await?.unwrap().into_inner();
+            assert_eq!(t.expiration - t.creation, chrono::Duration::hours(1));
+            assert!(matches!(PersistentTicket::try_new_substitute(&conn, s0, vec![d0], 25, None, EXPIRY_HOURS, CUTOFF_MINUTES).await?, NewTicketResult::WalkInDisabled));
+
+            query!("UPDATE department SET capacity = 0 WHERE id = $1", d0).execute(&conn).await?;
+            assert!(matches!(PersistentTicket::try_new(&conn, id_c2, s0, vec![d0], Some(25), EXPIRY_HOURS, CUTOFF_MINUTES).await?, NewTicketResult::WaitTooLong(w) if w > 0));
+
+            // c1 already holds a ticket for s0
+            assert!(matches!(PersistentTicket::try_new(&conn, id_c1, s1, vec![d1], Some(25), EXPIRY_HOURS, CUTOFF_MINUTES).await?, NewTicketResult::TooManyTickets));
+            let _ = PersistentTicket::try_new(&conn, id_c3, s1, vec![d1], Some(25), EXPIRY_HOURS, CUTOFF_MINUTES).await?.unwrap();
+            assert!(matches!(PersistentTicket::try_new(&conn, id_c2, s1, vec![d1], Some(25), EXPIRY_HOURS, CUTOFF_MINUTES).await?, NewTicketResult::QueueFull));
+            assert!(matches!(PersistentTicket::try_new_substitute(&conn, s1, vec![d1], 25, None, EXPIRY_HOURS, CUTOFF_MINUTES).await?, NewTicketResult::QueueFull));
+        });
+
+        del_customer(&conn, id_c1).await?;
+        del_customer(&conn, id_c2).await?;
+        del_customer(&conn, id_c3).await?;
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn concurrent_tickets_test() -> Result<(), Box<dyn Error>>{
+        let conn = db().await;
+
+        let id_c1 = test_customer(&conn).await?;
+        let id_c2 = test_customer(&conn).await?;
+        let id_c3 = test_customer(&conn).await?;
+
+        with_test_shop!(&conn, s0 [d0], s1 [d1], s2 [d2] {
+            for s in [s0, s1].iter() {
+                ShopPolicy { max_customer_tickets: Some(1), ..Default::default() }.save(&conn, *s).await?;
+            }
+            ShopPolicy { max_queue: Some(1), max_wait_minutes: Some(60), ..Default::default() }.save(&conn, s2).await?;
+
+            let created = |r: &sqlx::Result<NewTicketResult>| matches!(r, Ok(NewTicketResult::Created(_)));
+            let (r0, r1) = futures::future::join(
+                PersistentTicket::try_new(&conn, id_c1, s0, vec![d0], Some(25), EXPIRY_HOURS, CUTOFF_MINUTES),
+                PersistentTicket::try_new(&conn, id_c1, s1, vec![d1], Some(25), EXPIRY_HOURS, CUTOFF_MINUTES),
+            ).await;
+            assert_eq!([created(&r0), created(&r1)].iter().filter(|c| **c).count(), 1);
+
+            let (r2, r3) = futures::future::join(
+                PersistentTicket::try_new(&conn, id_c2, s2, vec![d2], Some(25), EXPIRY_HOURS, CUTOFF_MINUTES),
+                PersistentTicket::try_new(&conn, id_c3, s2, vec![d2], Some(25), EXPIRY_HOURS, CUTOFF_MINUTES),
+            ).await;
+            assert_eq!([created(&r2), created(&r3)].iter().filter(|c| **c).count(), 1);
+        });
+
+        del_customer(&conn, id_c1).await?;
+        del_customer(&conn, id_c2).await?;
+        del_customer(&conn, id_c3).await?;
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn janitor_test() -> Result<(), Box<dyn Error>>{
+        let conn = db().await;
+
+        let id_c1 = test_customer(&conn).await?;
+        let id_c2 = test_customer(&conn).await?;
+
+        with_test_shop!(&conn, shopid [d0] {
+            let d_small = test_department(&conn, shopid, 1).await?;
+            let ma_visit = || query!("SELECT ma_visit FROM department WHERE id = $1", d_small).fetch_one(&conn);
+            let before = ma_visit().await?.ma_visit;
+
+            // Entered but the exit was never logged
+            let t1 = PersistentTicket::try_new(&conn, id_c1, shopid, vec![d_small], Some(25), EXPIRY_HOURS, CUTOFF_MINUTES).await?.unwrap();
+            assert_eq!(t1.try_enter().await?, EnterResult::Entered);
+            query!("UPDATE ticket SET entry = entry - INTERVAL '3 hours' WHERE id = $1", t1.inner().id)
+                .execute(&conn).await?;
+            // Never showed up
+            let t2 = PersistentTicket::try_new(&conn, id_c2, shopid, vec![d0], Some(25), -1, CUTOFF_MINUTES).await?.unwrap();
+            let t3 = PersistentTicket::try_new_substitute(&conn, shopid, vec![d_small], 25, None, EXPIRY_HOURS, CUTOFF_MINUTES).await?.unwrap();
+            assert_eq!(t3.try_enter().await?, EnterResult::Full(d_small));
+
+            assert!(PersistentTicket::close_stale(&conn, 60).await? >= 1);
+            assert!(PersistentTicket::sweep_expired(&conn).await? >= 1);
+            PersistentTicket::close_stale(&conn, 60).await?;
+            PersistentTicket::sweep_expired(&conn).await?;
+
+            let actions = PersistentTicket::actions(&conn, shopid, 10).await?;
+            assert_eq!(actions.len(), 2);
+            assert!(actions.iter().any(|a| a.ticket_uid == encode_serial(t1.inner().id) && a.action == TicketAction::AutoClosed));
+            assert!(actions.iter().any(|a| a.ticket_uid == encode_serial(t2.inner().id) && a.action == TicketAction::Expired));
+
+            // The closed entry no longer counts toward occupancy and is not a visit
+            assert_eq!(t3.try_enter().await?, EnterResult::Entered);
+            assert!(!t1.exit().await?);
+            assert_eq!(ma_visit().await?.ma_visit, before);
+            assert!(visit::get_history(&mut *conn.acquire().await?, id_c1, shopid).await?.is_none());
+        });
+
+        del_customer(&conn, id_c1).await?;
+        del_customer(&conn, id_c2).await?;
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn mixed_entry_exit_test() -> Result<(), Box<dyn Error>>{
+        let conn = db().await;
+
+        let id_c1 = test_customer(&conn).await?;
+        let id_c2 = test_customer(&conn).await?;
+        let id_c3 = test_customer(&conn).await?;
+
+        with_test_shop!(&conn, shopid [d0] {
+            let d_small = test_department(&conn, shopid, 2).await?;
+            let start = Utc::now().naive_utc() + chrono::Duration::minutes(5);
+
+            let b1 = test_booking(&conn, id_c3, shopid, &[d_small], start, 30).await?;
+            let b1 = PersistentBooking::get(&conn, b1).await?.unwrap();
+
+            let t1 = PersistentTicket::try_new(&conn, id_c1, shopid, vec![d_small], Some(25), EXPIRY_HOURS, CUTOFF_MINUTES).await?.unwrap();
+            let t2 = PersistentTicket::try_new(&conn, id_c2, shopid, vec![d_small, d0], Some(25), EXPIRY_HOURS, CUTOFF_MINUTES).await?.unwrap();
+
+            assert_eq!(t1.try_enter().await.unwrap(), EnterResult::Entered);
+            assert_eq!(t2.try_enter().await.unwrap(), EnterResult::Full(d_small)); // One place is reserved for b1
+
+            assert_eq!(b1.try_enter().await.unwrap(), EnterResult::Entered); // Bookings do not wait in the queue
+            assert_eq!(b1.try_enter().await.unwrap(), EnterResult::Invalid);
+            assert_eq!(t2.try_enter().await.unwrap(), EnterResult::Full(d_small));
+
+            assert!(t1.exit().await.unwrap());
+            assert_eq!(t2.try_enter().await.unwrap(), EnterResult::Entered);
+
+            let occupancy = PersistentShop::get_occupancy(&conn, shopid).await?;
+            let small = occupancy.iter().find(|o| o.department.uid == encode_serial(d_small)).unwrap();
+            assert_eq!(small.occupancy, 2);
+
+            assert!(b1.exit().await.unwrap());
+            assert!(!b1.exit().await.unwrap());
+            assert!(t2.exit().await.unwrap());
+        });
+
+        del_customer(&conn, id_c1).await?;
+        del_customer(&conn, id_c2).await?;
+        del_customer(&conn, id_c3).await?;
+
+        Ok(())
+    }
+    #[actix_rt::test]
+    async fn concurrent_entry_test() -> Result<(), Box<dyn Error>>{
+        let conn = db().await;
+
+        let id_c1 = test_customer(&conn).await?;
+        let id_c2 = test_customer(&conn).await?;
+        let id_c3 = test_customer(&conn).await?;
+
+        with_test_shop!(&conn, shopid [], s1 [d1] {
+            let d_small = test_department(&conn, shopid, 1).await?;
+            let entered = |r: &sqlx::Result<EnterResult>| matches!(r, Ok(EnterResult::Entered));
+
+            // Started long enough ago that its place is not reserved anymore, but it can still enter
+            let b1 = test_booking(&conn, id_c3, shopid, &[d_small], Utc::now().naive_utc() - chrono::Duration::minutes(20), 60).await?;
+            let b1 = PersistentBooking::get(&conn, b1).await?.unwrap();
+            let t1 = PersistentTicket::try_new(&conn, id_c1, shopid, vec![d_small], Some(25), EXPIRY_HOURS, CUTOFF_MINUTES).await?.unwrap();
+
+            let (r1, r2) = futures::future::join(b1.try_enter(), t1.try_enter()).await;
+            assert_eq!([entered(&r1), entered(&r2)].iter().filter(|e| **e).count(), 1);
+
+            // The same ticket entering twice
+            let t2 = PersistentTicket::try_new(&conn, id_c2, s1, vec![d1], Some(25), EXPIRY_HOURS, CUTOFF_MINUTES).await?.unwrap();
+            let (r1, r2) = futures::future::join(t2.try_enter(), t2.try_enter()).await;
+            assert_eq!([entered(&r1), entered(&r2)].iter().filter(|e| **e).count(), 1);
+        });
+
+        del_customer(&conn, id_c1).await?;
+        del_customer(&conn, id_c2).await?;
+        del_customer(&conn, id_c3).await?;
+
+        Ok(())
+    }
 }
\ No newline at end of file
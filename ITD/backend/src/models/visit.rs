@@ -0,0 +1,107 @@
+use sqlx::{FromRow, PgConnection, query, query_as};
+
+/// Number of visits after which the history gets its maximum weight in the estimate
+const TRUSTED_VISITS: i32 = 4;
+/// Minimum weight of a new visit in the moving average, so the history keeps adapting
+const MIN_VISIT_WEIGHT: f32 = 0.25;
+
+/// Length of the past visits of a customer to a shop
+#[derive(Debug, Clone, PartialEq, FromRow)]
+pub struct VisitHistory {
+    /// Moving average of the visit length in minutes
+    pub ma_visit: f32,
+    /// Number of visits measured
+    pub visits: i32,
+}
+
+impl VisitHistory {
+    /// Weight of the history against the length declared by the customer, grows with the number of visits
+    fn weight(&self) -> f32 {
+        self.visits.min(TRUSTED_VISITS) as f32 / (TRUSTED_VISITS + 1) as f32
+    }
+}
+
+/// Retrieve the visit history of a customer for a shop
+pub async fn get_history(conn: &mut PgConnection, customer_id: i32, shop_id: i32) -> sqlx::Result<Option<VisitHistory>> {
+    query_as!(VisitHistory, r"SELECT ma_visit, visits FROM customer_visit
+        WHERE customer_id = $1 AND shop_id = $2",
+        customer_id, shop_id)
+        .fetch_optional(conn)
+        .await
+}
+
+/// Add a visit of `minutes` to the history of a customer for a shop.
+/// The first visits are averaged, later ones are weighted by [`MIN_VISIT_WEIGHT`]
+pub async fn record_visit(conn: &mut PgConnection, customer_id: i32, shop_id: i32, minutes: f32) -> sqlx::Result<()> {
+    query!(r"INSERT INTO customer_visit (customer_id, shop_id, ma_visit, visits) VALUES ($1, $2, $3, 1)
+        ON CONFLICT (customer_id, shop_id) DO UPDATE
+        SET
+            ma_visit = customer_visit.ma_visit + (EXCLUDED.ma_visit - customer_visit.ma_visit) * GREATEST(REAL '1' / (customer_visit.visits + 1), $4::REAL),
+            visits = customer_visit.visits + 1",
+        customer_id, shop_id, minutes, MIN_VISIT_WEIGHT)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Estimate the length of a visit from the one declared by the customer and their history.
+/// Returns `None` if neither is available
+pub fn infer_est_minutes(declared: Option<i32>, history: Option<&VisitHistory>) -> Option<i32> {
+    match (declared, history) {
+        (Some(d), Some(h)) => {
+            let w = h.weight();
+            Some((d as f32 * (1. - w) + h.ma_visit * w).round() as i32)
+        }
+        (Some(d), None) => Some(d),
+        (None, Some(h)) => Some(h.ma_visit.round() as i32),
+        (None, None) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+    use crate::utils::tests::*;
+    use crate::with_test_shop;
+
+    #[test]
+    fn infer_est_minutes_test() {
+        let new = VisitHistory { ma_visit: 40., visits: 1 };
+        let regular = VisitHistory { ma_visit: 40., visits: 10 };
+
+        assert_eq!(infer_est_minutes(None, None), None);
+        assert_eq!(infer_est_minutes(Some(20), None), Some(20));
+        assert_eq!(infer_est_minutes(None, Some(&new)), Some(40));
+        assert_eq!(infer_est_minutes(Some(20), Some(&new)), Some(24));
+        assert_eq!(infer_est_minutes(Some(20), Some(&regular)), Some(36));
+    }
+
+    #[actix_rt::test]
+    async fn record_visit_test() -> Result<(), Box<dyn Error>> {
+        let conn = db().await;
+        let customer_id = test_customer(&conn).await?;
+
+        with_test_shop!(&conn, s0 [] {
+            let mut c = conn.acquire().await?;
+            assert_eq!(get_history(&mut c, customer_id, s0).await?, None);
+
+            record_visit(&mut c, customer_id, s0, 10.).await?;
+            record_visit(&mut c, customer_id, s0, 20.).await?;
+            let h = get_history(&mut c, customer_id, s0).await?.unwrap();
+            assert_eq!(h.visits, 2);
+            assert!((h.ma_visit - 15.).abs() < 0.01);
+
+            for _ in 0..2 {
+                record_visit(&mut c, customer_id, s0, 15.).await?;
+            }
+            record_visit(&mut c, customer_id, s0, 35.).await?;
+            let h = get_history(&mut c, customer_id, s0).await?.unwrap();
+            assert_eq!(h.visits, 5);
+            assert!((h.ma_visit - 20.).abs() < 0.01);
+        });
+
+        del_customer(&conn, customer_id).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,5 @@
+pub mod notifier;
+pub mod scheduler;
+
+pub use notifier::{EmailNotifier, LogNotifier, Notification, Notifier, WebhookNotifier};
+pub use scheduler::NotificationScheduler;
\ No newline at end of file
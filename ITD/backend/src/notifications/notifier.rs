@@ -0,0 +1,188 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::client::Client;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use futures::future::{FutureExt, LocalBoxFuture};
+use serde::{Serialize, Deserialize};
+
+use crate::mail::{template, Mailer};
+
+/// Notification sent to a customer when their turn is about to come
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Notification {
+    pub customer_id: String,
+    pub email: String,
+    pub ticket_uid: String,
+    pub shop_id: String,
+    pub shop_name: String,
+    /// Number of people ahead in queue
+    pub people: u32,
+    /// Estimated time of entry
+    pub est: DateTime<Utc>,
+    /// Time zone of the shop, the estimated time of entry is shown to the customer in it
+    pub time_zone: Tz,
+}
+
+impl fmt::Display for Notification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}: ticket {} for {} ({}), {} ahead, estimated entry at {}",
+            Utc::now().to_rfc3339(), self.email, self.ticket_uid, self.shop_name, self.shop_id, self.people, self.est.to_rfc3339())
+    }
+}
+
+/// Delivery channel for customer notifications
+pub trait Notifier {
+    /// Deliver `notification`. When delivery fails the notification is retried at the next run of the scheduler
+    fn notify<'a>(&'a self, notification: &'a Notification) -> LocalBoxFuture<'a, Result<(), Box<dyn Error>>>;
+}
+
+/// Notifier writing notifications to the log, or appending them to a file if one is set
+#[derive(Default)]
+pub struct LogNotifier {
+    file: Option<PathBuf>,
+}
+
+impl LogNotifier {
+    pub fn new() -> Self {
+        Self { file: None }
+    }
+
+    /// Append one line per notification to `path`
+    pub fn with_file(path: impl Into<PathBuf>) -> Self {
+        Self { file: Some(path.into()) }
+    }
+}
+
+impl Notifier for LogNotifier {
+    fn notify<'a>(&'a self, notification: &'a Notification) -> LocalBoxFuture<'a, Result<(), Box<dyn Error>>> {
+        let res = match &self.file {
+            Some(path) => OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut f| writeln!(f, "{}", notification))
+                .map_err(Box::<dyn Error>::from),
+            None => {
+                log::info!("Notification: {}", notification);
+                Ok(())
+            }
+        };
+        futures::future::ready(res).boxed_local()
+    }
+}
+
+/// Notifier posting notifications as json to a webhook
+pub struct WebhookNotifier {
+    url: String,
+    client: Client,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: &str) -> Self {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(10))
+            .finish();
+        Self { url: url.to_owned(), client }
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn notify<'a>(&'a self, notification: &'a Notification) -> LocalBoxFuture<'a, Result<(), Box<dyn Error>>> {
+        async move {
+            let resp = self.client.post(&self.url)
+                .send_json(notification)
+                .await?;
+            if resp.status().is_success() {
+                Ok(())
+            } else {
+                Err(format!("Webhook responded with status {}", resp.status()).into())
+            }
+        }.boxed_local()
+    }
+}
+
+/// Notifier sending notifications by email
+pub struct EmailNotifier {
+    mailer: Arc<dyn Mailer>,
+}
+
+impl EmailNotifier {
+    pub fn new(mailer: Arc<dyn Mailer>) -> Self {
+        Self { mailer }
+    }
+}
+
+impl Notifier for EmailNotifier {
+    fn notify<'a>(&'a self, notification: &'a Notification) -> LocalBoxFuture<'a, Result<(), Box<dyn Error>>> {
+        async move {
+            self.mailer.send(&template::ticket_notification(notification)).await
+        }.boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use actix_web::{web, App, HttpResponse};
+
+    fn notification() -> Notification {
+        Notification {
+            customer_id: "c0".into(),
+            email: "customer@test.com".into(),
+            ticket_uid: "t0".into(),
+            shop_id: "s0".into(),
+            shop_name: "Test shop".into(),
+            people: 2,
+            est: Utc::now(),
+            time_zone: Tz::UTC,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn file_notifier_test() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("clup-notify-{:x}.log", rand::random::<u64>()));
+        let notifier = LogNotifier::with_file(&path);
+
+        notifier.notify(&notification()).await?;
+        notifier.notify(&notification()).await?;
+
+        let content = std::fs::read_to_string(&path)?;
+        assert_eq!(content.lines().count(), 2);
+        assert!(content.contains("customer@test.com"));
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    type Received = Arc<Mutex<Vec<Notification>>>;
+
+    async fn hook(received: web::Data<Received>, n: web::Json<Notification>) -> HttpResponse {
+        received.lock().unwrap().push(n.into_inner());
+        HttpResponse::Ok().finish()
+    }
+
+    #[actix_rt::test]
+    async fn webhook_notifier_test() {
+        let received = Received::default();
+        let r = received.clone();
+        let srv = actix_web::test::start(move || {
+            App::new()
+                .data(r.clone())
+                .route("/hook", web::post().to(hook))
+        });
+
+        let n = notification();
+        WebhookNotifier::new(&srv.url("/hook")).notify(&n).await.unwrap();
+        assert_eq!(*received.lock().unwrap(), vec![n.clone()]);
+
+        assert!(WebhookNotifier::new(&srv.url("/missing")).notify(&n).await.is_err());
+    }
+}
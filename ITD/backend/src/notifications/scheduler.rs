@@ -0,0 +1,166 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use sqlx::PgPool;
+
+use crate::models::notification::{self, PendingNotification};
+use crate::models::shop::{Opening, OpeningHours};
+use crate::models::ticket::{PersistentTicket, Ticket};
+use crate::utils::encoding::encode_serial;
+use super::notifier::{Notification, Notifier};
+
+/// Background job notifying customers when their turn is about to come
+///
+/// Every `period` the estimated wait of each queued ticket is recomputed, customers whose wait
+/// is below their threshold are notified once through the [`Notifier`].
+/// The queues of closed shops do not move, their customers are notified once the shop opens.
+pub struct NotificationScheduler {
+    conn: PgPool,
+    notifier: Box<dyn Notifier>,
+    period: Duration,
+}
+
+impl NotificationScheduler {
+    pub fn new(conn: PgPool, notifier: Box<dyn Notifier>, period: Duration) -> Self {
+        Self { conn, notifier, period }
+    }
+
+    /// Check the queues every `period` until the system is stopped
+    pub async fn run(self) {
+        let mut interval = actix_web::rt::time::interval(self.period);
+        loop {
+            interval.tick().await;
+            match self.run_once().await {
+                Ok(0) => {},
+                Ok(n) => log::info!("Sent {} notifications", n),
+                Err(e) => log::error!("Error checking notifications: {}", e),
+            }
+        }
+    }
+
+    /// Check all queues once, returns the number of notifications sent
+    pub async fn run_once(&self) -> sqlx::Result<usize> {
+        let mut sent = 0;
+        for shop_id in notification::pending_shops(&self.conn).await? {
+            let hours = OpeningHours::load(&mut *self.conn.acquire().await?, shop_id).await?;
+            if hours.at(Utc::now()) == Opening::Closed {
+                continue;
+            }
+            let pending = notification::pending_for_shop(&self.conn, shop_id).await?;
+            let queue = PersistentTicket::queue(&self.conn, shop_id).await?;
+
+            for (people, ticket) in queue.into_iter().enumerate() {
+                if let Some(p) = pending.iter().find(|p| p.ticket_id == ticket.id) {
+                    if self.check_ticket(&hours, shop_id, ticket, people as u32, p).await? {
+                        sent += 1;
+                    }
+                }
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Notify the customer holding `ticket` if the estimated wait is below the threshold,
+    /// the time of entry is estimated counting only the opening `hours` of the shop
+    async fn check_ticket(&self, hours: &OpeningHours, shop_id: i32, ticket: Ticket, people: u32, pending: &PendingNotification) -> sqlx::Result<bool> {
+        let w = PersistentTicket::est(&self.conn, shop_id, Some(ticket.clone())).await?;
+        let wait = w * people as f32;
+        if wait >= pending.notify_minutes as f32 {
+            return Ok(false);
+        }
+
+        let n = Notification {
+            customer_id: encode_serial(pending.customer_id),
+            email: pending.email.clone(),
+            ticket_uid: encode_serial(ticket.id),
+            shop_id: encode_serial(shop_id),
+            shop_name: ticket.shop_name,
+            people,
+            est: hours.advance(Utc::now(), wait as i64),
+            time_zone: hours.time_zone(),
+        };
+        if let Err(e) = self.notifier.notify(&n).await {
+            log::warn!("Could not notify customer {}: {}", n.customer_id, e);
+            return Ok(false);
+        }
+        notification::mark_notified(&self.conn, ticket.id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DEFAULT_TICKET_EXPIRY_HOURS as EXPIRY_HOURS;
+    use crate::config::DEFAULT_CLOSING_CUTOFF_MINUTES as CUTOFF_MINUTES;
+    use std::cell::RefCell;
+    use std::error::Error;
+    use std::rc::Rc;
+    use futures::future::{FutureExt, LocalBoxFuture};
+
+    use crate::utils::tests::*;
+    use crate::with_test_shop;
+
+    #[derive(Clone, Default)]
+    struct RecordingNotifier(Rc<RefCell<Vec<Notification>>>);
+
+    impl Notifier for RecordingNotifier {
+        fn notify<'a>(&'a self, notification: &'a Notification) -> LocalBoxFuture<'a, Result<(), Box<dyn Error>>> {
+            self.0.borrow_mut().push(notification.clone());
+            futures::future::ready(Ok(())).boxed_local()
+        }
+    }
+
+    #[actix_rt::test]
+    async fn notify_once_test() -> Result<(), Box<dyn Error>> {
+        let conn = db().await;
+        let c0 = test_customer(&conn).await?;
+        let c1 = test_customer(&conn).await?;
+        notification::set_threshold(&conn, c1, 0).await?;
+
+        with_test_shop!(&conn, s0 [d0] {
+            let t0 = PersistentTicket::try_new(&conn, c0, s0, vec![d0], Some(15), EXPIRY_HOURS, CUTOFF_MINUTES).await?.unwrap().into_inner();
+            let t1 = PersistentTicket::try_new(&conn, c1, s0, vec![d0], Some(15), EXPIRY_HOURS, CUTOFF_MINUTES).await?.unwrap().into_inner();
+            let t2 = PersistentTicket::try_new_substitute(&conn, s0, vec![d0], 15, None, EXPIRY_HOURS, CUTOFF_MINUTES).await?.unwrap().into_inner();
+
+            let notifier = RecordingNotifier::default();
+            let scheduler = NotificationScheduler::new(conn.clone(), Box::new(notifier.clone()), Duration::from_secs(1));
+
+            scheduler.run_once().await?;
+            scheduler.run_once().await?;
+
+            let ours = |uid: &String| [t0.id, t1.id, t2.id].iter().any(|&id| &encode_serial(id) == uid);
+            let sent: Vec<Notification> = notifier.0.borrow().iter()
+                .filter(|n| ours(&n.ticket_uid))
+                .cloned()
+                .collect();
+            assert_eq!(sent.len(), 1);
+            assert_eq!(sent[0].ticket_uid, encode_serial(t0.id));
+            assert_eq!(sent[0].people, 0);
+        });
+
+        del_customer(&conn, c0).await?;
+        del_customer(&conn, c1).await?;
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn closed_shop_test() -> Result<(), Box<dyn Error>> {
+        let conn = db().await;
+        let c0 = test_customer(&conn).await?;
+
+        with_test_shop!(&conn, s0 [d0] {
+            let t0 = PersistentTicket::try_new(&conn, c0, s0, vec![d0], Some(15), EXPIRY_HOURS, CUTOFF_MINUTES).await?.unwrap().into_inner();
+            // Open tomorrow only, closed now
+            test_schedule(&conn, s0, tomorrow_at(9, 0).date(), "09:00", "17:00").await?;
+
+            let notifier = RecordingNotifier::default();
+            let scheduler = NotificationScheduler::new(conn.clone(), Box::new(notifier.clone()), Duration::from_secs(1));
+            scheduler.run_once().await?;
+
+            assert!(notifier.0.borrow().iter().all(|n| n.ticket_uid != encode_serial(t0.id)));
+        });
+
+        del_customer(&conn, c0).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+use std::error::Error;
+use std::time::Duration;
+
+use actix_web::client::Client;
+use futures::future::{FutureExt, LocalBoxFuture};
+use serde::Deserialize;
+
+use crate::utils::geo::Coordinates;
+
+/// Estimates how long it takes to travel between two points
+pub trait TravelTimeProvider {
+    /// Travel time from `from` to `to`
+    fn travel_time<'a>(&'a self, from: Coordinates, to: Coordinates) -> LocalBoxFuture<'a, Result<Duration, Box<dyn Error>>>;
+}
+
+/// Estimate from the straight line distance travelled at an average speed.
+/// `detour` multiplies the distance to account for roads not being straight
+pub struct StraightLineProvider {
+    speed_kmh: f64,
+    detour: f64,
+}
+
+impl StraightLineProvider {
+    pub fn new(speed_kmh: f64, detour: f64) -> Self {
+        Self { speed_kmh, detour }
+    }
+}
+
+impl Default for StraightLineProvider {
+    /// Walking speed, roads about 30% longer than the straight line
+    fn default() -> Self {
+        Self::new(5.0, 1.3)
+    }
+}
+
+impl TravelTimeProvider for StraightLineProvider {
+    fn travel_time<'a>(&'a self, from: Coordinates, to: Coordinates) -> LocalBoxFuture<'a, Result<Duration, Box<dyn Error>>> {
+        let hours = from.distance_km(&to) * self.detour / self.speed_kmh;
+        futures::future::ready(Ok(Duration::from_secs_f64(hours * 3600.))).boxed_local()
+    }
+}
+
+/// Route duration from an OSRM compatible routing service
+pub struct OsrmProvider {
+    url: String,
+    profile: String,
+    client: Client,
+}
+
+#[derive(Deserialize)]
+struct OsrmResponse {
+    code: String,
+    #[serde(default)]
+    routes: Vec<OsrmRoute>,
+}
+
+#[derive(Deserialize)]
+struct OsrmRoute {
+    /// Seconds
+    duration: f64,
+}
+
+impl OsrmProvider {
+    /// `url` is the base url of the service, `profile` the mode of transport, e.g. `foot` or `driving`
+    pub fn new(url: &str, profile: &str) -> Self {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(5))
+            .finish();
+        Self { url: url.trim_end_matches('/').to_owned(), profile: profile.to_owned(), client }
+    }
+}
+
+impl TravelTimeProvider for OsrmProvider {
+    fn travel_time<'a>(&'a self, from: Coordinates, to: Coordinates) -> LocalBoxFuture<'a, Result<Duration, Box<dyn Error>>> {
+        async move {
+            let url = format!("{}/route/v1/{}/{},{};{},{}?overview=false",
+                self.url, self.profile, from.lon, from.lat, to.lon, to.lat);
+            let mut resp = self.client.get(&url).send().await?;
+            if !resp.status().is_success() {
+                return Err(format!("Routing service responded with status {}", resp.status()).into());
+            }
+            let body: OsrmResponse = resp.json().await?;
+            match body.routes.first() {
+                Some(route) if body.code == "Ok" && route.duration >= 0. => Ok(Duration::from_secs_f64(route.duration)),
+                _ => Err(format!("No route found: {}", body.code).into()),
+            }
+        }.boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpResponse};
+
+    #[actix_rt::test]
+    async fn straight_line_test() {
+        let from = Coordinates::new(45.4642, 9.1900).unwrap();
+        let to = Coordinates::new(45.4642, 9.2028).unwrap(); // About 1km east
+
+        let t = StraightLineProvider::new(5.0, 1.0).travel_time(from, to).await.unwrap();
+        assert!((t.as_secs() as i64 - 720).abs() < 10, "Travel time was {:?}", t);
+        let t = StraightLineProvider::new(5.0, 1.0).travel_time(from, from).await.unwrap();
+        assert_eq!(t, Duration::from_secs(0));
+    }
+
+    async fn route(path: web::Path<(String, String)>) -> HttpResponse {
+        let (_, coords) = path.into_inner();
+        if coords.starts_with("0,0;") {
+            HttpResponse::Ok().json(serde_json::json!({ "code": "NoRoute", "routes": [] }))
+        } else {
+            HttpResponse::Ok().json(serde_json::json!({ "code": "Ok", "routes": [{ "duration": 600.5, "distance": 1000.0 }] }))
+        }
+    }
+
+    #[actix_rt::test]
+    async fn osrm_test() {
+        let srv = actix_web::test::start(|| {
+            App::new().route("/route/v1/{profile}/{coords}", web::get().to(route))
+        });
+        let provider = OsrmProvider::new(&srv.url("/"), "foot");
+
+        let from = Coordinates::new(45.4642, 9.1900).unwrap();
+        let to = Coordinates::new(45.0703, 7.6869).unwrap();
+        let t = provider.travel_time(from, to).await.unwrap();
+        assert_eq!(t.as_secs(), 600);
+
+        let nowhere = Coordinates::new(0., 0.).unwrap();
+        assert!(provider.travel_time(nowhere, to).await.is_err());
+        assert!(OsrmProvider::new(&srv.url("/missing"), "foot").travel_time(from, to).await.is_err());
+    }
+}
@@ -2,4 +2,5 @@ pub mod session;
 pub mod encoding;
 // #[cfg(test)]
 pub mod tests;
-pub mod time;
\ No newline at end of file
+pub mod time;
+pub mod geo;
\ No newline at end of file
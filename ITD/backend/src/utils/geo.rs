@@ -0,0 +1,83 @@
+use serde::{Serialize, Deserialize};
+
+/// Mean radius of the earth in km
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+/// Position on the earth in decimal degrees
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Coordinates {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl Coordinates {
+    /// Create coordinates, returns `None` if they are out of range
+    pub fn new(lat: f64, lon: f64) -> Option<Self> {
+        if (-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lon) {
+            Some(Self { lat, lon })
+        } else {
+            None
+        }
+    }
+
+    /// Great-circle distance in km
+    pub fn distance_km(&self, other: &Coordinates) -> f64 {
+        let (lat1, lat2) = (self.lat.to_radians(), other.lat.to_radians());
+        let dlat = lat2 - lat1;
+        let dlon = (other.lon - self.lon).to_radians();
+        let a = (dlat / 2.).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.).sin().powi(2);
+        2. * EARTH_RADIUS_KM * a.sqrt().asin()
+    }
+}
+
+/// Parse a location string as stored for the shops.
+/// Accepts both `45.4642N,9.1900E` and signed decimal degrees `45.4642,9.1900`
+pub fn parse_location(location: &str) -> Option<Coordinates> {
+    let mut parts = location.split(',');
+    let lat = parse_degrees(parts.next()?, 'N', 'S')?;
+    let lon = parse_degrees(parts.next()?, 'E', 'W')?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Coordinates::new(lat, lon)
+}
+
+fn parse_degrees(s: &str, positive: char, negative: char) -> Option<f64> {
+    let s = s.trim();
+    let (value, sign) = match s.chars().last()?.to_ascii_uppercase() {
+        c if c == positive => (&s[..s.len() - 1], 1.),
+        c if c == negative => (&s[..s.len() - 1], -1.),
+        _ => (s, 1.),
+    };
+    let value: f64 = value.trim().parse().ok()?;
+    if value.is_finite() {
+        Some(value * sign)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_location_test() {
+        assert_eq!(parse_location("49.1234N,12.3456E"), Coordinates::new(49.1234, 12.3456));
+        assert_eq!(parse_location("2.1234S, 23.3456W"), Coordinates::new(-2.1234, -23.3456));
+        assert_eq!(parse_location("45.5,-9.25"), Coordinates::new(45.5, -9.25));
+        assert_eq!(parse_location("123.1234N,45.3456E"), None);
+        assert_eq!(parse_location("Via Roma 1, Milano"), None);
+        assert_eq!(parse_location("45.5"), None);
+        assert_eq!(parse_location("1,2,3"), None);
+    }
+
+    #[test]
+    fn distance_test() {
+        let milan = Coordinates::new(45.4642, 9.1900).unwrap();
+        let turin = Coordinates::new(45.0703, 7.6869).unwrap();
+        let d = milan.distance_km(&turin);
+        assert!((d - 125.).abs() < 2., "Distance was {}", d);
+        assert_eq!(milan.distance_km(&milan), 0.);
+    }
+}
@@ -11,11 +11,19 @@ pub struct StaffSession {
     pub id: i32,
     pub email: String,
     pub shop_id: i32,
+    #[serde(default)]
+    pub manager: bool,
+    /// Session version of the account at login, the session is invalid if the account has a newer one
+    #[serde(default)]
+    pub version: i32,
 }
 #[derive(Serialize, Deserialize)]
 pub struct CustomerSession {
     pub id: i32,
     pub email: String,
+    /// Session version of the account at login, the session is invalid if the account has a newer one
+    #[serde(default)]
+    pub version: i32,
 }
 
 /// Get customer account from session
@@ -25,8 +33,8 @@ pub fn get_account(session: &Session) -> Option<CustomerSession> {
 }
 
 /// Set customer account from session
-pub fn set_account(session: &Session, id: i32, email: &str) {
-    let sess = CustomerSession{id, email: email.to_owned()};
+pub fn set_account(session: &Session, id: i32, email: &str, version: i32) {
+    let sess = CustomerSession{id, email: email.to_owned(), version};
     session.set(KEY_CUSTOMER_ACCOUNT, Some(sess)).unwrap();
 }
 
@@ -42,8 +50,8 @@ pub fn get_staff_account(session: &Session) -> Option<StaffSession> {
 }
 
 /// Set staff account from session
-pub fn set_staff_account(session: &Session, id: i32, email: &str, shop_id: i32) {
-    session.set(KEY_STAFF_ACCOUNT, Some(StaffSession{id, email: email.to_owned(), shop_id})).unwrap();
+pub fn set_staff_account(session: &Session, id: i32, email: &str, shop_id: i32, manager: bool, version: i32) {
+    session.set(KEY_STAFF_ACCOUNT, Some(StaffSession{id, email: email.to_owned(), shop_id, manager, version})).unwrap();
 }
 
 /// Clear staff account from session
@@ -60,4 +68,17 @@ pub fn check_staff_auth(session: &Session, shop_id: &str) -> Option<StaffSession
     } else {
         None
     }
+}
+
+/// Get staff account if it belongs to a manager of `shop_id`
+pub fn check_manager_auth(session: &Session, shop_id: &str) -> Option<StaffSession> {
+    check_staff_auth(session, shop_id)
+        .filter(|staff| staff.manager)
+}
+
+/// Get staff account if it belongs to a manager of any shop, only for
+/// endpoints that do not act on an existing shop
+pub fn get_manager_account(session: &Session) -> Option<StaffSession> {
+    get_staff_account(session)
+        .filter(|staff| staff.manager)
 }
\ No newline at end of file
@@ -1,6 +1,9 @@
 use sqlx::PgPool;
+use chrono::prelude::*;
+use chrono::Duration;
 use rand::{RngCore, thread_rng};
 
+use crate::config::DEFAULT_REGISTRATION_EXPIRY_HOURS;
 use crate::models::customer::PersistentCustomer;
 use crate::models::staff::PersistentStaff;
 
@@ -17,8 +20,8 @@ pub fn wait_a_bit() {
 pub async fn test_customer(conn: &PgPool) -> sqlx::Result<i32> {
     let email = format!("{}@email.com", thread_rng().next_u64());
     let password = format!("pass{}", thread_rng().next_u64());
-    let code = PersistentCustomer::create(conn, &email, &password).await?.unwrap();
-    let cust = PersistentCustomer::finalize(conn, &code).await?.unwrap();
+    let code = PersistentCustomer::create(conn, &email, &password, DEFAULT_REGISTRATION_EXPIRY_HOURS).await?.unwrap();
+    let cust = PersistentCustomer::finalize(conn, &code, DEFAULT_REGISTRATION_EXPIRY_HOURS).await?.unwrap();
 
     Ok(cust.id())
 }
@@ -58,11 +61,47 @@ pub async fn test_department(conn: &PgPool, shopid: i32, cap: i32) -> sqlx::Resu
 }
 
 pub async fn test_staff(conn: &PgPool, email: &str, password: &str, shop_id: i32) -> sqlx::Result<i32> {
-    let staff = PersistentStaff::create(conn, &email, &password, shop_id).await?.unwrap();
+    let staff = PersistentStaff::create(conn, &email, &password, shop_id, false).await?.unwrap();
 
     Ok(staff.inner().account().id())
 }
 
+pub async fn test_schedule(conn: &PgPool, shop_id: i32, day: NaiveDate, open: &str, close: &str) -> sqlx::Result<()> {
+    let open = NaiveTime::parse_from_str(open, "%H:%M").unwrap();
+    let close = NaiveTime::parse_from_str(close, "%H:%M").unwrap();
+    sqlx::query!(
+        r"INSERT INTO schedule (shop_id, dow, open, close)
+        VALUES ($1, $2, $3, $4)",
+        shop_id, day.weekday().number_from_monday() as i16, open, close)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Insert a booking without checking the schedule, so that it can start at any time
+pub async fn test_booking(conn: &PgPool, customer_id: i32, shop_id: i32, department_ids: &[i32], start_time: NaiveDateTime, duration: i32) -> sqlx::Result<i32> {
+    let id = sqlx::query!(
+        r"INSERT INTO booking (customer_id, shop_id, creation, start_time, duration, valid, active)
+        VALUES ($1, $2, CURRENT_TIMESTAMP, $3, $4, TRUE, TRUE) RETURNING id",
+        customer_id, shop_id, start_time, duration)
+        .fetch_one(conn)
+        .await?
+        .id;
+    for did in department_ids {
+        sqlx::query!(
+            r"INSERT INTO booking_department (booking_id, department_id) VALUES ($1, $2)",
+            id, did)
+            .execute(conn)
+            .await?;
+    }
+    Ok(id)
+}
+
+/// Tomorrow (UTC) at the specified time
+pub fn tomorrow_at(hour: u32, minute: u32) -> NaiveDateTime {
+    (Utc::now().naive_utc().date() + Duration::days(1)).and_hms(hour, minute, 0)
+}
+
 #[macro_export]
 macro_rules! with_test_shop {
     ($conn:expr, $($s:ident [$($di:ident),*]),+ $block:expr) => {{
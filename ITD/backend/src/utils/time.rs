@@ -1,7 +1,7 @@
 use chrono::prelude::*;
 
 /// Get length of interval from `from` to `to` in `f32` minutes
-pub fn minute_diff(from: NaiveDateTime, to: NaiveDateTime) -> f32 {
+pub fn minute_diff(from: DateTime<Utc>, to: DateTime<Utc>) -> f32 {
     let duration = to - from;
     let millis = duration.num_milliseconds();
     millis as f32 / 60000.
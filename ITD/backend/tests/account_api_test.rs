@@ -1,5 +1,6 @@
 
 mod common;
+use clup::api::account::NotificationSettings;
 use common::{extract_session_cookie, requests::*};
 
 use actix_web::http;
@@ -22,11 +23,14 @@ async fn account_api_test() {
 
         actix_web::test::init_service(actix_web::App::new()
             .data(db_pool.clone())
+            .data(clup::config::Config::default())
+            .app_data(actix_web::web::Data::from(common::test_mailer()))
             .wrap(actix_redis::RedisSession::new(&redis_url, &key))
             .wrap(actix_web::middleware::Logger::default())
             .configure(api::account::endpoints)
             .configure(api::ticket::endpoints)
             .configure(api::shop::endpoints)
+            .configure(api::booking::endpoints)
             .service(actix_web::web::scope("/staff").configure(api::staff::endpoints))
             .service(actix_web::web::scope("/dev").configure(api::dev::endpoints))
         ).await
@@ -39,11 +43,13 @@ async fn account_api_test() {
     let resp = req!(register(&email, &password), &mut app);
     assert_eq!(resp.status(), StatusCode::OK);
 
-    let code = test::read_body(resp).await;
-    assert!(code.len() > 0);
+    assert!(test::read_body(resp).await.is_empty());
+    let resp = req!(register(&email, "another password"), &mut app); // Registration still pending
+    assert_eq!(error_code!(resp), "already_exists");
     
     // Confirm
-    let code = String::from_utf8(Vec::from(&code[..])).unwrap();
+    let code = common::mail_code(&email);
+    assert!(common::last_mail(&email).unwrap().contains(&format!("/confirm?code={}", code)));
     
     let resp = req!(confirm(&code), &mut app);
     assert_eq!(resp.status(), http::StatusCode::OK);
@@ -65,6 +71,15 @@ async fn account_api_test() {
     let resp_body = read_utf8_body(resp).await;
     assert!(resp_body.contains(&email));
 
+    // Notification settings
+    let resp = req!(notification_settings_edit(-1), session, &mut app);
+    assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+    let resp = req!(notification_settings_edit(20), session, &mut app);
+    assert_eq!(resp.status(), http::StatusCode::OK);
+    let resp = req!(notification_settings(), session, &mut app);
+    let settings: NotificationSettings = test::read_body_json(resp).await;
+    assert_eq!(settings.minutes, 20);
+
     let resp = req!(whoami(), &mut app);
     assert_eq!(resp.status(), http::StatusCode::OK);
 
@@ -0,0 +1,78 @@
+mod common;
+use clup::api::ticket::TokensResponse;
+use clup::models::booking::{BookingAvailability, BookingResponse};
+use clup::setup_db;
+use clup::utils::encoding::encode_serial;
+use clup::utils::tests::{test_department, test_schedule, test_shop, tomorrow_at};
+use common::requests::*;
+
+use actix_web::http::StatusCode;
+use actix_web::test;
+use chrono::{Duration, TimeZone, Utc};
+
+#[actix_rt::test]
+async fn booking_test() -> sqlx::Result<()> {
+    let mut app = setup_app!();
+
+    let (_, _, session) = quick_create_customer!(&mut app);
+
+    let start = Utc.from_utc_datetime(&tomorrow_at(10, 0));
+    let end = start + Duration::minutes(30);
+
+    let (s0, d0, d1) = async {
+        let conn = setup_db(&std::env::var("DATABASE_URL").unwrap()).await;
+        let sid = test_shop(&conn).await.unwrap();
+        test_schedule(&conn, sid, start.naive_utc().date(), "09:00", "17:00").await.unwrap();
+        let did0 = test_department(&conn, sid, 5).await.unwrap();
+        let did1 = test_department(&conn, sid, 5).await.unwrap();
+        (encode_serial(sid), encode_serial(did0), encode_serial(did1))
+    }.await;
+
+    let r = req!(booking_new(&s0, &[&d0], start, end), &mut app); // No session
+    assert_eq!(r.status(), StatusCode::FORBIDDEN);
+
+    let r = req!(booking_new(&s0, &[&d0], end, start), &session, &mut app); // Negative duration
+    assert_eq!(r.status(), StatusCode::BAD_REQUEST);
+
+    let r = req!(booking_new(&s0, &[&d0], start - Duration::days(2), end - Duration::days(2)), &session, &mut app); // In the past
+    assert_eq!(r.status(), StatusCode::BAD_REQUEST);
+
+    let late = start + Duration::hours(8);
+    let r = req!(booking_new(&s0, &[&d0], late, late + Duration::minutes(30)), &session, &mut app); // After closing
+    assert_eq!(r.status(), StatusCode::BAD_REQUEST);
+
+    let r = req!(booking_new(&s0, &[&d0, &d1], start, end), &session, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    let booking: BookingResponse = test::read_body_json(r).await;
+    assert_eq!(booking.shop_id, s0);
+    assert_eq!(booking.start_time, start);
+    assert_eq!(booking.end_time, end);
+    assert_eq!(booking.department_ids.len(), 2);
+
+    let r = req!(booking_new(&s0, &[&d1], start, end), &session, &mut app); // Overlapping
+    assert_eq!(r.status(), StatusCode::BAD_REQUEST);
+
+    let r = req!(booking_availability(&s0, start.naive_utc().date()), &session, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    let avail: Vec<BookingAvailability> = test::read_body_json(r).await;
+    assert_eq!(avail.len(), 2 * 16); // 8 hours in 30 minutes slots for 2 departments
+    for a in avail {
+        let booked = a.time.open() == start.naive_utc().time();
+        assert_eq!(a.available, if booked {4} else {5});
+    }
+
+    let r = req!(tokens(), &session, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    let toks: TokensResponse = test::read_body_json(r).await;
+    assert_eq!(toks.tickets.len(), 0);
+    assert_eq!(toks.bookings, vec![booking.clone()]);
+
+    let r = req!(booking_cancel(&booking.uid), &session, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+
+    let r = req!(tokens(), &session, &mut app);
+    let toks: TokensResponse = test::read_body_json(r).await;
+    assert_eq!(toks.bookings.len(), 0);
+
+    Ok(())
+}
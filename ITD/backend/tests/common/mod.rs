@@ -10,6 +10,8 @@ macro_rules! setup_app {
         dotenv::dotenv().ok();
         let conn_url = env::var("DATABASE_URL").expect("DATABASE_URL environment variable must be set");
         let db_pool = clup::setup_db(&conn_url).await;
+        let events = clup::events::ShopEvents::new();
+        actix_web::rt::spawn(events.clone().listen(db_pool.clone()));
 
         let redis_url = env::var("REDIS_URL").expect("REDIS_URL environment variable must be set");
         let session_key = env::var("SESSION_KEY").expect("SESSION_KEY environment variable must be set");
@@ -17,11 +19,18 @@ macro_rules! setup_app {
 
         actix_web::test::init_service(actix_web::App::new()
             .data(db_pool.clone())
+            .data(events)
+            .data(Box::new(clup::travel::StraightLineProvider::default()) as Box<dyn clup::travel::TravelTimeProvider>)
+            .data(clup::config::Config::default())
+            .app_data(actix_web::web::Data::from(common::test_mailer()))
+            .wrap(api::session_guard::SessionGuard)
             .wrap(actix_redis::RedisSession::new(&redis_url, &key))
             .wrap(actix_web::middleware::Logger::default())
+            .configure(api::error::config)
             .configure(api::account::endpoints)
             .configure(api::ticket::endpoints)
             .configure(api::shop::endpoints)
+            .configure(api::booking::endpoints)
             .service(actix_web::web::scope("/staff").configure(api::staff::endpoints))
             .service(actix_web::web::scope("/dev").configure(api::dev::endpoints))
         ).await
@@ -34,7 +43,8 @@ macro_rules! quick_create_customer {
         use rand::{RngCore, thread_rng};
         let (email, password) = (format!("{:x}@test.com", thread_rng().next_u64()), format!("{:x}", thread_rng().next_u64()));
         let r = req!(register(&email, &password), $app);
-        let code = read_utf8_body(r).await;
+        assert_eq!(r.status(), actix_web::http::StatusCode::OK);
+        let code = common::mail_code(&email);
         let r = req!(confirm(&code), $app);
         assert_eq!(r.status(), actix_web::http::StatusCode::OK);
         let r = req!(login(&email, &password, None), $app);
@@ -70,6 +80,43 @@ macro_rules! quick_create_staff {
     }};
 }
 
+#[macro_export]
+macro_rules! quick_create_manager {
+    ($app:expr, $shop_id:expr) => {{
+        use rand::{RngCore, thread_rng};
+        let (email, password) = (format!("{:x}@test.com", thread_rng().next_u64()), format!("{:x}", thread_rng().next_u64()));
+        let r = req!(create_manager(&email, &password, $shop_id), $app);
+        assert_eq!(r.status(), actix_web::http::StatusCode::OK);
+        let r = req!(staff_login(&email, &password, None), $app);
+        assert_eq!(r.status(), actix_web::http::StatusCode::OK);
+        let cookies = r.headers().get("Set-Cookie").unwrap();
+        let session = common::extract_session_cookie(cookies.to_str().unwrap()).unwrap().to_owned();
+
+        (email, password, session)
+    }};
+}
+
+/// Add a shop as the manager with session `$cookies`, returns the shop and the session of its new manager
+#[macro_export]
+macro_rules! quick_add_shop {
+    ($name:expr, $location:expr, $cookies:expr, $app:expr) => {{
+        use rand::{RngCore, thread_rng};
+        let (email, password) = (format!("{:x}@test.com", thread_rng().next_u64()), format!("{:x}", thread_rng().next_u64()));
+        let r = req!(manage_shop_add($name, $location, &email), $cookies, $app);
+        assert_eq!(r.status(), actix_web::http::StatusCode::OK);
+        let shop: clup::models::shop::ShopResponse = actix_web::test::read_body_json(r).await;
+
+        let r = req!(staff_activate(&common::mail_code(&email), &password), $app);
+        assert_eq!(r.status(), actix_web::http::StatusCode::OK);
+        let r = req!(staff_login(&email, &password, None), $app);
+        assert_eq!(r.status(), actix_web::http::StatusCode::OK);
+        let cookies = r.headers().get("Set-Cookie").unwrap();
+        let session = common::extract_session_cookie(cookies.to_str().unwrap()).unwrap().to_owned();
+
+        (shop, session)
+    }};
+}
+
 #[macro_export]
 macro_rules! ticket {
     ($shop:expr, [$($did:expr),+], $est:expr, $cookies:expr, $app:expr) => {{
@@ -89,6 +136,45 @@ macro_rules! ticket {
     }}
 }
 
+#[macro_export]
+macro_rules! error_code {
+    ($resp:expr) => {{
+        let e: clup::api::error::ErrorResponse = actix_web::test::read_body_json($resp).await;
+        e.code
+    }}
+}
+
+/// Maildir shared by the integration tests, emails are told apart by the recipient
+pub fn test_maildir() -> std::path::PathBuf {
+    std::env::temp_dir().join("clup-test-maildir")
+}
+
+pub fn test_mailer() -> std::sync::Arc<dyn clup::mail::Mailer> {
+    std::sync::Arc::new(clup::mail::MaildirMailer::new(test_maildir(), "CLup <noreply@localhost>".parse().unwrap()))
+}
+
+/// Last email delivered to `to`
+#[allow(dead_code)]
+pub fn last_mail(to: &str) -> Option<String> {
+    let header = format!("To: {}\r\n", to);
+    let mut files: Vec<_> = std::fs::read_dir(test_maildir().join("new")).ok()?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .collect();
+    files.sort();
+    files.iter().rev()
+        .filter_map(|f| std::fs::read_to_string(f).ok())
+        .find(|m| m.contains(&header))
+}
+
+/// Code in the last email delivered to `to`
+#[allow(dead_code)]
+pub fn mail_code(to: &str) -> String {
+    lazy_static::lazy_static!(
+        static ref RE: Regex = Regex::new("[0-9a-f]{64}").unwrap();
+    );
+    let mail = last_mail(to).unwrap_or_else(|| panic!("No email to {}", to));
+    RE.find(&mail).expect("No code in email").as_str().to_owned()
+}
 
 pub fn extract_session_cookie(cookies: &str) -> Option<&str> {
     lazy_static::lazy_static!(
@@ -2,10 +2,15 @@
 use actix_web::dev::{MessageBody, ServiceResponse};
 use actix_web::test::TestRequest;
 use actix_web::test;
-use clup::api::staff::LogTicketRequest;
-use clup::api::ticket::TicketNewRequest;
-use clup::api::account::{RequestLogin, RequestRegistration};
+use clup::api::staff::{ActivateRequest, LogTicketRequest, SubstituteTicketRequest};
+use clup::api::ticket::{TicketCancelRequest, TicketNewRequest};
+use clup::api::account::{NotificationSettings, PasswordChangeRequest, PasswordForgotRequest, PasswordResetRequest, RequestLogin, RequestRegistration};
 use clup::api::dev::{NewStaffRequest};
+use clup::api::manage::{CreateAccountRequest, DepartmentAddRequest, DepartmentEditRequest, ExceptionEditRequest, ScheduleEditRequest, ScheduleSlotRequest, ShopAddRequest, ShopRequest};
+use clup::api::booking::{BookingNewRequest, BookingCancelRequest};
+use clup::models::policy::ShopPolicy;
+use clup::models::shop::OpeningSlot;
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
 
 #[macro_export]
 macro_rules! req {
@@ -63,16 +68,33 @@ pub fn ticket_new(shop: &str, departments: &[&str], est_minutes: i32) -> TestReq
         .uri(&format!("/shop/{shop_id}/ticket/new", shop_id=shop))
         .set_json(&TicketNewRequest {
             department_ids: departments.iter().map(|&s|String::from(s)).collect(),
-            est_minutes,
+            est_minutes: Some(est_minutes),
         })
 } 
 
+/// New ticket without declaring the length of the visit
+#[allow(dead_code)]
+pub fn ticket_new_inferred(shop: &str, departments: &[&str]) -> TestRequest {
+    TestRequest::post()
+        .uri(&format!("/shop/{shop_id}/ticket/new", shop_id=shop))
+        .set_json(&TicketNewRequest {
+            department_ids: departments.iter().map(|&s|String::from(s)).collect(),
+            est_minutes: None,
+        })
+}
+
 #[allow(dead_code)]
 pub fn ticket_est(uid: &str) -> TestRequest {
     TestRequest::get()
         .uri(&format!("/ticket/est?uid={uid}", uid=uid))
 }
 
+#[allow(dead_code)]
+pub fn ticket_est_from(uid: &str, lat: f64, lon: f64) -> TestRequest {
+    TestRequest::get()
+        .uri(&format!("/ticket/est?uid={uid}&lat={lat}&lon={lon}", uid=uid, lat=lat, lon=lon))
+}
+
 #[allow(dead_code)]
 pub fn tokens() -> TestRequest {
     TestRequest::get()
@@ -93,6 +115,19 @@ pub fn create_staff(email: &str, password: &str, shop_id: &str) -> TestRequest {
             email :email.to_owned(),
             password: password.to_owned(),
             shop_id: shop_id.to_owned(),
+            manager: false,
+        })
+}
+
+#[allow(dead_code)]
+pub fn create_manager(email: &str, password: &str, shop_id: &str) -> TestRequest {
+    TestRequest::post()
+        .uri("/dev/new_staff")
+        .set_json(&NewStaffRequest{
+            email :email.to_owned(),
+            password: password.to_owned(),
+            shop_id: shop_id.to_owned(),
+            manager: true,
         })
 }
 
@@ -107,6 +142,17 @@ pub fn staff_login(email: &str, password: &str, remember: Option<bool>) -> TestR
         })
 }
 
+#[allow(dead_code)]
+pub fn ticket_new_substitute(shop_id: &str, departments: &[&str], est_minutes: i32, label: Option<&str>) -> TestRequest {
+    TestRequest::post()
+        .uri(&format!("/staff/shop/{shop_id}/ticket/new-substitute", shop_id=shop_id))
+        .set_json(&SubstituteTicketRequest {
+            department_ids: departments.iter().map(|&s|String::from(s)).collect(),
+            est_minutes,
+            label: label.map(String::from),
+        })
+}
+
 #[allow(dead_code)]
 pub fn log_entry(shop_id: &str, ticket_id: &str) -> TestRequest {
     TestRequest::post()
@@ -123,4 +169,299 @@ pub fn log_exit(shop_id: &str, ticket_id: &str) -> TestRequest {
         .set_json(&LogTicketRequest {
             uid: ticket_id.to_owned(),
         })
-}
\ No newline at end of file
+}
+#[allow(dead_code)]
+pub fn ticket_skip(shop_id: &str, ticket_id: &str) -> TestRequest {
+    TestRequest::post()
+        .uri(&format!("/staff/shop/{shop_id}/token/skip", shop_id=shop_id))
+        .set_json(&TicketCancelRequest {
+            uid: ticket_id.to_owned(),
+        })
+}
+
+#[allow(dead_code)]
+pub fn booking_new(shop: &str, departments: &[&str], start_time: DateTime<Utc>, end_time: DateTime<Utc>) -> TestRequest {
+    TestRequest::post()
+        .uri(&format!("/shop/{shop_id}/booking/new", shop_id=shop))
+        .set_json(&BookingNewRequest {
+            department_ids: departments.iter().map(|&s|String::from(s)).collect(),
+            start_time,
+            end_time,
+        })
+}
+
+#[allow(dead_code)]
+pub fn booking_cancel(uid: &str) -> TestRequest {
+    TestRequest::post()
+        .uri("/booking/cancel")
+        .set_json(&BookingCancelRequest {
+            uid: uid.to_owned(),
+        })
+}
+
+#[allow(dead_code)]
+pub fn booking_availability(shop: &str, day: NaiveDate) -> TestRequest {
+    TestRequest::get()
+        .uri(&format!("/shop/{shop_id}/booking/availability?day={day}", shop_id=shop, day=day))
+}
+
+#[allow(dead_code)]
+pub fn manage_shop_add(name: &str, location: &str, manager_email: &str) -> TestRequest {
+    TestRequest::post()
+        .uri("/staff/manage/shop/add")
+        .set_json(&ShopAddRequest {
+            shop: ShopRequest {
+                name: name.to_owned(),
+                description: String::new(),
+                image: None,
+                location: location.to_owned(),
+                position: None,
+                address: Default::default(),
+                time_zone: None,
+            },
+            manager_email: manager_email.to_owned(),
+        })
+}
+
+#[allow(dead_code)]
+pub fn manage_shop_list() -> TestRequest {
+    TestRequest::get()
+        .uri("/staff/manage/shop/list")
+}
+
+#[allow(dead_code)]
+pub fn manage_shop_edit(shop_id: &str, name: &str, description: &str, location: &str) -> TestRequest {
+    TestRequest::post()
+        .uri(&format!("/staff/manage/shop/{shop_id}/edit", shop_id=shop_id))
+        .set_json(&ShopRequest {
+            name: name.to_owned(),
+            description: description.to_owned(),
+            image: None,
+            location: location.to_owned(),
+            position: None,
+            address: Default::default(),
+            time_zone: None,
+        })
+}
+
+#[allow(dead_code)]
+pub fn manage_shop_time_zone(shop_id: &str, name: &str, location: &str, time_zone: &str) -> TestRequest {
+    TestRequest::post()
+        .uri(&format!("/staff/manage/shop/{shop_id}/edit", shop_id=shop_id))
+        .set_json(&ShopRequest {
+            name: name.to_owned(),
+            description: String::new(),
+            image: None,
+            location: location.to_owned(),
+            position: None,
+            address: Default::default(),
+            time_zone: Some(time_zone.to_owned()),
+        })
+}
+
+#[allow(dead_code)]
+pub fn manage_shop_hide(shop_id: &str) -> TestRequest {
+    TestRequest::post()
+        .uri(&format!("/staff/manage/shop/{shop_id}/hide", shop_id=shop_id))
+}
+
+#[allow(dead_code)]
+pub fn manage_shop_show(shop_id: &str) -> TestRequest {
+    TestRequest::post()
+        .uri(&format!("/staff/manage/shop/{shop_id}/show", shop_id=shop_id))
+}
+
+#[allow(dead_code)]
+pub fn manage_department_add(shop_id: &str, description: &str, capacity: i32) -> TestRequest {
+    TestRequest::post()
+        .uri(&format!("/staff/manage/shop/{shop_id}/department/add", shop_id=shop_id))
+        .set_json(&DepartmentAddRequest {
+            description: description.to_owned(),
+            capacity,
+        })
+}
+
+#[allow(dead_code)]
+pub fn manage_department_edit(shop_id: &str, uid: &str, description: Option<&str>, capacity: Option<i32>) -> TestRequest {
+    TestRequest::post()
+        .uri(&format!("/staff/manage/shop/{shop_id}/department/edit", shop_id=shop_id))
+        .set_json(&DepartmentEditRequest {
+            uid: uid.to_owned(),
+            description: description.map(String::from),
+            capacity,
+        })
+}
+
+#[allow(dead_code)]
+pub fn manage_schedule_edit(shop_id: &str, slots: &[(i16, NaiveTime, NaiveTime)]) -> TestRequest {
+    TestRequest::post()
+        .uri(&format!("/staff/manage/shop/{shop_id}/schedule/edit", shop_id=shop_id))
+        .set_json(&ScheduleEditRequest {
+            weekly_schedule: slots.iter()
+                .map(|&(dow, open, close)| ScheduleSlotRequest {dow, open, close})
+                .collect(),
+        })
+}
+
+#[allow(dead_code)]
+pub fn manage_exceptions(shop_id: &str) -> TestRequest {
+    TestRequest::get()
+        .uri(&format!("/staff/manage/shop/{shop_id}/schedule/exceptions", shop_id=shop_id))
+}
+
+#[allow(dead_code)]
+pub fn manage_exception_edit(shop_id: &str, day: NaiveDate, slots: Option<&[(NaiveTime, NaiveTime)]>) -> TestRequest {
+    TestRequest::post()
+        .uri(&format!("/staff/manage/shop/{shop_id}/schedule/exception/edit", shop_id=shop_id))
+        .set_json(&ExceptionEditRequest {
+            day,
+            slots: slots.map(|slots| slots.iter()
+                .map(|&(open, close)| OpeningSlot {open, close})
+                .collect()),
+        })
+}
+
+#[allow(dead_code)]
+pub fn shop_info(shop_id: &str) -> TestRequest {
+    TestRequest::get()
+        .uri(&format!("/shop/{shop_id}", shop_id=shop_id))
+}
+
+#[allow(dead_code)]
+pub fn search(q: &str) -> TestRequest {
+    TestRequest::get()
+        .uri(&format!("/search?q={q}", q=q))
+}
+
+#[allow(dead_code)]
+pub fn search_near(q: &str, lat: f64, lon: f64, radius_km: Option<f64>) -> TestRequest {
+    let radius = radius_km.map(|r| format!("&radius_km={}", r)).unwrap_or_default();
+    TestRequest::get()
+        .uri(&format!("/search?q={q}&lat={lat}&lon={lon}{radius}", q=q, lat=lat, lon=lon, radius=radius))
+}
+
+#[allow(dead_code)]
+pub fn manage_policy(shop_id: &str) -> TestRequest {
+    TestRequest::get()
+        .uri(&format!("/staff/manage/shop/{shop_id}/policy", shop_id=shop_id))
+}
+#[allow(dead_code)]
+pub fn manage_policy_edit(shop_id: &str, policy: &ShopPolicy) -> TestRequest {
+    TestRequest::post()
+        .uri(&format!("/staff/manage/shop/{shop_id}/policy/edit", shop_id=shop_id))
+        .set_json(policy)
+}
+
+#[allow(dead_code)]
+pub fn manage_create_account(shop_id: &str, email: &str, manager: Option<bool>) -> TestRequest {
+    TestRequest::post()
+        .uri(&format!("/staff/manage/create-account/{shop_id}", shop_id=shop_id))
+        .set_json(&CreateAccountRequest {
+            email: email.to_owned(),
+            manager,
+        })
+}
+
+#[allow(dead_code)]
+pub fn staff_status(shop_id: &str) -> TestRequest {
+    TestRequest::get()
+        .uri(&format!("/staff/shop/{shop_id}/status", shop_id=shop_id))
+}
+
+#[allow(dead_code)]
+pub fn ticket_actions(shop_id: &str) -> TestRequest {
+    TestRequest::get()
+        .uri(&format!("/staff/shop/{shop_id}/ticket/actions", shop_id=shop_id))
+}
+
+#[allow(dead_code)]
+pub fn password_forgot(email: &str, staff: bool) -> TestRequest {
+    TestRequest::post()
+        .uri(if staff { "/staff/password/forgot" } else { "/password/forgot" })
+        .set_json(&PasswordForgotRequest {
+            email: email.to_owned(),
+        })
+}
+
+#[allow(dead_code)]
+pub fn password_reset(code: &str, password: &str, staff: bool) -> TestRequest {
+    TestRequest::post()
+        .uri(if staff { "/staff/password/reset" } else { "/password/reset" })
+        .set_json(&PasswordResetRequest {
+            code: code.to_owned(),
+            password: password.to_owned(),
+        })
+}
+
+#[allow(dead_code)]
+pub fn password_change(current_password: &str, new_password: &str, staff: bool) -> TestRequest {
+    TestRequest::post()
+        .uri(if staff { "/staff/password/change" } else { "/password/change" })
+        .set_json(&PasswordChangeRequest {
+            current_password: current_password.to_owned(),
+            new_password: new_password.to_owned(),
+        })
+}
+
+#[allow(dead_code)]
+pub fn staff_activate(code: &str, password: &str) -> TestRequest {
+    TestRequest::post()
+        .uri("/staff/activate")
+        .set_json(&ActivateRequest {
+            code: code.to_owned(),
+            password: password.to_owned(),
+        })
+}
+
+#[allow(dead_code)]
+pub fn notification_settings() -> TestRequest {
+    TestRequest::get()
+        .uri("/notifications")
+}
+
+#[allow(dead_code)]
+pub fn notification_settings_edit(minutes: i32) -> TestRequest {
+    TestRequest::post()
+        .uri("/notifications")
+        .set_json(&NotificationSettings {minutes})
+}
+
+#[allow(dead_code)]
+pub fn ticket_cancel(uid: &str) -> TestRequest {
+    TestRequest::post()
+        .uri("/ticket/cancel")
+        .set_json(&TicketCancelRequest {
+            uid: uid.to_owned(),
+        })
+}
+
+#[allow(dead_code)]
+pub fn ticket_events(uid: &str) -> TestRequest {
+    TestRequest::get()
+        .uri(&format!("/ticket/{uid}/events", uid=uid))
+}
+
+/// Read the next chunk of a streaming response body
+#[allow(dead_code)]
+pub async fn next_chunk<B: MessageBody + Unpin>(body: &mut B) -> Option<String> {
+    let chunk = futures::future::poll_fn(|cx| std::pin::Pin::new(&mut *body).poll_next(cx)).await?;
+    Some(String::from_utf8(chunk.ok()?.to_vec()).unwrap())
+}
+
+#[allow(dead_code)]
+pub fn search_filtered(q: &str, max_wait: Option<f32>, limit: i64) -> TestRequest {
+    let max_wait = max_wait.map(|m| format!("&max_wait={}", m)).unwrap_or_default();
+    TestRequest::get()
+        .uri(&format!("/search?q={q}&limit={limit}{max_wait}", q=q, limit=limit, max_wait=max_wait))
+}
+
+#[allow(dead_code)]
+pub fn shop_keys(shop_id: &str) -> TestRequest {
+    TestRequest::get()
+        .uri(&format!("/shop/{shop_id}/keys", shop_id=shop_id))
+}
+#[allow(dead_code)]
+pub fn manage_keys_rotate(shop_id: &str) -> TestRequest {
+    TestRequest::post()
+        .uri(&format!("/staff/manage/shop/{shop_id}/keys/rotate", shop_id=shop_id))
+}
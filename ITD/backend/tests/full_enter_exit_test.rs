@@ -54,6 +54,7 @@ async fn full_enter_exit_test() -> sqlx::Result<()> {
 
     let r = req!(log_entry(&s0, &t2), &staff, &mut app); // C2 can't enter, not first in line
     assert_eq!(r.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(error_code!(r), "not_first");
 
     let r = req!(ticket_est(&t2), &customer_2, &mut app); // C2 checks queue
     assert_eq!(r.status(), StatusCode::OK);
@@ -66,6 +67,7 @@ async fn full_enter_exit_test() -> sqlx::Result<()> {
 
     let r = req!(log_entry(&s0, &t2), &staff, &mut app); // C2 can't enter, department is full
     assert_eq!(r.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(error_code!(r), "department_full");
 
     let r = req!(log_exit(&s0, &t0), &staff, &mut app); // C0 exits
     assert_eq!(r.status(), StatusCode::OK);
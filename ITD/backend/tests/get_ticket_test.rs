@@ -43,11 +43,17 @@ async fn get_ticket_test() -> sqlx::Result<()> {
 
     let r = req!(ticket_new(&s1, &[&d00, &d10], 15), &session, &mut app); // Dep from another shop should fail
     assert_eq!(r.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(error_code!(r), "constraint_violation");
 
     // Second ticket
     let ticket_2 = ticket!(&s1, [&d10], 20, &session, &mut app);
     check_tokens!([&ticket, &ticket_2], &session, &mut app);
 
+    // Visit length can be omitted
+    let (_, _, session_2) = quick_create_customer!(&mut app);
+    let r = req!(ticket_new_inferred(&s0, &[&d00]), &session_2, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+
     Ok(())
 }
 
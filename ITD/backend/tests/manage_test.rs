@@ -0,0 +1,173 @@
+mod common;
+use clup::api::error::ErrorResponse;
+use clup::models::shop::{DepartmentResponse, SearchPage, ShopResponse};
+use clup::setup_db;
+use clup::utils::encoding::encode_serial;
+use clup::utils::geo::Coordinates;
+use clup::utils::tests::test_shop;
+use common::requests::*;
+
+use actix_web::http::StatusCode;
+use actix_web::test;
+use chrono::NaiveTime;
+
+#[actix_rt::test]
+async fn manage_shop_test() -> sqlx::Result<()> {
+    let mut app = setup_app!();
+
+    let s0 = async {
+        let conn = setup_db(&std::env::var("DATABASE_URL").unwrap()).await;
+        encode_serial(test_shop(&conn).await.unwrap())
+    }.await;
+
+    let (_, _, customer) = quick_create_customer!(&mut app);
+    let (_, _, staff) = quick_create_staff!(&mut app, &s0);
+    let (manager_email, _, manager) = quick_create_manager!(&mut app, &s0);
+
+    let name = format!("Managed{:x}", rand::random::<u32>());
+
+    let r = req!(manage_shop_add(&name, "45.4642N,9.1900E", "owner@test.com"), &staff, &mut app); // Only managers can manage shops
+    assert_eq!(r.status(), StatusCode::FORBIDDEN);
+    let r = req!(manage_shop_list(), &customer, &mut app);
+    assert_eq!(r.status(), StatusCode::FORBIDDEN);
+
+    let r = req!(manage_shop_add("", "45.4642N,9.1900E", "owner@test.com"), &manager, &mut app);
+    assert_eq!(r.status(), StatusCode::BAD_REQUEST);
+    let r = req!(manage_shop_add(&name, "45.4642N,9.1900E", ""), &manager, &mut app);
+    assert_eq!(error_code!(r), "invalid_field");
+    let r = req!(manage_shop_add(&name, "45.4642N,9.1900E", &manager_email), &manager, &mut app); // The manager already has an account, no shop is created
+    assert_eq!(r.status(), StatusCode::BAD_REQUEST);
+
+    let (shop, owner) = quick_add_shop!(&name, "45.4642N,9.1900E", &manager, &mut app);
+    assert!(!shop.hidden);
+    assert_eq!(shop.position, Coordinates::new(45.4642, 9.19)); // Parsed from the location
+
+    let r = req!(manage_department_add(&shop.uid, "Fruit", 10), &owner, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    let dep: DepartmentResponse = test::read_body_json(r).await;
+
+    let r = req!(manage_department_add(&shop.uid, "Bread", -1), &owner, &mut app);
+    assert_eq!(r.status(), StatusCode::BAD_REQUEST);
+    let r = req!(manage_department_edit(&shop.uid, &dep.uid, Some("Vegetables"), Some(4)), &owner, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    let r = req!(manage_department_edit(&s0, &dep.uid, None, Some(4)), &manager, &mut app); // Department of another shop
+    assert_eq!(r.status(), StatusCode::BAD_REQUEST);
+
+    // Managers can only manage their own shop
+    let r = req!(manage_department_edit(&shop.uid, &dep.uid, None, Some(400)), &manager, &mut app);
+    assert_eq!(r.status(), StatusCode::FORBIDDEN);
+    let r = req!(manage_shop_hide(&shop.uid), &manager, &mut app);
+    assert_eq!(r.status(), StatusCode::FORBIDDEN);
+    let r = req!(manage_create_account(&shop.uid, "intruder@test.com", Some(true)), &manager, &mut app);
+    assert_eq!(r.status(), StatusCode::FORBIDDEN);
+    let r = req!(manage_policy(&s0), &owner, &mut app);
+    assert_eq!(r.status(), StatusCode::FORBIDDEN);
+
+    let t = |s| NaiveTime::parse_from_str(s, "%H:%M").unwrap();
+    let r = req!(manage_schedule_edit(&shop.uid, &[(1, t("09:00"), t("13:00")), (1, t("12:00"), t("18:00"))]), &owner, &mut app);
+    assert_eq!(r.status(), StatusCode::BAD_REQUEST);
+    let r = req!(manage_schedule_edit(&shop.uid, &[(1, t("09:00"), t("13:00")), (1, t("14:00"), t("18:00"))]), &owner, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+
+    let r = req!(manage_shop_edit(&shop.uid, &name, "Edited", "45.4642N,9.1900E"), &owner, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    let shop: ShopResponse = test::read_body_json(r).await;
+    assert_eq!(shop.description, "Edited");
+    assert_eq!(shop.departments.len(), 1);
+    assert_eq!(shop.weekly_schedule.len(), 2);
+
+    let r = req!(search(&name), &customer, &mut app);
+    let found: Vec<ShopResponse> = test::read_body_json::<SearchPage, _>(r).await.shops;
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].distance_km, None);
+
+    let r = req!(search_near(&name, 45.4700, 9.19, Some(1.)), &customer, &mut app);
+    let found: Vec<ShopResponse> = test::read_body_json::<SearchPage, _>(r).await.shops;
+    assert_eq!(found.len(), 1);
+    assert!(found[0].distance_km.unwrap() < 1.);
+    let r = req!(search_near(&name, 45.0703, 7.6869, Some(100.)), &customer, &mut app); // Too far
+    let found: Vec<ShopResponse> = test::read_body_json::<SearchPage, _>(r).await.shops;
+    assert!(found.is_empty());
+    let r = req!(search_near(&name, 95., 7.6869, None), &customer, &mut app);
+    assert_eq!(r.status(), StatusCode::BAD_REQUEST);
+    let r = req!(test::TestRequest::get().uri(&format!("/search?q={}&cursor=nothex", name)), &customer, &mut app);
+    assert_eq!(r.status(), StatusCode::BAD_REQUEST);
+    let r = req!(test::TestRequest::get().uri(&format!("/search?q={}&limit=0", name)), &customer, &mut app);
+    assert_eq!(r.status(), StatusCode::BAD_REQUEST);
+    let e: ErrorResponse = test::read_body_json(r).await;
+    assert_eq!(e.code, "invalid_field");
+    assert_eq!(e.fields[0].field, "limit");
+    let r = req!(test::TestRequest::get().uri(&format!("/search?q={}&limit=many", name)), &customer, &mut app);
+    assert_eq!(r.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(error_code!(r), "invalid_request");
+
+    let r = req!(manage_shop_hide(&shop.uid), &owner, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    let r = req!(search(&name), &customer, &mut app); // Hidden shops are not in search results
+    let found: Vec<ShopResponse> = test::read_body_json::<SearchPage, _>(r).await.shops;
+    assert!(found.is_empty());
+
+    let r = req!(manage_shop_list(), &owner, &mut app); // But they are listed for their managers
+    let all: Vec<ShopResponse> = test::read_body_json(r).await;
+    assert!(all.iter().any(|s| s.uid == shop.uid && s.hidden));
+    let r = req!(manage_shop_list(), &manager, &mut app); // And not for the managers of other shops
+    let all: Vec<ShopResponse> = test::read_body_json(r).await;
+    assert!(all.iter().all(|s| s.uid != shop.uid));
+    assert!(all.iter().any(|s| s.uid == s0));
+
+    let r = req!(manage_shop_show(&shop.uid), &owner, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    let r = req!(search(&name), &customer, &mut app);
+    let found: Vec<ShopResponse> = test::read_body_json::<SearchPage, _>(r).await.shops;
+    assert_eq!(found.len(), 1);
+
+    Ok(())
+}
+
+#[actix_rt::test]
+async fn staff_activation_test() -> sqlx::Result<()> {
+    let mut app = setup_app!();
+
+    let (s0, s1) = async {
+        let conn = setup_db(&std::env::var("DATABASE_URL").unwrap()).await;
+        (encode_serial(test_shop(&conn).await.unwrap()), encode_serial(test_shop(&conn).await.unwrap()))
+    }.await;
+
+    let (_, _, staff) = quick_create_staff!(&mut app, &s0);
+    let (_, _, manager) = quick_create_manager!(&mut app, &s0);
+    let (_, _, other_manager) = quick_create_manager!(&mut app, &s1);
+
+    let (email, password) = (format!("{:x}@test.com", rand::random::<u64>()), "newstaffpassword");
+
+    let r = req!(manage_create_account(&s0, &email, None), &staff, &mut app); // Only managers can create accounts
+    assert_eq!(r.status(), StatusCode::FORBIDDEN);
+
+    let r = req!(manage_create_account(&s0, &email, None), &manager, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    assert!(read_utf8_body(r).await.is_empty()); // The code is only sent by email
+    let code = common::mail_code(&email);
+
+    let r = req!(manage_create_account(&s1, &email, Some(true)), &other_manager, &mut app); // Pending for another shop
+    assert_eq!(error_code!(r), "already_exists");
+    assert_eq!(common::mail_code(&email), code);
+
+    let r = req!(staff_login(&email, password, None), &mut app); // Cannot login before activation
+    assert_eq!(r.status(), StatusCode::BAD_REQUEST);
+
+    let r = req!(staff_activate(&code, password), &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    let r = req!(staff_activate(&code, password), &mut app); // Codes can only be used once
+    assert_eq!(r.status(), StatusCode::BAD_REQUEST);
+
+    let r = req!(staff_login(&email, password, None), &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    let cookies = r.headers().get("Set-Cookie").unwrap();
+    let session = common::extract_session_cookie(cookies.to_str().unwrap()).unwrap().to_owned();
+    let r = req!(whoami_staff(), &session, &mut app);
+    assert!(read_utf8_body(r).await.contains(&s0)); // Member of the shop that sent the first invite
+
+    let r = req!(manage_create_account(&s0, &email, None), &manager, &mut app); // Account already exists
+    assert_eq!(r.status(), StatusCode::BAD_REQUEST);
+
+    Ok(())
+}
@@ -0,0 +1,109 @@
+mod common;
+use clup::api::occupancy::{DepartmentDelta, OccupancyMessage};
+use clup::api::staff;
+use clup::events::ShopEvents;
+use clup::models::ticket::TicketResponse;
+use clup::setup_db;
+use clup::utils::encoding::encode_serial;
+use clup::utils::tests::{test_department, test_shop};
+use common::requests::*;
+
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App};
+use actix_web::client::Client;
+use actix_web_actors::ws;
+use futures::{Stream, StreamExt};
+use sqlx::PgPool;
+
+/// Start a server with the staff endpoints, sharing the sessions of the test app
+fn start_server(events: ShopEvents) -> test::TestServer {
+    let conn_url = std::env::var("DATABASE_URL").unwrap();
+    let redis_url = std::env::var("REDIS_URL").unwrap();
+    let key = hex::decode(std::env::var("SESSION_KEY").unwrap()).unwrap();
+    test::start(move || {
+        App::new()
+            .data(PgPool::connect_lazy(&conn_url).unwrap())
+            .data(events.clone())
+            .wrap(actix_redis::RedisSession::new(&redis_url, &key))
+            .service(web::scope("/staff").configure(staff::endpoints))
+    })
+}
+
+/// Read the next message pushed by the server, skipping control frames
+async fn next_message<S>(framed: &mut S) -> OccupancyMessage
+    where S: Stream<Item = Result<ws::Frame, ws::ProtocolError>> + Unpin
+{
+    loop {
+        match framed.next().await {
+            Some(Ok(ws::Frame::Text(text))) => return serde_json::from_slice(&text).unwrap(),
+            Some(Ok(_)) => continue,
+            r => panic!("Connection closed: {:?}", r),
+        }
+    }
+}
+
+fn assert_delta(msg: OccupancyMessage, expected_departments: Vec<DepartmentDelta>, expected_queue: usize) {
+    match msg {
+        OccupancyMessage::Delta { departments, queue } => {
+            assert_eq!(departments, expected_departments);
+            assert_eq!(queue, expected_queue);
+        }
+        m => panic!("Expected delta, got {:?}", m),
+    }
+}
+
+#[actix_rt::test]
+async fn occupancy_live_test() -> sqlx::Result<()> {
+    let mut app = setup_app!();
+    let conn = setup_db(&std::env::var("DATABASE_URL").unwrap()).await;
+    let events = ShopEvents::new();
+    actix_web::rt::spawn(events.clone().listen(conn.clone()));
+    let srv = start_server(events);
+
+    let sid = test_shop(&conn).await.unwrap();
+    let did0 = test_department(&conn, sid, 2).await.unwrap();
+    let (s0, d0) = (encode_serial(sid), encode_serial(did0));
+
+    let (_, _, customer_0) = quick_create_customer!(&mut app);
+    let (_, _, customer_1) = quick_create_customer!(&mut app);
+    let (_, _, staff) = quick_create_staff!(&mut app, &s0);
+    let url = srv.url(&format!("/staff/shop/{}/status/live", s0));
+
+    let r = Client::new().ws(&url).connect().await; // Only staff of the shop can connect
+    assert!(r.is_err());
+
+    let (_, mut framed) = Client::new().ws(&url)
+        .header("Cookie", staff.clone())
+        .connect().await
+        .unwrap();
+
+    match next_message(&mut framed).await {
+        OccupancyMessage::Snapshot { departments, queue } => {
+            assert_eq!(departments.len(), 1);
+            assert_eq!(departments[0].department.uid, d0);
+            assert_eq!(departments[0].occupancy, 0);
+            assert_eq!(queue, 0);
+        }
+        m => panic!("Expected snapshot, got {:?}", m),
+    }
+
+    let t0 = ticket!(&s0, [&d0], 15, &customer_0, &mut app);
+    assert_delta(next_message(&mut framed).await, vec![], 1);
+
+    let r = req!(log_entry(&s0, &t0.uid), &staff, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    assert_delta(next_message(&mut framed).await, vec![DepartmentDelta { uid: d0.clone(), occupancy: 1, change: 1 }], 0);
+
+    let t1 = ticket!(&s0, [&d0], 15, &customer_1, &mut app);
+    assert_delta(next_message(&mut framed).await, vec![], 1);
+
+    let r = req!(ticket_skip(&s0, &t1.uid), &staff, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    assert_delta(next_message(&mut framed).await, vec![], 0);
+
+    let r = req!(log_exit(&s0, &t0.uid), &staff, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    assert_delta(next_message(&mut framed).await, vec![DepartmentDelta { uid: d0.clone(), occupancy: 0, change: -1 }], 0);
+
+    Ok(())
+}
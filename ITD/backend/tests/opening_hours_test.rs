@@ -0,0 +1,67 @@
+mod common;
+use clup::api::ticket::TicketEstResponse;
+use clup::models::ticket::TicketResponse;
+use clup::setup_db;
+use clup::utils::encoding::encode_serial;
+use clup::utils::tests::{test_department, test_shop, tomorrow_at};
+use common::requests::*;
+
+use actix_web::http::StatusCode;
+use actix_web::test;
+use chrono::{Datelike, NaiveTime, TimeZone, Utc};
+
+#[actix_rt::test]
+async fn opening_hours_test() -> sqlx::Result<()> {
+    let mut app = setup_app!();
+
+    let (s0, d0) = async {
+        let conn = setup_db(&std::env::var("DATABASE_URL").unwrap()).await;
+        let sid = test_shop(&conn).await.unwrap();
+        let did0 = test_department(&conn, sid, 10).await.unwrap();
+        (encode_serial(sid), encode_serial(did0))
+    }.await;
+
+    let (_, _, customer_0) = quick_create_customer!(&mut app);
+    let (_, _, customer_1) = quick_create_customer!(&mut app);
+    let (_, _, staff) = quick_create_staff!(&mut app, &s0);
+    let (_, _, manager) = quick_create_manager!(&mut app, &s0);
+
+    let t = |s: &str| NaiveTime::parse_from_str(s, "%H:%M:%S").unwrap();
+
+    // Without a weekly schedule the shop is always open
+    let t0 = ticket!(&s0, [&d0], 15, &customer_0, &mut app);
+
+    // Only open tomorrow
+    let opening = tomorrow_at(9, 0);
+    let tomorrow = opening.weekday().number_from_monday() as i16;
+    let r = req!(manage_schedule_edit(&s0, &[(tomorrow, t("09:00:00"), t("17:00:00"))]), &manager, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+
+    let r = req!(ticket_new(&s0, &[&d0], 15), &customer_1, &mut app);
+    assert_eq!(error_code!(r), "shop_closed");
+    let r = req!(ticket_new_substitute(&s0, &[&d0], 15, None), &staff, &mut app);
+    assert_eq!(error_code!(r), "shop_closed");
+    let r = req!(log_entry(&s0, &t0.uid), &staff, &mut app);
+    assert_eq!(error_code!(r), "shop_closed");
+
+    // The queue does not move until the shop opens
+    let r = req!(ticket_est(&t0.uid), &customer_0, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    let est: TicketEstResponse = test::read_body_json(r).await;
+    assert_eq!(est.est, Utc.from_utc_datetime(&opening));
+    let r = req!(shop_queue(&s0), &customer_1, &mut app);
+    let est: TicketEstResponse = test::read_body_json(r).await;
+    assert_eq!(est.people, 1);
+    assert!(est.est >= Utc.from_utc_datetime(&opening));
+
+    // Open all day, every day
+    let all_week: Vec<_> = (1..=7).map(|dow| (dow, t("00:00:00"), t("23:59:59"))).collect();
+    let r = req!(manage_schedule_edit(&s0, &all_week), &manager, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+
+    let r = req!(log_entry(&s0, &t0.uid), &staff, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    let _ = ticket!(&s0, [&d0], 15, &customer_1, &mut app);
+
+    Ok(())
+}
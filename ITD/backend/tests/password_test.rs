@@ -0,0 +1,112 @@
+mod common;
+use clup::setup_db;
+use clup::utils::encoding::encode_serial;
+use clup::utils::tests::test_shop;
+use common::requests::*;
+
+use actix_web::http::StatusCode;
+use actix_web::test;
+
+macro_rules! login_session {
+    ($req:expr, $app:expr) => {{
+        let r = req!($req, $app);
+        assert_eq!(r.status(), StatusCode::OK);
+        let cookies = r.headers().get("Set-Cookie").unwrap();
+        common::extract_session_cookie(cookies.to_str().unwrap()).unwrap().to_owned()
+    }};
+}
+
+#[actix_rt::test]
+async fn customer_password_test() {
+    let mut app = setup_app!();
+
+    let (email, password, s1) = quick_create_customer!(&mut app);
+    let s2 = login_session!(login(&email, &password, None), &mut app);
+
+    // Change password
+    let r = req!(password_change(&password, "new password", false), &mut app);
+    assert_eq!(r.status(), StatusCode::FORBIDDEN);
+    let r = req!(password_change("wrong password", "new password", false), &s1, &mut app);
+    assert_eq!(error_code!(r), "invalid_credentials");
+    let r = req!(password_change(&password, "", false), &s1, &mut app);
+    assert_eq!(error_code!(r), "invalid_field");
+
+    let r = req!(password_change(&password, "new password", false), &s1, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+
+    let r = req!(whoami(), &s1, &mut app); // The session used to change password is still valid
+    assert!(read_utf8_body(r).await.contains(&email));
+    let r = req!(whoami(), &s2, &mut app);
+    assert!(!read_utf8_body(r).await.contains(&email));
+
+    let r = req!(login(&email, &password, None), &mut app);
+    assert_eq!(r.status(), StatusCode::BAD_REQUEST);
+    let s2 = login_session!(login(&email, "new password", None), &mut app);
+
+    // Reset password
+    let r = req!(password_forgot("nobody@test.com", false), &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    assert!(read_utf8_body(r).await.is_empty());
+
+    let r = req!(password_forgot(&email, false), &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    assert!(read_utf8_body(r).await.is_empty());
+    let code = common::mail_code(&email);
+
+    let r = req!(password_reset("xyz", "reset password", false), &mut app);
+    assert_eq!(error_code!(r), "invalid_field");
+    let r = req!(password_reset(&"00".repeat(32), "reset password", false), &mut app);
+    assert_eq!(error_code!(r), "invalid_code");
+    let r = req!(password_reset(&code, "reset password", true), &mut app); // Customer codes are not valid for staff
+    assert_eq!(error_code!(r), "invalid_code");
+
+    let r = req!(password_reset(&code, "reset password", false), &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    let r = req!(password_reset(&code, "another password", false), &mut app); // Codes are single use
+    assert_eq!(error_code!(r), "invalid_code");
+
+    for s in [&s1, &s2].iter() {
+        let r = req!(whoami(), *s, &mut app);
+        assert!(!read_utf8_body(r).await.contains(&email));
+    }
+    let s3 = login_session!(login(&email, "reset password", None), &mut app);
+    let r = req!(whoami(), &s3, &mut app);
+    assert!(read_utf8_body(r).await.contains(&email));
+}
+
+#[actix_rt::test]
+async fn staff_password_test() {
+    let mut app = setup_app!();
+
+    let s0 = async {
+        let conn = setup_db(&std::env::var("DATABASE_URL").unwrap()).await;
+        encode_serial(test_shop(&conn).await.unwrap())
+    }.await;
+
+    let (email, password, s1) = quick_create_staff!(&mut app, &s0);
+    let s2 = login_session!(staff_login(&email, &password, None), &mut app);
+
+    let r = req!(password_change("wrong password", "new password", true), &s1, &mut app);
+    assert_eq!(error_code!(r), "invalid_credentials");
+    let r = req!(password_change(&password, "new password", true), &s1, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+
+    let r = req!(whoami_staff(), &s1, &mut app);
+    assert!(read_utf8_body(r).await.contains(&email));
+    let r = req!(staff_status(&s0), &s2, &mut app);
+    assert_eq!(r.status(), StatusCode::FORBIDDEN);
+
+    let r = req!(password_forgot(&email, true), &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    let code = common::mail_code(&email);
+    let r = req!(password_reset(&code, "reset password", true), &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+
+    let r = req!(staff_status(&s0), &s1, &mut app);
+    assert_eq!(r.status(), StatusCode::FORBIDDEN);
+    let r = req!(staff_login(&email, "new password", None), &mut app);
+    assert_eq!(r.status(), StatusCode::BAD_REQUEST);
+    let s3 = login_session!(staff_login(&email, "reset password", None), &mut app);
+    let r = req!(staff_status(&s0), &s3, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+}
@@ -0,0 +1,76 @@
+mod common;
+use clup::models::shop::{EFFECTIVE_HOURS_DAYS, ScheduleException, ShopResponse};
+use clup::models::ticket::TicketResponse;
+use clup::setup_db;
+use clup::utils::encoding::encode_serial;
+use clup::utils::tests::{test_department, test_shop, tomorrow_at};
+use common::requests::*;
+
+use actix_web::http::StatusCode;
+use actix_web::test;
+use chrono::{Duration, NaiveTime, TimeZone, Utc};
+
+#[actix_rt::test]
+async fn schedule_exception_test() -> sqlx::Result<()> {
+    let mut app = setup_app!();
+
+    let (s0, d0) = async {
+        let conn = setup_db(&std::env::var("DATABASE_URL").unwrap()).await;
+        let sid = test_shop(&conn).await.unwrap();
+        let did0 = test_department(&conn, sid, 10).await.unwrap();
+        (encode_serial(sid), encode_serial(did0))
+    }.await;
+
+    let (_, _, customer) = quick_create_customer!(&mut app);
+    let (_, _, staff) = quick_create_staff!(&mut app, &s0);
+    let (_, _, manager) = quick_create_manager!(&mut app, &s0);
+
+    let t = |s: &str| NaiveTime::parse_from_str(s, "%H:%M").unwrap();
+    let today = Utc::now().naive_utc().date();
+    let tomorrow = tomorrow_at(0, 0).date();
+
+    let r = req!(manage_exceptions(&s0), &manager, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    assert_eq!(test::read_body_json::<Vec<ScheduleException>, _>(r).await, vec![]);
+
+    let r = req!(manage_exception_edit(&s0, today, Some(&[])), &staff, &mut app); // Only managers can edit exceptions
+    assert_eq!(r.status(), StatusCode::FORBIDDEN);
+    let r = req!(manage_exception_edit(&s0, today - Duration::days(1), Some(&[])), &manager, &mut app);
+    assert_eq!(error_code!(r), "invalid_field");
+    let r = req!(manage_exception_edit(&s0, tomorrow, Some(&[(t("12:00"), t("10:00"))])), &manager, &mut app);
+    assert_eq!(error_code!(r), "invalid_field");
+
+    // Closed today, open only in the morning tomorrow
+    let r = req!(manage_exception_edit(&s0, today, Some(&[])), &manager, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    let r = req!(manage_exception_edit(&s0, tomorrow, Some(&[(t("10:00"), t("12:00"))])), &manager, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    let exceptions: Vec<ScheduleException> = test::read_body_json(r).await;
+    assert_eq!(exceptions.iter().map(|e| e.day).collect::<Vec<_>>(), vec![today, tomorrow]);
+
+    let r = req!(shop_info(&s0), &customer, &mut app);
+    let shop: ShopResponse = test::read_body_json(r).await;
+    assert_eq!(shop.effective_hours.len(), EFFECTIVE_HOURS_DAYS as usize);
+    assert_eq!((shop.effective_hours[0].day, shop.effective_hours[0].exception), (today, true));
+    assert!(shop.effective_hours[0].slots.is_empty());
+    assert_eq!(shop.effective_hours[1].slots.len(), 1);
+    assert!(!shop.effective_hours[2].exception);
+
+    let r = req!(ticket_new(&s0, &[&d0], 15), &customer, &mut app);
+    assert_eq!(error_code!(r), "shop_closed");
+
+    let start = Utc.from_utc_datetime(&tomorrow_at(10, 0));
+    let r = req!(booking_new(&s0, &[&d0], start, start + Duration::minutes(30)), &customer, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    let r = req!(booking_new(&s0, &[&d0], start + Duration::hours(3), start + Duration::hours(4)), &customer, &mut app);
+    assert_eq!(error_code!(r), "shop_closed");
+
+    // Back to the weekly schedule, that is not set
+    for day in [today, tomorrow].iter() {
+        let r = req!(manage_exception_edit(&s0, *day, None), &manager, &mut app);
+        assert_eq!(r.status(), StatusCode::OK);
+    }
+    let _ = ticket!(&s0, [&d0], 15, &customer, &mut app);
+
+    Ok(())
+}
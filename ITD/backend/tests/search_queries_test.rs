@@ -0,0 +1,90 @@
+mod common;
+use clup::models::shop::{DepartmentResponse, SearchPage};
+use clup::setup_db;
+use clup::utils::encoding::encode_serial;
+use clup::utils::tests::test_shop;
+use common::requests::*;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use actix_web::http::StatusCode;
+use actix_web::test;
+use chrono::NaiveTime;
+use log::{Level, Log, Metadata, Record};
+
+/// Counts the statements executed by sqlx, which logs every query under the `sqlx::query` target
+struct QueryCounter;
+
+static QUERIES: AtomicUsize = AtomicUsize::new(0);
+
+impl Log for QueryCounter {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.target() == "sqlx::query"
+    }
+
+    fn log(&self, record: &Record) {
+        // Connections are pinged when taken from the pool, those are not queries issued by the search
+        if self.enabled(record.metadata()) && !record.args().to_string().starts_with("/* SQLx ping") {
+            QUERIES.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static COUNTER: QueryCounter = QueryCounter;
+
+macro_rules! count_queries {
+    ($req:expr, $cookies:expr, $app:expr) => {{
+        let before = QUERIES.load(Ordering::SeqCst);
+        let r = req!($req, $cookies, $app);
+        assert_eq!(r.status(), StatusCode::OK);
+        let page: SearchPage = test::read_body_json(r).await;
+        (page, QUERIES.load(Ordering::SeqCst) - before)
+    }};
+}
+
+/// The number of queries issued by a search must not depend on the number of shops in the results
+#[actix_rt::test]
+async fn search_queries_test() -> sqlx::Result<()> {
+    log::set_logger(&COUNTER).unwrap();
+    log::set_max_level(Level::Info.to_level_filter());
+
+    let mut app = setup_app!();
+
+    let s0 = async {
+        let conn = setup_db(&std::env::var("DATABASE_URL").unwrap()).await;
+        encode_serial(test_shop(&conn).await.unwrap())
+    }.await;
+    let (_, _, customer) = quick_create_customer!(&mut app);
+    let (_, _, manager) = quick_create_manager!(&mut app, &s0);
+
+    let tag = format!("Bench{:x}", rand::random::<u32>());
+    let t = |s| NaiveTime::parse_from_str(s, "%H:%M").unwrap();
+    let mut results = Vec::new();
+    for n in [2, 10, 40].iter() {
+        while results.len() < *n {
+            let (shop, manager) = quick_add_shop!(&format!("{} {}", tag, results.len()), "45.4642N,9.1900E", &manager, &mut app);
+            for d in ["Fruit", "Bread"].iter() {
+                let r = req!(manage_department_add(&shop.uid, d, 10), &manager, &mut app);
+                let _: DepartmentResponse = test::read_body_json(r).await;
+            }
+            let r = req!(manage_schedule_edit(&shop.uid, &[(1, t("09:00"), t("13:00")), (2, t("09:00"), t("18:00"))]), &manager, &mut app);
+            assert_eq!(r.status(), StatusCode::OK);
+            results.push(shop.uid);
+        }
+
+        let (page, queries) = count_queries!(search_filtered(&tag, None, 100), &customer, &mut app);
+        assert_eq!(page.shops.len(), *n);
+        assert!(page.shops.iter().all(|s| s.departments.len() == 2 && s.weekly_schedule.len() == 2));
+        // Session version, search, schedules, exceptions, departments
+        assert_eq!(queries, 5, "Search of {} shops issued {} queries", n, queries);
+
+        let (page, queries_wait) = count_queries!(search_filtered(&tag, Some(60.), 100), &customer, &mut app);
+        assert_eq!(page.shops.len(), *n);
+        // Plus the estimates and the queue lengths
+        assert_eq!(queries_wait, 7, "Search of {} shops with wait filter issued {} queries", n, queries_wait);
+    }
+
+    Ok(())
+}
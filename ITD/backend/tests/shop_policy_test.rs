@@ -0,0 +1,51 @@
+mod common;
+use clup::models::policy::ShopPolicy;
+use clup::models::ticket::TicketResponse;
+use clup::setup_db;
+use clup::utils::encoding::encode_serial;
+use clup::utils::tests::{test_department, test_shop};
+use common::requests::*;
+
+use actix_web::http::StatusCode;
+use actix_web::test;
+
+#[actix_rt::test]
+async fn shop_policy_test() -> sqlx::Result<()> {
+    let mut app = setup_app!();
+
+    let (s0, d0) = async {
+        let conn = setup_db(&std::env::var("DATABASE_URL").unwrap()).await;
+        let sid = test_shop(&conn).await.unwrap();
+        let did0 = test_department(&conn, sid, 10).await.unwrap();
+        (encode_serial(sid), encode_serial(did0))
+    }.await;
+
+    let (_, _, customer_0) = quick_create_customer!(&mut app);
+    let (_, _, customer_1) = quick_create_customer!(&mut app);
+    let (_, _, staff) = quick_create_staff!(&mut app, &s0);
+    let (_, _, manager) = quick_create_manager!(&mut app, &s0);
+
+    let r = req!(manage_policy(&s0), &manager, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    let policy: ShopPolicy = test::read_body_json(r).await;
+    assert_eq!(policy, ShopPolicy::default());
+
+    let policy = ShopPolicy { max_queue: Some(1), walk_in: false, ..Default::default() };
+    let r = req!(manage_policy_edit(&s0, &policy), &staff, &mut app); // Only managers can edit the policy
+    assert_eq!(r.status(), StatusCode::FORBIDDEN);
+    let r = req!(manage_policy_edit(&s0, &ShopPolicy { max_queue: Some(-1), ..Default::default() }), &manager, &mut app);
+    assert_eq!(error_code!(r), "invalid_field");
+    let r = req!(manage_policy_edit(&s0, &policy), &manager, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+
+    let r = req!(manage_policy(&s0), &manager, &mut app);
+    assert_eq!(test::read_body_json::<ShopPolicy, _>(r).await, policy);
+
+    let _ = ticket!(&s0, [&d0], 15, &customer_0, &mut app);
+    let r = req!(ticket_new(&s0, &[&d0], 15), &customer_1, &mut app);
+    assert_eq!(error_code!(r), "queue_full");
+    let r = req!(ticket_new_substitute(&s0, &[&d0], 15, None), &staff, &mut app);
+    assert_eq!(error_code!(r), "walk_in_disabled");
+
+    Ok(())
+}
@@ -0,0 +1,77 @@
+mod common;
+use clup::models::signing::{self, PublicKeyResponse};
+use clup::models::ticket::TicketResponse;
+use clup::setup_db;
+use clup::utils::encoding::encode_serial;
+use clup::utils::tests::{test_department, test_shop};
+use common::requests::*;
+
+use actix_web::http::StatusCode;
+use actix_web::test;
+
+#[actix_rt::test]
+async fn signed_token_test() -> sqlx::Result<()> {
+    let mut app = setup_app!();
+
+    let (s0, s1, d0, d1) = async {
+        let conn = setup_db(&std::env::var("DATABASE_URL").unwrap()).await;
+        let sid0 = test_shop(&conn).await.unwrap();
+        let sid1 = test_shop(&conn).await.unwrap();
+        let did0 = test_department(&conn, sid0, 10).await.unwrap();
+        let did1 = test_department(&conn, sid1, 10).await.unwrap();
+        (encode_serial(sid0), encode_serial(sid1), encode_serial(did0), encode_serial(did1))
+    }.await;
+
+    let (_, _, customer) = quick_create_customer!(&mut app);
+    let (_, _, staff) = quick_create_staff!(&mut app, &s0);
+    let (_, _, manager) = quick_create_manager!(&mut app, &s0);
+
+    let t0 = ticket!(&s0, [&d0], 15, &customer, &mut app);
+    let token = t0.token.clone().unwrap();
+
+    // The token verifies offline with the published keys
+    let r = req!(shop_keys(&s0), &customer, &mut app);
+    let keys: Vec<PublicKeyResponse> = test::read_body_json(r).await;
+    assert_eq!(keys.len(), 1);
+    let payload = signing::verify(&token, &keys).unwrap();
+    assert_eq!((payload.ticket, payload.shop, payload.departments), (t0.uid.clone(), s0.clone(), vec![d0.clone()]));
+    assert_eq!(payload.expiry, t0.expiration.timestamp());
+
+    // Tokens are listed with the tickets
+    let r = req!(tokens(), &customer, &mut app);
+    let body: serde_json::Value = test::read_body_json(r).await;
+    assert_eq!(body["tickets"][0]["token"], token.as_str());
+
+    // Tampered tokens and tokens of other shops are refused
+    let (payload, signature) = token.split_once('.').unwrap();
+    let r = req!(log_entry(&s0, &format!("{}.{}", payload, &signature[1..])), &staff, &mut app);
+    assert_eq!(error_code!(r), "invalid_token");
+    let t1 = ticket!(&s1, [&d1], 15, &customer, &mut app);
+    let r = req!(log_entry(&s0, &t1.token.unwrap()), &staff, &mut app);
+    assert_eq!(error_code!(r), "invalid_token");
+
+    // Rotation keeps the old key published until the tokens it signed expire
+    let r = req!(manage_keys_rotate(&s0), &staff, &mut app);
+    assert_eq!(r.status(), StatusCode::FORBIDDEN);
+    let r = req!(manage_keys_rotate(&s0), &manager, &mut app);
+    let rotated: Vec<PublicKeyResponse> = test::read_body_json(r).await;
+    assert_eq!(rotated.len(), 2);
+    assert_eq!(rotated[1].id, keys[0].id);
+    assert!(rotated[1].retired.is_some());
+    assert!(signing::verify(&token, &rotated).is_some());
+
+    let r = req!(log_entry(&s0, &token), &staff, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    let r = req!(log_exit(&s0, &token), &staff, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+
+    let t2 = ticket!(&s0, [&d0], 15, &customer, &mut app);
+    let payload = signing::verify(&t2.token.unwrap(), &rotated).unwrap();
+    assert_eq!(payload.key, rotated[0].id);
+
+    // Plain uids are still accepted
+    let r = req!(log_entry(&s0, &t2.uid), &staff, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+
+    Ok(())
+}
@@ -0,0 +1,58 @@
+mod common;
+use clup::models::ticket::TicketResponse;
+use clup::setup_db;
+use clup::utils::encoding::encode_serial;
+use clup::utils::tests::{test_department, test_shop};
+use common::requests::*;
+
+use actix_web::http::StatusCode;
+use actix_web::test;
+
+#[actix_rt::test]
+async fn substitute_ticket_test() -> sqlx::Result<()> {
+    let mut app = setup_app!();
+
+    let (s0, d0) = async {
+        let conn = setup_db(&std::env::var("DATABASE_URL").unwrap()).await;
+        let sid = test_shop(&conn).await.unwrap();
+        let did0 = test_department(&conn, sid, 1).await.unwrap();
+        (encode_serial(sid), encode_serial(did0))
+    }.await;
+
+    let (_, _, customer) = quick_create_customer!(&mut app);
+    let (_, _, staff) = quick_create_staff!(&mut app, &s0);
+
+    let r = req!(ticket_new_substitute(&s0, &[&d0], 15, Some("A1")), &customer, &mut app); // Customers cannot issue substitute tickets
+    assert_eq!(r.status(), StatusCode::FORBIDDEN);
+
+    let t0 = ticket!(&s0, [&d0], 15, &customer, &mut app);
+
+    let r = req!(ticket_new_substitute(&s0, &[&d0], 15, Some("A1")), &staff, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    let s1: TicketResponse = test::read_body_json(r).await;
+    assert!(s1.substitute);
+    assert!(!t0.substitute);
+    assert_eq!(Some("A1".to_string()), s1.label);
+
+    let r = req!(ticket_new_substitute(&s0, &[&d0], 15, None), &staff, &mut app); // Many substitute tickets can be issued
+    assert_eq!(r.status(), StatusCode::OK);
+
+    let r = req!(log_entry(&s0, &s1.uid), &staff, &mut app); // Substitute ticket waits its turn
+    assert_eq!(r.status(), StatusCode::BAD_REQUEST);
+
+    let r = req!(log_entry(&s0, &t0.uid), &staff, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    let r = req!(log_exit(&s0, &t0.uid), &staff, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+
+    let r = req!(log_entry(&s0, &s1.uid), &staff, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    let r = req!(log_exit(&s0, &s1.uid), &staff, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+
+    let r = req!(ticket_new_substitute(&s0, &["invalid"], 15, None), &staff, &mut app);
+    assert_eq!(r.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(error_code!(r), "invalid_id");
+
+    Ok(())
+}
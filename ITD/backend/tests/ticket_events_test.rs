@@ -0,0 +1,63 @@
+mod common;
+use clup::models::ticket::TicketResponse;
+use clup::setup_db;
+use clup::utils::encoding::encode_serial;
+use clup::utils::tests::{test_department, test_shop};
+use common::requests::*;
+
+use actix_web::http::StatusCode;
+use actix_web::test;
+
+#[actix_rt::test]
+async fn ticket_events_test() -> sqlx::Result<()> {
+    let mut app = setup_app!();
+
+    let (s0, d0) = async {
+        let conn = setup_db(&std::env::var("DATABASE_URL").unwrap()).await;
+        let sid = test_shop(&conn).await.unwrap();
+        let did0 = test_department(&conn, sid, 1).await.unwrap();
+        (encode_serial(sid), encode_serial(did0))
+    }.await;
+
+    let (_, _, customer_0) = quick_create_customer!(&mut app);
+    let (_, _, customer_1) = quick_create_customer!(&mut app);
+    let (_, _, customer_2) = quick_create_customer!(&mut app);
+    let (_, _, staff) = quick_create_staff!(&mut app, &s0);
+
+    let t0 = ticket!(&s0, [&d0], 15, &customer_0, &mut app);
+    let t1 = ticket!(&s0, [&d0], 15, &customer_1, &mut app);
+
+    let r = req!(ticket_events(&t1.uid), &customer_0, &mut app); // Only the owner can follow a ticket
+    assert_eq!(r.status(), StatusCode::BAD_REQUEST);
+
+    let mut r = req!(ticket_events(&t1.uid), &customer_1, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    let mut body = r.take_body();
+
+    let event = next_chunk(&mut body).await.unwrap();
+    assert!(event.starts_with("event: position\n"));
+    assert!(event.contains(r#""people":1"#));
+
+    let r = req!(log_entry(&s0, &t0.uid), &staff, &mut app); // C0 enters, C1 is now first
+    assert_eq!(r.status(), StatusCode::OK);
+
+    let event = next_chunk(&mut body).await.unwrap();
+    assert!(event.starts_with("event: first\n"));
+    assert!(event.contains(r#""people":0"#));
+    assert_eq!(next_chunk(&mut body).await, None);
+
+    let t2 = ticket!(&s0, [&d0], 15, &customer_2, &mut app);
+    let mut r = req!(ticket_events(&t2.uid), &customer_2, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    let mut body = r.take_body();
+    let event = next_chunk(&mut body).await.unwrap();
+    assert!(event.contains(r#""people":1"#));
+
+    let r = req!(ticket_cancel(&t2.uid), &customer_2, &mut app); // C2 gives up
+    assert_eq!(r.status(), StatusCode::OK);
+    let event = next_chunk(&mut body).await.unwrap();
+    assert!(event.starts_with("event: expired\n"));
+    assert_eq!(next_chunk(&mut body).await, None);
+
+    Ok(())
+}
@@ -0,0 +1,58 @@
+mod common;
+use clup::cleanup::TicketJanitor;
+use clup::models::shop::DepartmentOccupancyResponse;
+use clup::models::ticket::{TicketAction, TicketActionResponse, TicketResponse};
+use clup::setup_db;
+use clup::utils::encoding::{decode_serial, encode_serial};
+use clup::utils::tests::{test_department, test_shop};
+use common::requests::*;
+
+use actix_web::http::StatusCode;
+use actix_web::test;
+use std::time::Duration;
+
+#[actix_rt::test]
+async fn ticket_janitor_test() -> sqlx::Result<()> {
+    let mut app = setup_app!();
+
+    let conn = setup_db(&std::env::var("DATABASE_URL").unwrap()).await;
+    let sid = test_shop(&conn).await.unwrap();
+    let s0 = encode_serial(sid);
+    let d0 = encode_serial(test_department(&conn, sid, 1).await.unwrap());
+
+    let (_, _, customer) = quick_create_customer!(&mut app);
+    let (_, _, staff) = quick_create_staff!(&mut app, &s0);
+
+    let t0 = ticket!(&s0, [&d0], 15, &customer, &mut app);
+    let r = req!(log_entry(&s0, &t0.uid), &staff, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+
+    let r = req!(ticket_actions(&s0), &staff, &mut app);
+    let actions: Vec<TicketActionResponse> = test::read_body_json(r).await;
+    assert!(actions.is_empty());
+
+    // The exit is never logged
+    sqlx::query!("UPDATE ticket SET entry = entry - INTERVAL '5 hours' WHERE id = $1", decode_serial(&t0.uid).unwrap())
+        .execute(&conn).await?;
+    let (_, closed) = TicketJanitor::new(conn.clone(), 240, Duration::from_secs(60)).run_once().await?;
+    assert!(closed >= 1);
+
+    let r = req!(ticket_actions(&s0), &customer, &mut app); // Only staff can see the actions
+    assert_eq!(r.status(), StatusCode::FORBIDDEN);
+
+    let r = req!(ticket_actions(&s0), &staff, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    let actions: Vec<TicketActionResponse> = test::read_body_json(r).await;
+    assert_eq!(actions.len(), 1);
+    assert_eq!(actions[0].ticket_uid, t0.uid);
+    assert_eq!(actions[0].action, TicketAction::AutoClosed);
+
+    let r = req!(staff_status(&s0), &staff, &mut app);
+    let status: Vec<DepartmentOccupancyResponse> = test::read_body_json(r).await;
+    assert_eq!(status[0].occupancy, 0);
+
+    let r = req!(log_exit(&s0, &t0.uid), &staff, &mut app); // Already closed
+    assert_eq!(r.status(), StatusCode::BAD_REQUEST);
+
+    Ok(())
+}
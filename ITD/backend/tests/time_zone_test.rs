@@ -0,0 +1,70 @@
+mod common;
+use clup::models::shop::{DepartmentResponse, ShopResponse};
+use clup::models::ticket::TicketResponse;
+use clup::setup_db;
+use clup::utils::encoding::encode_serial;
+use clup::utils::tests::test_shop;
+use common::requests::*;
+
+use actix_web::http::StatusCode;
+use actix_web::test;
+use chrono::{NaiveTime, Timelike, Utc};
+use chrono_tz::Tz;
+
+/// First of `zones` where the local hour is in `hours`, the zones are spread so that one always matches
+fn zone_at(hours: &[u32]) -> Tz {
+    let zones = [Tz::Pacific__Kiritimati, Tz::Asia__Tokyo, Tz::Asia__Kolkata, Tz::Europe__Rome, Tz::America__New_York, Tz::Pacific__Honolulu];
+    *zones.iter()
+        .find(|tz| hours.contains(&Utc::now().with_timezone(*tz).hour()))
+        .unwrap()
+}
+
+#[actix_rt::test]
+async fn time_zone_test() -> sqlx::Result<()> {
+    let mut app = setup_app!();
+
+    let s0 = async {
+        let conn = setup_db(&std::env::var("DATABASE_URL").unwrap()).await;
+        encode_serial(test_shop(&conn).await.unwrap())
+    }.await;
+
+    let (_, _, customer) = quick_create_customer!(&mut app);
+    let (_, _, manager) = quick_create_manager!(&mut app, &s0);
+
+    let name = format!("Zoned{:x}", rand::random::<u32>());
+    let location = "45.4642N,9.1900E";
+    let (shop, manager) = quick_add_shop!(&name, location, &manager, &mut app);
+    assert_eq!(shop.time_zone, "UTC");
+
+    let r = req!(manage_department_add(&shop.uid, "Fruit", 10), &manager, &mut app);
+    let dep: DepartmentResponse = test::read_body_json(r).await;
+    let t = |s| NaiveTime::parse_from_str(s, "%H:%M").unwrap();
+    let every_day: Vec<_> = (1..=7).map(|dow| (dow, t("09:00"), t("18:00"))).collect();
+    let r = req!(manage_schedule_edit(&shop.uid, &every_day), &manager, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+
+    let r = req!(manage_shop_time_zone(&shop.uid, &name, location, "Mars/Olympus_Mons"), &manager, &mut app);
+    assert_eq!(error_code!(r), "invalid_field");
+
+    // Closed at night in the local time of the shop
+    let night = zone_at(&[21, 22, 23, 0, 1, 2, 3, 4, 5]);
+    let r = req!(manage_shop_time_zone(&shop.uid, &name, location, night.name()), &manager, &mut app);
+    let edited: ShopResponse = test::read_body_json(r).await;
+    assert_eq!(edited.time_zone, night.name());
+    assert_eq!(edited.effective_hours[0].day, Utc::now().with_timezone(&night).date().naive_local());
+    let r = req!(ticket_new(&shop.uid, &[&dep.uid], 15), &customer, &mut app);
+    assert_eq!(error_code!(r), "shop_closed");
+
+    // Open during the day, at least two hours before closing
+    let day = zone_at(&[10, 11, 12, 13, 14, 15]);
+    let r = req!(manage_shop_time_zone(&shop.uid, &name, location, day.name()), &manager, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    let _ = ticket!(&shop.uid, [&dep.uid], 15, &customer, &mut app);
+
+    // The time zone is kept when omitted
+    let r = req!(manage_shop_edit(&shop.uid, &name, "Edited", location), &manager, &mut app);
+    let edited: ShopResponse = test::read_body_json(r).await;
+    assert_eq!(edited.time_zone, day.name());
+
+    Ok(())
+}
@@ -0,0 +1,61 @@
+mod common;
+use clup::api::ticket::TicketEstResponse;
+use clup::models::ticket::TicketResponse;
+use clup::setup_db;
+use clup::utils::encoding::encode_serial;
+use clup::utils::tests::{test_department, test_shop};
+use common::requests::*;
+
+use actix_web::http::StatusCode;
+use actix_web::test;
+use chrono::{Duration, Utc};
+
+#[actix_rt::test]
+async fn leave_by_test() -> sqlx::Result<()> {
+    let mut app = setup_app!();
+
+    let (s0, d0, s1, d1) = async {
+        let conn = setup_db(&std::env::var("DATABASE_URL").unwrap()).await;
+        let sid0 = test_shop(&conn).await.unwrap();
+        sqlx::query("UPDATE shop SET location = '45.4642N,9.1900E', lat = 45.4642, lon = 9.19 WHERE id = $1")
+            .bind(sid0)
+            .execute(&conn)
+            .await
+            .unwrap();
+        let did0 = test_department(&conn, sid0, 5).await.unwrap();
+        let sid1 = test_shop(&conn).await.unwrap(); // No coordinates
+        let did1 = test_department(&conn, sid1, 5).await.unwrap();
+        (encode_serial(sid0), encode_serial(did0), encode_serial(sid1), encode_serial(did1))
+    }.await;
+
+    let (_, _, customer) = quick_create_customer!(&mut app);
+    let t0 = ticket!(&s0, [&d0], 15, &customer, &mut app);
+    let t1 = ticket!(&s1, [&d1], 15, &customer, &mut app);
+
+    let r = req!(ticket_est(&t0.uid), &customer, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    let resp: TicketEstResponse = test::read_body_json(r).await;
+    assert_eq!(resp.leave_by, None);
+
+    let before = Utc::now();
+    let r = req!(ticket_est_from(&t0.uid, 45.4642, 9.2028), &customer, &mut app); // About 1km away
+    assert_eq!(r.status(), StatusCode::OK);
+    let resp: TicketEstResponse = test::read_body_json(r).await;
+    assert_eq!(resp.people, 0);
+    let leave_by = resp.leave_by.unwrap(); // First in line, should leave now
+    assert!(leave_by >= before - Duration::seconds(1));
+    assert!(leave_by <= Utc::now());
+
+    let r = req!(ticket_est_from(&t1.uid, 45.4642, 9.2028), &customer, &mut app);
+    assert_eq!(r.status(), StatusCode::OK);
+    let resp: TicketEstResponse = test::read_body_json(r).await;
+    assert_eq!(resp.leave_by, None);
+
+    let r = req!(ticket_est_from(&t0.uid, 95., 9.2028), &customer, &mut app);
+    assert_eq!(r.status(), StatusCode::BAD_REQUEST);
+
+    let r = req!(test::TestRequest::get().uri(&format!("/ticket/est?uid={}&lat=45.4642", t0.uid)), &customer, &mut app);
+    assert_eq!(r.status(), StatusCode::BAD_REQUEST);
+
+    Ok(())
+}